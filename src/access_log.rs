@@ -0,0 +1,129 @@
+//! One access-log record per request, emitted by the HTTP trace layer once
+//! the response status is known. Request attributes are recorded on the
+//! `access` span so structured (JSON) output carries them alongside the
+//! response fields of the event.
+
+use crate::auth::repository_in_path;
+use crate::metrics::CacheOutcome;
+use axum::http::{header, Request, Response};
+use std::time::Duration;
+use tracing::{field, Span};
+
+pub fn make_span<B>(request: &Request<B>) -> Span {
+    let path = request.uri().path();
+    let span = tracing::info_span!(
+        "access",
+        method = %request.method(),
+        path = %path,
+        repository = field::Empty,
+        bytes = field::Empty,
+        cache = field::Empty,
+    );
+    if let Some(repository) = repository_in_path(path) {
+        span.record("repository", repository);
+    }
+    span
+}
+
+/// Logs the final status and duration. Bytes served come from
+/// `Content-Length`, so streamed responses without one omit them; the cache
+/// outcome is set by pull handlers.
+pub fn on_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    if let Some(bytes) = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    {
+        span.record("bytes", bytes);
+    }
+    if let Some(outcome) = response.extensions().get::<CacheOutcome>() {
+        span.record(
+            "cache",
+            match outcome {
+                CacheOutcome::Hit => "hit",
+                CacheOutcome::Miss => "miss",
+            },
+        );
+    }
+
+    let _entered = span.enter();
+    tracing::info!(
+        target: "access_log",
+        status = response.status().as_u16(),
+        duration_ms = latency.as_secs_f64() * 1000.0,
+        "request completed"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tower_http::trace::TraceLayer;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_record_has_request_and_response_fields() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route(
+                "/v2/alpine/blobs/sha256:abc",
+                get(|| async {
+                    let mut response = axum::response::Response::new(Body::from("hello"));
+                    response
+                        .headers_mut()
+                        .insert(header::CONTENT_LENGTH, 5.into());
+                    response.extensions_mut().insert(CacheOutcome::Hit);
+                    response
+                }),
+            )
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(make_span)
+                    .on_response(on_response),
+            );
+        let request = Request::get("/v2/alpine/blobs/sha256:abc")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let record: serde_json::Value = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|record| record["target"] == "access_log")
+            .expect("access record");
+        assert_eq!(record["fields"]["status"], 200);
+        assert!(record["fields"]["duration_ms"].is_number());
+        let span = &record["span"];
+        assert_eq!(span["method"], "GET");
+        assert_eq!(span["path"], "/v2/alpine/blobs/sha256:abc");
+        assert_eq!(span["repository"], "alpine");
+        assert_eq!(span["bytes"], 5);
+        assert_eq!(span["cache"], "hit");
+    }
+}
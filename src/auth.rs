@@ -1,19 +1,49 @@
+use crate::config::{AuthConfig, Config, UpstreamAuth, User};
 use crate::error::{ProxyError, Result};
+use crate::ip_filter::client_ip;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
-    extract::{Request, State},
-    http::HeaderMap,
+    extract::{ConnectInfo, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, Uri},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use futures::future::BoxFuture;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tracing::warn;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: Option<usize>,
     pub access: AccessLevel,
+    /// Upstream credentials to use instead of the registry's configured
+    /// `auth`, so each user pulls with their own upstream identity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_auth: Option<UpstreamAuth>,
+}
+
+/// Subject of the claims given to requests without a token.
+pub const ANONYMOUS_SUBJECT: &str = "anonymous";
+
+impl Claims {
+    /// Claims for an unauthenticated pull from a public repository, granting
+    /// only that repository.
+    pub fn anonymous(repository: &str) -> Self {
+        Claims {
+            sub: ANONYMOUS_SUBJECT.to_string(),
+            exp: None,
+            access: AccessLevel::Repositories {
+                repos: vec![repository.to_string()],
+            },
+            upstream_auth: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,58 +54,459 @@ pub enum AccessLevel {
 }
 
 impl AccessLevel {
+    /// Lowercases every grant, so grants keep matching repository names
+    /// lowercased by `normalize_repository_case`.
+    pub fn lowercase_grants(&mut self) {
+        if let AccessLevel::Repositories { repos } = self {
+            for repo in repos {
+                *repo = repo.to_lowercase();
+            }
+        }
+    }
+
     pub fn can_access(&self, repository: &str) -> bool {
         match self {
             AccessLevel::All => true,
-            AccessLevel::Repositories { repos } => repos
-                .iter()
-                .any(|r| repository == r || repository.starts_with(&format!("{}/", r))),
+            AccessLevel::Repositories { repos } => {
+                repos.iter().any(|r| grant_matches(r, repository))
+            }
         }
     }
 }
 
+/// Matches a single repository grant. A grant ending in `*` matches any
+/// repository starting with the text before it (`team/*` covers `team/app`
+/// but not `teamother`); any other grant matches the repository itself and
+/// everything nested below it.
+fn grant_matches(grant: &str, repository: &str) -> bool {
+    match grant.strip_suffix('*') {
+        Some(prefix) => repository.len() > prefix.len() && repository.starts_with(prefix),
+        None => {
+            repository == grant
+                || repository
+                    .strip_prefix(grant)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }
+    }
+}
+
+/// Turns the credentials a request carries into the claims its access is
+/// checked against. [`JwtAuthenticator`] is the default; embedders can supply
+/// their own, for example to accept static API keys:
+///
+/// ```
+/// use axum::http::HeaderMap;
+/// use docker_registry_proxy::auth::{AccessLevel, Authenticator, Claims};
+/// use docker_registry_proxy::error::{ProxyError, Result};
+/// use futures::future::BoxFuture;
+///
+/// struct ApiKeys(Vec<(String, AccessLevel)>);
+///
+/// impl Authenticator for ApiKeys {
+///     fn authenticate<'a>(
+///         &'a self,
+///         headers: &'a HeaderMap,
+///     ) -> BoxFuture<'a, Result<Option<Claims>>> {
+///         Box::pin(async move {
+///             let Some(key) = headers.get("X-Api-Key") else {
+///                 return Ok(None);
+///             };
+///             let (name, access) = self
+///                 .0
+///                 .iter()
+///                 .find(|(name, _)| key.as_bytes() == name.as_bytes())
+///                 .ok_or_else(|| ProxyError::Unauthorized("Unknown API key".into()))?;
+///             Ok(Some(Claims {
+///                 sub: name.clone(),
+///                 exp: None,
+///                 access: access.clone(),
+///                 upstream_auth: None,
+///             }))
+///         })
+///     }
+/// }
+/// ```
+pub trait Authenticator: Send + Sync {
+    /// Returns `Ok(None)` when the request presents no credentials at all,
+    /// so pulls from public repositories can proceed anonymously, and an
+    /// error when it presents credentials that are not valid.
+    ///
+    /// A token passed as an `access_token` query parameter, where allowed,
+    /// arrives as an `Authorization: Bearer` header.
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap) -> BoxFuture<'a, Result<Option<Claims>>>;
+}
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    key_id: Option<String>,
+    key: DecodingKey,
+}
+
+/// Verifies bearer JWTs against the keys from the `[auth]` config.
+pub struct JwtAuthenticator {
+    keys: Vec<VerificationKey>,
+    validation: Validation,
+}
+
+impl JwtAuthenticator {
+    pub async fn from_config(config: &AuthConfig) -> anyhow::Result<Self> {
+        let mut keys = Vec::new();
+
+        if let Some(secret) = &config.jwt_secret {
+            keys.push(VerificationKey {
+                algorithm: Algorithm::HS256,
+                key_id: None,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            });
+        }
+
+        let algorithm = config.algorithm.unwrap_or(Algorithm::RS256);
+        for path in &config.public_keys {
+            let pem = std::fs::read(path).map_err(|e| {
+                anyhow::anyhow!("Failed to read public key {}: {}", path.display(), e)
+            })?;
+            let key = decoding_key_from_pem(algorithm, &pem).map_err(|e| {
+                anyhow::anyhow!("Failed to parse public key {}: {}", path.display(), e)
+            })?;
+            keys.push(VerificationKey {
+                algorithm,
+                key_id: None,
+                key,
+            });
+        }
+
+        if let Some(jwks_url) = &config.jwks_url {
+            let jwks: JwkSet = reqwest::get(jwks_url)
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let jwks_keys = keys_from_jwks(&jwks, algorithm);
+            if jwks_keys.is_empty() {
+                anyhow::bail!("JWKS at {} contains no usable keys", jwks_url);
+            }
+            keys.extend(jwks_keys);
+        }
+
+        if keys.is_empty() {
+            anyhow::bail!("No token verification keys configured");
+        }
+
+        Ok(Self {
+            keys,
+            validation: base_validation(config),
+        })
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap) -> BoxFuture<'a, Result<Option<Claims>>> {
+        Box::pin(async move {
+            extract_bearer_token(headers)
+                .map(|token| validate_token(&token, self))
+                .transpose()
+        })
+    }
+}
+
 pub struct AuthState {
-    pub jwt_secret: String,
+    authenticator: Arc<dyn Authenticator>,
+    realm: Option<String>,
+    service: String,
+    allow_query_token: bool,
+    lowercase_grants: bool,
+    trust_forwarded_for: bool,
+    /// Repository mappings, kept when any of them is public so requests
+    /// without a token can be checked against them.
+    repositories: Option<Config>,
+}
+
+impl AuthState {
+    /// Authenticates requests with JWTs verified by the configured keys.
+    pub async fn from_config(config: &AuthConfig) -> anyhow::Result<Self> {
+        let authenticator = JwtAuthenticator::from_config(config).await?;
+        Ok(Self::new(Arc::new(authenticator), config))
+    }
+
+    /// Authenticates requests with `authenticator`; `config` supplies the
+    /// realm and service of the challenge sent with a 401.
+    pub fn new(authenticator: Arc<dyn Authenticator>, config: &AuthConfig) -> Self {
+        Self {
+            authenticator,
+            realm: config.realm.clone(),
+            service: config.service.clone(),
+            allow_query_token: false,
+            lowercase_grants: false,
+            trust_forwarded_for: false,
+            repositories: None,
+        }
+    }
+
+    /// Accept tokens passed as an `access_token` query parameter when the
+    /// request has no `Authorization` header.
+    pub fn with_query_token(mut self, allowed: bool) -> Self {
+        self.allow_query_token = allowed;
+        self
+    }
+
+    /// Lowercase the repository grants of every token, for use with
+    /// `normalize_repository_case`.
+    pub fn with_lowercase_grants(mut self, lowercase: bool) -> Self {
+        self.lowercase_grants = lowercase;
+        self
+    }
+
+    /// Take the client address of anonymous requests from a trusted
+    /// `X-Forwarded-For` header, as for the IP allowlist.
+    pub fn with_trusted_forwarded_for(mut self, trusted: bool) -> Self {
+        self.trust_forwarded_for = trusted;
+        self
+    }
+
+    /// Let requests without a token pull from the repositories `config` marks
+    /// as public.
+    pub fn with_public_repositories(mut self, config: &Config) -> Self {
+        if config.repositories.iter().any(|repo| repo.public) {
+            self.repositories = Some(config.clone());
+        }
+        self
+    }
+
+    /// Claims for a tokenless request, if it is a pull from a public
+    /// repository.
+    fn anonymous_claims(&self, request: &Request) -> Option<Claims> {
+        let config = self.repositories.as_ref()?;
+        if !matches!(*request.method(), Method::GET | Method::HEAD) {
+            return None;
+        }
+        let repository = repository_in_path(request.uri().path())?;
+        config
+            .is_public_repository(repository)
+            .then(|| Claims::anonymous(&config.repository_key(repository)))
+    }
+}
+
+/// Claim checks shared by every key; the algorithm is filled in per key.
+fn base_validation(config: &AuthConfig) -> Validation {
+    let mut validation = Validation::default();
+    validation.validate_exp = true;
+    validation.validate_nbf = true;
+    validation.leeway = config.leeway_seconds;
+    validation.required_spec_claims.clear();
+
+    if config.require_exp {
+        validation.required_spec_claims.insert("exp".to_string());
+    }
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(&[issuer]);
+        validation.required_spec_claims.insert("iss".to_string());
+    }
+    match &config.audience {
+        Some(audience) => {
+            validation.set_audience(&[audience]);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        // Otherwise any `aud` a token carries would fail validation.
+        None => validation.validate_aud = false,
+    }
+
+    validation
+}
+
+fn decoding_key_from_pem(
+    algorithm: Algorithm,
+    pem: &[u8],
+) -> jsonwebtoken::errors::Result<DecodingKey> {
+    match algorithm {
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+        _ => DecodingKey::from_rsa_pem(pem),
+    }
+}
+
+/// Verification keys from a JWKS document. Keys that do not state their `alg`
+/// are used with `default_algorithm`. Only public keys are taken, each with an
+/// asymmetric algorithm of its own type: a symmetric `oct` key published in
+/// the document would let anyone who knows it mint tokens.
+fn keys_from_jwks(jwks: &JwkSet, default_algorithm: Algorithm) -> Vec<VerificationKey> {
+    jwks.keys
+        .iter()
+        .filter_map(|jwk| {
+            let algorithm = match jwk.common.key_algorithm {
+                Some(algorithm) => algorithm.to_string().parse().ok()?,
+                None => default_algorithm,
+            };
+            if !algorithm_fits_key(algorithm, &jwk.algorithm) {
+                warn!(
+                    "Ignoring JWKS key {:?}: {:?} cannot be verified with it",
+                    jwk.common.key_id, algorithm
+                );
+                return None;
+            }
+            let key = DecodingKey::from_jwk(jwk).ok()?;
+            Some(VerificationKey {
+                algorithm,
+                key_id: jwk.common.key_id.clone(),
+                key,
+            })
+        })
+        .collect()
+}
+
+/// Whether `algorithm` is an asymmetric algorithm for keys like `key`.
+fn algorithm_fits_key(algorithm: Algorithm, key: &AlgorithmParameters) -> bool {
+    match key {
+        AlgorithmParameters::RSA(_) => matches!(
+            algorithm,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+        ),
+        AlgorithmParameters::EllipticCurve(_) => {
+            matches!(algorithm, Algorithm::ES256 | Algorithm::ES384)
+        }
+        AlgorithmParameters::OctetKeyPair(_) => algorithm == Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => false,
+    }
 }
 
 pub async fn auth_middleware(
     State(state): State<Arc<AuthState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
     headers: HeaderMap,
     mut request: Request,
     next: Next,
-) -> Result<Response> {
-    let token = extract_bearer_token(&headers).ok_or_else(|| {
-        ProxyError::Unauthorized("Missing or invalid Authorization header".into())
-    })?;
+) -> Response {
+    let mut credentials = headers.clone();
+    if state.allow_query_token && !credentials.contains_key(header::AUTHORIZATION) {
+        if let Some(value) = query_token(request.uri())
+            .and_then(|token| HeaderValue::from_str(&format!("Bearer {}", token)).ok())
+        {
+            credentials.insert(header::AUTHORIZATION, value);
+        }
+    }
+    let claims = match state.authenticator.authenticate(&credentials).await {
+        Ok(Some(claims)) => Ok(claims),
+        Ok(None) => state.anonymous_claims(&request).ok_or_else(|| {
+            ProxyError::Unauthorized("Missing or invalid Authorization header".into())
+        }),
+        Err(e) => Err(e),
+    };
 
-    let claims = validate_token(&token, &state.jwt_secret)?;
+    match claims {
+        Ok(mut claims) => {
+            if state.lowercase_grants {
+                claims.access.lowercase_grants();
+            }
+            // Anonymous clients share a subject, so they are told apart by
+            // address to keep one from exhausting the per-subject limits of
+            // all others.
+            if claims.sub == ANONYMOUS_SUBJECT {
+                let client = client_ip(
+                    &headers,
+                    peer.map(|ConnectInfo(addr)| addr),
+                    state.trust_forwarded_for,
+                );
+                if let Some(ip) = client {
+                    claims.sub = format!("{}@{}", ANONYMOUS_SUBJECT, ip);
+                }
+            }
+            request.extensions_mut().insert(claims);
+            next.run(request).await
+        }
+        Err(e) => {
+            let mut response = e.into_response();
+            let path = request.uri().path();
+            let challenge = challenge(&state, &headers, path);
+            if let Ok(value) = HeaderValue::from_str(&challenge) {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, value);
+            }
+            response
+        }
+    }
+}
+
+/// Bearer challenge telling clients where to obtain a token, scoped to the
+/// repository when the request targets one.
+fn challenge(state: &AuthState, headers: &HeaderMap, path: &str) -> String {
+    let realm = state.realm.clone().unwrap_or_else(|| {
+        let scheme = headers
+            .get("X-Forwarded-Proto")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("http");
+        let host = headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("localhost");
+        format!("{}://{}/token", scheme, host)
+    });
 
-    request.extensions_mut().insert(claims);
+    let mut challenge = format!(r#"Bearer realm="{}",service="{}""#, realm, state.service);
+    if let Some(repository) = repository_in_path(path) {
+        challenge.push_str(&format!(r#",scope="repository:{}:pull""#, repository));
+    }
+    challenge
+}
 
-    Ok(next.run(request).await)
+/// Repository named in a `/v2/<name>/manifests|blobs|tags/...` path. The
+/// name ends at the last marker, since its own components may be
+/// `manifests`, `blobs` and so on.
+pub fn repository_in_path(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("/v2/")?;
+    ["/manifests/", "/blobs/", "/tags/", "/referrers/"]
+        .iter()
+        .filter_map(|marker| rest.rfind(marker))
+        .max()
+        .map(|end| &rest[..end])
 }
 
 fn extract_bearer_token(headers: &HeaderMap) -> Option<String> {
     headers
         .get("Authorization")
         .and_then(|value| value.to_str().ok())
-        .and_then(|value| {
-            if value.starts_with("Bearer ") {
-                Some(value[7..].to_string())
-            } else {
-                None
-            }
-        })
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
 }
 
-fn validate_token(token: &str, secret: &str) -> Result<Claims> {
-    let mut validation = Validation::default();
-    validation.required_spec_claims.clear();
-    let decoding_key = DecodingKey::from_secret(secret.as_bytes());
+fn query_token(uri: &Uri) -> Option<String> {
+    let Query(mut params) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+    params
+        .remove("access_token")
+        .filter(|token| !token.is_empty())
+}
+
+fn validate_token(token: &str, state: &JwtAuthenticator) -> Result<Claims> {
+    let header = decode_header(token)
+        .map_err(|e| ProxyError::Unauthorized(format!("Invalid token: {}", e)))?;
+
+    let candidates = state.keys.iter().filter(|k| {
+        k.algorithm == header.alg
+            && match (&header.kid, &k.key_id) {
+                (Some(kid), Some(key_id)) => kid == key_id,
+                _ => true,
+            }
+    });
 
-    decode::<Claims>(token, &decoding_key, &validation)
-        .map(|data| data.claims)
-        .map_err(|e| ProxyError::Unauthorized(format!("Invalid token: {}", e)))
+    let mut last_error = None;
+    for candidate in candidates {
+        let mut validation = state.validation.clone();
+        validation.algorithms = vec![candidate.algorithm];
+
+        match decode::<Claims>(token, &candidate.key, &validation) {
+            Ok(data) => return Ok(data.claims),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(ProxyError::Unauthorized(match last_error {
+        Some(e) => format!("Invalid token: {}", e),
+        None => format!("Invalid token: unsupported algorithm {:?}", header.alg),
+    }))
 }
 
 pub fn check_repository_access(claims: &Claims, repository: &str) -> Result<()> {
@@ -89,11 +520,111 @@ pub fn check_repository_access(claims: &Claims, repository: &str) -> Result<()>
     }
 }
 
+/// Returns the user whose name and password match. Both hash schemes compare
+/// in constant time. For unknown usernames the password is still checked
+/// against the first user's hash, with its algorithm and cost, and the result
+/// discarded, so response timing does not reveal which accounts exist.
+pub fn verify_credentials<'a>(
+    users: &'a [User],
+    username: &str,
+    password: &str,
+) -> Option<&'a User> {
+    let Some(user) = users.iter().find(|user| user.username == username) else {
+        if let Some(decoy) = users.first() {
+            verify_password(&decoy.password_hash, password);
+        }
+        return None;
+    };
+    verify_password(&user.password_hash, password).then_some(user)
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    if is_bcrypt(hash) {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+/// Checks that `hash` is a well-formed argon2 (PHC string) or bcrypt hash, so
+/// a plaintext password or a typo in the config is caught at startup.
+pub fn check_password_hash(hash: &str) -> std::result::Result<(), String> {
+    if is_bcrypt(hash) {
+        return hash
+            .parse::<bcrypt::HashParts>()
+            .map(|_| ())
+            .map_err(|e| format!("malformed bcrypt hash: {}", e));
+    }
+    let parsed = PasswordHash::new(hash).map_err(|e| {
+        format!(
+            "expected an argon2 or bcrypt hash, not a plaintext password ({})",
+            e
+        )
+    })?;
+    if !parsed.algorithm.as_str().starts_with("argon2") {
+        return Err(format!("unsupported hash algorithm '{}'", parsed.algorithm));
+    }
+    if parsed.hash.is_none() || parsed.salt.is_none() {
+        return Err("argon2 hash is missing its salt or hash value".into());
+    }
+    argon2::Params::try_from(&parsed)
+        .map(|_| ())
+        .map_err(|e| format!("malformed argon2 hash: {}", e))
+}
+
+pub fn check_admin_access(claims: &Claims) -> Result<()> {
+    match claims.access {
+        AccessLevel::All => Ok(()),
+        AccessLevel::Repositories { .. } => Err(ProxyError::Forbidden(
+            "Administrative access requires an unrestricted token".into(),
+        )),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
     use jsonwebtoken::{encode, EncodingKey, Header};
 
+    fn secret_state(secret: &str) -> JwtAuthenticator {
+        secret_state_with(secret, &AuthConfig::default())
+    }
+
+    fn secret_state_with(secret: &str, config: &AuthConfig) -> JwtAuthenticator {
+        JwtAuthenticator {
+            keys: vec![VerificationKey {
+                algorithm: Algorithm::HS256,
+                key_id: None,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            }],
+            validation: base_validation(config),
+        }
+    }
+
+    fn now() -> usize {
+        chrono::Utc::now().timestamp() as usize
+    }
+
+    fn sign(header: Header, key: &EncodingKey) -> String {
+        let claims = Claims {
+            sub: "user123".to_string(),
+            exp: None,
+            upstream_auth: None,
+            access: AccessLevel::All,
+        };
+        encode(&header, &claims, key).unwrap()
+    }
+
     #[test]
     fn test_access_level_all() {
         let access = AccessLevel::All;
@@ -114,12 +645,30 @@ mod tests {
         assert!(!access.can_access("team/other"));
     }
 
+    #[test]
+    fn test_access_level_glob_patterns() {
+        let access = AccessLevel::Repositories {
+            repos: vec!["team/*".to_string(), "library/al*".to_string()],
+        };
+
+        assert!(access.can_access("team/anything"));
+        assert!(access.can_access("team/nested/app"));
+        assert!(!access.can_access("team"));
+        assert!(!access.can_access("team/"));
+        assert!(!access.can_access("teamother"));
+        assert!(!access.can_access("teamother/app"));
+
+        assert!(access.can_access("library/alpine"));
+        assert!(!access.can_access("library/nginx"));
+    }
+
     #[test]
     fn test_token_validation() {
         let secret = "test-secret";
         let claims = Claims {
             sub: "user123".to_string(),
             exp: None,
+            upstream_auth: None,
             access: AccessLevel::All,
         };
 
@@ -130,7 +679,7 @@ mod tests {
         )
         .unwrap();
 
-        let result = validate_token(&token, secret);
+        let result = validate_token(&token, &secret_state(secret));
         assert!(result.is_ok());
 
         let decoded = result.unwrap();
@@ -139,15 +688,206 @@ mod tests {
 
     #[test]
     fn test_invalid_token() {
-        let result = validate_token("invalid.token.here", "secret");
+        let result = validate_token("invalid.token.here", &secret_state("secret"));
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_rs256_token_validation() {
+        let config = AuthConfig {
+            algorithm: Some(Algorithm::RS256),
+            public_keys: vec!["tests/fixtures/rsa_public.pem".into()],
+            ..Default::default()
+        };
+        let state = JwtAuthenticator::from_config(&config).await.unwrap();
+
+        let key =
+            EncodingKey::from_rsa_pem(include_bytes!("../tests/fixtures/rsa_private.pem")).unwrap();
+        let token = sign(Header::new(Algorithm::RS256), &key);
+        assert_eq!(validate_token(&token, &state).unwrap().sub, "user123");
+
+        let hs_token = sign(Header::default(), &EncodingKey::from_secret(b"secret"));
+        assert!(validate_token(&hs_token, &state).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_es256_key_rotation() {
+        let config = AuthConfig {
+            jwt_secret: Some("legacy-secret".to_string()),
+            algorithm: Some(Algorithm::ES256),
+            public_keys: vec!["tests/fixtures/ec_public.pem".into()],
+            ..Default::default()
+        };
+        let state = JwtAuthenticator::from_config(&config).await.unwrap();
+
+        let key =
+            EncodingKey::from_ec_pem(include_bytes!("../tests/fixtures/ec_private.pem")).unwrap();
+        let token = sign(Header::new(Algorithm::ES256), &key);
+        assert!(validate_token(&token, &state).is_ok());
+
+        let legacy = sign(
+            Header::default(),
+            &EncodingKey::from_secret(b"legacy-secret"),
+        );
+        assert!(validate_token(&legacy, &state).is_ok());
+    }
+
+    #[test]
+    fn test_expired_token_rejected() {
+        let secret = "test-secret";
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        let claims = Claims {
+            sub: "user".to_string(),
+            exp: Some(now() - 3600),
+            upstream_auth: None,
+            access: AccessLevel::All,
+        };
+        let token = encode(&Header::default(), &claims, &key).unwrap();
+
+        assert!(validate_token(&token, &secret_state(secret)).is_err());
+    }
+
+    #[test]
+    fn test_exp_and_nbf_leeway() {
+        let secret = "test-secret";
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        let state = secret_state_with(
+            secret,
+            &AuthConfig {
+                leeway_seconds: 30,
+                ..Default::default()
+            },
+        );
+        let token = |claim: &str, value: usize| {
+            let claims = serde_json::json!({
+                "sub": "user",
+                "access": { "type": "all" },
+                claim: value,
+            });
+            encode(&Header::default(), &claims, &key).unwrap()
+        };
+
+        assert!(validate_token(&token("exp", now() - 10), &state).is_ok());
+        assert!(validate_token(&token("exp", now() - 60), &state).is_err());
+        assert!(validate_token(&token("nbf", now() + 10), &state).is_ok());
+        assert!(validate_token(&token("nbf", now() + 60), &state).is_err());
+    }
+
+    #[test]
+    fn test_missing_exp_rejected_when_required() {
+        let secret = "test-secret";
+        let token = sign(
+            Header::default(),
+            &EncodingKey::from_secret(secret.as_bytes()),
+        );
+
+        assert!(validate_token(&token, &secret_state(secret)).is_ok());
+
+        let config = AuthConfig {
+            require_exp: true,
+            ..Default::default()
+        };
+        assert!(validate_token(&token, &secret_state_with(secret, &config)).is_err());
+    }
+
+    #[test]
+    fn test_verify_credentials() {
+        let salt = SaltString::encode_b64(b"test-salt-bytes").unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(b"argon-pass", &salt)
+            .unwrap()
+            .to_string();
+        let user = |username: &str, password_hash: String| User {
+            username: username.to_string(),
+            password_hash,
+            access: AccessLevel::All,
+        };
+        let users = [
+            user("argon", argon2_hash),
+            user("bcrypt", bcrypt::hash("bcrypt-pass", 4).unwrap()),
+        ];
+
+        let verified = |username, password| {
+            verify_credentials(&users, username, password).map(|user| user.username.as_str())
+        };
+        assert_eq!(verified("argon", "argon-pass"), Some("argon"));
+        assert_eq!(verified("bcrypt", "bcrypt-pass"), Some("bcrypt"));
+        assert_eq!(verified("argon", "bcrypt-pass"), None);
+        assert_eq!(verified("bcrypt", ""), None);
+        assert_eq!(verified("nobody", "argon-pass"), None);
+    }
+
+    #[test]
+    fn test_issuer_and_audience_validation() {
+        let secret = "test-secret";
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        let config = AuthConfig {
+            issuer: Some("https://issuer.example.com".to_string()),
+            audience: Some("cargo-bay".to_string()),
+            ..Default::default()
+        };
+        let state = secret_state_with(secret, &config);
+
+        let token_for = |iss: &str, aud: &str| {
+            let claims = serde_json::json!({
+                "sub": "user",
+                "iss": iss,
+                "aud": aud,
+                "access": {"type": "all"},
+            });
+            encode(&Header::default(), &claims, &key).unwrap()
+        };
+
+        assert!(validate_token(
+            &token_for("https://issuer.example.com", "cargo-bay"),
+            &state
+        )
+        .is_ok());
+        assert!(
+            validate_token(&token_for("https://evil.example.com", "cargo-bay"), &state).is_err()
+        );
+        assert!(validate_token(&token_for("https://issuer.example.com", "other"), &state).is_err());
+        assert!(validate_token(&sign(Header::default(), &key), &state).is_err());
+
+        // Without a configured audience, tokens carrying one are accepted.
+        let state = secret_state_with(secret, &AuthConfig::default());
+        assert!(validate_token(&token_for("https://any.example.com", "other"), &state).is_ok());
+    }
+
+    #[test]
+    fn test_keys_from_jwks() {
+        let jwks: JwkSet = serde_json::from_value(serde_json::json!({
+            "keys": [
+                {"kty": "oct", "k": "c2VjcmV0", "alg": "HS256", "kid": "secret"},
+                {"kty": "oct", "k": "c2VjcmV0"},
+                {"kty": "RSA", "n": "AQAB", "e": "AQAB", "alg": "RS256", "kid": "a"},
+                {"kty": "RSA", "n": "AQAB", "e": "AQAB", "kid": "b"},
+                {"kty": "RSA", "n": "AQAB", "e": "AQAB", "alg": "ES256", "kid": "c"}
+            ]
+        }))
+        .unwrap();
+
+        // Symmetric keys and keys of another type than their algorithm are
+        // ignored.
+        let keys = keys_from_jwks(&jwks, Algorithm::PS384);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key_id.as_deref(), Some("a"));
+        assert_eq!(keys[0].algorithm, Algorithm::RS256);
+        assert_eq!(keys[1].key_id.as_deref(), Some("b"));
+        assert_eq!(keys[1].algorithm, Algorithm::PS384);
+
+        // A configured algorithm that does not fit a key is not applied to it.
+        let keys = keys_from_jwks(&jwks, Algorithm::HS256);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].key_id.as_deref(), Some("a"));
+    }
+
     #[test]
     fn test_check_repository_access() {
         let claims = Claims {
             sub: "user".to_string(),
             exp: None,
+            upstream_auth: None,
             access: AccessLevel::Repositories {
                 repos: vec!["allowed".to_string()],
             },
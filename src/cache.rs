@@ -1,309 +1,2126 @@
-use crate::config::CacheConfig;
+use crate::cache_backend::{self, CacheBackend, ShardScheme};
+use crate::config::{CacheConfig, EvictionPolicy, MetadataFormat};
 use crate::error::{ProxyError, Result};
+use crate::manifest_cache::{LayerIndex, ManifestCache};
+use crate::memory_cache::MemoryCache;
+use crate::negative_cache::NegativeCache;
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::sync::Arc;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, warn};
 
+/// Version of the blob directory layout, recorded in `LAYOUT_VERSION_FILE`.
+/// Version 1 stored blobs under one prefix level taken from the digest
+/// including its algorithm (`blobs/sh/sha256_<hex>`), putting every sha256
+/// blob in the same directory. Version 2 shards on two levels of the hex
+/// part (`blobs/ab/cd/sha256_abcd...`).
+const LAYOUT_VERSION: u32 = 2;
+const LAYOUT_VERSION_FILE: &str = "layout_version";
+/// Records the `ShardScheme` blob files are laid out in. Layout 2 caches
+/// written before the scheme became configurable lack it and use the
+/// default scheme.
+const SHARD_SCHEME_FILE: &str = "shard_scheme";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CacheEntry {
     digest: String,
     size: u64,
     last_accessed: DateTime<Utc>,
     created: DateTime<Utc>,
+    #[serde(default)]
+    access_count: u64,
+    /// Per-entry idle expiry overriding `CacheConfig::max_age_seconds`.
+    #[serde(default)]
+    max_age_seconds: Option<u64>,
+    /// Size of the zstd-compressed blob file; `None` when it is stored as is.
+    #[serde(default)]
+    stored_size: Option<u64>,
+}
+
+/// `CacheEntry` as binary-encoded before blobs could be compressed at rest.
+#[derive(Deserialize)]
+struct CacheEntryV0 {
+    digest: String,
+    size: u64,
+    last_accessed: DateTime<Utc>,
+    created: DateTime<Utc>,
+    access_count: u64,
+    max_age_seconds: Option<u64>,
+}
+
+impl From<CacheEntryV0> for CacheEntry {
+    fn from(entry: CacheEntryV0) -> Self {
+        Self {
+            digest: entry.digest,
+            size: entry.size,
+            last_accessed: entry.last_accessed,
+            created: entry.created,
+            access_count: entry.access_count,
+            max_age_seconds: entry.max_age_seconds,
+            stored_size: None,
+        }
+    }
 }
 
 pub struct BlobCache {
     config: CacheConfig,
+    backend: Arc<dyn CacheBackend>,
     db: Arc<sled::Db>,
+    /// Bytes the blobs take up in the backend, compressed where they are.
     total_size: Arc<RwLock<u64>>,
+    /// Bytes saved by compressing blobs at rest.
+    saved_bytes: AtomicU64,
+    memory: MemoryCache,
+    manifests: ManifestCache,
+    /// Referrers indexes, keyed by subject digest and artifact type filter.
+    referrers: ManifestCache,
+    /// Tag lists, keyed by repository and pagination parameters.
+    tags: ManifestCache,
+    /// Blobs referenced by cached manifests, per repository.
+    known_layers: LayerIndex,
+    missing_manifests: NegativeCache,
+    pending_writes: Mutex<PendingWrites>,
+    /// Digests a `CacheWriter` is currently streaming in.
+    writing: Mutex<HashSet<String>>,
+    /// Size mismatches already warned about, by digest and file size. Kept
+    /// when `evict_size_mismatches` is off, as every pass finds them again.
+    reported_size_mismatches: Mutex<HashSet<(String, u64)>>,
+    counters: LayerCounters,
+    /// Generation of the `Checkpoint` written when this instance opened
+    /// the cache.
+    generation: u64,
 }
 
-impl BlobCache {
-    pub async fn new(config: CacheConfig) -> Result<Self> {
-        fs::create_dir_all(&config.directory)
-            .await
-            .map_err(|e| ProxyError::Cache(format!("Failed to create cache directory: {}", e)))?;
+#[derive(Default)]
+struct LayerCounters {
+    memory_hits: AtomicU64,
+    memory_misses: AtomicU64,
+    disk_hits: AtomicU64,
+    disk_misses: AtomicU64,
+}
 
-        let db_path = config.directory.join("metadata");
-        let db = sled::open(db_path)
-            .map_err(|e| ProxyError::Cache(format!("Failed to open cache database: {}", e)))?;
+/// Hit/miss counts for each cache layer. A memory miss falls through to
+/// disk, so `memory_misses == disk_hits + disk_misses`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub memory_hits: u64,
+    pub memory_misses: u64,
+    pub disk_hits: u64,
+    pub disk_misses: u64,
+}
 
-        let total_size = Self::calculate_total_size(&db)?;
+/// Aggregate view of the cache for the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheSummary {
+    pub entries: usize,
+    pub total_size: u64,
+    pub oldest_entry: Option<DateTime<Utc>>,
+    pub newest_entry: Option<DateTime<Utc>>,
+    /// Share of lookups since startup answered from memory or disk.
+    pub hit_rate: Option<f64>,
+}
 
-        Ok(Self {
-            config,
-            db: Arc::new(db),
-            total_size: Arc::new(RwLock::new(total_size)),
-        })
+/// A cache entry as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntryInfo {
+    pub digest: String,
+    pub size: u64,
+    /// Size of the blob file, smaller than `size` when compressed at rest.
+    pub stored_size: u64,
+    pub created: DateTime<Utc>,
+    pub last_accessed: DateTime<Utc>,
+    pub access_count: u64,
+}
+
+impl From<CacheEntry> for CacheEntryInfo {
+    fn from(entry: CacheEntry) -> Self {
+        Self {
+            stored_size: entry.stored_size(),
+            digest: entry.digest,
+            size: entry.size,
+            created: entry.created,
+            last_accessed: entry.last_accessed,
+            access_count: entry.access_count,
+        }
     }
+}
 
-    fn calculate_total_size(db: &sled::Db) -> Result<u64> {
-        let mut size = 0u64;
-        for item in db.iter() {
-            if let Ok((_, value)) = item {
-                if let Ok(entry) = serde_json::from_slice::<CacheEntry>(&value) {
-                    size += entry.size;
+/// A blob being streamed into the cache. It only becomes visible to readers
+/// once `commit` has verified its digest; dropping it uncommitted discards
+/// the partial file.
+pub struct CacheWriter {
+    cache: Arc<BlobCache>,
+    digest: String,
+    max_age_seconds: Option<u64>,
+    temp_path: PathBuf,
+    file: fs::File,
+    hasher: DigestHasher,
+    size: u64,
+    committed: bool,
+}
+
+impl CacheWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        self.file
+            .write_all(chunk)
+            .await
+            .map_err(|e| ProxyError::Cache(format!("Failed to write cache file: {}", e)))
+    }
+
+    /// Verifies the digest of everything written and moves the blob into
+    /// place.
+    pub async fn commit(mut self) -> Result<()> {
+        if !self.hasher.matches(&self.digest) {
+            return Err(ProxyError::Cache(format!(
+                "Digest mismatch for streamed blob {}",
+                self.digest
+            )));
+        }
+
+        self.file
+            .sync_all()
+            .await
+            .map_err(|e| ProxyError::Cache(format!("Failed to sync cache file: {}", e)))?;
+        self.committed = true;
+
+        let mut staged = self.temp_path.clone();
+        let mut stored_size = None;
+        if self.cache.config.compress_at_rest {
+            let raw = self.temp_path.clone();
+            let compressed = self.cache.backend.staging_path(&self.digest);
+            let target = compressed.clone();
+            match tokio::task::spawn_blocking(move || compress_file(&raw, &target)).await {
+                Ok(Ok(Some(size))) => {
+                    let _ = fs::remove_file(&self.temp_path).await;
+                    staged = compressed;
+                    stored_size = Some(size);
                 }
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => warn!("Failed to compress blob {}: {}", self.digest, e),
+                Err(e) => warn!("Failed to compress blob {}: {}", self.digest, e),
             }
         }
-        Ok(size)
+        self.cache.backend.put_file(&self.digest, &staged).await?;
+
+        self.cache
+            .record_entry(&self.digest, self.size, stored_size, self.max_age_seconds)
+            .await?;
+
+        debug!("Cached streamed blob {} ({} bytes)", self.digest, self.size);
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+        self.cache.writing.lock().unwrap().remove(&self.digest);
     }
+}
 
-    pub async fn get(&self, digest: &str) -> Result<Option<Bytes>> {
-        let key = digest.as_bytes();
+/// Incremental hasher for the algorithm named by a digest's prefix.
+#[derive(Clone)]
+pub enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
 
-        let entry_data = match self.db.get(key) {
-            Ok(Some(data)) => data,
-            Ok(None) => return Ok(None),
-            Err(e) => {
-                return Err(ProxyError::Cache(format!(
-                    "Failed to read cache metadata: {}",
-                    e
-                )))
-            }
+impl DigestHasher {
+    pub fn for_digest(digest: &str) -> Option<Self> {
+        match digest.split_once(':')?.0 {
+            "sha256" => Some(Self::Sha256(Sha256::new())),
+            "sha512" => Some(Self::Sha512(Sha512::new())),
+            _ => None,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Whether the data hashed so far matches `digest`.
+    pub fn matches(&self, digest: &str) -> bool {
+        let computed = match self {
+            Self::Sha256(hasher) => hex::encode(hasher.clone().finalize()),
+            Self::Sha512(hasher) => hex::encode(hasher.clone().finalize()),
         };
+        digest
+            .split_once(':')
+            .is_some_and(|(_, encoded)| encoded.eq_ignore_ascii_case(&computed))
+    }
+}
 
-        let mut entry: CacheEntry = serde_json::from_slice(&entry_data)
-            .map_err(|e| ProxyError::Cache(format!("Failed to parse cache entry: {}", e)))?;
+/// Leading byte of binary-encoded entries. JSON entries always start with
+/// `{`, so both formats can be told apart and coexist during migration.
+/// bincode is not self-describing, so entries written before a field was
+/// added keep the tag of their version.
+const BINARY_ENTRY_TAG: u8 = 1;
+const BINARY_ENTRY_TAG_V0: u8 = 0;
 
-        let blob_path = self.blob_path(digest);
+impl MetadataFormat {
+    fn of_entry(data: &[u8]) -> Self {
+        match data.first() {
+            Some(&BINARY_ENTRY_TAG | &BINARY_ENTRY_TAG_V0) => MetadataFormat::Binary,
+            _ => MetadataFormat::Json,
+        }
+    }
+}
 
-        if !blob_path.exists() {
-            warn!("Cache entry exists but blob file missing: {}", digest);
-            let _ = self.db.remove(key);
-            let mut total = self.total_size.write().await;
-            *total = total.saturating_sub(entry.size);
-            return Ok(None);
+/// zstd level for blobs compressed at rest; higher levels gain little on
+/// layers that are mostly gzip already.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Compresses `data` for storage, or returns `None` if that does not make it
+/// smaller.
+fn compress_blob(data: &[u8]) -> Option<Bytes> {
+    let compressed = zstd::bulk::compress(data, COMPRESSION_LEVEL).ok()?;
+    (compressed.len() < data.len()).then(|| Bytes::from(compressed))
+}
+
+/// Decompresses a blob stored compressed, off the async runtime.
+async fn decompress_blob(data: Bytes) -> std::io::Result<Bytes> {
+    tokio::task::spawn_blocking(move || zstd::stream::decode_all(&data[..]).map(Bytes::from))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Compresses the file at `raw` into `compressed`, returning the compressed
+/// size, or `None` (removing `compressed` again) if that does not make it
+/// smaller.
+fn compress_file(raw: &Path, compressed: &Path) -> std::io::Result<Option<u64>> {
+    let raw_size = std::fs::metadata(raw)?.len();
+    let result = (|| {
+        let mut output = std::fs::File::create(compressed)?;
+        zstd::stream::copy_encode(std::fs::File::open(raw)?, &mut output, COMPRESSION_LEVEL)?;
+        output.sync_all()?;
+        output.metadata().map(|metadata| metadata.len())
+    })();
+    match result {
+        Ok(size) if size < raw_size => Ok(Some(size)),
+        Ok(_) => {
+            std::fs::remove_file(compressed)?;
+            Ok(None)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(compressed);
+            Err(e)
         }
+    }
+}
 
-        match fs::read(&blob_path).await {
-            Ok(data) => {
-                entry.last_accessed = Utc::now();
-                if let Ok(updated) = serde_json::to_vec(&entry) {
-                    let _ = self.db.insert(key, updated);
-                }
-                debug!("Cache hit for digest: {}", digest);
-                Ok(Some(Bytes::from(data)))
+impl CacheEntry {
+    fn encode(&self, format: MetadataFormat) -> Result<Vec<u8>> {
+        match format {
+            MetadataFormat::Json => serde_json::to_vec(self)
+                .map_err(|e| ProxyError::Cache(format!("Failed to serialize cache entry: {}", e))),
+            MetadataFormat::Binary => {
+                let mut data = vec![BINARY_ENTRY_TAG];
+                bincode::serialize_into(&mut data, self).map_err(|e| {
+                    ProxyError::Cache(format!("Failed to serialize cache entry: {}", e))
+                })?;
+                Ok(data)
             }
-            Err(e) => {
-                error!("Failed to read cached blob {}: {}", digest, e);
-                Ok(None)
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        match MetadataFormat::of_entry(data) {
+            MetadataFormat::Json => serde_json::from_slice(data)
+                .map_err(|e| ProxyError::Cache(format!("Failed to parse cache entry: {}", e))),
+            MetadataFormat::Binary if data[0] == BINARY_ENTRY_TAG_V0 => {
+                bincode::deserialize::<CacheEntryV0>(&data[1..])
+                    .map(CacheEntry::from)
+                    .map_err(|e| ProxyError::Cache(format!("Failed to parse cache entry: {}", e)))
             }
+            MetadataFormat::Binary => bincode::deserialize(&data[1..])
+                .map_err(|e| ProxyError::Cache(format!("Failed to parse cache entry: {}", e))),
         }
     }
 
-    pub async fn put(&self, digest: &str, data: Bytes) -> Result<()> {
-        let size = data.len() as u64;
-        let blob_path = self.blob_path(digest);
+    /// Bytes the blob takes up in the backend.
+    fn stored_size(&self) -> u64 {
+        self.stored_size.unwrap_or(self.size)
+    }
 
-        if let Some(parent) = blob_path.parent() {
-            fs::create_dir_all(parent).await.map_err(|e| {
-                ProxyError::Cache(format!("Failed to create cache subdirectory: {}", e))
-            })?;
-        }
+    /// Bytes saved by compressing the blob at rest.
+    fn saved_bytes(&self) -> u64 {
+        self.size.saturating_sub(self.stored_size())
+    }
+}
 
-        let mut file = fs::File::create(&blob_path)
-            .await
-            .map_err(|e| ProxyError::Cache(format!("Failed to create cache file: {}", e)))?;
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub missing: usize,
+    pub corrupt: usize,
+}
 
-        file.write_all(&data)
-            .await
-            .map_err(|e| ProxyError::Cache(format!("Failed to write cache file: {}", e)))?;
+/// Sled tree holding the `Checkpoint`.
+const CHECKPOINT_TREE: &str = "checkpoint";
+const CHECKPOINT_KEY: &[u8] = b"state";
+
+/// Size counters saved when the cache is closed, so the next start can skip
+/// reconciling metadata with the blob files. `clean` is cleared as soon as
+/// the cache is opened, so a crash leaves it unset.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Number of times the cache has been opened.
+    generation: u64,
+    clean: bool,
+    total_size: u64,
+    saved_bytes: u64,
+}
+
+/// Outcome of reconciling metadata with the blob files on disk at startup.
+#[derive(Debug, Default)]
+struct ReconcileReport {
+    entries: usize,
+    missing: usize,
+    size_mismatched: usize,
+    orphaned: usize,
+    orphans_deleted: usize,
+}
 
-        file.sync_all()
+#[derive(PartialEq, Eq)]
+enum BlobState {
+    Valid,
+    Missing,
+    Corrupt,
+}
+
+enum CacheLayer {
+    Memory,
+    Disk,
+}
+
+/// Blobs whose cache write failed and is being retried in the background.
+#[derive(Default)]
+struct PendingWrites {
+    blobs: HashMap<String, Bytes>,
+    size: u64,
+}
+
+impl BlobCache {
+    pub async fn new(config: CacheConfig) -> Result<Self> {
+        fs::create_dir_all(&config.directory)
             .await
-            .map_err(|e| ProxyError::Cache(format!("Failed to sync cache file: {}", e)))?;
+            .map_err(|e| ProxyError::Cache(format!("Failed to create cache directory: {}", e)))?;
+        Self::migrate_layout(&config).await?;
 
-        let entry = CacheEntry {
-            digest: digest.to_string(),
-            size,
-            last_accessed: Utc::now(),
-            created: Utc::now(),
+        let db_path = config.directory.join("metadata");
+        let db = sled::open(db_path)
+            .map_err(|e| ProxyError::Cache(format!("Failed to open cache database: {}", e)))?;
+
+        Self::migrate_metadata(&db, config.metadata_format)?;
+
+        let memory = MemoryCache::new(config.memory_cache_bytes);
+        let manifest_tree = db
+            .open_tree("manifests")
+            .map_err(|e| ProxyError::Cache(format!("Failed to open manifest cache: {}", e)))?;
+        let manifests = ManifestCache::new(manifest_tree, config.manifest_ttl_seconds);
+        let referrers_tree = db
+            .open_tree("referrers")
+            .map_err(|e| ProxyError::Cache(format!("Failed to open referrers cache: {}", e)))?;
+        let referrers = ManifestCache::new(referrers_tree, config.referrers_ttl_seconds);
+        let tags_tree = db
+            .open_tree("tags")
+            .map_err(|e| ProxyError::Cache(format!("Failed to open tag list cache: {}", e)))?;
+        let tags = ManifestCache::new(tags_tree, config.tags_ttl_seconds);
+        let layers_tree = db
+            .open_tree("manifest_layers")
+            .map_err(|e| ProxyError::Cache(format!("Failed to open layer index: {}", e)))?;
+        let known_layers = LayerIndex::new(layers_tree, config.manifest_ttl_seconds);
+        let missing_manifests = NegativeCache::new(
+            config.negative_ttl_seconds,
+            config.negative_cache_min_misses,
+        );
+
+        let backend = cache_backend::from_config(
+            &config.backend,
+            &config.directory,
+            ShardScheme::from_config(&config),
+        )?;
+        let checkpoint = Self::read_checkpoint(&db)?;
+        let cache = Self {
+            config,
+            backend,
+            db: Arc::new(db),
+            total_size: Arc::new(RwLock::new(0)),
+            saved_bytes: AtomicU64::new(0),
+            memory,
+            manifests,
+            referrers,
+            tags,
+            known_layers,
+            missing_manifests,
+            pending_writes: Mutex::new(PendingWrites::default()),
+            writing: Mutex::new(HashSet::new()),
+            reported_size_mismatches: Mutex::new(HashSet::new()),
+            counters: LayerCounters::default(),
+            generation: checkpoint.generation + 1,
         };
 
-        let entry_data = serde_json::to_vec(&entry)
-            .map_err(|e| ProxyError::Cache(format!("Failed to serialize cache entry: {}", e)))?;
+        if checkpoint.clean {
+            *cache.total_size.write().await = checkpoint.total_size;
+            cache
+                .saved_bytes
+                .store(checkpoint.saved_bytes, Ordering::Relaxed);
+            info!(
+                "Cache was closed cleanly at generation {}; skipping reconciliation ({} bytes cached)",
+                checkpoint.generation, checkpoint.total_size
+            );
+        } else {
+            if checkpoint.generation > 0 {
+                warn!(
+                    "Cache was not closed cleanly at generation {}; reconciling metadata with blob files",
+                    checkpoint.generation
+                );
+            }
+            cache.sweep_partial_downloads().await;
+            let report = cache.reconcile().await?;
+            info!(
+                "Cache reconciled: {} entries ({} bytes), {} entries without blob files removed, {} with mismatched size, {} orphaned blob files found ({} deleted)",
+                report.entries,
+                *cache.total_size.read().await,
+                report.missing,
+                report.size_mismatched,
+                report.orphaned,
+                report.orphans_deleted
+            );
+        }
 
-        self.db
-            .insert(digest.as_bytes(), entry_data)
-            .map_err(|e| ProxyError::Cache(format!("Failed to store cache metadata: {}", e)))?;
+        cache
+            .write_checkpoint(&Checkpoint {
+                generation: cache.generation,
+                ..Default::default()
+            })
+            .await?;
 
-        let mut total = self.total_size.write().await;
-        *total += size;
+        Ok(cache)
+    }
 
-        debug!("Cached blob {} ({} bytes)", digest, size);
+    fn read_checkpoint(db: &sled::Db) -> Result<Checkpoint> {
+        let tree = db
+            .open_tree(CHECKPOINT_TREE)
+            .map_err(|e| ProxyError::Cache(format!("Failed to open cache checkpoint: {}", e)))?;
+        let Some(data) = tree
+            .get(CHECKPOINT_KEY)
+            .map_err(|e| ProxyError::Cache(format!("Failed to read cache checkpoint: {}", e)))?
+        else {
+            return Ok(Checkpoint::default());
+        };
+        // An unreadable checkpoint is treated like an unclean shutdown.
+        Ok(serde_json::from_slice(&data).unwrap_or_default())
+    }
 
-        Ok(())
+    /// Stores `checkpoint` and flushes it, together with all other pending
+    /// metadata changes, to disk.
+    async fn write_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let data = serde_json::to_vec(checkpoint).map_err(|e| {
+            ProxyError::Cache(format!("Failed to serialize cache checkpoint: {}", e))
+        })?;
+        self.db
+            .open_tree(CHECKPOINT_TREE)
+            .and_then(|tree| tree.insert(CHECKPOINT_KEY, data))
+            .map_err(|e| ProxyError::Cache(format!("Failed to write cache checkpoint: {}", e)))?;
+        self.flush().await
     }
 
-    pub async fn cleanup(&self) -> Result<()> {
-        info!("Starting cache cleanup");
+    /// Flushes the metadata and records the size counters so the next start
+    /// can trust them instead of reconciling the whole cache. Call it last,
+    /// once nothing writes to the cache any more; if a blob is still being
+    /// written the checkpoint is not marked clean.
+    pub async fn close(&self) -> Result<()> {
+        let idle = self.writing.lock().unwrap().is_empty()
+            && self.pending_writes.lock().unwrap().blobs.is_empty();
+        if !idle {
+            warn!("Closing the cache while blobs are still being written; the next start reconciles it");
+        }
+        self.write_checkpoint(&Checkpoint {
+            generation: self.generation,
+            clean: idle,
+            total_size: *self.total_size.read().await,
+            saved_bytes: self.saved_bytes.load(Ordering::Relaxed),
+        })
+        .await
+    }
 
-        let max_age = chrono::Duration::seconds(self.config.max_age_seconds as i64);
-        let now = Utc::now();
-        let mut entries_to_remove = Vec::new();
-        let mut size_ordered_entries: Vec<CacheEntry> = Vec::new();
+    /// Deletes the staged files of downloads interrupted by a restart or
+    /// crash. Interrupted downloads are not resumed: the next pull of the
+    /// blob fetches it from the start.
+    async fn sweep_partial_downloads(&self) {
+        let max_age = std::time::Duration::from_secs(self.config.partial_download_max_age_seconds);
+        let mut candidates = Vec::new();
+        if let Some(blobs_dir) = self.backend.blob_dir() {
+            candidates.extend(files_at_depth(blobs_dir, self.shard_scheme().file_depth()).await);
+        }
+        if let Some(staging_dir) = self.backend.staging_dir() {
+            candidates.extend(files_at_depth(staging_dir, 1).await);
+        }
 
-        for item in self.db.iter() {
-            if let Ok((key, value)) = item {
-                if let Ok(entry) = serde_json::from_slice::<CacheEntry>(&value) {
-                    if now - entry.last_accessed > max_age {
-                        entries_to_remove.push((key.to_vec(), entry));
-                    } else {
-                        size_ordered_entries.push(entry);
-                    }
+        let (mut discarded, mut kept, mut bytes) = (0, 0, 0);
+        for path in candidates {
+            if path.extension().is_none_or(|extension| extension != "tmp") {
+                continue;
+            }
+            let Ok(metadata) = fs::metadata(&path).await else {
+                continue;
+            };
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .unwrap_or_default();
+            if age < max_age {
+                kept += 1;
+                continue;
+            }
+            match fs::remove_file(&path).await {
+                Ok(()) => {
+                    debug!(
+                        "Discarded partial download {} ({} bytes)",
+                        path.display(),
+                        metadata.len()
+                    );
+                    discarded += 1;
+                    bytes += metadata.len();
                 }
+                Err(e) => warn!(
+                    "Failed to delete partial download {}: {}",
+                    path.display(),
+                    e
+                ),
             }
         }
 
-        for (key, entry) in &entries_to_remove {
-            if let Err(e) = self.remove_entry(key, entry).await {
-                error!("Failed to remove expired entry {}: {}", entry.digest, e);
+        if discarded > 0 || kept > 0 {
+            info!(
+                "Discarded {} partial downloads ({} bytes) left by an earlier run; kept {} younger than {}s",
+                discarded,
+                bytes,
+                kept,
+                max_age.as_secs()
+            );
+        }
+    }
+
+    /// Cross-checks metadata against the blob directory after a restart,
+    /// which may follow a crash: entries whose file is missing are removed,
+    /// and files without an entry (including partial writes) are logged or,
+    /// with `delete_orphaned_blobs`, deleted. `total_size` is recomputed from
+    /// the remaining entries. Blobs in a remote store are not looked up one by
+    /// one; an entry whose blob is gone is dropped when it is next read.
+    async fn reconcile(&self) -> Result<ReconcileReport> {
+        let mut report = ReconcileReport::default();
+        let mut known = HashSet::new();
+        let local = self.backend.blob_dir().is_some();
+
+        for (key, value) in self.db.iter().flatten() {
+            let Ok(entry) = CacheEntry::decode(&value) else {
+                continue;
+            };
+            // One lookup per entry answers both whether the file exists and
+            // whether its size matches.
+            let file_size = if local {
+                self.backend.head(&entry.digest).await?
             } else {
-                debug!("Removed expired entry: {}", entry.digest);
+                None
+            };
+            if local && file_size.is_none() {
+                warn!("Removing cache entry without blob file: {}", entry.digest);
+                self.db.remove(key).map_err(|e| {
+                    ProxyError::Cache(format!("Failed to remove cache metadata: {}", e))
+                })?;
+                report.missing += 1;
+            } else if file_size
+                .filter(|&size| size != entry.stored_size())
+                .is_some_and(|actual| self.report_size_mismatch(&entry, actual))
+            {
+                self.db.remove(key).map_err(|e| {
+                    ProxyError::Cache(format!("Failed to remove cache metadata: {}", e))
+                })?;
+                let _ = self.backend.remove(&entry.digest).await;
+                report.size_mismatched += 1;
+            } else {
+                report.entries += 1;
+                known.extend(self.backend.local_path(&entry.digest));
             }
         }
+        *self.total_size.write().await = Self::calculate_total_size(&self.db)?;
+        let saved = self
+            .db
+            .iter()
+            .flatten()
+            .filter_map(|(_, value)| CacheEntry::decode(&value).ok())
+            .map(|entry| entry.saved_bytes())
+            .sum();
+        self.saved_bytes.store(saved, Ordering::Relaxed);
 
-        let current_size = *self.total_size.read().await;
-        if current_size > self.config.max_size_bytes {
-            size_ordered_entries.sort_by_key(|e| e.last_accessed);
+        // Objects in a shared store may belong to other instances, so only
+        // local blob directories are scanned for orphans.
+        let Some(blobs_dir) = self.backend.blob_dir() else {
+            return Ok(report);
+        };
+        for path in files_at_depth(blobs_dir, self.shard_scheme().file_depth()).await {
+            if known.contains(&path) {
+                continue;
+            }
+            report.orphaned += 1;
+            if !self.config.delete_orphaned_blobs {
+                warn!("Orphaned blob file without cache entry: {}", path.display());
+            } else if let Err(e) = fs::remove_file(&path).await {
+                warn!(
+                    "Failed to delete orphaned blob file {}: {}",
+                    path.display(),
+                    e
+                );
+            } else {
+                debug!("Deleted orphaned blob file {}", path.display());
+                report.orphans_deleted += 1;
+            }
+        }
 
-            let mut removed_size = 0u64;
-            let target_size = (self.config.max_size_bytes as f64 * 0.9) as u64;
+        Ok(report)
+    }
 
-            for entry in size_ordered_entries {
-                if current_size - removed_size <= target_size {
-                    break;
-                }
+    fn shard_scheme(&self) -> ShardScheme {
+        ShardScheme::from_config(&self.config)
+    }
 
-                if let Err(e) = self.remove_entry(entry.digest.as_bytes(), &entry).await {
-                    error!("Failed to remove entry {}: {}", entry.digest, e);
-                } else {
-                    removed_size += entry.size;
-                    debug!("Removed entry to free space: {}", entry.digest);
-                }
-            }
+    /// Brings the blob directory to `LAYOUT_VERSION` and the configured
+    /// shard scheme. A cache without a version marker but with a blob
+    /// directory predates the marker and uses layout 1. Files are moved one
+    /// at a time and the markers are only written once all of them are in
+    /// place, so an interrupted migration picks up where it stopped on the
+    /// next start.
+    async fn migrate_layout(config: &CacheConfig) -> Result<()> {
+        let marker = config.directory.join(LAYOUT_VERSION_FILE);
+        let scheme_marker = config.directory.join(SHARD_SCHEME_FILE);
+        let blobs_dir = config.directory.join("blobs");
+        let scheme = ShardScheme::from_config(config);
+        let version = match fs::read_to_string(&marker).await {
+            Ok(contents) => contents.trim().parse::<u32>().map_err(|_| {
+                ProxyError::Cache(format!(
+                    "Invalid cache layout version in {}",
+                    marker.display()
+                ))
+            })?,
+            Err(_) if fs::try_exists(&blobs_dir).await.unwrap_or(false) => 1,
+            Err(_) => LAYOUT_VERSION,
+        };
 
+        if version > LAYOUT_VERSION {
+            return Err(ProxyError::Cache(format!(
+                "Cache directory uses layout version {}, newer than the supported version {}",
+                version, LAYOUT_VERSION
+            )));
+        }
+        if version < LAYOUT_VERSION {
+            if !config.migrate_layout {
+                return Err(ProxyError::Cache(format!(
+                    "Cache directory uses layout version {} but version {} is required; enable migrate_layout or clear the cache directory",
+                    version, LAYOUT_VERSION
+                )));
+            }
+            // Layout 1 files sit directly in the first prefix level.
+            Self::relocate_blobs(
+                &blobs_dir,
+                2,
+                scheme,
+                config.layout_migration_files_per_second,
+            )
+            .await?;
             info!(
-                "Cache cleanup: removed {} bytes to meet size limit",
-                removed_size
+                "Migrated cache layout from version {} to {}",
+                version, LAYOUT_VERSION
             );
+        } else {
+            let current = match fs::read_to_string(&scheme_marker).await {
+                Ok(contents) => contents.trim().parse::<ShardScheme>().map_err(|_| {
+                    ProxyError::Cache(format!(
+                        "Invalid shard scheme in {}",
+                        scheme_marker.display()
+                    ))
+                })?,
+                Err(_) if fs::try_exists(&blobs_dir).await.unwrap_or(false) => {
+                    ShardScheme::default()
+                }
+                Err(_) => scheme,
+            };
+            if current != scheme {
+                if !config.migrate_layout {
+                    return Err(ProxyError::Cache(format!(
+                        "Cache directory is sharded as {} but {} is configured; enable migrate_layout or clear the cache directory",
+                        current, scheme
+                    )));
+                }
+                Self::relocate_blobs(
+                    &blobs_dir,
+                    current.file_depth(),
+                    scheme,
+                    config.layout_migration_files_per_second,
+                )
+                .await?;
+                info!("Resharded blob files from {} to {}", current, scheme);
+            }
         }
 
-        let final_size = *self.total_size.read().await;
+        fs::write(&scheme_marker, format!("{}\n", scheme))
+            .await
+            .map_err(|e| ProxyError::Cache(format!("Failed to write cache shard scheme: {}", e)))?;
+        fs::write(&marker, format!("{}\n", LAYOUT_VERSION))
+            .await
+            .map_err(|e| ProxyError::Cache(format!("Failed to write cache layout version: {}", e)))
+    }
+
+    /// Moves the blob files found `depth` levels below `blobs_dir` to their
+    /// path under `scheme`, at most `files_per_second` per second, then
+    /// removes the directories left empty.
+    async fn relocate_blobs(
+        blobs_dir: &Path,
+        depth: usize,
+        scheme: ShardScheme,
+        files_per_second: u32,
+    ) -> Result<()> {
+        let files = files_at_depth(blobs_dir, depth).await;
         info!(
-            "Cache cleanup completed. Total size: {} bytes, entries removed: {}",
-            final_size,
-            entries_to_remove.len()
+            "Moving {} blob files to cache layout version {} sharded as {}",
+            files.len(),
+            LAYOUT_VERSION,
+            scheme
         );
 
+        let mut batch_started = tokio::time::Instant::now();
+        for (moved, path) in files.iter().enumerate() {
+            if files_per_second > 0 && moved > 0 && moved % files_per_second as usize == 0 {
+                tokio::time::sleep_until(batch_started + std::time::Duration::from_secs(1)).await;
+                batch_started = tokio::time::Instant::now();
+                info!("Migrated {}/{} blob files", moved, files.len());
+            }
+
+            let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let target = blobs_dir.join(scheme.path(file_name));
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).await.map_err(|e| {
+                    ProxyError::Cache(format!("Failed to create cache directory: {}", e))
+                })?;
+            }
+            fs::rename(path, &target)
+                .await
+                .map_err(|e| ProxyError::Cache(format!("Failed to move cache file: {}", e)))?;
+        }
+
+        remove_empty_dirs(blobs_dir).await;
         Ok(())
     }
 
-    async fn remove_entry(&self, key: &[u8], entry: &CacheEntry) -> Result<()> {
-        let blob_path = self.blob_path(&entry.digest);
+    /// Re-encodes entries written in another metadata format.
+    fn migrate_metadata(db: &sled::Db, format: MetadataFormat) -> Result<()> {
+        let mut migrated = 0;
+        for (key, value) in db.iter().flatten() {
+            if MetadataFormat::of_entry(&value) == format {
+                continue;
+            }
+            match CacheEntry::decode(&value) {
+                Ok(entry) => {
+                    db.insert(key, entry.encode(format)?).map_err(|e| {
+                        ProxyError::Cache(format!("Failed to store cache metadata: {}", e))
+                    })?;
+                    migrated += 1;
+                }
+                Err(e) => warn!("Skipping unreadable cache entry during migration: {}", e),
+            }
+        }
+        if migrated > 0 {
+            info!(
+                "Converted {} cache entries to {:?} metadata",
+                migrated, format
+            );
+        }
+        Ok(())
+    }
 
-        if blob_path.exists() {
-            fs::remove_file(&blob_path)
-                .await
-                .map_err(|e| ProxyError::Cache(format!("Failed to remove blob file: {}", e)))?;
+    fn calculate_total_size(db: &sled::Db) -> Result<u64> {
+        let mut size = 0u64;
+        for (_, value) in db.iter().flatten() {
+            if let Ok(entry) = CacheEntry::decode(&value) {
+                size += entry.stored_size();
+            }
         }
+        Ok(size)
+    }
 
+    /// Writes all pending metadata changes to disk.
+    pub async fn flush(&self) -> Result<()> {
         self.db
-            .remove(key)
-            .map_err(|e| ProxyError::Cache(format!("Failed to remove cache entry: {}", e)))?;
+            .flush_async()
+            .await
+            .map(|_| ())
+            .map_err(|e| ProxyError::Cache(format!("Failed to flush cache metadata: {}", e)))
+    }
 
-        let mut total = self.total_size.write().await;
-        *total = total.saturating_sub(entry.size);
+    pub fn manifests(&self) -> &ManifestCache {
+        &self.manifests
+    }
 
-        Ok(())
+    pub fn referrers(&self) -> &ManifestCache {
+        &self.referrers
     }
 
-    fn blob_path(&self, digest: &str) -> PathBuf {
-        let digest_clean = digest.replace(':', "_");
-        let prefix = &digest_clean[..std::cmp::min(2, digest_clean.len())];
-        self.config
-            .directory
-            .join("blobs")
-            .join(prefix)
-            .join(digest_clean)
+    pub fn tags(&self) -> &ManifestCache {
+        &self.tags
     }
 
-    pub async fn start_cleanup_task(cache: Arc<BlobCache>) {
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
-            loop {
-                interval.tick().await;
-                if let Err(e) = cache.cleanup().await {
-                    error!("Cache cleanup failed: {}", e);
-                }
-            }
-        });
+    pub fn known_layers(&self) -> &LayerIndex {
+        &self.known_layers
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+    pub fn missing_manifests(&self) -> &NegativeCache {
+        &self.missing_manifests
+    }
 
-    async fn create_test_cache() -> (BlobCache, TempDir) {
-        let temp_dir = TempDir::new().unwrap();
-        let config = CacheConfig {
-            directory: temp_dir.path().to_path_buf(),
+    pub fn write_holdback_bytes(&self) -> u64 {
+        self.config.write_holdback_bytes
+    }
+
+    /// Whether a blob of `size` bytes is within the configured cacheable
+    /// range.
+    pub fn caches_size(&self, size: u64) -> bool {
+        size >= self.config.min_blob_bytes && !self.too_large_to_cache(size)
+    }
+
+    pub fn too_large_to_cache(&self, size: u64) -> bool {
+        self.config.max_cacheable_bytes > 0 && size > self.config.max_cacheable_bytes
+    }
+
+    /// Bytes saved on disk by compressing blobs at rest.
+    pub fn compression_saved_bytes(&self) -> u64 {
+        self.saved_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            memory_hits: self.counters.memory_hits.load(Ordering::Relaxed),
+            memory_misses: self.counters.memory_misses.load(Ordering::Relaxed),
+            disk_hits: self.counters.disk_hits.load(Ordering::Relaxed),
+            disk_misses: self.counters.disk_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn summary(&self) -> CacheSummary {
+        let mut summary = CacheSummary {
+            entries: 0,
+            total_size: *self.total_size.read().await,
+            oldest_entry: None,
+            newest_entry: None,
+            hit_rate: None,
+        };
+        for (_, value) in self.db.iter().flatten() {
+            if let Ok(entry) = CacheEntry::decode(&value) {
+                summary.entries += 1;
+                let created = entry.created;
+                summary.oldest_entry =
+                    Some(summary.oldest_entry.map_or(created, |t| t.min(created)));
+                summary.newest_entry = summary.newest_entry.max(Some(created));
+            }
+        }
+
+        let stats = self.stats();
+        let hits = stats.memory_hits + stats.disk_hits;
+        let lookups = hits + stats.disk_misses;
+        if lookups > 0 {
+            summary.hit_rate = Some(hits as f64 / lookups as f64);
+        }
+        summary
+    }
+
+    /// Lists up to `limit` entries in digest order, starting after the
+    /// digest `after` so callers can page through the cache.
+    pub fn list_entries(&self, after: Option<&str>, limit: usize) -> Vec<CacheEntryInfo> {
+        let entries = match after {
+            Some(after) => self.db.range::<&[u8], _>((
+                std::ops::Bound::Excluded(after.as_bytes()),
+                std::ops::Bound::Unbounded,
+            )),
+            None => self.db.iter(),
+        };
+        entries
+            .flatten()
+            .filter_map(|(_, value)| CacheEntry::decode(&value).ok())
+            .take(limit)
+            .map(CacheEntryInfo::from)
+            .collect()
+    }
+
+    /// Iterates over all entries in digest order. The iterator reads the
+    /// metadata lazily, so entries added or evicted meanwhile may or may not
+    /// be seen.
+    pub fn entries(&self) -> impl Iterator<Item = CacheEntryInfo> + '_ {
+        self.db
+            .iter()
+            .flatten()
+            .filter_map(|(_, value)| CacheEntry::decode(&value).ok())
+            .map(CacheEntryInfo::from)
+    }
+
+    /// Removes a single entry and its blob file, or only the entry when the
+    /// blob lives in a shared store. Returns whether it existed.
+    pub async fn evict(&self, digest: &str) -> Result<bool> {
+        let Some(value) = self
+            .db
+            .get(digest.as_bytes())
+            .map_err(|e| ProxyError::Cache(format!("Failed to read cache metadata: {}", e)))?
+        else {
+            return Ok(false);
+        };
+        let entry = CacheEntry::decode(&value)?;
+        let removed = self.remove_entry(digest.as_bytes(), &entry).await?;
+        if removed {
+            info!("Evicted cache entry {}", digest);
+        }
+        Ok(removed)
+    }
+
+    /// Whether `digest` has a cache entry, without touching it.
+    pub fn contains(&self, digest: &str) -> bool {
+        self.db.contains_key(digest.as_bytes()).unwrap_or(false)
+    }
+
+    /// Size of the blob cached under `digest`, read from its metadata alone:
+    /// the blob itself is not read and the entry's access stats are left
+    /// alone.
+    pub fn size(&self, digest: &str) -> Result<Option<u64>> {
+        if self.config.serve_pending_writes {
+            if let Some(data) = self.pending_writes.lock().unwrap().blobs.get(digest) {
+                return Ok(Some(data.len() as u64));
+            }
+        }
+        let entry = self
+            .db
+            .get(digest.as_bytes())
+            .map_err(|e| ProxyError::Cache(format!("Failed to read cache metadata: {}", e)))?;
+        entry
+            .map(|data| CacheEntry::decode(&data).map(|entry| entry.size))
+            .transpose()
+    }
+
+    pub async fn get(&self, digest: &str) -> Result<Option<Bytes>> {
+        let result = self.lookup(digest).await;
+
+        let counters = &self.counters;
+        match &result {
+            Ok(Some((_, CacheLayer::Memory))) => {
+                counters.memory_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Some((_, CacheLayer::Disk))) => {
+                counters.memory_misses.fetch_add(1, Ordering::Relaxed);
+                counters.disk_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(None) | Err(_) => {
+                counters.memory_misses.fetch_add(1, Ordering::Relaxed);
+                counters.disk_misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        result.map(|found| found.map(|(data, _)| data))
+    }
+
+    async fn lookup(&self, digest: &str) -> Result<Option<(Bytes, CacheLayer)>> {
+        let key = digest.as_bytes();
+
+        if self.config.serve_pending_writes {
+            if let Some(data) = self.pending_writes.lock().unwrap().blobs.get(digest) {
+                debug!("Serving {} from pending cache write", digest);
+                return Ok(Some((data.clone(), CacheLayer::Memory)));
+            }
+        }
+
+        let entry_data = match self.db.get(key) {
+            Ok(Some(data)) => data,
+            Ok(None) if self.backend.is_shared() => return self.adopt(digest).await,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                return Err(ProxyError::Cache(format!(
+                    "Failed to read cache metadata: {}",
+                    e
+                )))
+            }
+        };
+
+        let mut entry = CacheEntry::decode(&entry_data)?;
+
+        entry.last_accessed = Utc::now();
+        entry.access_count += 1;
+
+        if let Some(data) = self.memory.get(digest) {
+            self.touch(key, &entry);
+            debug!("Memory cache hit for digest: {}", digest);
+            return Ok(Some((data, CacheLayer::Memory)));
+        }
+
+        match self.backend.get(digest).await {
+            Ok(None) => {
+                warn!("Cache entry exists but blob file missing: {}", digest);
+                if let Ok(Some(_)) = self.db.remove(key) {
+                    self.saved_bytes
+                        .fetch_sub(entry.saved_bytes(), Ordering::Relaxed);
+                    let mut total = self.total_size.write().await;
+                    *total = total.saturating_sub(entry.stored_size());
+                }
+                Ok(None)
+            }
+            Ok(Some(data)) => {
+                let data = if entry.stored_size.is_some() {
+                    match decompress_blob(data).await {
+                        Ok(data) => data,
+                        Err(e) => {
+                            error!("Failed to decompress cached blob {}: {}", digest, e);
+                            return Ok(None);
+                        }
+                    }
+                } else {
+                    data
+                };
+                self.touch(key, &entry);
+                if entry.access_count >= self.config.memory_promotion_threshold {
+                    self.promote(digest, &data);
+                }
+                debug!("Cache hit for digest: {}", digest);
+                Ok(Some((data, CacheLayer::Disk)))
+            }
+            Err(e) => {
+                error!("Failed to read cached blob {}: {}", digest, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Serves a blob another instance stored in the shared backend, recording
+    /// it locally so it is evicted like any other entry.
+    async fn adopt(&self, digest: &str) -> Result<Option<(Bytes, CacheLayer)>> {
+        let data = match self.backend.get(digest).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                error!("Failed to read shared blob {}: {}", digest, e);
+                return Ok(None);
+            }
+        };
+        debug!("Shared backend hit for digest: {}", digest);
+        self.record_entry(digest, data.len() as u64, None, None)
+            .await?;
+        Ok(Some((data, CacheLayer::Disk)))
+    }
+
+    fn promote(&self, digest: &str, data: &Bytes) {
+        if self.memory.is_enabled() && data.len() as u64 <= self.config.memory_max_item_bytes {
+            debug!("Promoting {} to memory cache", digest);
+            self.memory.insert(digest, data.clone());
+        }
+    }
+
+    fn touch(&self, key: &[u8], entry: &CacheEntry) {
+        if let Ok(updated) = entry.encode(self.config.metadata_format) {
+            let _ = self.db.insert(key, updated);
+        }
+    }
+
+    /// Stores a blob. `max_age_seconds` overrides the global idle expiry for
+    /// this entry.
+    pub async fn put(&self, digest: &str, data: Bytes, max_age_seconds: Option<u64>) -> Result<()> {
+        let size = data.len() as u64;
+        let compressed = if self.config.compress_at_rest {
+            let raw = data.clone();
+            tokio::task::spawn_blocking(move || compress_blob(&raw))
+                .await
+                .map_err(|e| ProxyError::Cache(format!("Failed to compress blob: {}", e)))?
+        } else {
+            None
+        };
+        let stored_size = compressed.as_ref().map(|data| data.len() as u64);
+        self.backend
+            .put(digest, compressed.unwrap_or_else(|| data.clone()))
+            .await?;
+
+        self.record_entry(digest, size, stored_size, max_age_seconds)
+            .await?;
+
+        if self.config.memory_promotion_threshold == 0 {
+            self.promote(digest, &data);
+        }
+
+        debug!("Cached blob {} ({} bytes)", digest, size);
+
+        Ok(())
+    }
+
+    /// Opens a writer that streams a blob into the cache, hashing it on the
+    /// way so the digest can be verified without reading the file back.
+    ///
+    /// Blobs are cached by digest alone, so pulls of the same layer through
+    /// different repositories share one file. Returns `None` while another
+    /// writer is streaming the same digest, as that one will cache it.
+    pub async fn writer(
+        self: &Arc<Self>,
+        digest: &str,
+        max_age_seconds: Option<u64>,
+    ) -> Result<Option<CacheWriter>> {
+        let hasher = DigestHasher::for_digest(digest).ok_or_else(|| {
+            ProxyError::Cache(format!("Unsupported digest algorithm: {}", digest))
+        })?;
+        if !self.writing.lock().unwrap().insert(digest.to_string()) {
+            return Ok(None);
+        }
+        // From here on, dropping the writer releases the digest again.
+        let temp_path = self.backend.staging_path(digest);
+        let release = || {
+            self.writing.lock().unwrap().remove(digest);
+        };
+
+        if let Some(parent) = temp_path.parent() {
+            if let Err(e) = fs::create_dir_all(parent).await {
+                release();
+                return Err(ProxyError::Cache(format!(
+                    "Failed to create cache subdirectory: {}",
+                    e
+                )));
+            }
+        }
+        let file = match fs::File::create(&temp_path).await {
+            Ok(file) => file,
+            Err(e) => {
+                release();
+                return Err(ProxyError::Cache(format!(
+                    "Failed to create cache file: {}",
+                    e
+                )));
+            }
+        };
+
+        Ok(Some(CacheWriter {
+            cache: self.clone(),
+            digest: digest.to_string(),
+            max_age_seconds,
+            temp_path,
+            file,
+            hasher,
+            size: 0,
+            committed: false,
+        }))
+    }
+
+    async fn record_entry(
+        &self,
+        digest: &str,
+        size: u64,
+        stored_size: Option<u64>,
+        max_age_seconds: Option<u64>,
+    ) -> Result<()> {
+        let entry = CacheEntry {
+            digest: digest.to_string(),
+            size,
+            last_accessed: Utc::now(),
+            created: Utc::now(),
+            access_count: 0,
+            max_age_seconds,
+            stored_size,
+        };
+
+        let entry_data = entry.encode(self.config.metadata_format)?;
+
+        // `insert` atomically returns any entry it replaced, so a digest cached
+        // twice (e.g. by racing cache misses) is only counted once.
+        let previous = self
+            .db
+            .insert(digest.as_bytes(), entry_data)
+            .map_err(|e| ProxyError::Cache(format!("Failed to store cache metadata: {}", e)))?
+            .and_then(|previous| CacheEntry::decode(&previous).ok());
+        let (previous_size, previous_saved) = previous.map_or((0, 0), |previous| {
+            (previous.stored_size(), previous.saved_bytes())
+        });
+
+        self.saved_bytes
+            .fetch_add(entry.saved_bytes(), Ordering::Relaxed);
+        self.saved_bytes
+            .fetch_sub(previous_saved, Ordering::Relaxed);
+        let mut total = self.total_size.write().await;
+        *total = total.saturating_sub(previous_size) + entry.stored_size();
+
+        Ok(())
+    }
+
+    /// Stores a blob, retrying in the background if the write fails. The blob
+    /// is held in memory (within `write_holdback_bytes`) until the retry
+    /// succeeds or gives up.
+    pub async fn put_with_retry(
+        self: &Arc<Self>,
+        digest: &str,
+        data: Bytes,
+        max_age_seconds: Option<u64>,
+    ) {
+        let error = match self.put(digest, data.clone(), max_age_seconds).await {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+
+        if self.config.write_retry_attempts == 0 || !self.hold_back(digest, data.clone()) {
+            warn!("Failed to cache blob {}: {}", digest, error);
+            return;
+        }
+
+        warn!(
+            "Failed to cache blob {}: {}; retrying in background",
+            digest, error
+        );
+
+        let cache = self.clone();
+        let digest = digest.to_string();
+        tokio::spawn(async move {
+            let mut delay = cache.config.write_retry_delay_ms;
+            for attempt in 1..=cache.config.write_retry_attempts {
+                tokio::time::sleep(tokio::time::Duration::from_millis(delay)).await;
+                delay = delay.saturating_mul(2);
+
+                match cache.put(&digest, data.clone(), max_age_seconds).await {
+                    Ok(()) => {
+                        info!("Cached blob {} on retry attempt {}", digest, attempt);
+                        break;
+                    }
+                    Err(e) if attempt == cache.config.write_retry_attempts => {
+                        error!(
+                            "Giving up caching blob {} after {} retries: {}",
+                            digest, attempt, e
+                        );
+                    }
+                    Err(e) => debug!("Retry {} for blob {} failed: {}", attempt, digest, e),
+                }
+            }
+            cache.release_held_back(&digest);
+        });
+    }
+
+    fn hold_back(&self, digest: &str, data: Bytes) -> bool {
+        let mut pending = self.pending_writes.lock().unwrap();
+        let size = data.len() as u64;
+        if pending.blobs.contains_key(digest)
+            || pending.size + size > self.config.write_holdback_bytes
+        {
+            return false;
+        }
+        pending.size += size;
+        pending.blobs.insert(digest.to_string(), data);
+        true
+    }
+
+    fn release_held_back(&self, digest: &str) {
+        let mut pending = self.pending_writes.lock().unwrap();
+        if let Some(data) = pending.blobs.remove(digest) {
+            pending.size -= data.len() as u64;
+        }
+    }
+
+    pub async fn cleanup(&self) -> Result<()> {
+        info!("Starting cache cleanup");
+
+        let now = Utc::now();
+        let mut entries_to_remove = Vec::new();
+        let mut mismatched = Vec::new();
+        let mut size_ordered_entries: Vec<CacheEntry> = Vec::new();
+
+        let permanent = self.config.immutable_digest_permanent;
+
+        for (key, value) in self.db.iter().flatten() {
+            if let Ok(entry) = CacheEntry::decode(&value) {
+                if self.evicts_size_mismatch(&entry).await {
+                    mismatched.push((key.to_vec(), entry));
+                    continue;
+                }
+                let max_age = entry.max_age_seconds.unwrap_or(self.config.max_age_seconds);
+                let expired = now - entry.last_accessed > chrono::Duration::seconds(max_age as i64);
+                if expired && !permanent {
+                    entries_to_remove.push((key.to_vec(), entry));
+                } else {
+                    size_ordered_entries.push(entry);
+                }
+            }
+        }
+
+        for (key, entry) in &entries_to_remove {
+            if let Err(e) = self.remove_entry(key, entry).await {
+                error!("Failed to remove expired entry {}: {}", entry.digest, e);
+            } else {
+                debug!("Removed expired entry: {}", entry.digest);
+            }
+        }
+        for (key, entry) in &mismatched {
+            if let Err(e) = self.remove_entry(key, entry).await {
+                error!("Failed to remove entry {}: {}", entry.digest, e);
+            }
+        }
+
+        let size_exempt = permanent && self.config.permanent_exempt_from_size_limit;
+        let current_size = *self.total_size.read().await;
+        if current_size > self.config.max_size_bytes && !size_exempt {
+            order_for_eviction(self.config.eviction_policy, &mut size_ordered_entries);
+
+            let mut removed_size = 0u64;
+            let target_size = (self.config.max_size_bytes as f64 * 0.9) as u64;
+
+            for entry in size_ordered_entries {
+                if current_size - removed_size <= target_size {
+                    break;
+                }
+
+                if let Err(e) = self.remove_entry(entry.digest.as_bytes(), &entry).await {
+                    error!("Failed to remove entry {}: {}", entry.digest, e);
+                } else {
+                    removed_size += entry.stored_size();
+                    debug!("Removed entry to free space: {}", entry.digest);
+                }
+            }
+
+            info!(
+                "Cache cleanup: removed {} bytes to meet size limit",
+                removed_size
+            );
+        }
+
+        self.remove_expired_metadata();
+
+        let final_size = *self.total_size.read().await;
+        info!(
+            "Cache cleanup completed. Total size: {} bytes, entries removed: {}",
+            final_size,
+            entries_to_remove.len() + mismatched.len()
+        );
+
+        Ok(())
+    }
+
+    /// Drops referrers, tag lists and layer records past their TTL.
+    /// Manifests are kept for `max_age_seconds` like blobs (or their TTL if
+    /// longer), so tags can still be served stale while upstream fails, and
+    /// those under a digest stay for good with `immutable_digest_permanent`.
+    fn remove_expired_metadata(&self) {
+        let manifest_age = self
+            .config
+            .manifest_ttl_seconds
+            .max(self.config.max_age_seconds);
+        let swept = [
+            (
+                "manifests",
+                self.manifests
+                    .remove_expired(manifest_age, self.config.immutable_digest_permanent),
+            ),
+            (
+                "referrers",
+                self.referrers
+                    .remove_expired(self.config.referrers_ttl_seconds, false),
+            ),
+            (
+                "tag lists",
+                self.tags
+                    .remove_expired(self.config.tags_ttl_seconds, false),
+            ),
+            ("layer records", self.known_layers.remove_expired()),
+        ];
+        for (kind, removed) in swept {
+            match removed {
+                Ok(0) => {}
+                Ok(removed) => debug!("Removed {} expired {}", removed, kind),
+                Err(e) => error!("Failed to remove expired {}: {}", kind, e),
+            }
+        }
+    }
+
+    /// Removes an entry and its blob. Returns whether this call removed the
+    /// metadata; when several callers race to remove the same entry only one
+    /// of them subtracts its size from `total_size`. Blobs in a shared store
+    /// are left in place, as other instances may still index and serve them.
+    async fn remove_entry(&self, key: &[u8], entry: &CacheEntry) -> Result<bool> {
+        self.memory.remove(&entry.digest);
+        if !self.backend.is_shared() {
+            self.backend.remove(&entry.digest).await?;
+        }
+
+        let Some(removed) = self
+            .db
+            .remove(key)
+            .map_err(|e| ProxyError::Cache(format!("Failed to remove cache entry: {}", e)))?
+        else {
+            return Ok(false);
+        };
+        let removed = CacheEntry::decode(&removed).unwrap_or_else(|_| entry.clone());
+        self.saved_bytes
+            .fetch_sub(removed.saved_bytes(), Ordering::Relaxed);
+
+        let mut total = self.total_size.write().await;
+        *total = total.saturating_sub(removed.stored_size());
+
+        Ok(true)
+    }
+
+    #[cfg(test)]
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        self.backend
+            .local_path(digest)
+            .expect("tests use the filesystem backend")
+    }
+
+    /// Re-hashes every cached blob, removing entries whose file is missing or
+    /// no longer matches its digest. Up to `verify_concurrency` blobs are
+    /// hashed at once.
+    pub async fn verify_integrity(&self) -> VerifyReport {
+        let entries: Vec<(Vec<u8>, CacheEntry)> = self
+            .db
+            .iter()
+            .flatten()
+            .filter_map(|(key, value)| Some((key.to_vec(), CacheEntry::decode(&value).ok()?)))
+            .collect();
+        let total = entries.len();
+        let progress_step = (total / 10).max(1);
+        info!("Verifying {} cached blobs", total);
+
+        let mut report = VerifyReport::default();
+        let mut outcomes = futures::stream::iter(entries)
+            .map(|(key, entry)| async move {
+                let outcome = self.verify_entry(&entry).await;
+                (key, entry, outcome)
+            })
+            .buffer_unordered(self.config.verify_concurrency.max(1));
+
+        while let Some((key, entry, outcome)) = outcomes.next().await {
+            report.checked += 1;
+            match outcome {
+                BlobState::Valid => {}
+                BlobState::Missing => {
+                    warn!("Pruning cache entry without blob file: {}", entry.digest);
+                    report.missing += 1;
+                }
+                BlobState::Corrupt => {
+                    warn!("Pruning corrupt cached blob: {}", entry.digest);
+                    report.corrupt += 1;
+                }
+            }
+            if outcome != BlobState::Valid {
+                if let Err(e) = self.remove_entry(&key, &entry).await {
+                    error!("Failed to prune cache entry {}: {}", entry.digest, e);
+                }
+            }
+            if report.checked % progress_step == 0 {
+                info!("Verified {}/{} cached blobs", report.checked, total);
+            }
+        }
+
+        info!(
+            "Cache verification complete: {} checked, {} missing, {} corrupt",
+            report.checked, report.missing, report.corrupt
+        );
+        report
+    }
+
+    /// Size of the blob file for `entry` if it exists but differs from the
+    /// recorded size, e.g. after a partial write or external modification.
+    /// Blobs in a remote store are not checked, as that would cost a request
+    /// per entry on every pass.
+    async fn size_mismatch(&self, entry: &CacheEntry) -> Option<u64> {
+        self.backend.blob_dir()?;
+        let actual = self.backend.head(&entry.digest).await.ok()??;
+        (actual != entry.stored_size()).then_some(actual)
+    }
+
+    /// Logs a size mismatch for `entry` and returns whether it should be
+    /// evicted for it.
+    async fn evicts_size_mismatch(&self, entry: &CacheEntry) -> bool {
+        match self.size_mismatch(entry).await {
+            Some(actual) => self.report_size_mismatch(entry, actual),
+            None => false,
+        }
+    }
+
+    /// Logs that the blob file for `entry` is `actual` bytes and returns
+    /// whether it should be evicted for it. A mismatch that is kept is only
+    /// warned about once; later passes report it at debug level.
+    fn report_size_mismatch(&self, entry: &CacheEntry, actual: u64) -> bool {
+        let evict = self.config.evict_size_mismatches;
+        let first = evict
+            || self
+                .reported_size_mismatches
+                .lock()
+                .unwrap()
+                .insert((entry.digest.clone(), actual));
+        if first {
+            warn!(
+                "Blob file for {} is {} bytes but {} were recorded{}",
+                entry.digest,
+                actual,
+                entry.stored_size(),
+                if evict { "; evicting" } else { "" }
+            );
+        } else {
+            debug!(
+                "Blob file for {} is still {} bytes instead of {}",
+                entry.digest,
+                actual,
+                entry.stored_size()
+            );
+        }
+        evict
+    }
+
+    async fn verify_entry(&self, entry: &CacheEntry) -> BlobState {
+        // A size mismatch makes the digest fail too; skip hashing it.
+        if self.size_mismatch(entry).await.is_some() {
+            return BlobState::Corrupt;
+        }
+
+        let Some(mut hasher) = DigestHasher::for_digest(&entry.digest) else {
+            return BlobState::Corrupt;
+        };
+        let Some(blob_path) = self.backend.local_path(&entry.digest) else {
+            return match self.backend.get(&entry.digest).await {
+                Ok(Some(data)) => {
+                    hasher.update(&data);
+                    if hasher.matches(&entry.digest) {
+                        BlobState::Valid
+                    } else {
+                        BlobState::Corrupt
+                    }
+                }
+                Ok(None) => BlobState::Missing,
+                Err(_) => BlobState::Corrupt,
+            };
+        };
+
+        let digest = entry.digest.clone();
+        let compressed = entry.stored_size.is_some();
+        let hashed = tokio::task::spawn_blocking(move || -> std::io::Result<bool> {
+            use std::io::Read;
+            let file = std::fs::File::open(&blob_path)?;
+            let mut reader: Box<dyn Read> = if compressed {
+                Box::new(zstd::stream::read::Decoder::new(file)?)
+            } else {
+                Box::new(file)
+            };
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let read = reader.read(&mut buffer)?;
+                if read == 0 {
+                    return Ok(hasher.matches(&digest));
+                }
+                hasher.update(&buffer[..read]);
+            }
+        })
+        .await;
+
+        match hashed {
+            Ok(Ok(true)) => BlobState::Valid,
+            Ok(Ok(false)) => BlobState::Corrupt,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => BlobState::Missing,
+            Ok(Err(_)) | Err(_) => BlobState::Corrupt,
+        }
+    }
+
+    pub async fn start_cleanup_task(cache: Arc<BlobCache>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = cache.cleanup().await {
+                    error!("Cache cleanup failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Sorts `entries` so the first ones are evicted first under `policy`.
+fn order_for_eviction(policy: EvictionPolicy, entries: &mut [CacheEntry]) {
+    match policy {
+        EvictionPolicy::Lru => entries.sort_by_key(|e| e.last_accessed),
+        EvictionPolicy::Lfu => entries.sort_by_key(|e| (e.access_count, e.last_accessed)),
+        EvictionPolicy::LargestFirst => {
+            entries.sort_by_key(|e| (std::cmp::Reverse(e.stored_size()), e.last_accessed))
+        }
+    }
+}
+
+/// Removes the directories below `dir` that hold no files, deepest first.
+/// Directories that still hold something are left in place.
+async fn remove_empty_dirs(dir: &Path) {
+    let mut dirs = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(mut entries) = fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                pending.push(entry.path());
+                dirs.push(entry.path());
+            }
+        }
+    }
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        let _ = fs::remove_dir(dir).await;
+    }
+}
+
+/// Regular files exactly `depth` directory levels below `dir`.
+async fn files_at_depth(dir: &Path, depth: usize) -> Vec<PathBuf> {
+    let mut dirs = vec![dir.to_path_buf()];
+    let mut files = Vec::new();
+    for level in 1..=depth {
+        let mut next = Vec::new();
+        for dir in dirs {
+            let Ok(mut entries) = fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let Ok(file_type) = entry.file_type().await else {
+                    continue;
+                };
+                if level == depth && file_type.is_file() {
+                    files.push(entry.path());
+                } else if level < depth && file_type.is_dir() {
+                    next.push(entry.path());
+                }
+            }
+        }
+        dirs = next;
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    async fn create_test_cache() -> (BlobCache, TempDir) {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            max_size_bytes: 1024 * 1024,
+            max_age_seconds: 3600,
+            ..Default::default()
+        };
+        let cache = BlobCache::new(config).await.unwrap();
+        (cache, temp_dir)
+    }
+
+    /// Opens the cache in `config.directory` again after the test dropped
+    /// its first handle. sled releases its file lock only once deferred
+    /// epoch cleanup runs, which may lag the drop, so the open is retried.
+    async fn reopen(config: CacheConfig) -> BlobCache {
+        for _ in 0..50 {
+            match BlobCache::new(config.clone()).await {
+                Ok(cache) => return cache,
+                Err(e) if e.to_string().contains("Failed to open cache database") => {
+                    tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+                }
+                Err(e) => panic!("Failed to reopen cache: {}", e),
+            }
+        }
+        BlobCache::new(config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache_put_and_get() {
+        let (cache, _temp) = create_test_cache().await;
+        let digest = "sha256:abc123";
+        let data = Bytes::from("test data");
+
+        cache.put(digest, data.clone(), None).await.unwrap();
+
+        let retrieved = cache.get(digest).await.unwrap();
+        assert!(retrieved.is_some());
+        assert_eq!(retrieved.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_put_leaves_no_temp_files() {
+        let (cache, _temp) = create_test_cache().await;
+        let digest = "sha256:atomic";
+
+        cache.put(digest, Bytes::from("first"), None).await.unwrap();
+        cache.put(digest, Bytes::from("first"), None).await.unwrap();
+
+        let blob_path = cache.blob_path(digest);
+        let siblings: Vec<_> = std::fs::read_dir(blob_path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(siblings, vec![blob_path]);
+    }
+
+    #[tokio::test]
+    async fn test_cache_miss() {
+        let (cache, _temp) = create_test_cache().await;
+        let result = cache.get("sha256:nonexistent").await.unwrap();
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cache_cleanup_by_age() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            max_size_bytes: 1024 * 1024,
+            max_age_seconds: 1,
+            ..Default::default()
+        };
+        let cache = BlobCache::new(config).await.unwrap();
+
+        let digest = "sha256:old";
+        let data = Bytes::from("old data");
+        cache.put(digest, data, None).await.unwrap();
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+
+        cache.cleanup().await.unwrap();
+
+        let result = cache.get(digest).await.unwrap();
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_eviction_policies_pick_expected_victims() {
+        let cases = [
+            (EvictionPolicy::Lru, "sha256:mid"),
+            (EvictionPolicy::Lfu, "sha256:small"),
+            (EvictionPolicy::LargestFirst, "sha256:big"),
+        ];
+
+        for (policy, victim) in cases {
+            let temp_dir = TempDir::new().unwrap();
+            // 34 bytes cached against a 28 byte cleanup target: evicting any
+            // single entry is enough.
+            let config = CacheConfig {
+                directory: temp_dir.path().to_path_buf(),
+                max_size_bytes: 32,
+                max_age_seconds: 3600,
+                eviction_policy: policy,
+                ..Default::default()
+            };
+            let cache = BlobCache::new(config).await.unwrap();
+            for (digest, size) in [("sha256:big", 20), ("sha256:mid", 8), ("sha256:small", 6)] {
+                cache
+                    .put(digest, Bytes::from(vec![0u8; size]), None)
+                    .await
+                    .unwrap();
+            }
+
+            // mid: oldest access; small: fewest accesses; big: largest.
+            for digest in ["sha256:mid", "sha256:mid", "sha256:mid", "sha256:small"] {
+                tokio::time::sleep(tokio::time::Duration::from_millis(2)).await;
+                cache.get(digest).await.unwrap();
+            }
+            for _ in 0..2 {
+                tokio::time::sleep(tokio::time::Duration::from_millis(2)).await;
+                cache.get("sha256:big").await.unwrap();
+            }
+
+            cache.cleanup().await.unwrap();
+
+            for digest in ["sha256:big", "sha256:mid", "sha256:small"] {
+                let kept = cache.db.contains_key(digest).unwrap();
+                assert_eq!(kept, digest != victim, "{:?} evicting {}", policy, digest);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_startup_reconciles_metadata_with_blob_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
             max_size_bytes: 1024 * 1024,
             max_age_seconds: 3600,
+            ..Default::default()
         };
-        let cache = BlobCache::new(config).await.unwrap();
-        (cache, temp_dir)
+
+        let cache = BlobCache::new(config.clone()).await.unwrap();
+        cache
+            .put("sha256:kept", Bytes::from("kept data"), None)
+            .await
+            .unwrap();
+        cache
+            .put("sha256:lost", Bytes::from("lost"), None)
+            .await
+            .unwrap();
+        let lost = cache.blob_path("sha256:lost");
+        let orphan = lost.with_file_name("sha256_orphan");
+        let partial = lost.with_file_name("sha256_partial.1234.tmp");
+        drop(cache);
+
+        // Simulate a crash: one blob file vanished, others were never recorded.
+        std::fs::remove_file(&lost).unwrap();
+        std::fs::write(&orphan, b"orphan").unwrap();
+        std::fs::write(&partial, b"part").unwrap();
+
+        let cache = reopen(config.clone()).await;
+        assert!(!cache.db.contains_key("sha256:lost").unwrap());
+        assert!(cache.get("sha256:kept").await.unwrap().is_some());
+        assert_eq!(*cache.total_size.read().await, "kept data".len() as u64);
+        assert!(orphan.exists() && !partial.exists());
+        drop(cache);
+
+        let config = CacheConfig {
+            delete_orphaned_blobs: true,
+            ..config
+        };
+        let cache = reopen(config).await;
+        assert!(!orphan.exists() && !partial.exists());
+        assert!(cache.blob_path("sha256:kept").exists());
     }
 
     #[tokio::test]
-    async fn test_cache_put_and_get() {
-        let (cache, _temp) = create_test_cache().await;
-        let digest = "sha256:abc123";
-        let data = Bytes::from("test data");
+    async fn test_clean_close_skips_reconciliation() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            max_size_bytes: 1024 * 1024,
+            max_age_seconds: 3600,
+            ..Default::default()
+        };
+
+        let cache = BlobCache::new(config.clone()).await.unwrap();
+        cache
+            .put("sha256:kept", Bytes::from("kept data"), None)
+            .await
+            .unwrap();
+        cache
+            .put("sha256:lost", Bytes::from("lost"), None)
+            .await
+            .unwrap();
+        let lost = cache.blob_path("sha256:lost");
+        let orphan = lost.with_file_name("sha256_orphan");
+        cache.close().await.unwrap();
+        drop(cache);
 
-        cache.put(digest, data.clone()).await.unwrap();
+        std::fs::remove_file(&lost).unwrap();
+        std::fs::write(&orphan, b"orphan").unwrap();
 
-        let retrieved = cache.get(digest).await.unwrap();
-        assert!(retrieved.is_some());
-        assert_eq!(retrieved.unwrap(), data);
+        // After a clean close the checkpoint is trusted as is.
+        let cache = reopen(config.clone()).await;
+        assert!(cache.db.contains_key("sha256:lost").unwrap());
+        assert_eq!(*cache.total_size.read().await, 13);
+        assert_eq!(BlobCache::read_checkpoint(&cache.db).unwrap().generation, 2);
+        drop(cache);
+
+        // Without a close, the next start reconciles.
+        let cache = reopen(config).await;
+        assert!(!cache.db.contains_key("sha256:lost").unwrap());
+        assert_eq!(*cache.total_size.read().await, "kept data".len() as u64);
+        let checkpoint = BlobCache::read_checkpoint(&cache.db).unwrap();
+        assert_eq!(checkpoint.generation, 3);
+        assert!(!checkpoint.clean);
     }
 
     #[tokio::test]
-    async fn test_cache_miss() {
-        let (cache, _temp) = create_test_cache().await;
-        let result = cache.get("sha256:nonexistent").await.unwrap();
-        assert!(result.is_none());
+    async fn test_startup_discards_stale_partial_downloads() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            max_size_bytes: 1024 * 1024,
+            partial_download_max_age_seconds: 3600,
+            ..Default::default()
+        };
+        let cache = BlobCache::new(config.clone()).await.unwrap();
+        let stale = cache.backend.staging_path("sha256:stale");
+        let fresh = cache.backend.staging_path("sha256:fresh");
+        drop(cache);
+
+        for path in [&stale, &fresh] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"part").unwrap();
+        }
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(7200))
+            .unwrap();
+
+        let _cache = reopen(config).await;
+        assert!(!stale.exists());
+        assert!(fresh.exists());
     }
 
     #[tokio::test]
-    async fn test_cache_cleanup_by_age() {
+    async fn test_layout_migration_relocates_old_blobs() {
         let temp_dir = TempDir::new().unwrap();
         let config = CacheConfig {
             directory: temp_dir.path().to_path_buf(),
             max_size_bytes: 1024 * 1024,
-            max_age_seconds: 1,
+            max_age_seconds: 3600,
+            layout_migration_files_per_second: 1,
+            ..Default::default()
         };
-        let cache = BlobCache::new(config).await.unwrap();
+        let blobs = [("sha256:abcdef", "first"), ("sha256:abcd99", "second")];
 
-        let digest = "sha256:old";
-        let data = Bytes::from("old data");
-        cache.put(digest, data).await.unwrap();
+        let cache = BlobCache::new(config.clone()).await.unwrap();
+        let mut paths = Vec::new();
+        for (digest, data) in blobs {
+            cache.put(digest, Bytes::from(data), None).await.unwrap();
+            paths.push(cache.blob_path(digest));
+        }
+        drop(cache);
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        // Move the first blob back to layout 1 and leave the second as if an
+        // interrupted migration had already moved it.
+        let blobs_dir = temp_dir.path().join("blobs");
+        let old_path = blobs_dir.join("sh").join("sha256_abcdef");
+        std::fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+        std::fs::rename(&paths[0], &old_path).unwrap();
+        std::fs::remove_file(temp_dir.path().join(LAYOUT_VERSION_FILE)).unwrap();
+
+        let cache = reopen(config).await;
+        assert_eq!(
+            paths[0],
+            blobs_dir.join("ab").join("cd").join("sha256_abcdef")
+        );
+        assert!(paths[0].exists());
+        assert!(!blobs_dir.join("sh").exists());
+        for (digest, data) in blobs {
+            assert_eq!(cache.get(digest).await.unwrap().unwrap(), Bytes::from(data));
+        }
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join(LAYOUT_VERSION_FILE)).unwrap(),
+            format!("{}\n", LAYOUT_VERSION)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shard_scheme_change_relocates_blobs() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            max_size_bytes: 1024 * 1024,
+            max_age_seconds: 3600,
+            ..Default::default()
+        };
+        let blobs = [("sha256:abcdef01", "first"), ("sha256:ab12", "second")];
+
+        let cache = BlobCache::new(config.clone()).await.unwrap();
+        for (digest, data) in blobs {
+            cache.put(digest, Bytes::from(data), None).await.unwrap();
+        }
+        drop(cache);
+        // Caches from before the scheme was recorded use the default one.
+        std::fs::remove_file(temp_dir.path().join(SHARD_SCHEME_FILE)).unwrap();
+
+        let blobs_dir = temp_dir.path().join("blobs");
+        config.shard_levels = 3;
+        config.shard_width = 1;
+        config.migrate_layout = false;
+        assert!(BlobCache::new(config.clone()).await.is_err());
+
+        config.migrate_layout = true;
+        let cache = reopen(config.clone()).await;
+        assert_eq!(
+            cache.blob_path("sha256:abcdef01"),
+            blobs_dir.join("a/b/c/sha256_abcdef01")
+        );
+        for (digest, data) in blobs {
+            assert!(cache.blob_path(digest).exists());
+            assert_eq!(cache.get(digest).await.unwrap().unwrap(), Bytes::from(data));
+        }
+        assert!(!blobs_dir.join("ab").exists());
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join(SHARD_SCHEME_FILE)).unwrap(),
+            "3x1\n"
+        );
+        drop(cache);
+
+        config.shard_levels = 0;
+        let cache = reopen(config).await;
+        assert_eq!(
+            cache.blob_path("sha256:ab12"),
+            blobs_dir.join("sha256_ab12")
+        );
+        for (digest, data) in blobs {
+            assert_eq!(cache.get(digest).await.unwrap().unwrap(), Bytes::from(data));
+        }
+        assert!(!blobs_dir.join("a").exists());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_blob_evicted_by_size_check() {
+        let data = Bytes::from("a complete blob");
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(&data)));
+
+        for evict in [true, false] {
+            let temp_dir = TempDir::new().unwrap();
+            let config = CacheConfig {
+                directory: temp_dir.path().to_path_buf(),
+                max_size_bytes: 1024 * 1024,
+                max_age_seconds: 3600,
+                evict_size_mismatches: evict,
+                ..Default::default()
+            };
+            let cache = BlobCache::new(config).await.unwrap();
+            cache.put(&digest, data.clone(), None).await.unwrap();
+            std::fs::write(cache.blob_path(&digest), b"a comp").unwrap();
+
+            cache.cleanup().await.unwrap();
+            assert_eq!(cache.db.contains_key(&digest).unwrap(), !evict);
+
+            if !evict {
+                // A kept mismatch is found again but only recorded once.
+                cache.cleanup().await.unwrap();
+                assert_eq!(cache.reported_size_mismatches.lock().unwrap().len(), 1);
+
+                // Verification evicts a mismatch regardless of the setting.
+                let report = cache.verify_integrity().await;
+                assert_eq!(report.corrupt, 1);
+                assert!(!cache.db.contains_key(&digest).unwrap());
+            }
+            assert_eq!(*cache.total_size.read().await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_permanent_digests_never_expire() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            max_size_bytes: 4,
+            max_age_seconds: 0,
+            immutable_digest_permanent: true,
+            permanent_exempt_from_size_limit: true,
+            ..Default::default()
+        };
+        let cache = BlobCache::new(config).await.unwrap();
+        cache
+            .put("sha256:forever", Bytes::from("seeded data"), None)
+            .await
+            .unwrap();
 
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
         cache.cleanup().await.unwrap();
 
-        let result = cache.get(digest).await.unwrap();
-        assert!(result.is_none());
+        assert!(cache.get("sha256:forever").await.unwrap().is_some());
     }
 
     #[tokio::test]
@@ -313,10 +2130,460 @@ mod tests {
         let data1 = Bytes::from(vec![0u8; 100]);
         let data2 = Bytes::from(vec![0u8; 200]);
 
-        cache.put("sha256:test1", data1).await.unwrap();
-        cache.put("sha256:test2", data2).await.unwrap();
+        cache.put("sha256:test1", data1, None).await.unwrap();
+        cache.put("sha256:test2", data2, None).await.unwrap();
 
         let total = *cache.total_size.read().await;
         assert_eq!(total, 300);
     }
+
+    #[tokio::test]
+    async fn test_concurrent_evictions_subtract_size_once() {
+        let (cache, _temp) = create_test_cache().await;
+        cache
+            .put("sha256:kept", Bytes::from(vec![0u8; 50]), None)
+            .await
+            .unwrap();
+        cache
+            .put("sha256:evicted", Bytes::from(vec![0u8; 100]), None)
+            .await
+            .unwrap();
+
+        let outcomes =
+            futures::future::join_all((0..8).map(|_| cache.evict("sha256:evicted"))).await;
+        let removed = outcomes
+            .into_iter()
+            .filter(|r| *r.as_ref().unwrap())
+            .count();
+        assert_eq!(removed, 1);
+        assert!(!cache.contains("sha256:evicted"));
+        assert_eq!(*cache.total_size.read().await, 50);
+
+        let entries: Vec<_> = cache.entries().map(|e| (e.digest, e.size)).collect();
+        assert_eq!(entries, [("sha256:kept".to_string(), 50)]);
+    }
+
+    #[tokio::test]
+    async fn test_total_size_not_double_counted() {
+        let (cache, _temp) = create_test_cache().await;
+        let data = Bytes::from(vec![0u8; 100]);
+
+        cache.put("sha256:same", data.clone(), None).await.unwrap();
+        cache.put("sha256:same", data, None).await.unwrap();
+
+        assert_eq!(*cache.total_size.read().await, 100);
+        assert_eq!(BlobCache::calculate_total_size(&cache.db).unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_honors_per_entry_max_age() {
+        let (cache, _temp) = create_test_cache().await;
+
+        cache
+            .put("sha256:short", Bytes::from("short"), Some(1))
+            .await
+            .unwrap();
+        cache
+            .put("sha256:default", Bytes::from("default"), None)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        cache.cleanup().await.unwrap();
+
+        assert!(cache.get("sha256:short").await.unwrap().is_none());
+        assert!(cache.get("sha256:default").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_is_retried_in_background() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            write_retry_attempts: 10,
+            write_retry_delay_ms: 20,
+            ..Default::default()
+        };
+        let cache = Arc::new(BlobCache::new(config).await.unwrap());
+
+        // A plain file where the blobs directory should be makes writes fail.
+        let blocker = temp_dir.path().join("blobs");
+        std::fs::write(&blocker, b"").unwrap();
+
+        let digest = "sha256:retry";
+        cache
+            .put_with_retry(digest, Bytes::from("payload"), None)
+            .await;
+        assert!(cache.db.get(digest).unwrap().is_none());
+        assert_eq!(
+            cache.get(digest).await.unwrap().unwrap(),
+            Bytes::from("payload")
+        );
+
+        std::fs::remove_file(&blocker).unwrap();
+
+        for _ in 0..100 {
+            if cache.db.get(digest).unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+        }
+
+        assert!(cache.db.get(digest).unwrap().is_some());
+        assert!(cache.blob_path(digest).exists());
+        tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+        assert!(cache.pending_writes.lock().unwrap().blobs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_blob_verified_while_writing() {
+        let (cache, _temp) = create_test_cache().await;
+        let cache = Arc::new(cache);
+        let data = b"streamed layer contents";
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(data)));
+
+        let mut writer = cache.writer(&digest, None).await.unwrap().unwrap();
+        for chunk in data.chunks(5) {
+            writer.write(chunk).await.unwrap();
+        }
+        // The blob is only reachable after the streamed hash is verified.
+        assert!(cache.get(&digest).await.unwrap().is_none());
+        writer.commit().await.unwrap();
+
+        assert_eq!(cache.get(&digest).await.unwrap().unwrap(), &data[..]);
+        assert_eq!(*cache.total_size.read().await, data.len() as u64);
+
+        let wrong = format!("sha256:{}", hex::encode(Sha256::digest(b"other")));
+        let mut writer = cache.writer(&wrong, None).await.unwrap().unwrap();
+        writer.write(data).await.unwrap();
+        assert!(writer.commit().await.is_err());
+        assert!(cache.get(&wrong).await.unwrap().is_none());
+
+        let blob_path = cache.blob_path(&digest);
+        let siblings: Vec<_> = std::fs::read_dir(blob_path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(siblings, vec![blob_path]);
+    }
+
+    #[tokio::test]
+    async fn test_one_writer_per_digest() {
+        let (cache, _temp) = create_test_cache().await;
+        let cache = Arc::new(cache);
+        let data = b"shared layer";
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(data)));
+
+        let mut first = cache.writer(&digest, None).await.unwrap().unwrap();
+        assert!(cache.writer(&digest, None).await.unwrap().is_none());
+        first.write(data).await.unwrap();
+        first.commit().await.unwrap();
+
+        // Once the first writer is done, the digest can be written again.
+        let again = cache.writer(&digest, None).await.unwrap();
+        assert!(again.is_some());
+        drop(again);
+        assert!(cache.writing.lock().unwrap().is_empty());
+        assert_eq!(*cache.total_size.read().await, data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_replicas_share_blobs_through_s3_backend() {
+        use crate::config::{CacheBackendConfig, S3BackendConfig};
+        use crate::test_support::{s3_upstream, spawn_upstream};
+
+        let (router, objects) = s3_upstream();
+        let endpoint = spawn_upstream(router).await;
+        let replica = |directory: &Path| CacheConfig {
+            directory: directory.to_path_buf(),
+            backend: CacheBackendConfig::S3(S3BackendConfig {
+                bucket: "blobs".to_string(),
+                region: Some("us-east-1".to_string()),
+                endpoint: Some(endpoint.clone()),
+                prefix: String::new(),
+                access_key_id: Some("test".to_string()),
+                secret_access_key: Some("test".to_string()),
+                allow_http: true,
+            }),
+            ..Default::default()
+        };
+        let (first_dir, second_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let first = Arc::new(BlobCache::new(replica(first_dir.path())).await.unwrap());
+        let second = BlobCache::new(replica(second_dir.path())).await.unwrap();
+
+        let data = b"streamed layer";
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(data)));
+        let mut writer = first.writer(&digest, None).await.unwrap().unwrap();
+        writer.write(data).await.unwrap();
+        writer.commit().await.unwrap();
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key(&format!("blobs/{}", digest.replace(':', "/"))));
+
+        // The second replica has no metadata for the blob but finds it in the
+        // shared bucket, and tracks it from then on.
+        assert_eq!(
+            second.get(&digest).await.unwrap(),
+            Some(Bytes::from_static(data))
+        );
+        assert_eq!(*second.total_size.read().await, data.len() as u64);
+        assert!(second.get("sha256:missing").await.unwrap().is_none());
+
+        // Evicting on one replica drops only its own metadata; the object
+        // stays for the other.
+        assert!(first.evict(&digest).await.unwrap());
+        assert_eq!(*first.total_size.read().await, 0);
+        assert_eq!(objects.lock().unwrap().len(), 1);
+        assert_eq!(
+            second.get(&digest).await.unwrap(),
+            Some(Bytes::from_static(data))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compress_at_rest() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            compress_at_rest: true,
+            metadata_format: MetadataFormat::Binary,
+            ..Default::default()
+        };
+        let sha256 = |data: &[u8]| format!("sha256:{}", hex::encode(Sha256::digest(data)));
+        let compressible = Bytes::from(vec![b'a'; 10_000]);
+        let streamed = Bytes::from(vec![b'b'; 20_000]);
+        let incompressible: Bytes = (0..4096).map(|_| rand::random::<u8>()).collect();
+
+        let cache = Arc::new(BlobCache::new(config).await.unwrap());
+        cache
+            .put(&sha256(&compressible), compressible.clone(), None)
+            .await
+            .unwrap();
+        cache
+            .put(&sha256(&incompressible), incompressible.clone(), None)
+            .await
+            .unwrap();
+        let mut writer = cache
+            .writer(&sha256(&streamed), None)
+            .await
+            .unwrap()
+            .unwrap();
+        writer.write(&streamed).await.unwrap();
+        writer.commit().await.unwrap();
+
+        let on_disk = |data: &[u8]| {
+            std::fs::metadata(cache.blob_path(&sha256(data)))
+                .unwrap()
+                .len()
+        };
+        assert!(on_disk(&compressible) < 1000);
+        assert!(on_disk(&streamed) < 1000);
+        assert_eq!(on_disk(&incompressible), 4096);
+        let stored = on_disk(&compressible) + on_disk(&streamed) + 4096;
+        assert_eq!(*cache.total_size.read().await, stored);
+        assert_eq!(cache.compression_saved_bytes(), 34_096 - stored);
+        assert_eq!(cache.verify_integrity().await.corrupt, 0);
+
+        // The startup reconcile recounts the savings from the metadata.
+        cache.saved_bytes.store(0, Ordering::Relaxed);
+        cache.reconcile().await.unwrap();
+        assert_eq!(cache.compression_saved_bytes(), 34_096 - stored);
+        for data in [&compressible, &streamed, &incompressible] {
+            assert_eq!(&cache.get(&sha256(data)).await.unwrap().unwrap(), data);
+        }
+        let info = cache
+            .entries()
+            .find(|e| e.digest == sha256(&compressible))
+            .unwrap();
+        assert_eq!(info.size, 10_000);
+        assert!(info.stored_size < 1000);
+
+        assert!(cache.evict(&sha256(&streamed)).await.unwrap());
+        assert_eq!(cache.compression_saved_bytes(), 10_000 - info.stored_size);
+    }
+
+    #[test]
+    fn test_binary_entries_without_stored_size_decode() {
+        let legacy = (
+            "sha256:old".to_string(),
+            42u64,
+            Utc::now(),
+            Utc::now(),
+            3u64,
+            Some(60u64),
+        );
+        let mut data = vec![BINARY_ENTRY_TAG_V0];
+        bincode::serialize_into(&mut data, &legacy).unwrap();
+
+        let entry = CacheEntry::decode(&data).unwrap();
+        assert_eq!(entry.size, 42);
+        assert_eq!(entry.access_count, 3);
+        assert_eq!(entry.max_age_seconds, Some(60));
+        assert_eq!(entry.stored_size, None);
+    }
+
+    #[tokio::test]
+    async fn test_binary_metadata_round_trip_and_migration() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = |metadata_format| CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            metadata_format,
+            ..Default::default()
+        };
+        let stored_entry = |cache: &BlobCache| cache.db.get("sha256:json").unwrap().unwrap();
+
+        let cache = BlobCache::new(config(MetadataFormat::Json)).await.unwrap();
+        cache
+            .put("sha256:json", Bytes::from("json"), Some(60))
+            .await
+            .unwrap();
+        let json_len = stored_entry(&cache).len();
+        drop(cache);
+
+        let cache = reopen(config(MetadataFormat::Binary)).await;
+        let migrated = stored_entry(&cache);
+        assert_eq!(MetadataFormat::of_entry(&migrated), MetadataFormat::Binary);
+        assert!(migrated.len() < json_len);
+
+        let entry = CacheEntry::decode(&migrated).unwrap();
+        assert_eq!(entry.size, 4);
+        assert_eq!(entry.max_age_seconds, Some(60));
+        assert_eq!(
+            CacheEntry::decode(&entry.encode(MetadataFormat::Binary).unwrap())
+                .unwrap()
+                .created,
+            entry.created
+        );
+
+        cache
+            .put("sha256:binary", Bytes::from("binary"), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.get("sha256:json").await.unwrap().unwrap(), "json");
+        assert_eq!(*cache.total_size.read().await, 10);
+        drop(cache);
+
+        let cache = reopen(config(MetadataFormat::Json)).await;
+        assert_eq!(
+            MetadataFormat::of_entry(&stored_entry(&cache)),
+            MetadataFormat::Json
+        );
+        assert_eq!(cache.get("sha256:binary").await.unwrap().unwrap(), "binary");
+        assert_eq!(*cache.total_size.read().await, 10);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_verification_prunes_bad_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            verify_concurrency: 4,
+            ..Default::default()
+        };
+        let cache = BlobCache::new(config).await.unwrap();
+
+        let mut digests = Vec::new();
+        for i in 0..24 {
+            let data = format!("blob {}", i);
+            let digest = format!("sha256:{}", hex::encode(Sha256::digest(&data)));
+            cache.put(&digest, Bytes::from(data), None).await.unwrap();
+            digests.push(digest);
+        }
+        std::fs::write(cache.blob_path(&digests[3]), "tampered").unwrap();
+        std::fs::remove_file(cache.blob_path(&digests[7])).unwrap();
+        std::fs::remove_file(cache.blob_path(&digests[11])).unwrap();
+
+        let report = cache.verify_integrity().await;
+        assert_eq!(
+            report,
+            VerifyReport {
+                checked: 24,
+                missing: 2,
+                corrupt: 1,
+            }
+        );
+
+        for (i, digest) in digests.iter().enumerate() {
+            let present = cache.db.contains_key(digest.as_bytes()).unwrap();
+            assert_eq!(present, ![3, 7, 11].contains(&i), "entry {}", i);
+        }
+        assert_eq!(
+            *cache.total_size.read().await,
+            BlobCache::calculate_total_size(&cache.db).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_layer_stats_and_item_size_bypass() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            memory_cache_bytes: 1024,
+            memory_promotion_threshold: 0,
+            memory_max_item_bytes: 8,
+            ..Default::default()
+        };
+        let cache = BlobCache::new(config).await.unwrap();
+
+        cache
+            .put("sha256:small", Bytes::from("small"), None)
+            .await
+            .unwrap();
+        cache
+            .put("sha256:large", Bytes::from(vec![0u8; 64]), None)
+            .await
+            .unwrap();
+        assert!(cache.memory.contains("sha256:small"));
+        assert!(!cache.memory.contains("sha256:large"));
+
+        cache.get("sha256:small").await.unwrap();
+        cache.get("sha256:large").await.unwrap();
+        cache.get("sha256:missing").await.unwrap();
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                memory_hits: 1,
+                memory_misses: 2,
+                disk_hits: 1,
+                disk_misses: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_promotion_after_threshold() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheConfig {
+            directory: temp_dir.path().to_path_buf(),
+            max_size_bytes: 1024 * 1024,
+            max_age_seconds: 3600,
+            memory_cache_bytes: 1024,
+            memory_promotion_threshold: 3,
+            ..Default::default()
+        };
+        let cache = BlobCache::new(config).await.unwrap();
+
+        cache
+            .put("sha256:once", Bytes::from("cold"), None)
+            .await
+            .unwrap();
+        cache
+            .put("sha256:often", Bytes::from("hot"), None)
+            .await
+            .unwrap();
+
+        cache.get("sha256:once").await.unwrap();
+        for _ in 0..3 {
+            cache.get("sha256:often").await.unwrap();
+        }
+
+        assert!(!cache.memory.contains("sha256:once"));
+        assert!(cache.memory.contains("sha256:often"));
+        assert_eq!(
+            cache.get("sha256:often").await.unwrap().unwrap(),
+            Bytes::from("hot")
+        );
+    }
 }
@@ -0,0 +1,446 @@
+//! Storage for cached blob contents. `BlobCache` keeps metadata, the memory
+//! tier and eviction to itself and only hands the bytes to a backend: local
+//! files by default, or an object store shared by several proxy replicas.
+
+use crate::config::{CacheBackendConfig, CacheConfig, S3BackendConfig};
+use crate::error::{ProxyError, Result};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncWriteExt, BufReader};
+
+pub trait CacheBackend: Send + Sync {
+    /// Contents of the blob, or `None` if it is not stored.
+    fn get<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<Option<Bytes>>>;
+
+    /// Size of the blob, or `None` if it is not stored.
+    fn head<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<Option<u64>>>;
+
+    /// Stores a blob so that readers never observe it partially written.
+    fn put<'a>(&'a self, digest: &'a str, data: Bytes) -> BoxFuture<'a, Result<()>>;
+
+    /// Stores the verified blob written to `staged`, a path obtained from
+    /// `staging_path`, consuming the file.
+    fn put_file<'a>(&'a self, digest: &'a str, staged: &'a Path) -> BoxFuture<'a, Result<()>>;
+
+    /// Removes a blob; removing one that is not stored succeeds.
+    fn remove<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Fresh local path a blob can be streamed to before `put_file`.
+    fn staging_path(&self, digest: &str) -> PathBuf;
+
+    /// Local file holding the blob, for backends that keep blobs on disk.
+    fn local_path(&self, _digest: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Directory holding the blob files of a local backend.
+    fn blob_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Directory of staged blobs, for backends that do not stage them next to
+    /// the blob files in `blob_dir`.
+    fn staging_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Whether other proxy instances store blobs here too, so blobs may exist
+    /// that this instance has no metadata for.
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
+
+pub fn from_config(
+    config: &CacheBackendConfig,
+    directory: &Path,
+    scheme: ShardScheme,
+) -> Result<Arc<dyn CacheBackend>> {
+    match config {
+        CacheBackendConfig::Filesystem => Ok(Arc::new(FilesystemBackend::new(directory, scheme))),
+        CacheBackendConfig::S3(s3) => {
+            let store = build_s3(s3)
+                .map_err(|e| ProxyError::Cache(format!("Failed to set up S3 backend: {}", e)))?;
+            Ok(Arc::new(ObjectStoreBackend::new(
+                Arc::new(store),
+                &s3.prefix,
+                directory,
+            )))
+        }
+    }
+}
+
+fn build_s3(config: &S3BackendConfig) -> object_store::Result<object_store::aws::AmazonS3> {
+    let mut builder = AmazonS3Builder::from_env()
+        .with_bucket_name(&config.bucket)
+        .with_allow_http(config.allow_http);
+    if let Some(region) = &config.region {
+        builder = builder.with_region(region);
+    }
+    if let Some(endpoint) = &config.endpoint {
+        builder = builder.with_endpoint(endpoint);
+    }
+    if let Some(access_key_id) = &config.access_key_id {
+        builder = builder.with_access_key_id(access_key_id);
+    }
+    if let Some(secret_access_key) = &config.secret_access_key {
+        builder = builder.with_secret_access_key(secret_access_key);
+    }
+    builder.build()
+}
+
+fn cache_error(action: &str, e: impl std::fmt::Display) -> ProxyError {
+    ProxyError::Cache(format!("Failed to {}: {}", action, e))
+}
+
+/// Blob files below `<cache directory>/blobs`, sharded by digest prefix.
+pub struct FilesystemBackend {
+    blobs_dir: PathBuf,
+    scheme: ShardScheme,
+}
+
+impl FilesystemBackend {
+    pub fn new(directory: &Path, scheme: ShardScheme) -> Self {
+        Self {
+            blobs_dir: directory.join("blobs"),
+            scheme,
+        }
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        self.blobs_dir
+            .join(self.scheme.path(&digest.replace(':', "_")))
+    }
+
+    async fn create_parent(path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| cache_error("create cache subdirectory", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl CacheBackend for FilesystemBackend {
+    fn get<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<Option<Bytes>>> {
+        Box::pin(async move {
+            match fs::read(self.blob_path(digest)).await {
+                Ok(data) => Ok(Some(Bytes::from(data))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(cache_error("read cached blob", e)),
+            }
+        })
+    }
+
+    fn head<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(async move {
+            match fs::metadata(self.blob_path(digest)).await {
+                Ok(metadata) => Ok(Some(metadata.len())),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(cache_error("stat cached blob", e)),
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, digest: &'a str, data: Bytes) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // Write to a uniquely named sibling and rename it into place, so
+            // readers never observe a partially written blob.
+            let staged = self.staging_path(digest);
+            Self::create_parent(&staged).await?;
+            let written = async {
+                let mut file = fs::File::create(&staged)
+                    .await
+                    .map_err(|e| cache_error("create cache file", e))?;
+                file.write_all(&data)
+                    .await
+                    .map_err(|e| cache_error("write cache file", e))?;
+                file.sync_all()
+                    .await
+                    .map_err(|e| cache_error("sync cache file", e))
+            }
+            .await;
+            match written {
+                Ok(()) => self.put_file(digest, &staged).await,
+                Err(e) => {
+                    let _ = fs::remove_file(&staged).await;
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    fn put_file<'a>(&'a self, digest: &'a str, staged: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let blob_path = self.blob_path(digest);
+            Self::create_parent(&blob_path).await?;
+            if let Err(e) = fs::rename(staged, &blob_path).await {
+                let _ = fs::remove_file(staged).await;
+                return Err(cache_error("move cache file", e));
+            }
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match fs::remove_file(self.blob_path(digest)).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(cache_error("remove blob file", e)),
+            }
+        })
+    }
+
+    /// A sibling of the blob file, so the final rename stays on one
+    /// filesystem and leftovers are found by the orphan scan.
+    fn staging_path(&self, digest: &str) -> PathBuf {
+        temp_path_for(&self.blob_path(digest))
+    }
+
+    fn local_path(&self, digest: &str) -> Option<PathBuf> {
+        Some(self.blob_path(digest))
+    }
+
+    fn blob_dir(&self) -> Option<&Path> {
+        Some(&self.blobs_dir)
+    }
+}
+
+/// Blobs as objects named `<prefix><algorithm>/<hex>` in an object store.
+/// Streamed blobs are staged in `<cache directory>/staging` and uploaded once
+/// verified.
+pub struct ObjectStoreBackend {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+    staging_dir: PathBuf,
+}
+
+impl ObjectStoreBackend {
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: &str, directory: &Path) -> Self {
+        Self {
+            store,
+            prefix: prefix.to_string(),
+            staging_dir: directory.join("staging"),
+        }
+    }
+
+    fn object_path(&self, digest: &str) -> ObjectPath {
+        ObjectPath::from(format!("{}{}", self.prefix, digest.replacen(':', "/", 1)))
+    }
+}
+
+impl CacheBackend for ObjectStoreBackend {
+    fn get<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<Option<Bytes>>> {
+        Box::pin(async move {
+            let result = match self.store.get(&self.object_path(digest)).await {
+                Ok(result) => result,
+                Err(object_store::Error::NotFound { .. }) => return Ok(None),
+                Err(e) => return Err(cache_error("fetch blob object", e)),
+            };
+            result
+                .bytes()
+                .await
+                .map(Some)
+                .map_err(|e| cache_error("read blob object", e))
+        })
+    }
+
+    fn head<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(async move {
+            match self.store.head(&self.object_path(digest)).await {
+                Ok(meta) => Ok(Some(meta.size as u64)),
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(cache_error("stat blob object", e)),
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, digest: &'a str, data: Bytes) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.store
+                .put(&self.object_path(digest), PutPayload::from(data))
+                .await
+                .map(|_| ())
+                .map_err(|e| cache_error("upload blob object", e))
+        })
+    }
+
+    fn put_file<'a>(&'a self, digest: &'a str, staged: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // Large blobs are uploaded in parts without reading them into
+            // memory.
+            let uploaded = async {
+                let file = fs::File::open(staged)
+                    .await
+                    .map_err(|e| cache_error("open staged blob", e))?;
+                let mut upload = BufWriter::new(self.store.clone(), self.object_path(digest));
+                tokio::io::copy(&mut BufReader::new(file), &mut upload)
+                    .await
+                    .map_err(|e| cache_error("upload blob object", e))?;
+                upload
+                    .shutdown()
+                    .await
+                    .map_err(|e| cache_error("upload blob object", e))
+            }
+            .await;
+            let _ = fs::remove_file(staged).await;
+            uploaded
+        })
+    }
+
+    fn remove<'a>(&'a self, digest: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match self.store.delete(&self.object_path(digest)).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+                Err(e) => Err(cache_error("delete blob object", e)),
+            }
+        })
+    }
+
+    fn staging_path(&self, digest: &str) -> PathBuf {
+        temp_path_for(&self.staging_dir.join(digest.replace(':', "_")))
+    }
+
+    fn staging_dir(&self) -> Option<&Path> {
+        Some(&self.staging_dir)
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+/// How blob files are spread over subdirectories of the blob directory:
+/// `levels` directories deep, each named after the next `width` characters
+/// of the digest's hex part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardScheme {
+    pub levels: usize,
+    pub width: usize,
+}
+
+impl Default for ShardScheme {
+    /// The scheme of layout version 2 before sharding became configurable.
+    fn default() -> Self {
+        Self {
+            levels: 2,
+            width: 2,
+        }
+    }
+}
+
+impl ShardScheme {
+    pub fn from_config(config: &CacheConfig) -> Self {
+        Self {
+            levels: config.shard_levels,
+            width: config.shard_width,
+        }
+    }
+
+    /// Path of a blob file below the blob directory: the prefix levels, then
+    /// the file itself. Digests too short for a level use `_` for it.
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        let hex = file_name.split_once('_').map_or(file_name, |(_, hex)| hex);
+        let mut path: PathBuf = (0..self.levels)
+            .map(|level| {
+                hex.get(level * self.width..(level + 1) * self.width)
+                    .unwrap_or("_")
+            })
+            .collect();
+        path.push(file_name);
+        path
+    }
+
+    /// Depth of blob files below the blob directory.
+    pub fn file_depth(&self) -> usize {
+        self.levels + 1
+    }
+}
+
+impl std::fmt::Display for ShardScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.levels, self.width)
+    }
+}
+
+impl std::str::FromStr for ShardScheme {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        let (levels, width) = s.split_once('x').ok_or(())?;
+        Ok(Self {
+            levels: levels.parse().map_err(|_| ())?,
+            width: width.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+fn temp_path_for(blob_path: &Path) -> PathBuf {
+    let file_name = blob_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    blob_path.with_file_name(format!(
+        "{}.{}.tmp",
+        file_name,
+        uuid::Uuid::new_v4().simple()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{s3_upstream, spawn_upstream};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_s3_backend_round_trip() {
+        let (router, objects) = s3_upstream();
+        let endpoint = spawn_upstream(router).await;
+        let temp_dir = TempDir::new().unwrap();
+        let config = CacheBackendConfig::S3(S3BackendConfig {
+            bucket: "blobs".to_string(),
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(endpoint),
+            prefix: "cargo-bay/".to_string(),
+            access_key_id: Some("test".to_string()),
+            secret_access_key: Some("test".to_string()),
+            allow_http: true,
+        });
+        let backend = from_config(&config, temp_dir.path(), ShardScheme::default()).unwrap();
+        let digest = "sha256:0123abcd";
+
+        assert_eq!(backend.get(digest).await.unwrap(), None);
+        backend.put(digest, Bytes::from("layer")).await.unwrap();
+        assert_eq!(backend.head(digest).await.unwrap(), Some(5));
+        assert_eq!(
+            backend.get(digest).await.unwrap(),
+            Some(Bytes::from("layer"))
+        );
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key("blobs/cargo-bay/sha256/0123abcd"));
+
+        let staged = backend.staging_path(digest);
+        fs::create_dir_all(staged.parent().unwrap()).await.unwrap();
+        fs::write(&staged, "streamed layer").await.unwrap();
+        backend.put_file(digest, &staged).await.unwrap();
+        assert!(!staged.exists());
+        assert_eq!(backend.head(digest).await.unwrap(), Some(14));
+
+        backend.remove(digest).await.unwrap();
+        backend.remove(digest).await.unwrap();
+        assert_eq!(backend.head(digest).await.unwrap(), None);
+        assert!(objects.lock().unwrap().is_empty());
+    }
+}
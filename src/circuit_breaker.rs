@@ -0,0 +1,223 @@
+//! Stops sending requests to a registry that keeps failing. After a run of
+//! consecutive failures the circuit opens and requests fail immediately
+//! instead of each waiting out the timeouts; once the cooldown has passed a
+//! single request is let through to test whether the registry recovered.
+
+use crate::error::{ProxyError, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    /// When the circuit opened; `None` while closed.
+    opened_at: Option<Instant>,
+    /// When the request testing recovery was let through. A probe that never
+    /// reports back, e.g. because its client went away, is replaced after
+    /// another cooldown.
+    probe_started: Option<Instant>,
+    opens: u64,
+}
+
+impl Circuit {
+    fn state(&self, cooldown: Duration) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    /// Consecutive failures that open a circuit; 0 never opens one.
+    failure_threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fails fast while the circuit of `registry_id` is open, and while
+    /// another request is testing a half-open one.
+    pub fn check(&self, registry_id: &str) -> Result<()> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(registry_id) else {
+            return Ok(());
+        };
+        match circuit.state(self.cooldown) {
+            CircuitState::Closed => Ok(()),
+            CircuitState::HalfOpen
+                if circuit
+                    .probe_started
+                    .is_none_or(|started| started.elapsed() >= self.cooldown) =>
+            {
+                info!(
+                    "Circuit of registry {} is half-open; testing recovery",
+                    registry_id
+                );
+                circuit.probe_started = Some(Instant::now());
+                Ok(())
+            }
+            CircuitState::Open | CircuitState::HalfOpen => {
+                let opened_at = circuit.opened_at.expect("the circuit is not closed");
+                let retry_after = self.cooldown.saturating_sub(opened_at.elapsed());
+                Err(ProxyError::CircuitOpen {
+                    registry: registry_id.to_string(),
+                    retry_after: retry_after.as_secs_f64().ceil().max(1.0) as u64,
+                })
+            }
+        }
+    }
+
+    /// Records the outcome of a request that reached the registry.
+    pub fn record(&self, registry_id: &str, success: bool) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(registry_id.to_string()).or_default();
+
+        if success {
+            if circuit.opened_at.is_some() {
+                info!("Registry {} recovered; circuit closed", registry_id);
+            }
+            circuit.consecutive_failures = 0;
+            circuit.opened_at = None;
+            circuit.probe_started = None;
+            return;
+        }
+
+        circuit.consecutive_failures = circuit.consecutive_failures.saturating_add(1);
+        let reopen = circuit.state(self.cooldown) == CircuitState::HalfOpen;
+        if reopen
+            || circuit.opened_at.is_none() && circuit.consecutive_failures >= self.failure_threshold
+        {
+            warn!(
+                "Registry {} failed {} consecutive requests; circuit open for {:?}",
+                registry_id, circuit.consecutive_failures, self.cooldown
+            );
+            circuit.opened_at = Some(Instant::now());
+            circuit.probe_started = None;
+            circuit.opens += 1;
+        }
+    }
+
+    /// State of each registry's circuit and how often it has opened, sorted
+    /// by registry id. Registries without any recorded failure are absent.
+    pub fn circuits(&self) -> Vec<(String, CircuitState, u64)> {
+        let mut circuits: Vec<_> = self
+            .circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(registry, circuit)| {
+                (
+                    registry.clone(),
+                    circuit.state(self.cooldown),
+                    circuit.opens,
+                )
+            })
+            .collect();
+        circuits.sort_by(|a, b| a.0.cmp(&b.0));
+        circuits
+    }
+
+    pub fn state(&self, registry_id: &str) -> CircuitState {
+        self.circuits
+            .lock()
+            .unwrap()
+            .get(registry_id)
+            .map_or(CircuitState::Closed, |circuit| circuit.state(self.cooldown))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record("hub", false);
+        breaker.record("hub", false);
+        breaker.record("hub", true);
+        breaker.record("hub", false);
+        breaker.record("hub", false);
+        assert!(breaker.check("hub").is_ok());
+
+        breaker.record("hub", false);
+        assert_eq!(breaker.state("hub"), CircuitState::Open);
+        assert!(matches!(
+            breaker.check("hub"),
+            Err(ProxyError::CircuitOpen {
+                retry_after: 60,
+                ..
+            })
+        ));
+        assert!(breaker.check("ghcr").is_ok());
+        assert_eq!(
+            breaker.circuits(),
+            [("hub".to_string(), CircuitState::Open, 1)]
+        );
+    }
+
+    #[test]
+    fn test_half_open_lets_one_probe_through() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        breaker.record("hub", false);
+        assert!(breaker.check("hub").is_err());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.state("hub"), CircuitState::HalfOpen);
+        assert!(breaker.check("hub").is_ok());
+        assert!(breaker.check("hub").is_err());
+
+        // A failed probe opens the circuit for another cooldown.
+        breaker.record("hub", false);
+        assert_eq!(breaker.state("hub"), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.check("hub").is_ok());
+        breaker.record("hub", true);
+        assert_eq!(breaker.state("hub"), CircuitState::Closed);
+        assert!(breaker.check("hub").is_ok());
+        assert!(breaker.check("hub").is_ok());
+    }
+
+    #[test]
+    fn test_threshold_zero_never_opens() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record("hub", false);
+        }
+        assert!(breaker.check("hub").is_ok());
+        assert!(breaker.circuits().is_empty());
+    }
+}
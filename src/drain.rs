@@ -0,0 +1,88 @@
+//! Connection draining during graceful shutdown. Once draining starts, new
+//! requests are refused with `503` and `Connection: close`, while requests
+//! already in flight run to completion.
+
+use crate::error::ProxyError;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Default)]
+pub struct DrainState {
+    draining: AtomicBool,
+}
+
+impl DrainState {
+    pub fn start(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+pub async fn drain_middleware(
+    State(drain): State<Arc<DrainState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !drain.is_draining() {
+        return next.run(request).await;
+    }
+
+    let mut response =
+        ProxyError::ServiceUnavailable("Server is shutting down".into()).into_response();
+    response
+        .headers_mut()
+        .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_draining_refuses_new_requests_but_finishes_in_flight() {
+        let entered = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+
+        let drain = Arc::new(DrainState::default());
+        let (handler_entered, handler_release) = (entered.clone(), release.clone());
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(move || async move {
+                    handler_entered.notify_one();
+                    handler_release.notified().await;
+                    "done"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                drain.clone(),
+                drain_middleware,
+            ));
+
+        let request = || Request::get("/slow").body(Body::empty()).unwrap();
+        let in_flight = tokio::spawn(app.clone().oneshot(request()));
+        entered.notified().await;
+
+        drain.start();
+        let refused = app.oneshot(request()).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(refused.headers()[header::CONNECTION], "close");
+
+        release.notify_one();
+        let finished = in_flight.await.unwrap().unwrap();
+        assert_eq!(finished.status(), StatusCode::OK);
+    }
+}
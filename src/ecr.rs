@@ -0,0 +1,246 @@
+//! Credentials for Amazon ECR registries. ECR accepts basic auth with the
+//! user `AWS` and a password from `GetAuthorizationToken`, valid for 12
+//! hours. The call is signed with credentials from the AWS SDK's default
+//! provider chain, and its result is reused until shortly before it expires.
+
+use crate::config::{EcrAuth, UpstreamAuth};
+use crate::error::{ProxyError, Result};
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use aws_credential_types::provider::ProvideCredentials;
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::sign::v4;
+use base64::Engine;
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, OnceCell};
+use tracing::info;
+
+const GET_AUTHORIZATION_TOKEN: &str = "AmazonEC2ContainerRegistry_V20150921.GetAuthorizationToken";
+
+/// How long before its expiry a registry password is replaced.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizationResponse {
+    authorization_data: Vec<AuthorizationData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizationData {
+    /// Base64 of `user:password`.
+    authorization_token: String,
+    /// Seconds since the epoch.
+    expires_at: f64,
+}
+
+pub struct EcrCredentials {
+    config: EcrAuth,
+    client: Client,
+    sdk_config: OnceCell<SdkConfig>,
+    /// The current registry credential and when it expires. Held across a
+    /// refresh so concurrent requests share one `GetAuthorizationToken` call.
+    current: Mutex<Option<(UpstreamAuth, SystemTime)>>,
+}
+
+impl EcrCredentials {
+    pub fn new(config: EcrAuth, client: Client) -> Self {
+        Self {
+            config,
+            client,
+            sdk_config: OnceCell::new(),
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Uses `sdk_config` instead of loading the SDK's default configuration.
+    #[cfg(test)]
+    fn with_sdk_config(config: EcrAuth, client: Client, sdk_config: SdkConfig) -> Self {
+        Self {
+            sdk_config: OnceCell::new_with(Some(sdk_config)),
+            ..Self::new(config, client)
+        }
+    }
+
+    /// The registry credential, fetching a new one when none is held or the
+    /// held one is about to expire.
+    pub async fn credential(&self) -> Result<UpstreamAuth> {
+        let mut current = self.current.lock().await;
+        if let Some((auth, expires_at)) = &*current {
+            if SystemTime::now() + EXPIRY_MARGIN < *expires_at {
+                return Ok(auth.clone());
+            }
+        }
+
+        let (auth, expires_at) = self.fetch().await?;
+        info!(
+            "Obtained ECR registry credentials valid until {:?}",
+            expires_at
+        );
+        *current = Some((auth.clone(), expires_at));
+        Ok(auth)
+    }
+
+    async fn fetch(&self) -> Result<(UpstreamAuth, SystemTime)> {
+        let sdk_config = self
+            .sdk_config
+            .get_or_init(|| async {
+                let mut loader = aws_config::defaults(BehaviorVersion::latest());
+                if let Some(region) = &self.config.region {
+                    loader = loader.region(Region::new(region.clone()));
+                }
+                loader.load().await
+            })
+            .await;
+        let region = sdk_config
+            .region()
+            .ok_or_else(|| ProxyError::Internal("No AWS region configured for ECR".into()))?;
+        let credentials = sdk_config
+            .credentials_provider()
+            .ok_or_else(|| ProxyError::Internal("No AWS credentials provider for ECR".into()))?
+            .provide_credentials()
+            .await
+            .map_err(|e| ProxyError::Internal(format!("Failed to load AWS credentials: {}", e)))?;
+
+        let url = match &self.config.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => format!("https://api.ecr.{}.amazonaws.com/", region),
+        };
+        let body = b"{}";
+        let headers = [
+            ("content-type", "application/x-amz-json-1.1"),
+            ("x-amz-target", GET_AUTHORIZATION_TOKEN),
+        ];
+
+        let identity = credentials.into();
+        let signing_params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(region.as_ref())
+            .name("ecr")
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()
+            .map_err(|e| ProxyError::Internal(format!("Failed to sign ECR request: {}", e)))?
+            .into();
+        let signable = SignableRequest::new(
+            "POST",
+            &url,
+            headers.iter().copied(),
+            SignableBody::Bytes(body),
+        )
+        .and_then(|signable| sign(signable, &signing_params))
+        .map_err(|e| ProxyError::Internal(format!("Failed to sign ECR request: {}", e)))?;
+        let (instructions, _signature) = signable.into_parts();
+
+        let mut request = self.client.post(&url).body(body.as_slice());
+        for (name, value) in headers.into_iter().chain(instructions.headers()) {
+            request = request.header(name, value);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(ProxyError::Internal(format!(
+                "ECR GetAuthorizationToken failed with status: {}",
+                response.status()
+            )));
+        }
+
+        let response: AuthorizationResponse = response.json().await?;
+        let data = response
+            .authorization_data
+            .into_iter()
+            .next()
+            .ok_or_else(|| ProxyError::Internal("No authorization data in ECR response".into()))?;
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(&data.authorization_token)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let Some((username, password)) = decoded.as_deref().and_then(|d| d.split_once(':')) else {
+            return Err(ProxyError::Internal(
+                "Malformed authorization token in ECR response".into(),
+            ));
+        };
+
+        let auth = UpstreamAuth {
+            username: username.to_string(),
+            password: password.to_string(),
+            refresh_token: None,
+            client_id: None,
+        };
+        let expires_at = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(data.expires_at.max(0.0));
+        Ok((auth, expires_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_credential_types::provider::SharedCredentialsProvider;
+    use aws_credential_types::Credentials;
+    use axum::http::HeaderMap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_credential_fetched_once_until_expiry() {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let router = axum::Router::new().route(
+            "/",
+            axum::routing::post(move |headers: HeaderMap| async move {
+                let mut calls = recorded.lock().unwrap();
+                calls.push(headers);
+                // The first password is already within the expiry margin.
+                let lifetime = if calls.len() == 1 { 60.0 } else { 43200.0 };
+                let expires_at = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs_f64()
+                    + lifetime;
+                let token = base64::engine::general_purpose::STANDARD
+                    .encode(format!("AWS:password-{}", calls.len()));
+                axum::Json(serde_json::json!({
+                    "authorizationData": [{
+                        "authorizationToken": token,
+                        "expiresAt": expires_at,
+                        "proxyEndpoint": "https://123456789012.dkr.ecr.eu-west-1.amazonaws.com"
+                    }]
+                }))
+            }),
+        );
+        let endpoint = crate::test_support::spawn_upstream(router).await;
+
+        let sdk_config = SdkConfig::builder()
+            .region(Region::new("eu-west-1"))
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+                "AKIDEXAMPLE",
+                "secret",
+                None,
+                None,
+                "test",
+            )))
+            .build();
+        let ecr = EcrCredentials::with_sdk_config(
+            EcrAuth {
+                region: None,
+                endpoint: Some(format!("{}/", endpoint)),
+            },
+            Client::new(),
+            sdk_config,
+        );
+
+        assert_eq!(ecr.credential().await.unwrap().password, "password-1");
+        for _ in 0..2 {
+            let auth = ecr.credential().await.unwrap();
+            assert_eq!(auth.username, "AWS");
+            assert_eq!(auth.password, "password-2");
+        }
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0]["x-amz-target"], GET_AUTHORIZATION_TOKEN);
+        let authorization = calls[0]["authorization"].to_str().unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(authorization.contains("/eu-west-1/ecr/aws4_request"));
+    }
+}
@@ -1,7 +1,24 @@
-use axum::http::StatusCode;
+use crate::config::ErrorDetailLevel;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
-use serde_json::json;
+use serde_json::{json, Value};
+
+tokio::task_local! {
+    static ERROR_DETAIL_LEVEL: ErrorDetailLevel;
+}
+
+/// Renders the errors of the requests it wraps with `level` of detail, so
+/// routers built from different configs each keep their own level.
+pub async fn error_detail_middleware(
+    State(level): State<ErrorDetailLevel>,
+    request: Request,
+    next: Next,
+) -> Response {
+    ERROR_DETAIL_LEVEL.scope(level, next.run(request)).await
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum ProxyError {
@@ -11,12 +28,56 @@ pub enum ProxyError {
     #[error("Forbidden: {0}")]
     Forbidden(String),
 
-    #[error("Not found: {0}")]
-    NotFound(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Repository not mapped: {0}")]
+    NameUnknown(String),
+
+    #[error("Invalid repository name: {0}")]
+    NameInvalid(String),
+
+    #[error("Manifest not found: {0}")]
+    ManifestUnknown(String),
+
+    #[error("Blob not found: {0}")]
+    BlobUnknown(String),
+
+    #[error("Invalid digest: {0}")]
+    DigestInvalid(String),
+
+    #[error("Upload session not found: {0}")]
+    BlobUploadUnknown(String),
+
+    /// Carries the number of seconds the client should wait before retrying,
+    /// when known. Passed on from upstream 429s as their `Retry-After`.
+    #[error("Too many requests{}", retry_hint(*.0))]
+    RateLimited(Option<u64>),
+
+    #[error("Loop detected: {0}")]
+    LoopDetected(String),
 
     #[error("Upstream error: {0}")]
     Upstream(#[from] reqwest::Error),
 
+    /// An upstream answered with a 5xx status, after retries and failover.
+    #[error("Upstream returned status {status}")]
+    UpstreamStatus {
+        status: u16,
+        retry_after: Option<u64>,
+    },
+
+    /// Requests to the registry are suspended after repeated failures.
+    /// Carries the seconds until a request is let through again.
+    #[error("Registry {registry} is failing; requests are suspended{}", retry_hint(Some(*retry_after)))]
+    CircuitOpen { registry: String, retry_after: u64 },
+
+    #[error("Upstream timeout: {0}")]
+    GatewayTimeout(String),
+
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+
     #[error("Cache error: {0}")]
     Cache(String),
 
@@ -24,29 +85,308 @@ pub enum ProxyError {
     Internal(String),
 }
 
-impl IntoResponse for ProxyError {
-    fn into_response(self) -> Response {
+impl ProxyError {
+    /// Error code from the OCI distribution spec. Server-side failures have
+    /// no spec code and use `UNKNOWN` and `UNAVAILABLE` like the reference
+    /// registry does.
+    fn code(&self) -> &'static str {
+        match self {
+            ProxyError::Unauthorized(_) => "UNAUTHORIZED",
+            ProxyError::Forbidden(_) | ProxyError::LoopDetected(_) => "DENIED",
+            ProxyError::BadRequest(_) => "UNSUPPORTED",
+            ProxyError::NameUnknown(_) => "NAME_UNKNOWN",
+            ProxyError::NameInvalid(_) => "NAME_INVALID",
+            ProxyError::ManifestUnknown(_) => "MANIFEST_UNKNOWN",
+            ProxyError::BlobUnknown(_) => "BLOB_UNKNOWN",
+            ProxyError::DigestInvalid(_) => "DIGEST_INVALID",
+            ProxyError::BlobUploadUnknown(_) => "BLOB_UPLOAD_UNKNOWN",
+            ProxyError::RateLimited(_) => "TOOMANYREQUESTS",
+            ProxyError::ServiceUnavailable(_)
+            | ProxyError::CircuitOpen { .. }
+            | ProxyError::UpstreamStatus { status: 503, .. } => "UNAVAILABLE",
+            ProxyError::Upstream(_)
+            | ProxyError::UpstreamStatus { .. }
+            | ProxyError::GatewayTimeout(_)
+            | ProxyError::Cache(_)
+            | ProxyError::Internal(_) => "UNKNOWN",
+        }
+    }
+
+    /// Structured detail naming what was not found, in the shape the
+    /// reference registry uses; null for other errors.
+    fn detail(&self) -> Value {
+        match self {
+            ProxyError::NameUnknown(name) | ProxyError::NameInvalid(name) => {
+                json!({ "name": name })
+            }
+            ProxyError::ManifestUnknown(reference) => json!({ "reference": reference }),
+            ProxyError::BlobUnknown(digest) | ProxyError::DigestInvalid(digest) => {
+                json!({ "digest": digest })
+            }
+            ProxyError::BlobUploadUnknown(id) => json!({ "uuid": id }),
+            _ => Value::Null,
+        }
+    }
+
+    fn to_response(&self, detail_level: ErrorDetailLevel) -> Response {
         let (status, error_message) = match self {
-            ProxyError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
-            ProxyError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
-            ProxyError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            ProxyError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
+            ProxyError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg.clone()),
+            ProxyError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
+            ProxyError::NameInvalid(_) | ProxyError::DigestInvalid(_) => {
+                (StatusCode::BAD_REQUEST, self.to_string())
+            }
+            ProxyError::NameUnknown(_)
+            | ProxyError::ManifestUnknown(_)
+            | ProxyError::BlobUnknown(_)
+            | ProxyError::BlobUploadUnknown(_) => (StatusCode::NOT_FOUND, self.to_string()),
+            ProxyError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
+            ProxyError::LoopDetected(msg) => (StatusCode::LOOP_DETECTED, msg.clone()),
             ProxyError::Upstream(e) => (
                 StatusCode::BAD_GATEWAY,
                 format!("Upstream registry error: {}", e),
             ),
-            ProxyError::Cache(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            ProxyError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+            ProxyError::UpstreamStatus { status, .. } => {
+                let status = match *status {
+                    503 => StatusCode::SERVICE_UNAVAILABLE,
+                    504 => StatusCode::GATEWAY_TIMEOUT,
+                    _ => StatusCode::BAD_GATEWAY,
+                };
+                (status, self.to_string())
+            }
+            ProxyError::CircuitOpen { .. } => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
+            ProxyError::GatewayTimeout(msg) => (StatusCode::GATEWAY_TIMEOUT, msg.clone()),
+            ProxyError::ServiceUnavailable(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg.clone()),
+            ProxyError::Cache(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
+            ProxyError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
+        };
+
+        let error_message = if status.is_server_error() {
+            tracing::error!("{}", self);
+            match detail_level {
+                ErrorDetailLevel::Full => error_message,
+                ErrorDetailLevel::Minimal
+                    if matches!(
+                        self,
+                        ProxyError::Upstream(_)
+                            | ProxyError::UpstreamStatus { .. }
+                            | ProxyError::CircuitOpen { .. }
+                            | ProxyError::GatewayTimeout(_)
+                    ) =>
+                {
+                    "Upstream registry error".to_string()
+                }
+                ErrorDetailLevel::Minimal => "Internal server error".to_string(),
+            }
+        } else {
+            error_message
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "errors": [{
-                "code": "PROXY_ERROR",
+                "code": self.code(),
                 "message": error_message,
+                "detail": self.detail(),
             }]
-        }));
+        });
+        if let Some(request_id) = crate::request_id::current() {
+            body["request_id"] = json!(request_id);
+        }
+        let body = Json(body);
+
+        let mut response = (status, body).into_response();
+        if let ProxyError::RateLimited(Some(retry_after))
+        | ProxyError::UpstreamStatus {
+            retry_after: Some(retry_after),
+            ..
+        }
+        | ProxyError::CircuitOpen { retry_after, .. } = self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(*retry_after));
+        }
+        response
+    }
+}
+
+fn retry_hint(retry_after: Option<u64>) -> String {
+    retry_after
+        .map(|seconds| format!("; retry in {} seconds", seconds))
+        .unwrap_or_default()
+}
 
-        (status, body).into_response()
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        let detail_level = ERROR_DETAIL_LEVEL
+            .try_with(|level| *level)
+            .unwrap_or_default();
+        self.to_response(detail_level)
     }
 }
 
 pub type Result<T> = std::result::Result<T, ProxyError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn error_message(error: ProxyError, level: ErrorDetailLevel) -> (StatusCode, String) {
+        let response = error.to_response(level);
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (
+            status,
+            json["errors"][0]["message"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_minimal_detail_hides_internal_errors() {
+        let (status, message) = error_message(
+            ProxyError::Cache("disk /var/cache is full".into()),
+            ErrorDetailLevel::Minimal,
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(message, "Internal server error");
+
+        let (_, message) = error_message(
+            ProxyError::NameUnknown("foo".into()),
+            ErrorDetailLevel::Minimal,
+        )
+        .await;
+        assert_eq!(message, "Repository not mapped: foo");
+    }
+
+    #[tokio::test]
+    async fn test_errors_use_distribution_codes() {
+        let cases = [
+            (ProxyError::Unauthorized("no token".into()), "UNAUTHORIZED"),
+            (ProxyError::Forbidden("no access".into()), "DENIED"),
+            (ProxyError::BadRequest("too long".into()), "UNSUPPORTED"),
+            (ProxyError::NameUnknown("foo".into()), "NAME_UNKNOWN"),
+            (ProxyError::NameInvalid("../etc".into()), "NAME_INVALID"),
+            (
+                ProxyError::ManifestUnknown("latest".into()),
+                "MANIFEST_UNKNOWN",
+            ),
+            (ProxyError::BlobUnknown("sha256:abc".into()), "BLOB_UNKNOWN"),
+            (
+                ProxyError::DigestInvalid("sha256:a/b".into()),
+                "DIGEST_INVALID",
+            ),
+            (
+                ProxyError::BlobUploadUnknown("1234".into()),
+                "BLOB_UPLOAD_UNKNOWN",
+            ),
+            (ProxyError::RateLimited(Some(5)), "TOOMANYREQUESTS"),
+            (ProxyError::LoopDetected("via self".into()), "DENIED"),
+            (ProxyError::GatewayTimeout("slow".into()), "UNKNOWN"),
+            (
+                ProxyError::ServiceUnavailable("draining".into()),
+                "UNAVAILABLE",
+            ),
+            (ProxyError::Cache("disk full".into()), "UNKNOWN"),
+            (ProxyError::Internal("bug".into()), "UNKNOWN"),
+        ];
+        for (error, code) in cases {
+            let response = error.to_response(ErrorDetailLevel::Full);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["errors"][0]["code"], code, "{}", error);
+        }
+
+        let response =
+            ProxyError::ManifestUnknown("latest".into()).to_response(ErrorDetailLevel::Full);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["errors"][0]["message"], "Manifest not found: latest");
+        assert_eq!(json["errors"][0]["detail"]["reference"], "latest");
+    }
+
+    #[tokio::test]
+    async fn test_full_detail_includes_internal_errors() {
+        let (status, message) = error_message(
+            ProxyError::Internal("token endpoint returned 500".into()),
+            ErrorDetailLevel::Full,
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(message, "token endpoint returned 500");
+    }
+
+    #[tokio::test]
+    async fn test_detail_level_set_per_router() {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use tower::ServiceExt;
+
+        let app = |level: ErrorDetailLevel| {
+            Router::new()
+                .route(
+                    "/fail",
+                    get(|| async { ProxyError::Cache("disk /var/cache is full".into()) }),
+                )
+                .layer(middleware::from_fn_with_state(
+                    level,
+                    error_detail_middleware,
+                ))
+        };
+        let message = |router: Router| async move {
+            let response = router
+                .oneshot(Request::get("/fail").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            json["errors"][0]["message"].as_str().unwrap().to_string()
+        };
+
+        let minimal = app(ErrorDetailLevel::Minimal);
+        let full = app(ErrorDetailLevel::Full);
+        assert_eq!(message(minimal).await, "Internal server error");
+        assert_eq!(message(full).await, "disk /var/cache is full");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_status_preserved() {
+        let cases = [
+            (503, StatusCode::SERVICE_UNAVAILABLE, "UNAVAILABLE"),
+            (504, StatusCode::GATEWAY_TIMEOUT, "UNKNOWN"),
+            (500, StatusCode::BAD_GATEWAY, "UNKNOWN"),
+            (501, StatusCode::BAD_GATEWAY, "UNKNOWN"),
+        ];
+        for (upstream, expected, code) in cases {
+            let response = ProxyError::UpstreamStatus {
+                status: upstream,
+                retry_after: Some(7),
+            }
+            .to_response(ErrorDetailLevel::Minimal);
+            assert_eq!(response.status(), expected, "upstream {}", upstream);
+            assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["errors"][0]["code"], code);
+            assert_eq!(json["errors"][0]["message"], "Upstream registry error");
+        }
+
+        let response = ProxyError::UpstreamStatus {
+            status: 502,
+            retry_after: None,
+        }
+        .to_response(ErrorDetailLevel::Full);
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    }
+}
@@ -0,0 +1,399 @@
+//! Credentials for Google Artifact Registry and Container Registry. Their
+//! token services accept basic auth with the user `oauth2accesstoken` and a
+//! Google OAuth access token as the password. Access tokens come from a
+//! service account key, exchanged through a signed JWT assertion, from the
+//! refresh token of `authorized_user` application default credentials, or
+//! from the metadata server, and are reused until shortly before they
+//! expire. They are only sent to token services of Google registries.
+
+use crate::config::{GcpAuth, UpstreamAuth};
+use crate::error::{ProxyError, Result};
+use anyhow::Context;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+use tracing::info;
+
+const USERNAME: &str = "oauth2accesstoken";
+/// Read-only access is all pulls need; a leaked token cannot modify
+/// anything.
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform.read-only";
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+
+/// How long before its expiry an access token is replaced.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// The `type` field shared by Google credential files. Files without one are
+/// taken to be service account keys.
+#[derive(Deserialize)]
+struct CredentialsType {
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+}
+
+/// User credentials written by `gcloud auth application-default login`.
+#[derive(Deserialize)]
+struct AuthorizedUser {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+    #[serde(default = "default_user_token_uri")]
+    token_uri: String,
+}
+
+fn default_user_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+enum TokenSource {
+    ServiceAccount {
+        client_email: String,
+        token_uri: String,
+        key: EncodingKey,
+    },
+    AuthorizedUser(AuthorizedUser),
+    /// Token endpoint of the metadata server.
+    MetadataServer(String),
+}
+
+pub struct GcpCredentials {
+    source: TokenSource,
+    client: Client,
+    /// The current access token and when it expires. Held across a refresh
+    /// so concurrent requests share one token request.
+    current: Mutex<Option<(UpstreamAuth, Instant)>>,
+}
+
+impl GcpCredentials {
+    /// Reads the credentials file, if any, so a missing or malformed file
+    /// fails startup rather than the first pull.
+    pub fn new(config: &GcpAuth, client: Client) -> anyhow::Result<Self> {
+        let key_path = config
+            .service_account_key
+            .clone()
+            .or_else(|| std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS").map(Into::into));
+
+        let source = match key_path {
+            Some(path) => {
+                let contents = std::fs::read(&path).with_context(|| {
+                    format!("Failed to read Google credentials {}", path.display())
+                })?;
+                let kind: CredentialsType = serde_json::from_slice(&contents)
+                    .with_context(|| format!("Invalid Google credentials {}", path.display()))?;
+                match kind.kind.as_deref() {
+                    Some("authorized_user") => TokenSource::AuthorizedUser(
+                        serde_json::from_slice(&contents).with_context(|| {
+                            format!("Invalid authorized user credentials {}", path.display())
+                        })?,
+                    ),
+                    None | Some("service_account") => {
+                        let key: ServiceAccountKey = serde_json::from_slice(&contents)
+                            .with_context(|| {
+                                format!("Invalid service account key {}", path.display())
+                            })?;
+                        TokenSource::ServiceAccount {
+                            key: EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+                                .with_context(|| {
+                                    format!("Invalid private key in {}", path.display())
+                                })?,
+                            client_email: key.client_email,
+                            token_uri: key.token_uri,
+                        }
+                    }
+                    Some(other) => anyhow::bail!(
+                        "Unsupported Google credentials type '{}' in {}; use a service account key or authorized_user credentials",
+                        other,
+                        path.display()
+                    ),
+                }
+            }
+            None => {
+                let host = std::env::var("GCE_METADATA_HOST")
+                    .unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_string());
+                TokenSource::MetadataServer(format!(
+                    "http://{}/computeMetadata/v1/instance/service-accounts/default/token?scopes={}",
+                    host, SCOPE
+                ))
+            }
+        };
+
+        Ok(Self {
+            source,
+            client,
+            current: Mutex::new(None),
+        })
+    }
+
+    /// The registry credential, fetching a new access token when none is
+    /// held or the held one is about to expire.
+    pub async fn credential(&self) -> Result<UpstreamAuth> {
+        let mut current = self.current.lock().await;
+        if let Some((auth, expires_at)) = &*current {
+            if Instant::now() + EXPIRY_MARGIN < *expires_at {
+                return Ok(auth.clone());
+            }
+        }
+
+        let token = self.fetch().await?;
+        info!(
+            "Obtained Google access token valid for {}s",
+            token.expires_in
+        );
+        let auth = UpstreamAuth {
+            username: USERNAME.to_string(),
+            password: token.access_token,
+            refresh_token: None,
+            client_id: None,
+        };
+        let expires_at = Instant::now() + Duration::from_secs(token.expires_in);
+        *current = Some((auth.clone(), expires_at));
+        Ok(auth)
+    }
+
+    async fn fetch(&self) -> Result<AccessToken> {
+        let request = match &self.source {
+            TokenSource::ServiceAccount {
+                client_email,
+                token_uri,
+                key,
+            } => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let claims = AssertionClaims {
+                    iss: client_email,
+                    scope: SCOPE,
+                    aud: token_uri,
+                    iat: now,
+                    exp: now + 3600,
+                };
+                let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, key)
+                    .map_err(|e| {
+                        ProxyError::Internal(format!("Failed to sign token assertion: {}", e))
+                    })?;
+                self.client
+                    .post(token_uri)
+                    .form(&[("grant_type", JWT_BEARER_GRANT), ("assertion", &assertion)])
+            }
+            TokenSource::AuthorizedUser(user) => self.client.post(&user.token_uri).form(&[
+                ("grant_type", "refresh_token"),
+                ("client_id", &user.client_id),
+                ("client_secret", &user.client_secret),
+                ("refresh_token", &user.refresh_token),
+            ]),
+            TokenSource::MetadataServer(url) => {
+                self.client.get(url).header("Metadata-Flavor", "Google")
+            }
+        };
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(ProxyError::Internal(format!(
+                "Google access token request failed with status: {}",
+                response.status()
+            )));
+        }
+        Ok(response.json().await?)
+    }
+}
+
+/// Whether the access token may be sent to `realm`: only token services on
+/// the registry's own host or on Google's registry domains receive it.
+pub fn accepts_realm(realm: &reqwest::Url, registry_url: &str) -> bool {
+    let Some(host) = realm.host_str() else {
+        return false;
+    };
+    let registry_host = reqwest::Url::parse(registry_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    registry_host.as_deref() == Some(host)
+        || ["pkg.dev", "gcr.io"]
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_service_account_token_reused_until_expiry() {
+        let assertions = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = assertions.clone();
+        let router = axum::Router::new().route(
+            "/token",
+            axum::routing::post(
+                move |axum::Form(form): axum::Form<HashMap<String, String>>| async move {
+                    assert_eq!(form["grant_type"], JWT_BEARER_GRANT);
+                    let mut assertions = recorded.lock().unwrap();
+                    assertions.push(form["assertion"].clone());
+                    // The first token is already within the expiry margin.
+                    let expires_in = if assertions.len() == 1 { 60 } else { 3600 };
+                    axum::Json(serde_json::json!({
+                        "access_token": format!("ya29.token-{}", assertions.len()),
+                        "expires_in": expires_in,
+                        "token_type": "Bearer",
+                    }))
+                },
+            ),
+        );
+        let url = crate::test_support::spawn_upstream(router).await;
+
+        let temp = tempfile::tempdir().unwrap();
+        let key_path = temp.path().join("key.json");
+        std::fs::write(
+            &key_path,
+            serde_json::json!({
+                "type": "service_account",
+                "client_email": "puller@project.iam.gserviceaccount.com",
+                "private_key": include_str!("../tests/fixtures/rsa_private.pem"),
+                "token_uri": format!("{}/token", url),
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let gcp = GcpCredentials::new(
+            &GcpAuth {
+                service_account_key: Some(key_path),
+            },
+            Client::new(),
+        )
+        .unwrap();
+
+        assert_eq!(gcp.credential().await.unwrap().password, "ya29.token-1");
+        for _ in 0..2 {
+            let auth = gcp.credential().await.unwrap();
+            assert_eq!(auth.username, "oauth2accesstoken");
+            assert_eq!(auth.password, "ya29.token-2");
+        }
+
+        let assertions = assertions.lock().unwrap();
+        assert_eq!(assertions.len(), 2);
+        let mut validation = jsonwebtoken::Validation::new(Algorithm::RS256);
+        validation.set_audience(&[format!("{}/token", url)]);
+        let claims = jsonwebtoken::decode::<serde_json::Value>(
+            &assertions[0],
+            &jsonwebtoken::DecodingKey::from_rsa_pem(include_bytes!(
+                "../tests/fixtures/rsa_public.pem"
+            ))
+            .unwrap(),
+            &validation,
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims["iss"], "puller@project.iam.gserviceaccount.com");
+        assert_eq!(claims["scope"], SCOPE);
+    }
+
+    #[tokio::test]
+    async fn test_authorized_user_token_from_refresh_token() {
+        let router = axum::Router::new().route(
+            "/token",
+            axum::routing::post(
+                |axum::Form(form): axum::Form<HashMap<String, String>>| async move {
+                    assert_eq!(form["grant_type"], "refresh_token");
+                    assert_eq!(form["client_id"], "client");
+                    assert_eq!(form["client_secret"], "secret");
+                    axum::Json(serde_json::json!({
+                        "access_token": format!("ya29.{}", form["refresh_token"]),
+                        "expires_in": 3600,
+                    }))
+                },
+            ),
+        );
+        let url = crate::test_support::spawn_upstream(router).await;
+
+        let temp = tempfile::tempdir().unwrap();
+        let key_path = temp.path().join("adc.json");
+        std::fs::write(
+            &key_path,
+            serde_json::json!({
+                "type": "authorized_user",
+                "client_id": "client",
+                "client_secret": "secret",
+                "refresh_token": "refresh",
+                "token_uri": format!("{}/token", url),
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let gcp = GcpCredentials::new(
+            &GcpAuth {
+                service_account_key: Some(key_path),
+            },
+            Client::new(),
+        )
+        .unwrap();
+
+        assert_eq!(gcp.credential().await.unwrap().password, "ya29.refresh");
+    }
+
+    #[test]
+    fn test_token_only_sent_to_google_realms() {
+        let realm = |url: &str| reqwest::Url::parse(url).unwrap();
+        let registry = "https://europe-docker.pkg.dev";
+        assert!(accepts_realm(
+            &realm("https://europe-docker.pkg.dev/v2/token"),
+            registry
+        ));
+        assert!(accepts_realm(
+            &realm("https://us-docker.pkg.dev/v2/token"),
+            registry
+        ));
+        assert!(accepts_realm(&realm("https://gcr.io/v2/token"), registry));
+        assert!(accepts_realm(
+            &realm("https://mirror.example.com/token"),
+            "https://mirror.example.com"
+        ));
+        assert!(!accepts_realm(
+            &realm("https://auth.example.com/token"),
+            registry
+        ));
+        assert!(!accepts_realm(
+            &realm("https://evilpkg.dev/token"),
+            registry
+        ));
+    }
+
+    #[test]
+    fn test_invalid_service_account_key_fails_startup() {
+        let temp = tempfile::tempdir().unwrap();
+        let key_path = temp.path().join("key.json");
+        std::fs::write(&key_path, r#"{"client_email": "puller"}"#).unwrap();
+        let config = GcpAuth {
+            service_account_key: Some(key_path.clone()),
+        };
+        assert!(GcpCredentials::new(&config, Client::new()).is_err());
+
+        std::fs::write(&key_path, r#"{"type": "external_account"}"#).unwrap();
+        assert!(GcpCredentials::new(&config, Client::new()).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Network-level access control: requests from clients outside the allowed
+//! ranges, or inside a denied range, are refused before authentication, so
+//! even a valid token cannot be used from elsewhere.
+
+use crate::config::ServerConfig;
+use crate::error::ProxyError;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::warn;
+
+pub struct IpFilter {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+    trust_forwarded_for: bool,
+}
+
+impl IpFilter {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            allowed: config.allowed_cidrs.clone(),
+            denied: config.denied_cidrs.clone(),
+            trust_forwarded_for: config.trust_forwarded_for,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        !self.allowed.is_empty() || !self.denied.is_empty()
+    }
+
+    fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4 clients reaching a dual-stack listener show up as
+        // IPv4-mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        !self.denied.iter().any(|net| net.contains(&ip))
+            && (self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&ip)))
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+        client_ip(headers, peer, self.trust_forwarded_for)
+    }
+}
+
+/// Address of the client: the last `X-Forwarded-For` entry when that header
+/// is trusted, as it was appended by the proxy in front of us, and the peer
+/// address otherwise.
+pub fn client_ip(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_forwarded_for: bool,
+) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let forwarded = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    peer.map(|addr| addr.ip())
+}
+
+pub async fn ip_filter_middleware(
+    State(filter): State<Arc<IpFilter>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !filter.is_active() {
+        return next.run(request).await;
+    }
+    let client = filter.client_ip(request.headers(), peer.map(|ConnectInfo(addr)| addr));
+    match client {
+        Some(ip) if filter.permits(ip) => next.run(request).await,
+        _ => {
+            warn!(
+                "Refused {} from {}",
+                request.uri().path(),
+                client.map_or("unknown address".to_string(), |ip| ip.to_string())
+            );
+            ProxyError::Forbidden("Client address not allowed".into()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn filter(allowed: &[&str], denied: &[&str], trust_forwarded_for: bool) -> IpFilter {
+        IpFilter {
+            allowed: allowed.iter().map(|net| net.parse().unwrap()).collect(),
+            denied: denied.iter().map(|net| net.parse().unwrap()).collect(),
+            trust_forwarded_for,
+        }
+    }
+
+    async fn status(filter: IpFilter, peer: &str, forwarded_for: Option<&str>) -> StatusCode {
+        let app = Router::new().route("/v2/", get(|| async { "ok" })).layer(
+            middleware::from_fn_with_state(Arc::new(filter), ip_filter_middleware),
+        );
+        let mut request = Request::get("/v2/");
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("X-Forwarded-For", forwarded_for);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        let peer: SocketAddr = peer.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_ipv4_and_ipv6_ranges() {
+        let allow_internal = || filter(&["10.0.0.0/8", "fd00::/8"], &["10.9.0.0/16"], false);
+
+        assert_eq!(
+            status(allow_internal(), "10.1.2.3:4000", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(allow_internal(), "[fd12::1]:4000", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(allow_internal(), "[::ffff:10.1.2.3]:4000", None).await,
+            StatusCode::OK
+        );
+        for refused in ["10.9.0.1:4000", "192.168.1.1:4000", "[2001:db8::1]:4000"] {
+            assert_eq!(
+                status(allow_internal(), refused, None).await,
+                StatusCode::FORBIDDEN,
+                "{}",
+                refused
+            );
+        }
+
+        let deny_only = || filter(&[], &["2001:db8::/32"], false);
+        assert_eq!(
+            status(deny_only(), "[2001:db8::1]:4000", None).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(deny_only(), "192.168.1.1:4000", None).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_only_honored_when_trusted() {
+        let external = Some("203.0.113.7");
+
+        // Untrusted, the header is ignored and the load balancer's internal
+        // address is checked.
+        assert_eq!(
+            status(
+                filter(&["10.0.0.0/8"], &[], false),
+                "10.0.0.1:4000",
+                external
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(
+                filter(&["10.0.0.0/8"], &[], true),
+                "10.0.0.1:4000",
+                external
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        // Only the entry appended by the trusted proxy counts, so clients
+        // cannot spoof an allowed address.
+        assert_eq!(
+            status(
+                filter(&["10.0.0.0/8"], &[], true),
+                "10.0.0.1:4000",
+                Some("10.1.2.3, 203.0.113.7")
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(
+                filter(&["10.0.0.0/8"], &[], true),
+                "10.0.0.1:4000",
+                Some("203.0.113.7, 10.1.2.3")
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(filter(&["10.0.0.0/8"], &[], true), "192.168.1.1:4000", None).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,944 @@
+//! Read-only Docker registry proxy with JWT authentication and local blob
+//! caching.
+//!
+//! The `docker-registry-proxy` binary is a thin wrapper around [`run`].
+//! Applications that embed the proxy can mount the router returned by
+//! [`build_router`] in their own axum server, or build the state with
+//! [`build_state`] first to reuse the cache and upstream client directly.
+
+mod access_log;
+mod admin;
+pub mod auth;
+pub mod cache;
+mod cache_backend;
+mod circuit_breaker;
+pub mod config;
+mod drain;
+#[cfg(feature = "ecr")]
+mod ecr;
+pub mod error;
+#[cfg(feature = "gcp")]
+mod gcp;
+mod ip_filter;
+mod loop_guard;
+mod manifest_cache;
+mod memory_cache;
+mod metrics;
+mod negative_cache;
+mod platform;
+mod prefetch;
+mod preload;
+mod rate_limit;
+pub mod registry;
+mod repository_guard;
+mod request_id;
+mod server;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod token;
+mod upload_session;
+pub mod upstream;
+mod upstream_limit;
+mod upstream_throttle;
+
+pub use crate::auth::Authenticator;
+pub use crate::cache::BlobCache;
+pub use crate::config::Config;
+pub use crate::registry::RegistryState;
+pub use crate::upstream::UpstreamClient;
+
+use crate::auth::{auth_middleware, AuthState};
+use crate::drain::{drain_middleware, DrainState};
+use crate::ip_filter::IpFilter;
+use crate::metrics::PullLatency;
+use crate::prefetch::Prefetcher;
+use crate::rate_limit::{AddressRateLimiter, RateLimiter};
+use crate::repository_guard::RepositoryGuard;
+use axum::{
+    middleware,
+    routing::{delete, get, post, put},
+    Router,
+};
+use std::sync::Arc;
+use tower::Layer;
+use tower_http::compression::CompressionLayer;
+use tower_http::trace::TraceLayer;
+use tracing::{info, warn};
+
+/// Opens the cache and connects the upstream client described by `config`.
+///
+/// Also starts the cache cleanup task and, if configured, the startup
+/// integrity check and preload, so this must run inside a tokio runtime.
+pub async fn build_state(config: Config) -> anyhow::Result<Arc<RegistryState>> {
+    info!("Cache directory: {:?}", config.cache.directory);
+    info!(
+        "Cache limits: max_size={} bytes, max_age={} seconds",
+        config.cache.max_size_bytes, config.cache.max_age_seconds
+    );
+    info!("Configured {} upstream registries", config.registries.len());
+    info!(
+        "Configured {} repository mappings",
+        config.repositories.len()
+    );
+
+    let cache = Arc::new(BlobCache::new(config.cache.clone()).await?);
+    if config.cache.verify_on_startup {
+        if config.cache.verify_in_background {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.verify_integrity().await });
+        } else {
+            cache.verify_integrity().await;
+        }
+    }
+    BlobCache::start_cleanup_task(cache.clone()).await;
+
+    let upstream = UpstreamClient::new(
+        &config.upstream,
+        &config.registries,
+        config.server.user_agent(),
+    )?;
+
+    let registry_state = Arc::new(RegistryState {
+        config: config.clone(),
+        upstream,
+        cache,
+        pull_latency: PullLatency::default(),
+        repository_guard: RepositoryGuard::new(config.auth.repository_limit.clone()),
+        prefetcher: Prefetcher::new(&config.cache),
+    });
+
+    if !config.cache.preload.is_empty() {
+        if config.cache.preload_in_background {
+            let state = registry_state.clone();
+            tokio::spawn(async move { preload::preload(&state).await });
+        } else {
+            preload::preload(&registry_state).await;
+        }
+    }
+
+    Ok(registry_state)
+}
+
+/// Builds the proxy's complete router, including the admin, health and token
+/// routes, for `config`.
+pub async fn build_router(config: Config) -> anyhow::Result<Router> {
+    router_with_state(build_state(config).await?).await
+}
+
+/// Builds the router around state made by [`build_state`], so the caller
+/// keeps a handle on the cache it serves from.
+pub async fn router_with_state(registry_state: Arc<RegistryState>) -> anyhow::Result<Router> {
+    let auth_state = auth_state(&registry_state.config).await?;
+    Ok(routes(
+        registry_state,
+        auth_state,
+        Arc::new(DrainState::default()),
+    ))
+}
+
+/// Like [`router_with_state`], but authenticates requests with
+/// `authenticator` instead of the JWT keys from the config.
+pub fn router_with_authenticator(
+    registry_state: Arc<RegistryState>,
+    authenticator: Arc<dyn Authenticator>,
+) -> Router {
+    let config = &registry_state.config;
+    let auth_state = Arc::new(
+        AuthState::new(authenticator, &config.auth)
+            .with_query_token(config.server.allow_query_token)
+            .with_lowercase_grants(config.server.normalize_repository_case)
+            .with_trusted_forwarded_for(config.server.trust_forwarded_for)
+            .with_public_repositories(config),
+    );
+    routes(registry_state, auth_state, Arc::new(DrainState::default()))
+}
+
+/// Serves the proxy described by `config` until SIGINT or SIGTERM, then
+/// drains requests in flight and flushes the cache metadata.
+pub async fn run(config: Config) -> anyhow::Result<()> {
+    info!("Starting Docker Registry Proxy");
+    let registry_state = build_state(config).await?;
+    let cache = registry_state.cache.clone();
+    let server_config = registry_state.config.server.clone();
+
+    let auth_state = auth_state(&registry_state.config).await?;
+    let drain = Arc::new(DrainState::default());
+    let app = routes(registry_state, auth_state, drain.clone());
+
+    server::serve(&server_config, app, shutdown_signal(drain)).await?;
+
+    info!("Flushing cache metadata");
+    cache.close().await?;
+    info!("Shutdown complete");
+    Ok(())
+}
+
+async fn auth_state(config: &Config) -> anyhow::Result<Arc<AuthState>> {
+    Ok(Arc::new(
+        AuthState::from_config(&config.auth)
+            .await?
+            .with_query_token(config.server.allow_query_token)
+            .with_lowercase_grants(config.server.normalize_repository_case)
+            .with_trusted_forwarded_for(config.server.trust_forwarded_for)
+            .with_public_repositories(config),
+    ))
+}
+
+/// Resolves on SIGINT or SIGTERM after switching the server into draining
+/// mode. The server then stops accepting connections and waits for requests
+/// in flight to finish.
+async fn shutdown_signal(drain: Arc<DrainState>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    info!("Shutdown requested; refusing new requests and draining in-flight ones");
+    drain.start();
+}
+
+fn routes(
+    registry_state: Arc<RegistryState>,
+    auth_state: Arc<AuthState>,
+    drain: Arc<DrainState>,
+) -> Router {
+    // Public routes sit outside the auth layer and never look at the
+    // `Authorization` header, so a malformed token cannot fail them.
+    let public = Router::new()
+        .route("/healthz", get(registry::handle_health_check))
+        .route("/readyz", get(registry::handle_readiness))
+        .route("/metrics", get(metrics::handle_metrics))
+        .route(
+            "/token",
+            get(token::handle_token).layer(middleware::from_fn_with_state(
+                Arc::new(AddressRateLimiter::new(
+                    registry_state.config.auth.token_rate_limit.clone(),
+                    registry_state.config.server.trust_forwarded_for,
+                )),
+                rate_limit::address_rate_limit_middleware,
+            )),
+        );
+    let ip_filter = Arc::new(IpFilter::from_config(&registry_state.config.server));
+    let rate_limiter = Arc::new(RateLimiter::new(
+        registry_state.config.auth.rate_limit.clone(),
+    ));
+
+    let registry_routes = Router::new()
+        .route("/v2/", get(registry::handle_version_check))
+        .route("/v2/_capabilities", get(registry::handle_capabilities))
+        .route(
+            "/v2/:repository/manifests/:reference",
+            get(registry::handle_get_manifest)
+                .layer(CompressionLayer::new())
+                .put(registry::handle_unsupported_write)
+                .delete(registry::handle_unsupported_write),
+        )
+        .route(
+            "/v2/:repository/blobs/:digest",
+            get(registry::handle_get_blob)
+                .head(registry::handle_head_blob)
+                .delete(registry::handle_unsupported_write),
+        )
+        .route(
+            "/v2/:repository/blobs/uploads/",
+            put(registry::handle_unsupported_write),
+        )
+        .route(
+            "/v2/:repository/referrers/:digest",
+            get(registry::handle_get_referrers),
+        )
+        .route(
+            "/v2/:repository/tags/list",
+            get(registry::handle_get_tags).layer(CompressionLayer::new()),
+        )
+        .layer(middleware::from_fn_with_state(
+            registry_state.clone(),
+            registry::cache_header_middleware,
+        ))
+        // Only the registry routes are rate limited; admin routes are not.
+        .layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit::rate_limit_middleware,
+        ));
+    let auth = middleware::from_fn_with_state(auth_state, auth_middleware);
+    // `:repository` matches a single segment, so requests for nested names
+    // fall through and are routed again with the name's `/`s encoded.
+    let nested_routes = middleware::map_request(registry::encode_nested_repository).layer(
+        registry_routes
+            .clone()
+            .route_layer(auth.clone())
+            .with_state(registry_state.clone()),
+    );
+
+    Router::new()
+        .merge(registry_routes)
+        .route("/admin/config", get(admin::handle_get_config))
+        .route("/admin/registries", get(admin::handle_get_registries))
+        .route("/admin/cache/stats", get(admin::handle_cache_stats))
+        .route("/admin/cache/entries", get(admin::handle_cache_entries))
+        .route("/admin/cache/purge", post(admin::handle_cache_purge))
+        .route("/admin/cache/:digest", delete(admin::handle_cache_evict))
+        .layer(auth)
+        .merge(public)
+        .fallback_service(nested_routes)
+        .layer(middleware::from_fn_with_state(
+            ip_filter,
+            ip_filter::ip_filter_middleware,
+        ))
+        .layer(middleware::from_fn(loop_guard::loop_guard_middleware))
+        .layer(middleware::from_fn_with_state(drain, drain_middleware))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(access_log::make_span)
+                .on_response(access_log::on_response),
+        )
+        .layer(middleware::from_fn(request_id::request_id_middleware))
+        .layer(middleware::from_fn(registry::api_version_middleware))
+        .layer(middleware::from_fn_with_state(
+            registry_state.config.server.error_detail_level,
+            error::error_detail_middleware,
+        ))
+        .with_state(registry_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{admin_token, alpine_registry, test_state};
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
+    use tower::ServiceExt;
+
+    async fn test_router(extra: &str) -> (Router, tempfile::TempDir) {
+        let (state, temp) = test_state(extra).await;
+        let auth_state = Arc::new(
+            AuthState::from_config(&state.config.auth)
+                .await
+                .unwrap()
+                .with_public_repositories(&state.config),
+        );
+        let drain = Arc::new(DrainState::default());
+        (routes(state, auth_state, drain), temp)
+    }
+
+    async fn get_with_token(router: Router, uri: &str, token: &str) -> StatusCode {
+        let request = Request::get(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_public_routes_ignore_invalid_token() {
+        let (router, _temp) = test_router("").await;
+
+        assert_eq!(
+            get_with_token(router.clone(), "/healthz", "not-a-jwt").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_with_token(router.clone(), "/metrics", "not-a-jwt").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_with_token(router, "/v2/", "not-a-jwt").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encoded_traversal_rejected_as_invalid_name() {
+        let (router, _temp) = test_router("").await;
+        let token = admin_token();
+
+        for uri in [
+            "/v2/..%2F..%2Fadmin/tags/list",
+            "/v2/library%2F..%2Fsecret/manifests/latest",
+            "/v2/Alpine/manifests/latest",
+        ] {
+            assert_eq!(
+                get_with_token(router.clone(), uri, &token).await,
+                StatusCode::BAD_REQUEST,
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_digests_rejected() {
+        let (router, _temp) = test_router("").await;
+        let token = admin_token();
+
+        for uri in [
+            "/v2/alpine/blobs/sha256:..%2F..%2Fadmin",
+            "/v2/alpine/blobs/sha256:abc%3Fn=1%23x",
+            "/v2/alpine/blobs/not-a-digest",
+            "/v2/alpine/referrers/sha256:abc%2Fdef",
+            "/v2/alpine/manifests/sha256:..%2Ftags%2Flist",
+        ] {
+            let request = Request::get(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["errors"][0]["code"], "DIGEST_INVALID", "{uri}");
+        }
+
+        for uri in [
+            "/v2/alpine/manifests/..",
+            "/v2/alpine/manifests/latest%3Fn=1",
+        ] {
+            assert_eq!(
+                get_with_token(router.clone(), uri, &token).await,
+                StatusCode::NOT_FOUND,
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_authenticator_replaces_jwt() {
+        struct ApiKey;
+
+        impl Authenticator for ApiKey {
+            fn authenticate<'a>(
+                &'a self,
+                headers: &'a axum::http::HeaderMap,
+            ) -> futures::future::BoxFuture<'a, error::Result<Option<auth::Claims>>> {
+                Box::pin(async move {
+                    match headers.get("X-Api-Key") {
+                        None => Ok(None),
+                        Some(key) if key == "letmein" => {
+                            Ok(Some(crate::test_support::admin_claims()))
+                        }
+                        Some(_) => Err(error::ProxyError::Unauthorized("Unknown API key".into())),
+                    }
+                })
+            }
+        }
+
+        let (state, _temp) = test_state("").await;
+        let router = router_with_authenticator(state, Arc::new(ApiKey));
+        let send = |key: Option<&'static str>| {
+            let router = router.clone();
+            async move {
+                let mut request = Request::get("/admin/cache/stats");
+                if let Some(key) = key {
+                    request = request.header("X-Api-Key", key);
+                }
+                let response = router
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                response.status()
+            }
+        };
+
+        assert_eq!(send(Some("letmein")).await, StatusCode::OK);
+        assert_eq!(send(Some("guess")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(None).await, StatusCode::UNAUTHORIZED);
+
+        let token = admin_token();
+        assert_eq!(
+            get_with_token(router, "/admin/cache/stats", &token).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_responses_carry_challenge() {
+        let (router, _temp) = test_router("").await;
+        let request = Request::get("/v2/alpine/manifests/latest")
+            .header(header::HOST, "proxy.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            r#"Bearer realm="http://proxy.example.com/token",service="cargo-bay",scope="repository:alpine:pull""#
+        );
+
+        let (state, _temp) = test_state("").await;
+        let mut auth = state.config.auth.clone();
+        auth.realm = Some("https://auth.example.com/token".into());
+        let auth_state = Arc::new(AuthState::from_config(&auth).await.unwrap());
+        let router = routes(state, auth_state, Arc::new(DrainState::default()));
+        let request = Request::get("/v2/")
+            .header(header::AUTHORIZATION, "Bearer not-a-jwt")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            r#"Bearer realm="https://auth.example.com/token",service="cargo-bay""#
+        );
+        assert_eq!(
+            response.headers()["Docker-Distribution-Api-Version"],
+            "registry/2.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_every_response_carries_api_version() {
+        let (router, _temp) = test_router("").await;
+        for (uri, status) in [
+            ("/v2/", StatusCode::UNAUTHORIZED),
+            ("/v2/alpine/manifests/latest", StatusCode::UNAUTHORIZED),
+            ("/healthz", StatusCode::OK),
+            ("/nowhere", StatusCode::NOT_FOUND),
+        ] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status, "{uri}");
+            assert_eq!(
+                response.headers()[registry::API_VERSION_HEADER],
+                registry::API_VERSION,
+                "{uri}"
+            );
+        }
+
+        let drain = Arc::new(DrainState::default());
+        drain.start();
+        let (state, _temp) = test_state("").await;
+        let auth_state = Arc::new(AuthState::from_config(&state.config.auth).await.unwrap());
+        let request = Request::get("/v2/").body(Body::empty()).unwrap();
+        let response = routes(state, auth_state, drain)
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[registry::API_VERSION_HEADER],
+            registry::API_VERSION
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nested_names_routed_through_wildcard_mapping() {
+        const DIGEST: &str =
+            "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+        let upstream = crate::test_support::blob_upstream(DIGEST, b"layer").route(
+            "/v2/library/alpine/manifests/latest",
+            get(|| async { "{}" }),
+        );
+        let upstream = crate::test_support::spawn_upstream(upstream).await;
+        let (router, _temp) = test_router(&format!(
+            r#"
+[[registries]]
+id = "hub"
+url = "{upstream}"
+allow_http = true
+
+[[repositories]]
+name = "proxy/*"
+registry_id = "hub"
+upstream_name = "$1"
+"#
+        ))
+        .await;
+        let token = admin_token();
+
+        for uri in [
+            "/v2/proxy/library/alpine/manifests/latest".to_string(),
+            format!("/v2/proxy/library/alpine/blobs/{}", DIGEST),
+        ] {
+            assert_eq!(
+                get_with_token(router.clone(), &uri, &token).await,
+                StatusCode::OK,
+                "{uri}"
+            );
+        }
+        assert_eq!(
+            get_with_token(router.clone(), "/v2/proxy/library/alpine/nothing", &token).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get_with_token(router, "/v2/proxy/library/alpine/tags/list", "not-a-jwt").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nested_unmapped_names_use_default_registry() {
+        const DIGEST: &str =
+            "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+        let upstream = crate::test_support::spawn_upstream(crate::test_support::blob_upstream(
+            DIGEST, b"layer",
+        ))
+        .await;
+        let (state, _temp) = crate::test_support::alpine_state(&upstream, |config| {
+            config.default_registry_id = Some("hub".to_string());
+        })
+        .await;
+        let auth_state = Arc::new(AuthState::from_config(&state.config.auth).await.unwrap());
+        let router = routes(state, auth_state, Arc::new(DrainState::default()));
+        let token = admin_token();
+
+        let request = Request::get(format!("/v2/library/alpine/blobs/{}", DIGEST))
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_clients_rate_limited_per_address() {
+        let upstream = crate::test_support::spawn_upstream(Router::new()).await;
+        let (state, _temp) = crate::test_support::alpine_state(&upstream, |config| {
+            config.repositories[0].public = true;
+            config.auth.rate_limit = Some(config::RateLimit {
+                requests_per_minute: 1,
+                burst: 1,
+            });
+        })
+        .await;
+        let router = router_with_state(state).await.unwrap();
+        let pull = |address: &str| {
+            let mut request = Request::get("/v2/alpine/tags/list")
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(axum::extract::ConnectInfo(
+                address.parse::<std::net::SocketAddr>().unwrap(),
+            ));
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_ne!(pull("10.0.0.1:5000").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(pull("10.0.0.1:5001").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_ne!(pull("10.0.0.2:5000").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_manifests_compressed_but_blobs_not() {
+        const DIGEST: &str =
+            "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+        let manifest = format!(r#"{{"schemaVersion":2,"layers":[{}]}}"#, "{}".repeat(64));
+        let upstream = crate::test_support::blob_upstream(DIGEST, b"layer").route(
+            "/v2/library/alpine/manifests/latest",
+            get(move || async move { manifest }),
+        );
+        let upstream = crate::test_support::spawn_upstream(upstream).await;
+        let (router, _temp) = test_router(&alpine_registry(&upstream)).await;
+        let token = admin_token();
+
+        let get_gzip = |uri: String| {
+            let request = Request::get(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+
+        let response = get_gzip("/v2/alpine/manifests/latest".into())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+
+        let response = get_gzip(format!("/v2/alpine/blobs/{}", DIGEST))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+    }
+
+    #[tokio::test]
+    async fn test_pull_responses_report_cache_outcome() {
+        const DIGEST: &str =
+            "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+        let upstream = crate::test_support::blob_upstream(DIGEST, b"layer").route(
+            "/v2/library/alpine/manifests/latest",
+            get(|| async { r#"{"schemaVersion":2}"# }),
+        );
+        let upstream = crate::test_support::spawn_upstream(upstream).await;
+        let repositories = alpine_registry(&upstream);
+        let token = admin_token();
+        let x_cache = |router: Router, uri: String, if_none_match: Option<String>| {
+            let mut request =
+                Request::get(uri).header(header::AUTHORIZATION, format!("Bearer {}", token));
+            if let Some(etag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            async move {
+                let response = router
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let value = response
+                    .headers()
+                    .get("X-Cache")
+                    .map(|value| value.to_str().unwrap().to_string());
+                let etag = response.headers().get(header::ETAG).cloned();
+                // Reading the body lets a streamed blob finish caching.
+                axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (value, etag)
+            }
+        };
+
+        let (router, _temp) = test_router(&repositories).await;
+        let blob = format!("/v2/alpine/blobs/{}", DIGEST);
+        let manifest = "/v2/alpine/manifests/latest".to_string();
+        assert_eq!(
+            x_cache(router.clone(), blob.clone(), None)
+                .await
+                .0
+                .as_deref(),
+            Some("MISS")
+        );
+        assert_eq!(
+            x_cache(router.clone(), blob.clone(), None)
+                .await
+                .0
+                .as_deref(),
+            Some("HIT")
+        );
+        let (value, etag) = x_cache(router.clone(), manifest.clone(), None).await;
+        assert_eq!(value.as_deref(), Some("MISS"));
+        let etag = etag.unwrap().to_str().unwrap().to_string();
+        assert_eq!(
+            x_cache(router, manifest, Some(etag)).await.0.as_deref(),
+            Some("REVALIDATED")
+        );
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(temp.path(), &repositories);
+        config.server.emit_cache_header = false;
+        let state = crate::test_support::state_from_config(config).await;
+        let auth_state = Arc::new(AuthState::from_config(&state.config.auth).await.unwrap());
+        let router = routes(state, auth_state, Arc::new(DrainState::default()));
+        assert_eq!(x_cache(router, blob, None).await.0, None);
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_rate_limit_get_429() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(temp.path(), "");
+        config.auth.rate_limit = Some(crate::config::RateLimit {
+            requests_per_minute: 1,
+            burst: 2,
+        });
+        let state = crate::test_support::state_from_config(config).await;
+        let auth_state = Arc::new(AuthState::from_config(&state.config.auth).await.unwrap());
+        let router = routes(state, auth_state, Arc::new(DrainState::default()));
+        let token = admin_token();
+        let get = |uri: &str| {
+            let request = Request::get(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+
+        for _ in 0..2 {
+            assert_eq!(get("/v2/").await.unwrap().status(), StatusCode::OK);
+        }
+        let response = get("/v2/").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+
+        assert_eq!(
+            get("/admin/cache/stats").await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_requests_rate_limited_per_address() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(temp.path(), "");
+        config.auth.token_rate_limit = crate::config::RateLimit {
+            requests_per_minute: 1,
+            burst: 2,
+        };
+        config.server.trust_forwarded_for = true;
+        let state = crate::test_support::state_from_config(config).await;
+        let auth_state = Arc::new(AuthState::from_config(&state.config.auth).await.unwrap());
+        let router = routes(state, auth_state, Arc::new(DrainState::default()));
+        let login = |client: &str| {
+            let request = Request::get("/token")
+                .header("X-Forwarded-For", client)
+                .header(header::AUTHORIZATION, "Basic Y2k6d3Jvbmc=")
+                .body(Body::empty())
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+
+        for _ in 0..2 {
+            let status = login("192.0.2.1").await.unwrap().status();
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let status = login("192.0.2.1").await.unwrap().status();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let status = login("192.0.2.2").await.unwrap().status();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_pulls_allowed_from_public_repositories_only() {
+        const DIGEST: &str =
+            "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+        let upstream = crate::test_support::spawn_upstream(crate::test_support::blob_upstream(
+            DIGEST, b"layer",
+        ))
+        .await;
+        let (router, _temp) = test_router(&format!(
+            r#"{}public = true
+
+[[repositories]]
+name = "app"
+registry_id = "hub"
+upstream_name = "library/alpine"
+"#,
+            alpine_registry(&upstream)
+        ))
+        .await;
+        let anonymous = |method: &str, uri: String| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+
+        let response = anonymous("GET", format!("/v2/alpine/blobs/{}", DIGEST))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+        assert_eq!(
+            anonymous("HEAD", format!("/v2/alpine/blobs/{}", DIGEST))
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::OK
+        );
+
+        for (method, uri) in [
+            ("GET", format!("/v2/app/blobs/{}", DIGEST)),
+            ("DELETE", format!("/v2/alpine/manifests/{}", DIGEST)),
+            ("GET", "/v2/".to_string()),
+        ] {
+            assert_eq!(
+                anonymous(method, uri).await.unwrap().status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        // A presented token is still validated.
+        assert_eq!(
+            get_with_token(router, &format!("/v2/alpine/blobs/{}", DIGEST), "bad").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_token_accepted_only_when_allowed() {
+        let token = admin_token();
+        let uri = format!("/v2/?access_token={}", token);
+        let status = |router: Router| {
+            let request = Request::get(&uri).body(Body::empty()).unwrap();
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+
+        let (router, _temp) = test_router("").await;
+        assert_eq!(status(router).await, StatusCode::UNAUTHORIZED);
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(temp.path(), "");
+        config.server.allow_query_token = true;
+        let state = crate::test_support::state_from_config(config).await;
+        let auth_state = Arc::new(
+            AuthState::from_config(&state.config.auth)
+                .await
+                .unwrap()
+                .with_query_token(state.config.server.allow_query_token),
+        );
+        let router = routes(state, auth_state, Arc::new(DrainState::default()));
+        assert_eq!(status(router.clone()).await, StatusCode::OK);
+        assert_eq!(
+            get_with_token(router, "/v2/?access_token=", "not-a-jwt").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_grants_lowercased_with_name_normalization() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(temp.path(), "");
+        config.server.normalize_repository_case = true;
+        let state = crate::test_support::state_from_config(config).await;
+        let auth_state = Arc::new(
+            AuthState::from_config(&state.config.auth)
+                .await
+                .unwrap()
+                .with_lowercase_grants(state.config.server.normalize_repository_case),
+        );
+        let router = routes(state, auth_state, Arc::new(DrainState::default()));
+        let token = crate::test_support::token_for(&crate::test_support::repo_claims(&["Alpine"]));
+
+        // The grant matches, so the request gets as far as the missing
+        // mapping.
+        assert_eq!(
+            get_with_token(router.clone(), "/v2/Alpine/tags/list", &token).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get_with_token(router, "/v2/debian/tags/list", &token).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
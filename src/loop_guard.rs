@@ -0,0 +1,80 @@
+use crate::error::{ProxyError, Result};
+use axum::{extract::Request, http::HeaderMap, middleware::Next, response::Response};
+use std::sync::OnceLock;
+
+/// Header carrying the instance id of every proxy a request passed through.
+pub const LOOP_GUARD_HEADER: &str = "x-cargo-bay-instance";
+
+/// Random id identifying this process, attached to all upstream requests.
+pub fn instance_id() -> &'static str {
+    static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+    INSTANCE_ID.get_or_init(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+/// Rejects requests that this instance itself sent upstream, which happens
+/// when a registry URL points back at the proxy.
+pub async fn loop_guard_middleware(request: Request, next: Next) -> Result<Response> {
+    if contains_own_instance(request.headers()) {
+        return Err(ProxyError::LoopDetected(
+            "Request was sent by this proxy; check that no registry URL points at the proxy itself"
+                .into(),
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+fn contains_own_instance(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(LOOP_GUARD_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|id| id.trim() == instance_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/v2/", get(|| async { "ok" }))
+            .layer(middleware::from_fn(loop_guard_middleware))
+    }
+
+    async fn status_with_header(value: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().uri("/v2/");
+        if let Some(value) = value {
+            request = request.header(LOOP_GUARD_HEADER, value);
+        }
+        app()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_request_from_self_is_rejected() {
+        assert_eq!(
+            status_with_header(Some(instance_id())).await,
+            StatusCode::LOOP_DETECTED
+        );
+        assert_eq!(
+            status_with_header(Some(&format!("other, {}", instance_id()))).await,
+            StatusCode::LOOP_DETECTED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_from_other_proxy_is_allowed() {
+        assert_eq!(status_with_header(None).await, StatusCode::OK);
+        assert_eq!(
+            status_with_header(Some("another-instance")).await,
+            StatusCode::OK
+        );
+    }
+}
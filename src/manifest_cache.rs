@@ -0,0 +1,425 @@
+//! Manifests fetched from upstream, keyed by repository and reference.
+//!
+//! Tags are mutable, so entries are only served for `manifest_ttl_seconds`
+//! after they were fetched and can be purged when a tag is re-pushed.
+
+use crate::error::{ProxyError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedManifest {
+    pub content_type: String,
+    pub data: Vec<u8>,
+    pub cached_at: DateTime<Utc>,
+}
+
+impl CachedManifest {
+    /// Digests of the blobs this manifest references: its config and layers.
+    /// Manifest lists reference other manifests rather than blobs, so they
+    /// yield nothing.
+    pub fn blob_digests(&self) -> Vec<String> {
+        blob_digests(&self.data)
+    }
+}
+
+/// Digests of the config and layer blobs referenced by a manifest.
+pub fn blob_digests(manifest: &[u8]) -> Vec<String> {
+    blob_descriptors(manifest)
+        .into_iter()
+        .map(|(digest, _)| digest)
+        .collect()
+}
+
+/// Config and layer blobs referenced by a manifest, with the sizes their
+/// descriptors declare.
+fn blob_descriptors(manifest: &[u8]) -> Vec<(String, Option<u64>)> {
+    let Ok(manifest) = serde_json::from_slice::<serde_json::Value>(manifest) else {
+        return Vec::new();
+    };
+    let layers = manifest["layers"].as_array().into_iter().flatten();
+    std::iter::once(&manifest["config"])
+        .chain(layers)
+        .filter_map(|descriptor| {
+            let digest = descriptor["digest"].as_str()?;
+            Some((digest.to_string(), descriptor["size"].as_u64()))
+        })
+        .collect()
+}
+
+pub struct ManifestCache {
+    tree: sled::Tree,
+    ttl_seconds: u64,
+}
+
+impl ManifestCache {
+    pub fn new(tree: sled::Tree, ttl_seconds: u64) -> Self {
+        Self { tree, ttl_seconds }
+    }
+
+    /// Manifest caching is off when the TTL is 0.
+    pub fn is_enabled(&self) -> bool {
+        self.ttl_seconds > 0
+    }
+
+    /// Returns the cached manifest if it is younger than the TTL.
+    pub fn get(&self, repository: &str, reference: &str) -> Result<Option<CachedManifest>> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let Some(data) = self
+            .tree
+            .get(key(repository, reference))
+            .map_err(storage_error)?
+        else {
+            return Ok(None);
+        };
+        let manifest = decode(&data)?;
+        let age = Utc::now() - manifest.cached_at;
+        Ok((age < chrono::Duration::seconds(self.ttl_seconds as i64)).then_some(manifest))
+    }
+
+    /// Returns the cached manifest whatever its age, for digest references
+    /// and for when upstream cannot be asked for a fresh one.
+    pub fn get_stale(&self, repository: &str, reference: &str) -> Result<Option<CachedManifest>> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let Some(data) = self
+            .tree
+            .get(key(repository, reference))
+            .map_err(storage_error)?
+        else {
+            return Ok(None);
+        };
+        decode(&data).map(Some)
+    }
+
+    pub fn put(
+        &self,
+        repository: &str,
+        reference: &str,
+        content_type: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let manifest = CachedManifest {
+            content_type: content_type.to_string(),
+            data: data.to_vec(),
+            cached_at: Utc::now(),
+        };
+        let encoded = bincode::serialize(&manifest)
+            .map_err(|e| ProxyError::Cache(format!("Failed to encode manifest: {}", e)))?;
+        self.tree
+            .insert(key(repository, reference), encoded)
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    /// Removes the manifest cached for `reference` along with the platform
+    /// manifests resolved from it, or every manifest of `repository` when no
+    /// reference is given, returning what was removed.
+    pub fn purge(&self, repository: &str, reference: Option<&str>) -> Result<Vec<CachedManifest>> {
+        let mut keys: Vec<sled::IVec> = Vec::new();
+        if let Some(reference) = reference {
+            keys.push(key(repository, reference).as_bytes().into());
+        }
+        let prefix = match reference {
+            Some(reference) => key(repository, &platform_reference(reference, "")),
+            None => key(repository, ""),
+        };
+        for key in self.tree.scan_prefix(prefix).keys() {
+            keys.push(key.map_err(storage_error)?);
+        }
+
+        let mut removed = Vec::new();
+        for key in keys {
+            if let Some(data) = self.tree.remove(key).map_err(storage_error)? {
+                removed.push(decode(&data)?);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes entries cached more than `max_age_seconds` ago, returning how
+    /// many were removed. With `keep_digests`, manifests cached under a digest
+    /// and the platform manifests resolved from them stay.
+    pub fn remove_expired(&self, max_age_seconds: u64, keep_digests: bool) -> Result<usize> {
+        let max_age = chrono::Duration::seconds(max_age_seconds as i64);
+        let now = Utc::now();
+        let mut removed = 0;
+        for item in self.tree.iter() {
+            let (key, data) = item.map_err(storage_error)?;
+            let Ok(manifest) = decode(&data) else {
+                continue;
+            };
+            if now - manifest.cached_at <= max_age {
+                continue;
+            }
+            let reference = std::str::from_utf8(&key)
+                .ok()
+                .and_then(|key| key.split_once(':'))
+                .map(|(_, reference)| reference.split('#').next().unwrap_or(reference));
+            if keep_digests && reference.is_some_and(is_digest_reference) {
+                continue;
+            }
+            // Entries refreshed since they were read are left alone.
+            if self
+                .tree
+                .compare_and_swap(&key, Some(&data), None::<&[u8]>)
+                .map_err(storage_error)?
+                .is_ok()
+            {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Blobs known to exist upstream because a cached manifest of the repository
+/// references them, with their declared sizes. While the manifest is fresh,
+/// existence checks for these blobs need no upstream round-trip.
+pub struct LayerIndex {
+    tree: sled::Tree,
+    ttl_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KnownBlob {
+    size: u64,
+    recorded_at: DateTime<Utc>,
+}
+
+impl LayerIndex {
+    pub fn new(tree: sled::Tree, ttl_seconds: u64) -> Self {
+        Self { tree, ttl_seconds }
+    }
+
+    /// Records the blobs of a manifest just cached for `repository`.
+    /// Descriptors without a size are skipped.
+    pub fn record(&self, repository: &str, manifest: &[u8]) -> Result<()> {
+        if self.ttl_seconds == 0 {
+            return Ok(());
+        }
+        let recorded_at = Utc::now();
+        for (digest, size) in blob_descriptors(manifest) {
+            let Some(size) = size else {
+                continue;
+            };
+            let encoded = bincode::serialize(&KnownBlob { size, recorded_at })
+                .map_err(|e| ProxyError::Cache(format!("Failed to encode layer: {}", e)))?;
+            self.tree
+                .insert(key(repository, &digest), encoded)
+                .map_err(storage_error)?;
+        }
+        Ok(())
+    }
+
+    /// Size of `digest` if a manifest cached within the TTL references it.
+    pub fn size(&self, repository: &str, digest: &str) -> Result<Option<u64>> {
+        if self.ttl_seconds == 0 {
+            return Ok(None);
+        }
+        let Some(data) = self
+            .tree
+            .get(key(repository, digest))
+            .map_err(storage_error)?
+        else {
+            return Ok(None);
+        };
+        let known: KnownBlob = bincode::deserialize(&data)
+            .map_err(|e| ProxyError::Cache(format!("Corrupt layer record: {}", e)))?;
+        let age = Utc::now() - known.recorded_at;
+        Ok((age < chrono::Duration::seconds(self.ttl_seconds as i64)).then_some(known.size))
+    }
+
+    /// Drops what is known about `digests` in `repository`, e.g. because the
+    /// manifests referencing them were purged.
+    pub fn forget(&self, repository: &str, digests: &[String]) -> Result<()> {
+        for digest in digests {
+            self.tree
+                .remove(key(repository, digest))
+                .map_err(storage_error)?;
+        }
+        Ok(())
+    }
+
+    /// Removes records older than the TTL, which are no longer used, returning
+    /// how many were removed.
+    pub fn remove_expired(&self) -> Result<usize> {
+        let max_age = chrono::Duration::seconds(self.ttl_seconds as i64);
+        let now = Utc::now();
+        let mut removed = 0;
+        for item in self.tree.iter() {
+            let (key, data) = item.map_err(storage_error)?;
+            let fresh = bincode::deserialize::<KnownBlob>(&data)
+                .is_ok_and(|known| now - known.recorded_at < max_age);
+            if !fresh
+                && self
+                    .tree
+                    .compare_and_swap(&key, Some(&data), None::<&[u8]>)
+                    .map_err(storage_error)?
+                    .is_ok()
+            {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Whether `reference` names a manifest by digest rather than by tag. The
+/// content behind a digest never changes.
+pub fn is_digest_reference(reference: &str) -> bool {
+    let Some((algorithm, encoded)) = reference.split_once(':') else {
+        return false;
+    };
+    let length = match algorithm {
+        "sha256" => 64,
+        "sha512" => 128,
+        _ => return false,
+    };
+    encoded.len() == length
+        && encoded
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Reference under which the manifest resolved for one platform of the
+/// manifest list `reference` is cached. Tags and digests cannot contain `#`.
+pub fn platform_reference(reference: &str, platform: &str) -> String {
+    format!("{}#{}", reference, platform)
+}
+
+/// Repository names cannot contain `:`, so the prefix `"{repository}:"` never
+/// matches a different repository.
+fn key(repository: &str, reference: &str) -> String {
+    format!("{}:{}", repository, reference)
+}
+
+fn decode(data: &[u8]) -> Result<CachedManifest> {
+    bincode::deserialize(data)
+        .map_err(|e| ProxyError::Cache(format!("Corrupt cached manifest: {}", e)))
+}
+
+fn storage_error(e: sled::Error) -> ProxyError {
+    ProxyError::Cache(format!("Manifest cache storage error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifests(ttl_seconds: u64) -> ManifestCache {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ManifestCache::new(db.open_tree("manifests").unwrap(), ttl_seconds)
+    }
+
+    #[test]
+    fn test_digest_references() {
+        assert!(is_digest_reference(&format!("sha256:{}", "a1".repeat(32))));
+        assert!(is_digest_reference(&format!("sha512:{}", "0f".repeat(64))));
+        assert!(!is_digest_reference("latest"));
+        assert!(!is_digest_reference("sha256:abc"));
+        assert!(!is_digest_reference(&format!("sha256:{}", "A1".repeat(32))));
+        assert!(!is_digest_reference(&format!("md5:{}", "a1".repeat(32))));
+    }
+
+    #[test]
+    fn test_purge_is_scoped_to_repository() {
+        let cache = manifests(60);
+        for (repository, reference) in [
+            ("app", "latest"),
+            ("app", "v1"),
+            ("app", "v1#linux/amd64"),
+            ("app/sub", "latest"),
+        ] {
+            cache
+                .put(repository, reference, "application/json", b"{}")
+                .unwrap();
+        }
+
+        assert_eq!(cache.purge("app", Some("v1")).unwrap().len(), 2);
+        assert!(cache.get("app", "v1").unwrap().is_none());
+        assert!(cache.get("app", "latest").unwrap().is_some());
+
+        assert_eq!(cache.purge("app", None).unwrap().len(), 1);
+        assert!(cache.get("app/sub", "latest").unwrap().is_some());
+        assert!(manifests(0).get("app", "latest").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_layer_index_records_sized_descriptors() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let index = LayerIndex::new(db.open_tree("layers").unwrap(), 60);
+        let manifest = br#"{
+            "config": {"digest": "sha256:config", "size": 10},
+            "layers": [{"digest": "sha256:layer", "size": 2048}, {"digest": "sha256:unsized"}]
+        }"#;
+        index.record("app", manifest).unwrap();
+
+        assert_eq!(index.size("app", "sha256:config").unwrap(), Some(10));
+        assert_eq!(index.size("app", "sha256:layer").unwrap(), Some(2048));
+        assert_eq!(index.size("app", "sha256:unsized").unwrap(), None);
+        assert_eq!(index.size("other", "sha256:layer").unwrap(), None);
+
+        index.forget("app", &["sha256:layer".to_string()]).unwrap();
+        assert_eq!(index.size("app", "sha256:layer").unwrap(), None);
+    }
+
+    #[test]
+    fn test_expired_entries_removed() {
+        let cache = manifests(60);
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let old = |reference: &str| {
+            let manifest = CachedManifest {
+                content_type: "application/json".to_string(),
+                data: b"{}".to_vec(),
+                cached_at: Utc::now() - chrono::Duration::hours(2),
+            };
+            cache
+                .tree
+                .insert(
+                    key("app", reference),
+                    bincode::serialize(&manifest).unwrap(),
+                )
+                .unwrap();
+        };
+        old("latest");
+        old(&digest);
+        old(&platform_reference(&digest, "linux/amd64"));
+        cache
+            .put("app", "fresh", "application/json", b"{}")
+            .unwrap();
+
+        assert_eq!(cache.remove_expired(3600, true).unwrap(), 1);
+        assert!(cache.get_stale("app", &digest).unwrap().is_some());
+        assert!(cache.get_stale("app", "fresh").unwrap().is_some());
+        assert_eq!(cache.remove_expired(3600, false).unwrap(), 2);
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let index = LayerIndex::new(db.open_tree("layers").unwrap(), 60);
+        index
+            .record(
+                "app",
+                br#"{"config": {"digest": "sha256:config", "size": 10}}"#,
+            )
+            .unwrap();
+        assert_eq!(index.remove_expired().unwrap(), 0);
+        let stale = KnownBlob {
+            size: 10,
+            recorded_at: Utc::now() - chrono::Duration::hours(2),
+        };
+        index
+            .tree
+            .insert(
+                key("app", "sha256:config"),
+                bincode::serialize(&stale).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(index.remove_expired().unwrap(), 1);
+    }
+}
@@ -0,0 +1,113 @@
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Byte-bounded LRU holding hot blobs in memory in front of the disk cache.
+pub struct MemoryCache {
+    capacity_bytes: u64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, (Bytes, u64)>,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+impl MemoryCache {
+    pub fn new(capacity_bytes: u64) -> Self {
+        Self {
+            capacity_bytes,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity_bytes > 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let (data, last_used) = inner.entries.get_mut(key)?;
+        let data = data.clone();
+        let previous = std::mem::replace(last_used, tick);
+        inner.recency.remove(&previous);
+        inner.recency.insert(tick, key.to_string());
+
+        Some(data)
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, key: &str) -> bool {
+        self.inner.lock().unwrap().entries.contains_key(key)
+    }
+
+    pub fn insert(&self, key: &str, data: Bytes) {
+        let size = data.len() as u64;
+        if size > self.capacity_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(key);
+
+        while inner.size + size > self.capacity_bytes {
+            let Some((_, victim)) = inner.recency.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = inner.entries.remove(&victim) {
+                inner.size -= evicted.len() as u64;
+            }
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.recency.insert(tick, key.to_string());
+        inner.entries.insert(key.to_string(), (data, tick));
+        inner.size += size;
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some((data, tick)) = self.entries.remove(key) {
+            self.recency.remove(&tick);
+            self.size -= data.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = MemoryCache::new(10);
+        cache.insert("a", Bytes::from(vec![0u8; 4]));
+        cache.insert("b", Bytes::from(vec![0u8; 4]));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c", Bytes::from(vec![0u8; 4]));
+
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("c"));
+    }
+
+    #[test]
+    fn test_oversized_entry_not_stored() {
+        let cache = MemoryCache::new(4);
+        cache.insert("big", Bytes::from(vec![0u8; 5]));
+        assert!(!cache.contains("big"));
+    }
+}
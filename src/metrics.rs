@@ -0,0 +1,525 @@
+use crate::circuit_breaker::CircuitState;
+use crate::config::ResolvedRepository;
+use crate::registry::RegistryState;
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the pull latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; slower ones only count
+    /// towards `count`.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                cumulative
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum_seconds: self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+struct HistogramSnapshot {
+    /// Cumulative counts for each of `LATENCY_BUCKETS`.
+    buckets: Vec<u64>,
+    count: u64,
+    sum_seconds: f64,
+}
+
+/// Labels identifying a repository mapping. Both values come from the
+/// config rather than from request paths, which keeps the number of series
+/// bounded.
+fn repository_labels(registry_id: &str, mapping: &str) -> String {
+    format!("registry=\"{}\",repository=\"{}\"", registry_id, mapping)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PullKind {
+    Manifest,
+    Blob,
+}
+
+impl PullKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            PullKind::Manifest => "manifest",
+            PullKind::Blob => "blob",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheOutcome {
+    Hit,
+    Miss,
+}
+
+/// End-to-end latency of manifest and blob pulls, split by whether they were
+/// served from the cache, and hit and miss counts per repository mapping.
+#[derive(Default)]
+pub struct PullLatency {
+    manifest_hit: Histogram,
+    manifest_miss: Histogram,
+    blob_hit: Histogram,
+    blob_miss: Histogram,
+    /// Pulls keyed by registry id, mapping, kind and outcome.
+    by_repository: Mutex<HashMap<(String, String, PullKind, CacheOutcome), u64>>,
+}
+
+impl PullLatency {
+    /// Records a pull of `repository`, which is `None` for cached blobs whose
+    /// mapping has since been removed; those only count towards the latency.
+    pub fn record(
+        &self,
+        repository: Option<&ResolvedRepository>,
+        kind: PullKind,
+        outcome: CacheOutcome,
+        elapsed: Duration,
+    ) {
+        let histogram = match (kind, outcome) {
+            (PullKind::Manifest, CacheOutcome::Hit) => &self.manifest_hit,
+            (PullKind::Manifest, CacheOutcome::Miss) => &self.manifest_miss,
+            (PullKind::Blob, CacheOutcome::Hit) => &self.blob_hit,
+            (PullKind::Blob, CacheOutcome::Miss) => &self.blob_miss,
+        };
+        histogram.observe(elapsed);
+
+        if let Some(repository) = repository {
+            *self
+                .by_repository
+                .lock()
+                .unwrap()
+                .entry((
+                    repository.registry_id.clone(),
+                    repository.mapping.clone(),
+                    kind,
+                    outcome,
+                ))
+                .or_default() += 1;
+        }
+    }
+
+    /// Pulls with `outcome` per registry, mapping and kind, sorted by label.
+    fn repository_counts(&self, outcome: CacheOutcome) -> Vec<(String, u64)> {
+        let mut counts: Vec<_> = self
+            .by_repository
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((.., pulled), _)| *pulled == outcome)
+            .map(|((registry_id, mapping, kind, _), count)| {
+                (
+                    format!(
+                        "{},kind=\"{}\"",
+                        repository_labels(registry_id, mapping),
+                        kind.as_str()
+                    ),
+                    *count,
+                )
+            })
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    fn snapshots(&self) -> [(&'static str, HistogramSnapshot); 4] {
+        [
+            (
+                r#"kind="manifest",cache="hit""#,
+                self.manifest_hit.snapshot(),
+            ),
+            (
+                r#"kind="manifest",cache="miss""#,
+                self.manifest_miss.snapshot(),
+            ),
+            (r#"kind="blob",cache="hit""#, self.blob_hit.snapshot()),
+            (r#"kind="blob",cache="miss""#, self.blob_miss.snapshot()),
+        ]
+    }
+}
+
+/// Requests sent upstream and their latency, per registry and repository
+/// mapping and by whether the registry answered without a server error.
+#[derive(Default)]
+pub struct UpstreamRequests {
+    series: Mutex<HashMap<(String, String, bool), Histogram>>,
+}
+
+impl UpstreamRequests {
+    pub fn record(&self, repository: &ResolvedRepository, success: bool, elapsed: Duration) {
+        self.series
+            .lock()
+            .unwrap()
+            .entry((
+                repository.registry_id.clone(),
+                repository.mapping.clone(),
+                success,
+            ))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    /// Snapshots labelled with registry, mapping and outcome, sorted by label.
+    fn snapshots(&self) -> Vec<(String, HistogramSnapshot)> {
+        let mut snapshots: Vec<_> = self
+            .series
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((registry_id, mapping, success), histogram)| {
+                let outcome = if *success { "success" } else { "failure" };
+                (
+                    format!(
+                        "{},outcome=\"{}\"",
+                        repository_labels(registry_id, mapping),
+                        outcome
+                    ),
+                    histogram.snapshot(),
+                )
+            })
+            .collect();
+        snapshots.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshots
+    }
+}
+
+/// Builds a Prometheus text exposition document.
+#[derive(Default)]
+struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    fn metric<L: AsRef<str>>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (L, u64)>,
+    ) {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let labels = labels.as_ref();
+            if labels.is_empty() {
+                let _ = writeln!(self.output, "{} {}", name, value);
+            } else {
+                let _ = writeln!(self.output, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    fn counter<L: AsRef<str>>(
+        &mut self,
+        name: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (L, u64)>,
+    ) {
+        self.metric(name, "counter", help, samples);
+    }
+
+    fn gauge<L: AsRef<str>>(
+        &mut self,
+        name: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (L, u64)>,
+    ) {
+        self.metric(name, "gauge", help, samples);
+    }
+
+    fn histogram<L: AsRef<str>>(
+        &mut self,
+        name: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (L, HistogramSnapshot)>,
+    ) {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} histogram", name);
+        for (labels, snapshot) in samples {
+            let labels = labels.as_ref();
+            for (le, count) in LATENCY_BUCKETS.iter().zip(&snapshot.buckets) {
+                let _ = writeln!(
+                    self.output,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, le, count
+                );
+            }
+            let _ = writeln!(
+                self.output,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name, labels, snapshot.count
+            );
+            let _ = writeln!(
+                self.output,
+                "{}_sum{{{}}} {}",
+                name, labels, snapshot.sum_seconds
+            );
+            let _ = writeln!(
+                self.output,
+                "{}_count{{{}}} {}",
+                name, labels, snapshot.count
+            );
+        }
+    }
+}
+
+pub async fn handle_metrics(State(state): State<Arc<RegistryState>>) -> impl IntoResponse {
+    let mut writer = MetricsWriter::default();
+
+    let stats = state.cache.stats();
+    writer.counter(
+        "cache_hits_total",
+        "Blob cache hits by cache layer.",
+        [
+            (r#"layer="memory""#, stats.memory_hits),
+            (r#"layer="disk""#, stats.disk_hits),
+        ],
+    );
+    writer.counter(
+        "cache_misses_total",
+        "Blob cache misses by cache layer.",
+        [
+            (r#"layer="memory""#, stats.memory_misses),
+            (r#"layer="disk""#, stats.disk_misses),
+        ],
+    );
+
+    writer.gauge(
+        "cache_compression_saved_bytes",
+        "Disk space saved by compressing cached blobs at rest.",
+        [("", state.cache.compression_saved_bytes())],
+    );
+
+    writer.histogram(
+        "pull_duration_seconds",
+        "End-to-end manifest and blob pull latency by cache outcome.",
+        state.pull_latency.snapshots(),
+    );
+    writer.counter(
+        "repository_cache_hits_total",
+        "Manifest and blob pulls served from the cache, per registry and repository mapping.",
+        state.pull_latency.repository_counts(CacheOutcome::Hit),
+    );
+    writer.counter(
+        "repository_cache_misses_total",
+        "Manifest and blob pulls fetched from upstream, per registry and repository mapping.",
+        state.pull_latency.repository_counts(CacheOutcome::Miss),
+    );
+
+    let upstream_requests = state.upstream.requests().snapshots();
+    writer.counter(
+        "upstream_requests_total",
+        "Requests sent upstream per registry and repository mapping.",
+        upstream_requests
+            .iter()
+            .map(|(labels, snapshot)| (labels.as_str(), snapshot.count)),
+    );
+    writer.histogram(
+        "upstream_request_duration_seconds",
+        "Upstream request latency, including mirror failover, per registry and repository mapping.",
+        upstream_requests,
+    );
+
+    writer.counter(
+        "upstream_host_failures_total",
+        "Failed upstream requests per registry URL or mirror.",
+        state
+            .upstream
+            .host_failures()
+            .into_iter()
+            .map(|(host, count)| (format!("host=\"{}\"", host), count)),
+    );
+
+    writer.counter(
+        "upstream_throttled_total",
+        "Upstream requests delayed or refused by a registry's rate limit.",
+        state
+            .upstream
+            .throttled_requests()
+            .into_iter()
+            .map(|(registry, count)| (format!("registry=\"{}\"", registry), count)),
+    );
+
+    writer.gauge(
+        "upstream_in_flight",
+        "Upstream fetches in progress, counting blobs until their body has been streamed.",
+        [("", state.upstream.in_flight() as u64)],
+    );
+    writer.gauge(
+        "upstream_queued",
+        "Upstream fetches waiting for a slot under max_concurrent_upstream.",
+        [("", state.upstream.queued() as u64)],
+    );
+
+    let circuits = state.upstream.circuits();
+    writer.gauge(
+        "upstream_circuit_state",
+        "Circuit of each registry that has failed: 0 closed, 1 half-open, 2 open.",
+        circuits.iter().map(|(registry, circuit, _)| {
+            let value = match circuit {
+                CircuitState::Closed => 0,
+                CircuitState::HalfOpen => 1,
+                CircuitState::Open => 2,
+            };
+            (format!("registry=\"{}\"", registry), value)
+        }),
+    );
+    writer.counter(
+        "upstream_circuit_opened_total",
+        "Times requests to a registry were suspended after repeated failures.",
+        circuits
+            .iter()
+            .map(|(registry, _, opens)| (format!("registry=\"{}\"", registry), *opens)),
+    );
+
+    writer.counter(
+        "prefetch_blobs_total",
+        "Blobs fetched into the cache by layer prefetching.",
+        [("", state.prefetcher.fetched())],
+    );
+    writer.counter(
+        "prefetch_hits_total",
+        "Blob pulls served from the cache because the blob was prefetched.",
+        [("", state.prefetcher.hits())],
+    );
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        writer.output,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{alpine_state, test_state};
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_metrics_report_cache_layers() {
+        let (state, _temp) = test_state("").await;
+        state
+            .cache
+            .put("sha256:abc", Bytes::from("data"), None)
+            .await
+            .unwrap();
+        state.cache.get("sha256:abc").await.unwrap();
+        state.cache.get("sha256:missing").await.unwrap();
+
+        let response = handle_metrics(State(state)).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(text.contains("# TYPE cache_hits_total counter"));
+        assert!(text.contains(r#"cache_hits_total{layer="disk"} 1"#));
+        assert!(text.contains(r#"cache_misses_total{layer="memory"} 2"#));
+        assert!(text.contains(r#"cache_misses_total{layer="disk"} 1"#));
+        assert!(text.contains("\ncache_compression_saved_bytes 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_latency_split_by_cache_outcome() {
+        use crate::registry::handle_get_blob;
+        use axum::extract::Path;
+        use axum::http::HeaderMap;
+        use axum::Extension;
+
+        const DIGEST: &str =
+            "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+        let upstream = crate::test_support::spawn_upstream(crate::test_support::blob_upstream(
+            DIGEST, b"layer",
+        ))
+        .await;
+        let (state, _temp) = alpine_state(&upstream, |_| {}).await;
+        state
+            .cache
+            .put(
+                "sha256:cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc",
+                Bytes::from("data"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // The first pull of DIGEST misses and fills the cache; the rest hit.
+        for digest in [
+            DIGEST,
+            "sha256:cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc",
+            "sha256:cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc",
+            DIGEST,
+        ] {
+            let response = handle_get_blob(
+                State(state.clone()),
+                Extension(crate::test_support::admin_claims()),
+                Path(("alpine".to_string(), digest.to_string())),
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+        }
+
+        let response = handle_metrics(State(state)).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(text.contains("# TYPE pull_duration_seconds histogram"));
+        assert!(text.contains(r#"pull_duration_seconds_count{kind="blob",cache="hit"} 3"#));
+        assert!(text.contains(r#"pull_duration_seconds_count{kind="blob",cache="miss"} 1"#));
+        assert!(
+            text.contains(r#"pull_duration_seconds_bucket{kind="blob",cache="miss",le="+Inf"} 1"#)
+        );
+        assert!(text.contains(r#"pull_duration_seconds_count{kind="manifest",cache="miss"} 0"#));
+
+        assert!(text.contains(
+            r#"repository_cache_hits_total{registry="hub",repository="alpine",kind="blob"} 3"#
+        ));
+        assert!(text.contains(
+            r#"repository_cache_misses_total{registry="hub",repository="alpine",kind="blob"} 1"#
+        ));
+        assert!(text.contains(
+            r#"upstream_requests_total{registry="hub",repository="alpine",outcome="success"} 1"#
+        ));
+        assert!(text.contains(
+            r#"upstream_request_duration_seconds_count{registry="hub",repository="alpine",outcome="success"} 1"#
+        ));
+    }
+}
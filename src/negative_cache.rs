@@ -0,0 +1,118 @@
+//! Manifests upstream reported as missing, so repeated pulls of a tag that
+//! does not exist are answered without a round-trip.
+//!
+//! A tag that was just pushed may still 404 on some upstream replicas, so a
+//! reference is only negatively cached once it has missed
+//! `negative_cache_min_misses` times, each within `negative_ttl_seconds` of
+//! the previous miss.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Misses {
+    count: u32,
+    last_miss: Instant,
+}
+
+pub struct NegativeCache {
+    ttl: Duration,
+    min_misses: u32,
+    entries: Mutex<HashMap<String, Misses>>,
+}
+
+impl NegativeCache {
+    pub fn new(ttl_seconds: u64, min_misses: u32) -> Self {
+        Self {
+            ttl: Duration::from_secs(ttl_seconds),
+            min_misses: min_misses.max(1),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `reference` is known to be missing and need not be fetched.
+    pub fn contains(&self, repository: &str, reference: &str) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key(repository, reference))
+            .is_some_and(|misses| {
+                misses.count >= self.min_misses && misses.last_miss.elapsed() < self.ttl
+            })
+    }
+
+    /// Records an upstream 404 for `reference`.
+    pub fn record_miss(&self, repository: &str, reference: &str) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, misses| now.duration_since(misses.last_miss) < self.ttl);
+
+        let misses = entries.entry(key(repository, reference)).or_insert(Misses {
+            count: 0,
+            last_miss: now,
+        });
+        misses.count += 1;
+        misses.last_miss = now;
+    }
+
+    /// Forgets misses recorded for `reference`, or for every reference of
+    /// `repository` when none is given. Returns whether any were recorded.
+    pub fn forget(&self, repository: &str, reference: Option<&str>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match reference {
+            Some(reference) => entries.remove(&key(repository, reference)).is_some(),
+            None => {
+                let before = entries.len();
+                entries.retain(|entry, _| !entry.starts_with(&key(repository, "")));
+                entries.len() < before
+            }
+        }
+    }
+}
+
+fn key(repository: &str, reference: &str) -> String {
+    format!("{}:{}", repository, reference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_only_after_repeated_misses() {
+        let cache = NegativeCache::new(60, 3);
+
+        cache.record_miss("app", "new-tag");
+        assert!(!cache.contains("app", "new-tag"));
+        cache.record_miss("app", "new-tag");
+        assert!(!cache.contains("app", "new-tag"));
+        cache.record_miss("app", "new-tag");
+        assert!(cache.contains("app", "new-tag"));
+        assert!(!cache.contains("app", "latest"));
+    }
+
+    #[test]
+    fn test_forget_is_scoped_to_repository() {
+        let cache = NegativeCache::new(60, 1);
+        for (repository, reference) in [("app", "a"), ("app", "b"), ("app/sub", "a")] {
+            cache.record_miss(repository, reference);
+        }
+
+        cache.forget("app", Some("a"));
+        assert!(!cache.contains("app", "a"));
+        assert!(cache.contains("app", "b"));
+
+        cache.forget("app", None);
+        assert!(!cache.contains("app", "b"));
+        assert!(cache.contains("app/sub", "a"));
+    }
+
+    #[test]
+    fn test_disabled_with_zero_ttl() {
+        let cache = NegativeCache::new(0, 1);
+        cache.record_miss("app", "missing");
+        assert!(!cache.contains("app", "missing"));
+    }
+}
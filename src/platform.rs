@@ -0,0 +1,180 @@
+//! Resolving a manifest list / image index to the manifest of one platform,
+//! for clients that cannot handle multi-platform manifests.
+
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+pub const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.index.v1+json",
+];
+
+/// A platform written as `os/architecture[/variant]`, e.g. `linux/arm64/v8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').collect();
+        let valid_part = |part: &&str| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        };
+        if !(2..=3).contains(&parts.len()) || !parts.iter().all(valid_part) {
+            return Err(format!(
+                "invalid platform '{}'; expected os/architecture[/variant]",
+                s
+            ));
+        }
+        Ok(Platform {
+            os: parts[0].to_string(),
+            architecture: parts[1].to_string(),
+            variant: parts.get(2).map(|v| v.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Index {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: String,
+    platform: Option<DescriptorPlatform>,
+}
+
+#[derive(Deserialize)]
+struct DescriptorPlatform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+pub fn is_index(content_type: &str) -> bool {
+    INDEX_MEDIA_TYPES.contains(&content_type)
+}
+
+/// Digest of the manifest in `index` built for `platform`. A platform without
+/// a variant matches any variant. When several entries match, the one whose
+/// media type comes first in `preferred_media_types` wins, then the earliest
+/// in the index.
+pub fn select(
+    index: &[u8],
+    platform: &Platform,
+    preferred_media_types: &[String],
+) -> Option<String> {
+    let index: Index = serde_json::from_slice(index).ok()?;
+    let rank = |media_type: &str| {
+        preferred_media_types
+            .iter()
+            .position(|preferred| preferred == media_type)
+            .unwrap_or(usize::MAX)
+    };
+
+    index
+        .manifests
+        .into_iter()
+        .filter(|descriptor| {
+            descriptor.platform.as_ref().is_some_and(|p| {
+                p.os == platform.os
+                    && p.architecture == platform.architecture
+                    && (platform.variant.is_none() || p.variant == platform.variant)
+            })
+        })
+        .min_by_key(|descriptor| rank(&descriptor.media_type))
+        .map(|descriptor| descriptor.digest)
+}
+
+/// Digests of every manifest listed in `index`.
+pub fn manifest_digests(index: &[u8]) -> Vec<String> {
+    serde_json::from_slice::<Index>(index)
+        .map(|index| {
+            index
+                .manifests
+                .into_iter()
+                .map(|descriptor| descriptor.digest)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [
+            {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:amd64-oci",
+             "platform": {"os": "linux", "architecture": "amd64"}},
+            {"mediaType": "application/vnd.docker.distribution.manifest.v2+json", "digest": "sha256:amd64-docker",
+             "platform": {"os": "linux", "architecture": "amd64"}},
+            {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:arm-v7",
+             "platform": {"os": "linux", "architecture": "arm", "variant": "v7"}},
+            {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:attestation",
+             "platform": {"os": "unknown", "architecture": "unknown"}}
+        ]
+    }"#;
+
+    fn preferred() -> Vec<String> {
+        vec![
+            "application/vnd.docker.distribution.manifest.v2+json".to_string(),
+            "application/vnd.oci.image.manifest.v1+json".to_string(),
+        ]
+    }
+
+    fn select_for(platform: &str) -> Option<String> {
+        select(INDEX.as_bytes(), &platform.parse().unwrap(), &preferred())
+    }
+
+    #[test]
+    fn test_select_platform_manifest() {
+        assert_eq!(
+            select_for("linux/amd64").as_deref(),
+            Some("sha256:amd64-docker")
+        );
+        assert_eq!(select_for("linux/arm").as_deref(), Some("sha256:arm-v7"));
+        assert_eq!(select_for("linux/arm/v7").as_deref(), Some("sha256:arm-v7"));
+        assert_eq!(select_for("linux/arm/v6"), None);
+        assert_eq!(select_for("windows/amd64"), None);
+    }
+
+    #[test]
+    fn test_platform_parsing() {
+        let platform: Platform = "linux/arm64/v8".parse().unwrap();
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+        for invalid in ["linux", "linux/", "linux/amd64/v1/x", "linux/amd 64"] {
+            assert!(
+                invalid.parse::<Platform>().is_err(),
+                "accepted {:?}",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Layer prefetching. A pulled manifest names exactly the blobs the client
+//! requests next, so they are fetched into the cache in the background and
+//! the blob pulls that follow are served warm.
+
+use crate::config::{CacheConfig, ResolvedRepository};
+use crate::error::{ProxyError, Result};
+use crate::manifest_cache::is_digest_reference;
+use crate::registry::{validate_digest, RegistryState};
+use futures::StreamExt;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+/// Prefetched digests remembered until their first pull. Beyond this many,
+/// further prefetches are skipped, so blobs that are never pulled cannot
+/// accumulate.
+const MAX_PENDING: usize = 10_000;
+
+pub struct Prefetcher {
+    enabled: bool,
+    permits: Semaphore,
+    /// Digests being or already prefetched that no client has pulled yet.
+    pending: Mutex<HashSet<String>>,
+    fetched: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Prefetcher {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            enabled: config.prefetch_layers,
+            permits: Semaphore::new(config.prefetch_concurrency.max(1)),
+            pending: Mutex::new(HashSet::new()),
+            fetched: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Records a blob pull served from the cache, counting a prefetch hit if
+    /// the blob was prefetched.
+    pub fn record_hit(&self, digest: &str) {
+        if self.enabled && self.pending.lock().unwrap().remove(digest) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Blobs written to the cache by prefetching.
+    pub fn fetched(&self) -> u64 {
+        self.fetched.load(Ordering::Relaxed)
+    }
+
+    /// Cached blob pulls served thanks to prefetching.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+/// Starts fetching each of `digests` that is neither cached nor already
+/// being prefetched. At most `prefetch_concurrency` blobs are fetched at
+/// once. The digests come from an upstream manifest, so malformed ones are
+/// dropped before they can name a cache path.
+pub fn prefetch_blobs(
+    state: &Arc<RegistryState>,
+    repository: ResolvedRepository,
+    digests: Vec<String>,
+) {
+    let repository = Arc::new(repository);
+    for digest in digests {
+        if !is_digest_reference(&digest) {
+            warn!("Not prefetching invalid blob digest {:?}", digest);
+            continue;
+        }
+        if state.cache.contains(&digest) {
+            continue;
+        }
+        {
+            let mut pending = state.prefetcher.pending.lock().unwrap();
+            if pending.len() >= MAX_PENDING || !pending.insert(digest.clone()) {
+                continue;
+            }
+        }
+
+        let (state, repository) = (state.clone(), repository.clone());
+        tokio::spawn(async move {
+            let prefetcher = &state.prefetcher;
+            let Ok(_permit) = prefetcher.permits.acquire().await else {
+                return;
+            };
+            match fetch_blob(&state, &repository, &digest).await {
+                Ok(true) => {
+                    debug!("Prefetched blob {}", digest);
+                    prefetcher.fetched.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(false) => {
+                    prefetcher.pending.lock().unwrap().remove(&digest);
+                }
+                Err(e) => {
+                    warn!("Failed to prefetch blob {}: {}", digest, e);
+                    prefetcher.pending.lock().unwrap().remove(&digest);
+                }
+            }
+        });
+    }
+}
+
+/// Streams one blob from upstream into the cache. Returns false when
+/// another pull is already writing it.
+pub async fn fetch_blob(
+    state: &RegistryState,
+    repository: &ResolvedRepository,
+    digest: &str,
+) -> Result<bool> {
+    validate_digest(digest)?;
+    let max_age_seconds = repository.cache_policy.max_age_seconds;
+    let Some(mut writer) = state.cache.writer(digest, max_age_seconds).await? else {
+        return Ok(false);
+    };
+    let response = state.upstream.get_blob(repository, digest).await?;
+    if let Some(length) = response.content_length() {
+        if !state.cache.caches_size(length) {
+            return Ok(false);
+        }
+    }
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        writer.write(&chunk.map_err(ProxyError::Upstream)?).await?;
+    }
+    writer.commit().await?;
+    Ok(true)
+}
@@ -0,0 +1,253 @@
+//! Cache preloading. The images listed in `cache.preload` are fetched into
+//! the cache at startup, so deployments pulling a known set of images are
+//! served warm from the first pull, even without upstream access later.
+
+use crate::error::{ProxyError, Result};
+use crate::manifest_cache::blob_digests;
+use crate::platform::{is_index, manifest_digests, select as select_platform, Platform};
+use crate::prefetch::fetch_blob;
+use crate::registry::{validate_digest, RegistryState};
+use bytes::Bytes;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Outcome of a preload run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreloadReport {
+    pub images: usize,
+    pub failed: usize,
+    /// Blobs fetched from upstream; blobs already cached are not counted.
+    pub blobs: u64,
+}
+
+/// Splits a preload entry into repository and reference. A missing
+/// reference means `latest`.
+pub fn parse_image(entry: &str) -> std::result::Result<(String, String), String> {
+    let (repository, reference) = match entry.split_once('@') {
+        Some((repository, digest)) => (repository, digest),
+        None => match entry.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+            _ => (entry, "latest"),
+        },
+    };
+    if repository.is_empty() || reference.is_empty() {
+        return Err(format!(
+            "invalid image '{}'; expected repository:tag or repository@digest",
+            entry
+        ));
+    }
+    Ok((repository.to_string(), reference.to_string()))
+}
+
+/// Fetches every configured image into the cache, logging progress. A
+/// failing image is logged and skipped.
+pub async fn preload(state: &Arc<RegistryState>) -> PreloadReport {
+    let entries = &state.config.cache.preload;
+    let mut report = PreloadReport::default();
+    for (position, entry) in entries.iter().enumerate() {
+        info!("Preloading {} ({}/{})", entry, position + 1, entries.len());
+        match preload_image(state, entry).await {
+            Ok(blobs) => {
+                report.images += 1;
+                report.blobs += blobs;
+            }
+            Err(e) => {
+                warn!("Failed to preload {}: {}", entry, e);
+                report.failed += 1;
+            }
+        }
+    }
+    info!(
+        "Preload finished: {} images cached, {} failed, {} blobs fetched",
+        report.images, report.failed, report.blobs
+    );
+    report
+}
+
+/// Caches one image's manifest and blobs. For a manifest list, the manifest
+/// of `upstream.default_platform` is cached, or every listed manifest when
+/// no default is configured. Returns the number of blobs fetched. Digests
+/// named by upstream manifests are validated before they are fetched or
+/// written to the cache.
+async fn preload_image(state: &RegistryState, entry: &str) -> Result<u64> {
+    let (repository, reference) = parse_image(entry).map_err(ProxyError::BadRequest)?;
+    let repository = state.config.repository_key(&repository);
+    let resolved = state
+        .config
+        .resolve_repository(&repository)
+        .ok_or_else(|| ProxyError::NameUnknown(repository.clone()))?;
+    if resolved.cache_policy.no_cache {
+        return Err(ProxyError::BadRequest(format!(
+            "repository '{}' is not cached",
+            repository
+        )));
+    }
+
+    let (data, content_type) = state.upstream.get_manifest(&resolved, &reference).await?;
+    let children = if is_index(&content_type) {
+        let default_platform = state
+            .config
+            .upstream
+            .default_platform
+            .as_deref()
+            .and_then(|platform| platform.parse::<Platform>().ok());
+        match default_platform {
+            Some(platform) => select_platform(
+                &data,
+                &platform,
+                &state.config.upstream.manifest_media_types,
+            )
+            .into_iter()
+            .collect(),
+            None => manifest_digests(&data),
+        }
+    } else {
+        Vec::new()
+    };
+
+    let mut manifests: Vec<(String, Bytes, String)> = vec![(reference, data, content_type)];
+    for digest in children {
+        validate_digest(&digest)?;
+        let (data, content_type) = state.upstream.get_manifest(&resolved, &digest).await?;
+        manifests.push((digest, data, content_type));
+    }
+
+    let mut fetched = 0;
+    for (reference, data, content_type) in &manifests {
+        state
+            .cache
+            .manifests()
+            .put(&repository, reference, content_type, data)?;
+        state.cache.known_layers().record(&repository, data)?;
+        for digest in blob_digests(data) {
+            validate_digest(&digest)?;
+            if !state.cache.contains(&digest) && fetch_blob(state, &resolved, &digest).await? {
+                fetched += 1;
+            }
+        }
+    }
+    Ok(fetched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{alpine_state, spawn_upstream};
+    use axum::http::header;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_parse_image() {
+        let parse = |entry| parse_image(entry).unwrap();
+        assert_eq!(parse("alpine:3.19"), ("alpine".into(), "3.19".into()));
+        assert_eq!(parse("team/app"), ("team/app".into(), "latest".into()));
+        assert_eq!(
+            parse("alpine@sha256:abc"),
+            ("alpine".into(), "sha256:abc".into())
+        );
+        assert!(parse_image(":latest").is_err());
+        assert!(parse_image("alpine@").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_preload_caches_index_children_and_blobs() {
+        let layer = b"preloaded layer";
+        let layer_digest = format!("sha256:{}", hex::encode(Sha256::digest(layer)));
+        let child = json!({
+            "schemaVersion": 2,
+            "layers": [{ "digest": layer_digest }],
+        })
+        .to_string();
+        let child_digest = format!("sha256:{}", hex::encode(Sha256::digest(&child)));
+        let index = json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": child_digest,
+                "platform": { "os": "linux", "architecture": "amd64" },
+            }],
+        })
+        .to_string();
+        let router = axum::Router::new()
+            .route(
+                "/v2/library/alpine/manifests/3.19",
+                axum::routing::get(move || async move {
+                    (
+                        [(
+                            header::CONTENT_TYPE,
+                            "application/vnd.oci.image.index.v1+json",
+                        )],
+                        index,
+                    )
+                }),
+            )
+            .route(
+                &format!("/v2/library/alpine/manifests/{}", child_digest),
+                axum::routing::get(move || async move {
+                    (
+                        [(
+                            header::CONTENT_TYPE,
+                            "application/vnd.oci.image.manifest.v1+json",
+                        )],
+                        child,
+                    )
+                }),
+            )
+            .route(
+                &format!("/v2/library/alpine/blobs/{}", layer_digest),
+                axum::routing::get(move || async move { &layer[..] }),
+            );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.manifest_ttl_seconds = 60;
+            config.cache.preload = vec!["alpine:3.19".to_string(), "unmapped:1.0".to_string()];
+        })
+        .await;
+
+        let report = preload(&state).await;
+        assert_eq!(
+            report,
+            PreloadReport {
+                images: 1,
+                failed: 1,
+                blobs: 1
+            }
+        );
+        assert!(state.cache.contains(&layer_digest));
+        let manifests = state.cache.manifests();
+        assert!(manifests.get("alpine", "3.19").unwrap().is_some());
+        assert!(manifests.get("alpine", &child_digest).unwrap().is_some());
+
+        // A second run finds everything cached.
+        assert_eq!(preload(&state).await.blobs, 0);
+    }
+
+    #[tokio::test]
+    async fn test_preload_rejects_invalid_manifest_digests() {
+        let manifest = json!({
+            "schemaVersion": 2,
+            "layers": [{ "digest": "sha256:../../../escaped" }],
+        })
+        .to_string();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(move || async move {
+                (
+                    [(
+                        header::CONTENT_TYPE,
+                        "application/vnd.oci.image.manifest.v1+json",
+                    )],
+                    manifest,
+                )
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |_| {}).await;
+
+        assert!(matches!(
+            preload_image(&state, "alpine").await,
+            Err(ProxyError::DigestInvalid(_))
+        ));
+    }
+}
@@ -0,0 +1,188 @@
+//! Per-subject request rate limiting. Each token subject gets a token bucket
+//! that refills at the configured rate, so one misbehaving client cannot
+//! burn through the upstream registries' own rate limits for everyone. The
+//! token endpoint, reached before a client has a subject, is limited per
+//! client address instead.
+
+use crate::auth::Claims;
+use crate::config::RateLimit;
+use crate::error::{ProxyError, Result};
+use crate::ip_filter::client_ip;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How often buckets that have refilled completely are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    limit: Option<RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    next_prune: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                next_prune: Instant::now() + PRUNE_INTERVAL,
+            }),
+        }
+    }
+
+    /// Takes one token from `subject`'s bucket, refusing the request with the
+    /// number of seconds until a token is available when the bucket is empty.
+    pub fn check(&self, subject: &str) -> Result<()> {
+        let Some(limit) = &self.limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let per_second = f64::from(limit.requests_per_minute) / 60.0;
+        let capacity = f64::from(limit.burst);
+        let refill = |bucket: &mut Bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+            bucket.updated = now;
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        // Full buckets behave exactly like new ones, so they need not be kept.
+        // They are only swept periodically, so a request does not walk every
+        // tracked bucket.
+        if now >= buckets.next_prune {
+            buckets.by_key.retain(|_, bucket| {
+                refill(bucket);
+                bucket.tokens < capacity
+            });
+            buckets.next_prune = now + PRUNE_INTERVAL;
+        }
+
+        let bucket = buckets.by_key.entry(subject.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        refill(bucket);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) / per_second).ceil() as u64;
+        warn!(
+            "Subject {} exceeded {} requests per minute; retry in {}s",
+            subject, limit.requests_per_minute, retry_after
+        );
+        Err(ProxyError::RateLimited(Some(retry_after.max(1))))
+    }
+}
+
+/// Applies the limiter to authenticated requests. Runs after the auth layer,
+/// which has already stored the caller's claims on the request.
+pub async fn rate_limit_middleware(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(claims) = request.extensions().get::<Claims>() {
+        if let Err(e) = limiter.check(&claims.sub) {
+            return e.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Rate limiter keyed by client address rather than token subject.
+pub struct AddressRateLimiter {
+    limiter: RateLimiter,
+    trust_forwarded_for: bool,
+}
+
+impl AddressRateLimiter {
+    pub fn new(limit: RateLimit, trust_forwarded_for: bool) -> Self {
+        Self {
+            limiter: RateLimiter::new(Some(limit)),
+            trust_forwarded_for,
+        }
+    }
+}
+
+pub async fn address_rate_limit_middleware(
+    State(limiter): State<Arc<AddressRateLimiter>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = client_ip(
+        request.headers(),
+        peer.map(|ConnectInfo(addr)| addr),
+        limiter.trust_forwarded_for,
+    );
+    let key = client.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    if let Err(e) = limiter.limiter.check(&key) {
+        return e.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_beyond_burst_are_rejected() {
+        let limiter = RateLimiter::new(Some(RateLimit {
+            requests_per_minute: 1,
+            burst: 3,
+        }));
+
+        for _ in 0..3 {
+            assert!(limiter.check("ci").is_ok());
+        }
+        match limiter.check("ci") {
+            Err(ProxyError::RateLimited(Some(retry_after))) => {
+                assert!((1..=60).contains(&retry_after))
+            }
+            other => panic!("expected RateLimited, got {:?}", other),
+        }
+        // Other subjects have their own buckets.
+        assert!(limiter.check("dev").is_ok());
+        assert!(RateLimiter::new(None).check("ci").is_ok());
+    }
+
+    #[test]
+    fn test_full_buckets_swept_periodically() {
+        let limiter = RateLimiter::new(Some(RateLimit {
+            requests_per_minute: 60_000,
+            burst: 1,
+        }));
+        let tracked = || limiter.buckets.lock().unwrap().by_key.len();
+
+        assert!(limiter.check("idle").is_ok());
+        assert!(limiter.check("active").is_ok());
+        assert_eq!(tracked(), 2);
+
+        // One millisecond refills a token at this rate.
+        std::thread::sleep(Duration::from_millis(5));
+        limiter.buckets.lock().unwrap().next_prune = Instant::now();
+        assert!(limiter.check("active").is_ok());
+        assert_eq!(tracked(), 1);
+    }
+}
@@ -1,47 +1,462 @@
-use crate::auth::{check_repository_access, Claims};
-use crate::cache::BlobCache;
-use crate::config::Config;
+use crate::auth::{check_repository_access, repository_in_path, AccessLevel, Claims};
+use crate::cache::{BlobCache, CacheWriter, DigestHasher};
+use crate::config::{
+    check_upstream_name, CacheBackendConfig, CacheBypassAccess, Config, ResolvedRepository,
+};
 use crate::error::{ProxyError, Result};
-use crate::upstream::UpstreamClient;
+use crate::manifest_cache::{blob_digests, is_digest_reference, platform_reference};
+use crate::metrics::{CacheOutcome, PullKind, PullLatency};
+use crate::platform::{is_index, select as select_platform, Platform};
+use crate::prefetch::{prefetch_blobs, Prefetcher};
+use crate::repository_guard::RepositoryGuard;
+use crate::upstream::{BlobLocation, UpstreamClient};
 use axum::{
     body::Body,
-    extract::{Path, State},
-    http::{header, StatusCode},
+    extract::{Path, Query, Request, State},
+    http::{header, uri::PathAndQuery, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware::Next,
     response::{IntoResponse, Response},
     Extension, Json,
 };
+use bytes::{Bytes, BytesMut};
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt};
+use serde::Deserialize;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
-use tracing::{debug, info};
+use std::time::Instant;
+use tracing::{debug, info, warn, Instrument};
+
+/// Header announcing the registry API version, which clients check on `/v2/`
+/// and on its `401` challenge before starting the login flow.
+pub const API_VERSION_HEADER: &str = "Docker-Distribution-Api-Version";
+pub const API_VERSION: &str = "registry/2.0";
+
+/// `Warning` header marking a response served from an expired cache entry
+/// because upstream could not be reached (RFC 7234, section 5.5.1).
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
 
 pub struct RegistryState {
     pub config: Config,
     pub upstream: UpstreamClient,
     pub cache: Arc<BlobCache>,
+    pub pull_latency: PullLatency,
+    pub repository_guard: RepositoryGuard,
+    pub prefetcher: Prefetcher,
+}
+
+/// Checks that the caller's token grants `repository` and that its subject
+/// stays within the distinct-repository limit.
+fn authorize(state: &RegistryState, claims: &Claims, repository: &str) -> Result<()> {
+    check_repository_access(claims, repository)?;
+    state.repository_guard.check(&claims.sub, repository)
+}
+
+/// Resolves `repository` to its upstream, applying any credential override
+/// carried by the caller's token.
+fn resolve(state: &RegistryState, claims: &Claims, repository: &str) -> Result<ResolvedRepository> {
+    let mut resolved = state
+        .config
+        .resolve_repository(repository)
+        .ok_or_else(|| ProxyError::NameUnknown(repository.to_string()))?;
+
+    // Validation rejects bad mappings at load time; this catches names that
+    // only turn malformed once wildcard captures are substituted.
+    check_upstream_name(&resolved.upstream_name).map_err(|problem| {
+        ProxyError::Internal(format!(
+            "Repository '{}' resolves to invalid upstream name '{}': {}",
+            repository, resolved.upstream_name, problem
+        ))
+    })?;
+
+    if let Some(upstream_auth) = &claims.upstream_auth {
+        resolved.credentials = vec![upstream_auth.clone().into()];
+        resolved.caller_credentials = true;
+    }
+
+    Ok(resolved)
 }
 
-pub async fn handle_version_check() -> impl IntoResponse {
-    Json(json!({}))
+/// Longest repository name accepted. The distribution spec leaves the limit
+/// to implementations; together with a registry host name, longer names
+/// break many clients.
+const MAX_REPOSITORY_NAME_LENGTH: usize = 255;
+
+/// The repository named in a request path, as used for access checks and
+/// mapping, after checking it against the distribution spec's name grammar.
+fn repository_name(state: &RegistryState, name: &str) -> Result<String> {
+    let repository = state.config.repository_key(name);
+    if !is_valid_repository_name(&repository) {
+        return Err(ProxyError::NameInvalid(name.to_string()));
+    }
+    Ok(repository)
+}
+
+/// Whether `name` matches the distribution spec's grammar: `/`-separated
+/// components of lowercase letters and digits, joined within a component by
+/// `.`, `_`, `__` or runs of `-`.
+pub fn is_valid_repository_name(name: &str) -> bool {
+    name.len() <= MAX_REPOSITORY_NAME_LENGTH && name.split('/').all(is_valid_name_component)
+}
+
+pub fn is_valid_name_component(component: &str) -> bool {
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    let mut rest = component;
+    loop {
+        // Each separator must sit between two non-empty alphanumeric runs.
+        let after_run = rest.trim_start_matches(alphanumeric);
+        if after_run.len() == rest.len() {
+            return false;
+        }
+        if after_run.is_empty() {
+            return true;
+        }
+        let next_run = after_run.trim_start_matches(|c: char| !alphanumeric(c));
+        let separator = &after_run[..after_run.len() - next_run.len()];
+        if !matches!(separator, "." | "_" | "__") && !separator.chars().all(|c| c == '-') {
+            return false;
+        }
+        rest = next_run;
+    }
+}
+
+/// Checks that `digest` is a supported `algorithm:hex` digest before it is
+/// used as a cache key or in an upstream URL.
+pub fn validate_digest(digest: &str) -> Result<()> {
+    if !is_digest_reference(digest) {
+        return Err(ProxyError::DigestInvalid(digest.to_string()));
+    }
+    Ok(())
+}
+
+/// Rejects a manifest fetched by digest whose bytes do not hash to that
+/// digest, so a faulty or malicious upstream cannot plant content under a
+/// digest that is then cached without expiry. Tags are not checked.
+fn verify_manifest_digest(reference: &str, data: &[u8]) -> Result<()> {
+    let Some(mut hasher) = DigestHasher::for_digest(reference) else {
+        return Ok(());
+    };
+    hasher.update(data);
+    if !hasher.matches(reference) {
+        return Err(ProxyError::Internal(format!(
+            "Upstream manifest does not match digest {}",
+            reference
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `tag` matches the distribution spec's tag grammar: up to 128
+/// letters, digits, `_`, `.` and `-`, not starting with `.` or `-`.
+fn is_valid_tag(tag: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-');
+    tag.len() <= 128
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag.chars().all(valid_char)
+}
+
+/// Resolves a digest request before the cache is consulted. With
+/// `immutable_digest_permanent`, an unmapped repository yields `None` rather
+/// than an error, so cached digests are served even once their registry or
+/// mapping has been removed.
+fn resolve_digest_request(
+    state: &RegistryState,
+    claims: &Claims,
+    repository: &str,
+) -> Result<Option<ResolvedRepository>> {
+    match resolve(state, claims, repository) {
+        Ok(resolved) => Ok(Some(resolved)),
+        Err(ProxyError::NameUnknown(_)) if state.config.cache.immutable_digest_permanent => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A client's request to skip the cache, from its `Cache-Control` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CacheDirective {
+    Default,
+    /// Fetch from upstream, but still store the result.
+    NoCache,
+    /// Fetch from upstream without touching the cache.
+    NoStore,
+}
+
+/// Reads `Cache-Control` from clients allowed to bypass the cache by
+/// `server.cache_bypass`; other clients always get [`CacheDirective::Default`].
+fn cache_directive(state: &RegistryState, claims: &Claims, headers: &HeaderMap) -> CacheDirective {
+    let directives: Vec<String> = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect();
+    let directive = if directives.iter().any(|d| d == "no-store") {
+        CacheDirective::NoStore
+    } else if directives.iter().any(|d| d == "no-cache") {
+        CacheDirective::NoCache
+    } else {
+        return CacheDirective::Default;
+    };
+
+    let allowed = match state.config.server.cache_bypass {
+        CacheBypassAccess::Disabled => false,
+        CacheBypassAccess::Admin => matches!(claims.access, AccessLevel::All),
+        CacheBypassAccess::All => true,
+    };
+    if !allowed {
+        debug!("Ignoring Cache-Control from {}: not permitted", claims.sub);
+        return CacheDirective::Default;
+    }
+    directive
+}
+
+pub async fn handle_health_check() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Readiness with a per-registry health summary. An unhealthy registry only
+/// affects pulls from that registry, so it never fails readiness.
+pub async fn handle_readiness(State(state): State<Arc<RegistryState>>) -> impl IntoResponse {
+    let health = state.upstream.registry_health();
+    let registries: serde_json::Map<String, serde_json::Value> = state
+        .config
+        .registries
+        .iter()
+        .map(|registry| {
+            let status = match health.get(&registry.id) {
+                Some(health) if health.healthy => "healthy",
+                Some(_) => "unhealthy",
+                None => "unknown",
+            };
+            (registry.id.clone(), json!(status))
+        })
+        .collect();
+
+    Json(json!({ "status": "ready", "registries": registries }))
+}
+
+/// Answers the `/v2/` version check. With `verify_upstream_on_ping`, every
+/// configured registry is probed first and the check fails with 503 when one
+/// cannot be reached.
+pub async fn handle_version_check(State(state): State<Arc<RegistryState>>) -> Result<Response> {
+    if state.config.upstream.verify_upstream_on_ping {
+        let pings = state
+            .config
+            .registries
+            .iter()
+            .map(|registry| state.upstream.ping(registry));
+        for outcome in futures::future::join_all(pings).await {
+            outcome?;
+        }
+    }
+    Ok(Json(json!({})).into_response())
+}
+
+/// Describes which optional parts of the registry API and which proxy
+/// features this instance supports with its current configuration.
+pub async fn handle_capabilities(State(state): State<Arc<RegistryState>>) -> impl IntoResponse {
+    let config = &state.config;
+    let mut auth_modes = vec!["bearer"];
+    if !config.users.is_empty() {
+        auth_modes.push("token");
+    }
+    if config
+        .repositories
+        .iter()
+        .any(|repository| repository.public)
+    {
+        auth_modes.push("anonymous");
+    }
+    if config.server.allow_query_token {
+        auth_modes.push("query_token");
+    }
+
+    let mut upstream_auth = vec!["basic", "refresh_token"];
+    if cfg!(feature = "ecr") {
+        upstream_auth.push("ecr");
+    }
+    if cfg!(feature = "gcp") {
+        upstream_auth.push("gcp");
+    }
+
+    let cache_backend = match config.cache.backend {
+        CacheBackendConfig::Filesystem => "filesystem",
+        CacheBackendConfig::S3(_) => "s3",
+    };
+
+    Json(json!({
+        "api_version": API_VERSION,
+        "pull": true,
+        "push": false,
+        "catalog": false,
+        "referrers": true,
+        "range_requests": false,
+        "tags_pagination": true,
+        "compression": ["gzip"],
+        "auth": auth_modes,
+        "upstream_auth": upstream_auth,
+        "tls": config.server.tls.is_some(),
+        "protocols": config.server.protocols,
+        "cache_backend": cache_backend,
+        "manifest_cache": config.cache.manifest_ttl_seconds > 0,
+        "negative_cache": config.cache.negative_ttl_seconds > 0,
+        "tags_cache": config.cache.tags_ttl_seconds > 0,
+        "prefetch": config.cache.prefetch_layers,
+        "serve_stale": config.cache.serve_stale_on_error,
+        "compress_at_rest": config.cache.compress_at_rest,
+        "default_platform": config.upstream.default_platform,
+        "cache_bypass": config.server.cache_bypass,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ManifestQuery {
+    /// Resolve a manifest list to this platform's manifest.
+    platform: Option<String>,
 }
 
 pub async fn handle_get_manifest(
     State(state): State<Arc<RegistryState>>,
     Extension(claims): Extension<Claims>,
     Path((repository, reference)): Path<(String, String)>,
+    Query(query): Query<ManifestQuery>,
+    headers: HeaderMap,
 ) -> Result<Response> {
     info!(
         "GET manifest request: repository={}, reference={}",
         repository, reference
     );
+    let started = Instant::now();
 
-    check_repository_access(&claims, &repository)?;
+    let repository = repository_name(&state, &repository)?;
+    // Tags cannot contain `:`, so anything with one must be a digest. No
+    // manifest can exist under a tag outside the tag grammar.
+    if reference.contains(':') {
+        validate_digest(&reference)?;
+    } else if !is_valid_tag(&reference) {
+        return Err(ProxyError::ManifestUnknown(reference));
+    }
+    authorize(&state, &claims, &repository)?;
 
-    let resolved = state
-        .config
-        .resolve_repository(&repository)
-        .ok_or_else(|| ProxyError::NotFound(format!("Repository not mapped: {}", repository)))?;
+    let resolved = if is_digest_reference(&reference) {
+        resolve_digest_request(&state, &claims, &repository)?
+    } else {
+        Some(resolve(&state, &claims, &repository)?)
+    };
+
+    let platform = requested_platform(&state, query.platform.as_deref(), &headers)?;
+    let cache_reference = match &platform {
+        Some(platform) => platform_reference(&reference, &platform.to_string()),
+        None => reference.clone(),
+    };
+
+    // Platform resolution needs the manifest list, which the client's own
+    // `Accept` excludes, so the configured media types are sent instead.
+    let accept = match platform {
+        Some(_) => None,
+        None => client_accept(&headers),
+    };
+
+    let manifests = state.cache.manifests();
+    let directive = cache_directive(&state, &claims, &headers);
+    if directive == CacheDirective::Default {
+        // A manifest pinned by digest never changes, so the TTL only applies
+        // to tags.
+        let cached = if is_digest_reference(&reference) {
+            manifests.get_stale(&repository, &cache_reference)?
+        } else {
+            manifests.get(&repository, &cache_reference)?
+        };
+        let cached =
+            cached.filter(|cached| platform.is_some() || accepts(&headers, &cached.content_type));
+        if let Some(cached) = cached {
+            debug!("Serving manifest {}/{} from cache", repository, reference);
+            state.pull_latency.record(
+                resolved.as_ref(),
+                PullKind::Manifest,
+                CacheOutcome::Hit,
+                started.elapsed(),
+            );
+            if let Some(resolved) = &resolved {
+                prefetch(&state, resolved, directive, &cached.data);
+            }
+            return Ok(with_outcome(
+                manifest_response(&cached.content_type, Bytes::from(cached.data), &headers),
+                CacheOutcome::Hit,
+            ));
+        }
+    }
+
+    let resolved = resolved.ok_or_else(|| ProxyError::NameUnknown(repository.clone()))?;
+
+    let missing = state.cache.missing_manifests();
+    if directive == CacheDirective::Default && missing.contains(&repository, &reference) {
+        debug!("Manifest {}/{} is negatively cached", repository, reference);
+        return Err(ProxyError::ManifestUnknown(reference));
+    }
 
-    let (manifest_data, content_type) = state.upstream.get_manifest(&resolved, &reference).await?;
+    let (mut manifest_data, mut content_type) = match state
+        .upstream
+        .get_manifest_accepting(&resolved, &reference, accept.as_deref())
+        .await
+    {
+        Ok(fetched) => fetched,
+        Err(e @ ProxyError::ManifestUnknown(_)) => {
+            missing.record_miss(&repository, &reference);
+            return Err(e);
+        }
+        Err(e) if may_serve_stale(&state, directive, &e) => {
+            let stale = manifests
+                .get_stale(&repository, &cache_reference)?
+                .filter(|cached| platform.is_some() || accepts(&headers, &cached.content_type));
+            let Some(cached) = stale else {
+                return Err(e);
+            };
+            warn!(
+                "Serving stale manifest {}/{} from cache: {}",
+                repository, reference, e
+            );
+            state.pull_latency.record(
+                Some(&resolved),
+                PullKind::Manifest,
+                CacheOutcome::Hit,
+                started.elapsed(),
+            );
+            let mut response =
+                manifest_response(&cached.content_type, Bytes::from(cached.data), &headers);
+            response
+                .headers_mut()
+                .insert(header::WARNING, HeaderValue::from_static(STALE_WARNING));
+            return Ok(with_outcome(response, CacheOutcome::Hit));
+        }
+        Err(e) => return Err(e),
+    };
+    verify_manifest_digest(&reference, &manifest_data)?;
+    // A tag upstream reported missing has appeared since, so cached tag
+    // lists of the repository no longer hold.
+    if missing.forget(&repository, Some(&reference)) && !is_digest_reference(&reference) {
+        state.cache.tags().purge(&repository, None)?;
+    }
+
+    if let Some(platform) = platform.filter(|_| is_index(&content_type)) {
+        let digest = select_platform(
+            &manifest_data,
+            &platform,
+            &state.config.upstream.manifest_media_types,
+        )
+        .ok_or_else(|| {
+            ProxyError::ManifestUnknown(format!("{} for platform {}", reference, platform))
+        })?;
+        debug!(
+            "Resolved {}/{} for {} to {}",
+            repository, reference, platform, digest
+        );
+        (manifest_data, content_type) = state.upstream.get_manifest(&resolved, &digest).await?;
+        verify_manifest_digest(&digest, &manifest_data)?;
+    }
 
     debug!(
         "Retrieved manifest for {}/{}: {} bytes",
@@ -49,56 +464,550 @@ pub async fn handle_get_manifest(
         reference,
         manifest_data.len()
     );
+    if !resolved.cache_policy.no_cache && directive != CacheDirective::NoStore {
+        let cached = manifests
+            .put(&repository, &cache_reference, &content_type, &manifest_data)
+            .and_then(|()| {
+                state
+                    .cache
+                    .known_layers()
+                    .record(&repository, &manifest_data)
+            });
+        if let Err(e) = cached {
+            warn!(
+                "Failed to cache manifest {}/{}: {}",
+                repository, reference, e
+            );
+        }
+    }
+    state.pull_latency.record(
+        Some(&resolved),
+        PullKind::Manifest,
+        CacheOutcome::Miss,
+        started.elapsed(),
+    );
+    prefetch(&state, &resolved, directive, &manifest_data);
+
+    Ok(with_outcome(
+        manifest_response(&content_type, manifest_data, &headers),
+        CacheOutcome::Miss,
+    ))
+}
+
+/// Warms the cache with the blobs `manifest` references when layer
+/// prefetching is on and the blobs would be cached when pulled.
+fn prefetch(
+    state: &Arc<RegistryState>,
+    resolved: &ResolvedRepository,
+    directive: CacheDirective,
+    manifest: &[u8],
+) {
+    if !state.prefetcher.is_enabled()
+        || resolved.cache_policy.no_cache
+        || resolved.redirect_blobs
+        || directive != CacheDirective::Default
+    {
+        return;
+    }
+    prefetch_blobs(state, resolved.clone(), blob_digests(manifest));
+}
+
+/// Platform to resolve manifest lists to: the `platform` query parameter, or
+/// the configured default when the client does not accept lists.
+fn requested_platform(
+    state: &RegistryState,
+    query: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<Option<Platform>> {
+    if let Some(platform) = query {
+        return platform.parse().map(Some).map_err(ProxyError::BadRequest);
+    }
+    let Some(default) = &state.config.upstream.default_platform else {
+        return Ok(None);
+    };
+    let accepts_lists =
+        accepted_media_types(headers).any(|media_type| is_index(media_type) || media_type == "*/*");
+    if accepts_lists || headers.get(header::ACCEPT).is_none() {
+        return Ok(None);
+    }
+    // Validated at startup.
+    Ok(default.parse().ok())
+}
+
+/// Media types listed in the request's `Accept` headers, without parameters.
+fn accepted_media_types(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+}
+
+/// The client's `Accept` headers combined into one value, if it sent any.
+fn client_accept(headers: &HeaderMap) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter(|value| !value.trim().is_empty())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// Whether a manifest of `content_type` is acceptable to the client, so a
+/// cached manifest negotiated for another client is not served to one that
+/// cannot use it.
+fn accepts(headers: &HeaderMap, content_type: &str) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or("").trim();
+    client_accept(headers).is_none()
+        || accepted_media_types(headers)
+            .any(|media_type| media_type == "*/*" || media_type == content_type)
+}
+
+/// Whether a failed upstream fetch may be answered from a cached copy the
+/// request would otherwise not use: always while the registry's circuit is
+/// open, and after other upstream failures with `cache.serve_stale_on_error`.
+fn may_serve_stale(state: &RegistryState, directive: CacheDirective, error: &ProxyError) -> bool {
+    if directive == CacheDirective::NoStore {
+        return false;
+    }
+    match error {
+        ProxyError::CircuitOpen { .. } => true,
+        ProxyError::Upstream(_)
+        | ProxyError::UpstreamStatus { .. }
+        | ProxyError::GatewayTimeout(_)
+        | ProxyError::RateLimited(_) => state.config.cache.serve_stale_on_error,
+        _ => false,
+    }
+}
 
-    Ok(Response::builder()
+fn cached_blob_response(data: Bytes) -> Response {
+    Response::builder()
         .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, data.len())
+        .body(Body::from(data))
+        .unwrap()
+}
+
+/// Marks a pull response as served from the cache or upstream, for the
+/// access log.
+fn with_outcome(mut response: Response, outcome: CacheOutcome) -> Response {
+    response.extensions_mut().insert(outcome);
+    response
+}
+
+/// Adds the API version header to every response, including errors raised
+/// by the outer layers, so no handler has to remember it.
+pub async fn api_version_middleware(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(API_VERSION_HEADER, HeaderValue::from_static(API_VERSION));
+    response
+}
+
+/// Percent-encodes the `/`s of a multi-segment repository name, so that
+/// `/v2/library/alpine/manifests/latest` matches the single-segment
+/// `:repository` routes. `Path` decodes the name again.
+pub async fn encode_nested_repository(mut request: Request) -> Request {
+    let path = request.uri().path();
+    let Some(repository) = repository_in_path(path).filter(|name| name.contains('/')) else {
+        return request;
+    };
+    let rest = &path["/v2/".len() + repository.len()..];
+    let mut encoded = format!("/v2/{}{}", repository.replace('/', "%2F"), rest);
+    if let Some(query) = request.uri().query() {
+        encoded.push('?');
+        encoded.push_str(query);
+    }
+
+    let mut parts = request.uri().clone().into_parts();
+    let Ok(path_and_query) = PathAndQuery::try_from(encoded) else {
+        return request;
+    };
+    parts.path_and_query = Some(path_and_query);
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+    request
+}
+
+/// Adds `X-Cache` to pull responses: `HIT` or `MISS` as recorded by the
+/// handler, or `REVALIDATED` when a conditional request was answered with
+/// `304 Not Modified`.
+pub async fn cache_header_middleware(
+    State(state): State<Arc<RegistryState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if !state.config.server.emit_cache_header {
+        return response;
+    }
+    let value = match response.extensions().get::<CacheOutcome>() {
+        Some(_) if response.status() == StatusCode::NOT_MODIFIED => "REVALIDATED",
+        Some(CacheOutcome::Hit) => "HIT",
+        Some(CacheOutcome::Miss) => "MISS",
+        None => return response,
+    };
+    response
+        .headers_mut()
+        .insert("X-Cache", HeaderValue::from_static(value));
+    response
+}
+
+/// Manifest response tagged with the manifest's digest as its `ETag`. A
+/// request whose `If-None-Match` already names that digest gets an empty
+/// `304 Not Modified`.
+fn manifest_response(content_type: &str, data: Bytes, headers: &HeaderMap) -> Response {
+    let etag = format!("\"sha256:{}\"", hex::encode(Sha256::digest(&data)));
+    let builder = Response::builder()
         .header(header::CONTENT_TYPE, content_type)
-        .header(header::CONTENT_LENGTH, manifest_data.len())
-        .body(Body::from(manifest_data))
-        .unwrap())
+        .header(header::ETAG, &etag);
+    if if_none_match(headers, &etag) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+    builder
+        .status(StatusCode::OK)
+        .header(header::CONTENT_LENGTH, data.len())
+        .body(Body::from(data))
+        .unwrap()
+}
+
+/// Whether any `If-None-Match` entry matches `etag`, compared weakly as
+/// RFC 9110 requires.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
 }
 
 pub async fn handle_get_blob(
     State(state): State<Arc<RegistryState>>,
     Extension(claims): Extension<Claims>,
     Path((repository, digest)): Path<(String, String)>,
+    headers: HeaderMap,
 ) -> Result<Response> {
     info!(
         "GET blob request: repository={}, digest={}",
         repository, digest
     );
+    let started = Instant::now();
 
-    check_repository_access(&claims, &repository)?;
+    let repository = repository_name(&state, &repository)?;
+    validate_digest(&digest)?;
+    authorize(&state, &claims, &repository)?;
 
-    let resolved = state
-        .config
-        .resolve_repository(&repository)
-        .ok_or_else(|| ProxyError::NotFound(format!("Repository not mapped: {}", repository)))?;
+    let resolved = resolve_digest_request(&state, &claims, &repository)?;
+
+    let directive = cache_directive(&state, &claims, &headers);
+    let cached = match directive {
+        CacheDirective::Default => state.cache.get(&digest).await?,
+        _ => {
+            debug!("Bypassing cache for blob {}: {:?}", digest, directive);
+            None
+        }
+    };
 
-    if let Some(cached_data) = state.cache.get(&digest).await? {
+    if let Some(cached_data) = cached {
         debug!("Serving blob {} from cache", digest);
-        return Ok(Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "application/octet-stream")
-            .header(header::CONTENT_LENGTH, cached_data.len())
-            .body(Body::from(cached_data))
-            .unwrap());
+        state.prefetcher.record_hit(&digest);
+        state.pull_latency.record(
+            resolved.as_ref(),
+            PullKind::Blob,
+            CacheOutcome::Hit,
+            started.elapsed(),
+        );
+        return Ok(with_outcome(
+            cached_blob_response(cached_data),
+            CacheOutcome::Hit,
+        ));
     }
 
+    let resolved = resolved.ok_or_else(|| ProxyError::NameUnknown(repository.clone()))?;
+
     debug!("Cache miss for blob {}, fetching from upstream", digest);
 
-    let blob_data = state.upstream.get_blob(&resolved, &digest).await?;
+    let fetched = if resolved.redirect_blobs {
+        match state.upstream.locate_blob(&resolved, &digest).await {
+            Ok(BlobLocation::Redirect(location)) => {
+                debug!("Redirecting blob {} to {}", digest, location);
+                state.pull_latency.record(
+                    Some(&resolved),
+                    PullKind::Blob,
+                    CacheOutcome::Miss,
+                    started.elapsed(),
+                );
+                let response = Response::builder()
+                    .status(StatusCode::TEMPORARY_REDIRECT)
+                    .header(header::LOCATION, location)
+                    .body(Body::empty())
+                    .unwrap();
+                return Ok(with_outcome(response, CacheOutcome::Miss));
+            }
+            // Upstream serves the blob itself, so it is streamed as usual.
+            Ok(BlobLocation::Served(blob)) => Ok(blob),
+            Err(e) => Err(e),
+        }
+    } else {
+        state.upstream.get_blob(&resolved, &digest).await
+    };
 
-    if let Err(e) = state.cache.put(&digest, blob_data.clone()).await {
-        tracing::warn!("Failed to cache blob {}: {}", digest, e);
-    }
+    let upstream_response = match fetched {
+        Ok(response) => response,
+        // Only a request bypassing the cache can have missed a cached copy.
+        Err(e)
+            if directive == CacheDirective::NoCache && may_serve_stale(&state, directive, &e) =>
+        {
+            let Some(cached_data) = state.cache.get(&digest).await? else {
+                return Err(e);
+            };
+            warn!("Serving cached blob {} despite no-cache: {}", digest, e);
+            state.pull_latency.record(
+                Some(&resolved),
+                PullKind::Blob,
+                CacheOutcome::Hit,
+                started.elapsed(),
+            );
+            let mut response = cached_blob_response(cached_data);
+            response
+                .headers_mut()
+                .insert(header::WARNING, HeaderValue::from_static(STALE_WARNING));
+            return Ok(with_outcome(response, CacheOutcome::Hit));
+        }
+        Err(e) => return Err(e),
+    };
+    let content_length = upstream_response.content_length();
+    let upstream_body = verify_digest(digest.clone(), upstream_response.bytes_stream());
 
-    Ok(Response::builder()
+    let policy = &resolved.cache_policy;
+    let body = if policy.no_cache || directive == CacheDirective::NoStore {
+        debug!("Not caching blob {} for {}", digest, repository);
+        Body::from_stream(record_when_finished(
+            state.clone(),
+            resolved.clone(),
+            started,
+            upstream_body,
+        ))
+    } else if content_length.is_some_and(|length| !state.cache.caches_size(length)) {
+        debug!(
+            "Not caching blob {}: {} bytes is outside the cacheable size range",
+            digest,
+            content_length.unwrap_or_default()
+        );
+        Body::from_stream(record_when_finished(
+            state.clone(),
+            resolved.clone(),
+            started,
+            upstream_body,
+        ))
+    } else {
+        let (client, client_body) = mpsc::channel(16);
+        tokio::spawn(
+            stream_into_cache(
+                state.cache.clone(),
+                digest.clone(),
+                policy.max_age_seconds,
+                Box::pin(upstream_body),
+                client,
+            )
+            .in_current_span(),
+        );
+        Body::from_stream(record_when_finished(
+            state.clone(),
+            resolved.clone(),
+            started,
+            client_body,
+        ))
+    };
+
+    let mut response = Response::builder()
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
-        .header(header::CONTENT_LENGTH, blob_data.len())
-        .body(Body::from(blob_data))
-        .unwrap())
+        .header(header::CONTENT_TYPE, "application/octet-stream");
+    if let Some(length) = content_length {
+        response = response.header(header::CONTENT_LENGTH, length);
+    }
+    Ok(with_outcome(
+        response.body(body).unwrap(),
+        CacheOutcome::Miss,
+    ))
+}
+
+/// Records a blob cache miss once `body` has been fully sent, so the latency
+/// covers the whole transfer. Transfers the client abandons are not recorded.
+fn record_when_finished<T>(
+    state: Arc<RegistryState>,
+    resolved: ResolvedRepository,
+    started: Instant,
+    body: impl Stream<Item = T>,
+) -> impl Stream<Item = T> {
+    let finished = futures::stream::once(async move {
+        state.pull_latency.record(
+            Some(&resolved),
+            PullKind::Blob,
+            CacheOutcome::Miss,
+            started.elapsed(),
+        );
+    })
+    .filter_map(|()| async { None });
+    body.chain(finished)
+}
+
+/// Passes an upstream blob through while hashing it. The last chunk is held
+/// back until the digest has been checked, and replaced by an error if it
+/// does not match, so a client never receives a complete corrupted blob.
+fn verify_digest(
+    digest: String,
+    upstream: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static {
+    let hasher = DigestHasher::for_digest(&digest);
+    let state = (Box::pin(upstream), hasher, None::<Bytes>);
+    futures::stream::unfold(Some(state), move |state| {
+        let digest = digest.clone();
+        async move {
+            let (mut upstream, mut hasher, mut held) = state?;
+            loop {
+                match upstream.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(&chunk);
+                        }
+                        if let Some(previous) = held.replace(chunk) {
+                            return Some((Ok(previous), Some((upstream, hasher, held))));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(std::io::Error::other(e)), None)),
+                    None => {
+                        if hasher.is_some_and(|hasher| !hasher.matches(&digest)) {
+                            warn!("Upstream blob does not match digest {}", digest);
+                            let error = std::io::Error::other(format!(
+                                "upstream blob does not match digest {}",
+                                digest
+                            ));
+                            return Some((Err(error), None));
+                        }
+                        return held.map(|last| (Ok(last), None));
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Where a streamed blob is written alongside the client response.
+enum BlobSink {
+    Writer(CacheWriter),
+    /// The cache could not be opened for writing; the blob is buffered (up to
+    /// the write holdback limit) and handed to the retrying writer instead.
+    Buffer(DigestHasher, BytesMut),
+    Discard,
+}
+
+/// Forwards an upstream blob to the client while writing it to the cache and
+/// hashing it. The cache entry is only committed once the digest verifies.
+/// The transfer runs to completion even if the client goes away, so the
+/// cache is still filled. The client's body only ends once the cache write
+/// has been committed or rejected. Blobs whose size upstream did not announce
+/// are checked against the cacheable size range as they arrive.
+async fn stream_into_cache(
+    cache: Arc<BlobCache>,
+    digest: String,
+    max_age_seconds: Option<u64>,
+    mut upstream: impl Stream<Item = std::io::Result<Bytes>> + Unpin,
+    mut client: mpsc::Sender<std::io::Result<Bytes>>,
+) {
+    let mut sink = match cache.writer(&digest, max_age_seconds).await {
+        Ok(Some(writer)) => BlobSink::Writer(writer),
+        Ok(None) => {
+            debug!("Blob {} is already being cached by another pull", digest);
+            BlobSink::Discard
+        }
+        Err(e) => match DigestHasher::for_digest(&digest) {
+            Some(hasher) => {
+                warn!("Cannot stream blob {} into cache: {}", digest, e);
+                BlobSink::Buffer(hasher, BytesMut::new())
+            }
+            None => {
+                warn!("Not caching blob {}: {}", digest, e);
+                BlobSink::Discard
+            }
+        },
+    };
+
+    let mut received = 0u64;
+    while let Some(chunk) = upstream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("Upstream transfer of blob {} failed: {}", digest, e);
+                let _ = client.send(Err(e)).await;
+                return;
+            }
+        };
+
+        received += chunk.len() as u64;
+        if cache.too_large_to_cache(received) && !matches!(sink, BlobSink::Discard) {
+            debug!(
+                "Not caching blob {}: larger than max_cacheable_bytes",
+                digest
+            );
+            sink = BlobSink::Discard;
+        }
+
+        match &mut sink {
+            BlobSink::Writer(writer) => {
+                if let Err(e) = writer.write(&chunk).await {
+                    warn!("Abandoning cache write for blob {}: {}", digest, e);
+                    sink = BlobSink::Discard;
+                }
+            }
+            BlobSink::Buffer(hasher, buffer) => {
+                if (buffer.len() + chunk.len()) as u64 > cache.write_holdback_bytes() {
+                    warn!("Not caching blob {}: too large to hold back", digest);
+                    sink = BlobSink::Discard;
+                } else {
+                    hasher.update(&chunk);
+                    buffer.extend_from_slice(&chunk);
+                }
+            }
+            BlobSink::Discard => {}
+        }
+
+        // A closed channel only means the client went away.
+        let _ = client.send(Ok(chunk)).await;
+    }
+
+    if !matches!(sink, BlobSink::Discard) && !cache.caches_size(received) {
+        debug!("Not caching blob {}: smaller than min_blob_bytes", digest);
+        return;
+    }
+
+    match sink {
+        BlobSink::Writer(writer) => {
+            if let Err(e) = writer.commit().await {
+                warn!("Failed to cache blob {}: {}", digest, e);
+            }
+        }
+        BlobSink::Buffer(hasher, buffer) => {
+            if hasher.matches(&digest) {
+                cache
+                    .put_with_retry(&digest, buffer.freeze(), max_age_seconds)
+                    .await;
+            } else {
+                warn!("Not caching blob {}: digest mismatch", digest);
+            }
+        }
+        BlobSink::Discard => {}
+    }
 }
 
 pub async fn handle_head_blob(
@@ -111,54 +1020,227 @@ pub async fn handle_head_blob(
         repository, digest
     );
 
-    check_repository_access(&claims, &repository)?;
+    let repository = repository_name(&state, &repository)?;
+    validate_digest(&digest)?;
+    authorize(&state, &claims, &repository)?;
 
-    let resolved = state
-        .config
-        .resolve_repository(&repository)
-        .ok_or_else(|| ProxyError::NotFound(format!("Repository not mapped: {}", repository)))?;
+    let resolved = resolve_digest_request(&state, &claims, &repository)?;
 
-    if let Some(cached_data) = state.cache.get(&digest).await? {
-        debug!("Blob {} found in cache", digest);
+    // A fresh cached manifest referencing the blob shows that it exists, and
+    // a cache entry records its size, so neither needs the blob to be read.
+    let known = match state.cache.known_layers().size(&repository, &digest)? {
+        Some(size) => {
+            debug!("Blob {} known from a cached manifest", digest);
+            Some(size)
+        }
+        None => state.cache.size(&digest)?.inspect(|_| {
+            debug!("Blob {} found in cache", digest);
+        }),
+    };
+    if let Some(size) = known {
         return Ok(Response::builder()
             .status(StatusCode::OK)
             .header(header::CONTENT_TYPE, "application/octet-stream")
-            .header(header::CONTENT_LENGTH, cached_data.len())
+            .header(header::CONTENT_LENGTH, size)
             .body(Body::empty())
             .unwrap());
     }
 
-    let blob_data = state.upstream.get_blob(&resolved, &digest).await?;
-
-    Ok(Response::builder()
+    // Answered without a length when upstream does not state one, rather
+    // than downloading the blob to count it. The body must be of unknown
+    // size, or the router would announce a length of zero.
+    let resolved = resolved.ok_or_else(|| ProxyError::NameUnknown(repository.clone()))?;
+    let response = Response::builder()
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
-        .header(header::CONTENT_LENGTH, blob_data.len())
-        .body(Body::empty())
-        .unwrap())
+        .header(header::CONTENT_TYPE, "application/octet-stream");
+    let response = match state.upstream.head_blob(&resolved, &digest).await? {
+        Some(length) => response
+            .header(header::CONTENT_LENGTH, length)
+            .body(Body::empty()),
+        None => response.body(Body::from_stream(futures::stream::empty::<
+            std::io::Result<Bytes>,
+        >())),
+    };
+    Ok(response.unwrap())
+}
+
+/// Pagination parameters of the tag list endpoint.
+#[derive(Debug, Default, Deserialize)]
+pub struct TagsQuery {
+    n: Option<u32>,
+    last: Option<String>,
 }
 
 pub async fn handle_get_tags(
     State(state): State<Arc<RegistryState>>,
     Extension(claims): Extension<Claims>,
     Path(repository): Path<String>,
+    Query(query): Query<TagsQuery>,
+    headers: HeaderMap,
 ) -> Result<Response> {
     info!("GET tags request: repository={}", repository);
 
-    check_repository_access(&claims, &repository)?;
+    let repository = repository_name(&state, &repository)?;
+    authorize(&state, &claims, &repository)?;
 
-    let resolved = state
-        .config
-        .resolve_repository(&repository)
-        .ok_or_else(|| ProxyError::NotFound(format!("Repository not mapped: {}", repository)))?;
+    let resolved = resolve(&state, &claims, &repository)?;
 
-    let tags_data = state.upstream.get_tags(&resolved).await?;
+    // Each page is cached separately. The key cannot collide with another
+    // repository's, as names cannot contain `:`.
+    let cache_reference = format!(
+        "list?n={}&last={}",
+        query.n.map(|n| n.to_string()).unwrap_or_default(),
+        query.last.as_deref().unwrap_or_default()
+    );
+    let tags = state.cache.tags();
+    let directive = cache_directive(&state, &claims, &headers);
+    if directive == CacheDirective::Default {
+        if let Some(cached) = tags.get(&repository, &cache_reference)? {
+            debug!("Serving tag list of {} from cache", repository);
+            return Ok(with_outcome(
+                tags_response(Bytes::from(cached.data)),
+                CacheOutcome::Hit,
+            ));
+        }
+    }
+
+    let page = state
+        .upstream
+        .get_tags(&resolved, query.n, query.last.as_deref())
+        .await?
+        .ok_or_else(|| ProxyError::NameUnknown(repository.clone()))?;
+    let next = page
+        .next
+        .map(|query| format!("</v2/{}/tags/list?{}>; rel=\"next\"", repository, query));
+
+    // The cache keeps only the body, so pages continuing on another one are
+    // not cached.
+    if next.is_none() && !resolved.cache_policy.no_cache && directive != CacheDirective::NoStore {
+        if let Err(e) = tags.put(
+            &repository,
+            &cache_reference,
+            "application/json",
+            &page.data,
+        ) {
+            warn!("Failed to cache tag list of {}: {}", repository, e);
+        }
+    }
+
+    let mut response = tags_response(page.data);
+    if let Some(next) = next.and_then(|next| HeaderValue::from_str(&next).ok()) {
+        response.headers_mut().insert(header::LINK, next);
+    }
+    Ok(with_outcome(response, CacheOutcome::Miss))
+}
 
-    Ok(Response::builder()
+fn tags_response(data: Bytes) -> Response {
+    Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_TYPE, "application/json")
-        .body(Body::from(tags_data))
-        .unwrap())
+        .body(Body::from(data))
+        .unwrap()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReferrersQuery {
+    #[serde(rename = "artifactType")]
+    artifact_type: Option<String>,
+}
+
+const OCI_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+/// Lists the manifests (signatures, SBOMs, ...) that refer to `digest`. For
+/// upstreams without the referrers API, falls back to the index stored under
+/// the `sha256-<hex>` tag, as the tag schema of the distribution spec does.
+pub async fn handle_get_referrers(
+    State(state): State<Arc<RegistryState>>,
+    Extension(claims): Extension<Claims>,
+    Path((repository, digest)): Path<(String, String)>,
+    Query(query): Query<ReferrersQuery>,
+) -> Result<Response> {
+    info!(
+        "GET referrers request: repository={}, digest={}",
+        repository, digest
+    );
+
+    let repository = repository_name(&state, &repository)?;
+    validate_digest(&digest)?;
+    authorize(&state, &claims, &repository)?;
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .expect("validated digests contain a colon");
+    let resolved = resolve(&state, &claims, &repository)?;
+
+    let artifact_type = query.artifact_type.as_deref();
+    let cache_reference = match artifact_type {
+        Some(artifact_type) => format!("{}?artifactType={}", digest, artifact_type),
+        None => digest.clone(),
+    };
+    let referrers = state.cache.referrers();
+    let index = match referrers.get(&repository, &cache_reference)? {
+        Some(cached) => Bytes::from(cached.data),
+        None => {
+            let index = match state
+                .upstream
+                .get_referrers(&resolved, &digest, artifact_type)
+                .await?
+            {
+                Some(index) => index,
+                None => {
+                    let tag = format!("{}-{}", algorithm, hex);
+                    debug!(
+                        "Upstream has no referrers API; reading {}:{}",
+                        repository, tag
+                    );
+                    match state.upstream.get_manifest(&resolved, &tag).await {
+                        Ok((index, _)) => index,
+                        Err(ProxyError::ManifestUnknown(_)) => Bytes::new(),
+                        Err(e) => return Err(e),
+                    }
+                }
+            };
+            let index = referrers_index(&index, artifact_type);
+            if !resolved.cache_policy.no_cache {
+                if let Err(e) =
+                    referrers.put(&repository, &cache_reference, OCI_INDEX_MEDIA_TYPE, &index)
+                {
+                    warn!(
+                        "Failed to cache referrers of {}/{}: {}",
+                        repository, digest, e
+                    );
+                }
+            }
+            index
+        }
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, OCI_INDEX_MEDIA_TYPE);
+    if artifact_type.is_some() {
+        response = response.header("OCI-Filters-Applied", "artifactType");
+    }
+    Ok(response.body(Body::from(index)).unwrap())
+}
+
+/// Normalizes an upstream referrers or tag-schema index into an OCI image
+/// index, keeping only descriptors of `artifact_type` when given. Missing or
+/// malformed indexes yield an empty one.
+fn referrers_index(upstream: &[u8], artifact_type: Option<&str>) -> Bytes {
+    let mut manifests: Vec<serde_json::Value> =
+        serde_json::from_slice::<serde_json::Value>(upstream)
+            .ok()
+            .and_then(|index| index["manifests"].as_array().cloned())
+            .unwrap_or_default();
+    if let Some(artifact_type) = artifact_type {
+        manifests.retain(|descriptor| descriptor["artifactType"] == artifact_type);
+    }
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_INDEX_MEDIA_TYPE,
+        "manifests": manifests,
+    });
+    Bytes::from(index.to_string())
 }
 
 pub async fn handle_unsupported_write() -> Result<Response> {
@@ -171,12 +1253,1641 @@ pub async fn handle_unsupported_write() -> Result<Response> {
 mod tests {
     use super::*;
     use crate::auth::AccessLevel;
+    use crate::config::UpstreamAuth;
+    use crate::test_support::{
+        admin_claims, alpine_registry, alpine_state, blob_upstream, spawn_upstream, test_state,
+        token_upstream,
+    };
+    use base64::Engine;
+
+    /// The digest of `b"layer"`.
+    const DIGEST: &str = "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+
+    async fn pull_blob(state: &Arc<RegistryState>, repository: &str) -> Response {
+        handle_get_blob(
+            State(state.clone()),
+            Extension(admin_claims()),
+            Path((repository.to_string(), DIGEST.to_string())),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_blob_cached_via_one_repository_served_to_another() {
+        // Only `library/alpine` has the blob upstream.
+        let upstream = spawn_upstream(blob_upstream(DIGEST, b"layer")).await;
+        let (state, _temp) = test_state(&format!(
+            r#"{}
+[[repositories]]
+name = "app"
+registry_id = "hub"
+upstream_name = "team/app"
+"#,
+            alpine_registry(&upstream)
+        ))
+        .await;
+
+        let response = pull_blob(&state, "alpine").await;
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        let pull_as = |claims: Claims| {
+            handle_get_blob(
+                State(state.clone()),
+                Extension(claims),
+                Path(("app".to_string(), DIGEST.to_string())),
+                HeaderMap::new(),
+            )
+        };
+        let response = pull_as(crate::test_support::repo_claims(&["app"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.extensions().get::<CacheOutcome>(),
+            Some(&CacheOutcome::Hit)
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+
+        // Access is still checked against the repository being pulled.
+        assert!(matches!(
+            pull_as(crate::test_support::repo_claims(&["alpine"])).await,
+            Err(ProxyError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_claim_credentials_override_registry_auth() {
+        let (router, token_requests) = token_upstream();
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = test_state(&format!(
+            r#"{}
+[registries.auth]
+username = "robot"
+password = "robot-password"
+"#,
+            alpine_registry(&upstream)
+        ))
+        .await;
+
+        let pull = |claims: Claims| {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(claims),
+                Path(("alpine".to_string(), "latest".to_string())),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+
+        let mut claims = admin_claims();
+        claims.upstream_auth = Some(UpstreamAuth {
+            username: "alice".to_string(),
+            password: "alice-pat".to_string(),
+            refresh_token: None,
+            client_id: None,
+        });
+        assert_eq!(pull(claims).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(pull(admin_claims()).await.unwrap().status(), StatusCode::OK);
+
+        let basic = |credentials: &str| {
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        };
+        assert_eq!(
+            *token_requests.lock().unwrap(),
+            vec![basic("alice:alice-pat"), basic("robot:robot-password")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_registry_auth_is_isolated() {
+        let (router, _) = token_upstream();
+        let healthy = spawn_upstream(router).await;
+        let rejecting = axum::Router::new()
+            .route(
+                "/token",
+                axum::routing::get(|| async { StatusCode::UNAUTHORIZED }),
+            )
+            .route(
+                "/v2/library/alpine/manifests/:reference",
+                axum::routing::get(|headers: axum::http::HeaderMap| async move {
+                    let challenge = format!(
+                        r#"Bearer realm="http://{}/token",service="test""#,
+                        headers[header::HOST].to_str().unwrap()
+                    );
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge)],
+                    )
+                }),
+            );
+        let broken = spawn_upstream(rejecting).await;
+        let (state, _temp) = test_state(&format!(
+            r#"
+[[registries]]
+id = "healthy"
+url = "{healthy}"
+allow_http = true
+
+[[registries]]
+id = "broken"
+url = "{broken}"
+allow_http = true
+
+[registries.auth]
+username = "robot"
+password = "wrong-password"
+
+[[registries]]
+id = "idle"
+url = "{healthy}"
+allow_http = true
+
+[[repositories]]
+name = "good/*"
+registry_id = "healthy"
+upstream_name = "library/$1"
+
+[[repositories]]
+name = "bad/*"
+registry_id = "broken"
+upstream_name = "library/$1"
+"#
+        ))
+        .await;
+
+        let pull = |repository: &str| {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path((repository.to_string(), "latest".to_string())),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+
+        assert!(pull("bad/alpine").await.is_err());
+        assert_eq!(pull("good/alpine").await.unwrap().status(), StatusCode::OK);
+
+        let health = state.upstream.registry_health();
+        assert!(health["healthy"].healthy);
+        assert!(!health["broken"].healthy);
+        assert!(health["broken"]
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("Authentication failed"));
+
+        let response = handle_readiness(State(state.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let readiness: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            readiness["registries"],
+            json!({ "healthy": "healthy", "broken": "unhealthy", "idle": "unknown" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_permanent_digest_served_without_upstream() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(temp.path(), "");
+        config.cache.immutable_digest_permanent = true;
+        config.cache.manifest_ttl_seconds = 60;
+        let state = crate::test_support::state_from_config(config).await;
+        state
+            .cache
+            .put(DIGEST, Bytes::from_static(b"layer"), None)
+            .await
+            .unwrap();
+
+        let response = pull_blob(&state, "alpine").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+
+        // Manifests pinned by digest are permanent too, tags are not.
+        let manifest_digest = format!("sha256:{}", hex::encode(Sha256::digest(b"{}")));
+        let manifests = state.cache.manifests();
+        for reference in [manifest_digest.as_str(), "latest"] {
+            manifests
+                .put(
+                    "alpine",
+                    reference,
+                    "application/vnd.oci.image.manifest.v1+json",
+                    b"{}",
+                )
+                .unwrap();
+        }
+        let pull_manifest = |reference: &str| {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), reference.to_string())),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+        let response = pull_manifest(&manifest_digest).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(matches!(
+            pull_manifest("latest").await,
+            Err(ProxyError::NameUnknown(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_upstream_name_is_not_requested() {
+        let (state, _temp) = test_state(
+            r#"
+[[registries]]
+id = "hub"
+url = "https://registry-1.docker.io"
+
+[[repositories]]
+name = "proxy/*"
+registry_id = "hub"
+upstream_name = "library/$1"
+"#,
+        )
+        .await;
+
+        let result = handle_get_blob(
+            State(state),
+            Extension(admin_claims()),
+            Path(("proxy/.hidden".to_string(), DIGEST.to_string())),
+            HeaderMap::new(),
+        )
+        .await;
+        // Rejected as a client name before the mapping is applied.
+        assert!(matches!(result, Err(ProxyError::NameInvalid(_))));
+    }
+
+    #[test]
+    fn test_repository_names_follow_distribution_grammar() {
+        for name in [
+            "alpine",
+            "library/alpine",
+            "my-org/my--app",
+            "a/b/c/d",
+            "team_x/app__v2.1",
+            "0/9",
+        ] {
+            assert!(is_valid_repository_name(name), "{name} should be valid");
+        }
+        for name in [
+            "",
+            "../etc/passwd",
+            "library/../secret",
+            "library/./alpine",
+            "/alpine",
+            "alpine/",
+            "library//alpine",
+            "Library/Alpine",
+            "-alpine",
+            "alpine-",
+            "al___pine",
+            "al.-pine",
+            "al%2fpine",
+            "al pine",
+            "alpine:latest",
+        ] {
+            assert!(!is_valid_repository_name(name), "{name} should be invalid");
+        }
+        assert!(is_valid_repository_name(&"a".repeat(255)));
+        assert!(!is_valid_repository_name(&"a".repeat(256)));
+    }
+
+    #[tokio::test]
+    async fn test_cache_control_bypasses_cached_blob() {
+        // The cache disagrees with the upstream, showing where each response
+        // came from.
+        let upstream = spawn_upstream(blob_upstream(DIGEST, b"layer")).await;
+        let (state, _temp) = alpine_state(&upstream, |_| {}).await;
+        state
+            .cache
+            .put(DIGEST, Bytes::from_static(b"cached"), None)
+            .await
+            .unwrap();
+
+        let pull = |claims: Claims, cache_control: Option<&'static str>| {
+            let state = state.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                if let Some(value) = cache_control {
+                    headers.insert(header::CACHE_CONTROL, value.parse().unwrap());
+                }
+                let response = handle_get_blob(
+                    State(state),
+                    Extension(claims),
+                    Path(("alpine".to_string(), DIGEST.to_string())),
+                    headers,
+                )
+                .await
+                .unwrap();
+                axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(&pull(admin_claims(), None).await[..], b"cached");
+
+        // Restricted tokens may not bypass the cache by default.
+        let restricted = crate::test_support::repo_claims(&["alpine"]);
+        assert_eq!(&pull(restricted, Some("no-cache")).await[..], b"cached");
+
+        assert_eq!(
+            &pull(admin_claims(), Some("max-age=0, no-store")).await[..],
+            b"layer"
+        );
+        assert_eq!(&pull(admin_claims(), Some("no-cache")).await[..], b"layer");
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_reflect_configuration() {
+        let capabilities = |state| async move {
+            let response = handle_capabilities(State(state)).await.into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let (state, _temp) = test_state("").await;
+        let defaults = capabilities(state).await;
+        assert_eq!(defaults["auth"], json!(["bearer"]));
+        assert_eq!(defaults["cache_backend"], "filesystem");
+        assert_eq!(defaults["prefetch"], false);
+        assert_eq!(defaults["manifest_cache"], false);
+        assert_eq!(defaults["push"], false);
+        assert_eq!(defaults["cache_bypass"], "admin");
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(
+            temp.path(),
+            r#"
+[[users]]
+username = "ci"
+password_hash = "$2b$04$EGdrhbKUv8Oc9vGiXX0HQOxSg445d458Muh7DAHskb6QbtCvdxcie"
+access = { type = "all" }
+"#,
+        );
+        config.cache.manifest_ttl_seconds = 60;
+        config.cache.negative_ttl_seconds = 0;
+        config.cache.prefetch_layers = true;
+        config.server.allow_query_token = true;
+        let configured = capabilities(crate::test_support::state_from_config(config).await).await;
+        assert_eq!(
+            configured["auth"],
+            json!(["bearer", "token", "query_token"])
+        );
+        assert_eq!(configured["prefetch"], true);
+        assert_eq!(configured["manifest_cache"], true);
+        assert_eq!(configured["negative_cache"], false);
+    }
+
+    #[tokio::test]
+    async fn test_manifests_cached_for_ttl() {
+        let hits = Arc::new(std::sync::Mutex::new(0));
+        let counter = hits.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(move || {
+                *counter.lock().unwrap() += 1;
+                async {
+                    (
+                        [(
+                            header::CONTENT_TYPE,
+                            "application/vnd.oci.image.manifest.v1+json",
+                        )],
+                        "{}",
+                    )
+                }
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.manifest_ttl_seconds = 60;
+        })
+        .await;
+
+        let pull = |cache_control: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = cache_control {
+                headers.insert(header::CACHE_CONTROL, value.parse().unwrap());
+            }
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), "latest".to_string())),
+                Query(ManifestQuery::default()),
+                headers,
+            )
+        };
+
+        for _ in 0..2 {
+            let response = pull(None).await.unwrap();
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/vnd.oci.image.manifest.v1+json"
+            );
+        }
+        assert_eq!(*hits.lock().unwrap(), 1);
+
+        pull(Some("no-cache")).await.unwrap();
+        assert_eq!(*hits.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tag_lists_cached_per_page() {
+        let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = queries.clone();
+        let router = axum::Router::new()
+            .route(
+                "/v2/library/alpine/tags/list",
+                axum::routing::get(move |axum::extract::RawQuery(query)| {
+                    let query = query.unwrap_or_default();
+                    seen.lock().unwrap().push(query.clone());
+                    async move {
+                        let mut response =
+                            r#"{"name":"library/alpine","tags":["3.19","latest"]}"#.into_response();
+                        if query == "n=2" {
+                            response.headers_mut().insert(
+                                header::LINK,
+                                HeaderValue::from_static(
+                                    r#"</v2/library/alpine/tags/list?n=2&last=latest>; rel="next""#,
+                                ),
+                            );
+                        }
+                        response
+                    }
+                }),
+            )
+            .route(
+                "/v2/library/gone/tags/list",
+                axum::routing::get(|| async { StatusCode::NOT_FOUND }),
+            );
+        let upstream = spawn_upstream(router).await;
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(
+            temp.path(),
+            &format!(
+                r#"{}
+[[repositories]]
+name = "gone"
+registry_id = "hub"
+upstream_name = "library/gone"
+"#,
+                alpine_registry(&upstream)
+            ),
+        );
+        config.cache.tags_ttl_seconds = 60;
+        let state = crate::test_support::state_from_config(config).await;
+
+        let list = |n: Option<u32>, last: Option<&str>| {
+            handle_get_tags(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path("alpine".to_string()),
+                Query(TagsQuery {
+                    n,
+                    last: last.map(str::to_string),
+                }),
+                HeaderMap::new(),
+            )
+        };
+        let outcome = |response: &Response| response.extensions().get::<CacheOutcome>().copied();
+
+        assert_eq!(
+            outcome(&list(None, None).await.unwrap()),
+            Some(CacheOutcome::Miss)
+        );
+        let cached = list(None, None).await.unwrap();
+        assert_eq!(outcome(&cached), Some(CacheOutcome::Hit));
+        let body = axum::body::to_bytes(cached.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(std::str::from_utf8(&body).unwrap().contains("latest"));
+
+        // Pages are cached independently.
+        assert_eq!(
+            outcome(&list(Some(1), Some("3.19")).await.unwrap()),
+            Some(CacheOutcome::Miss)
+        );
+        assert_eq!(
+            outcome(&list(Some(1), Some("3.19")).await.unwrap()),
+            Some(CacheOutcome::Hit)
+        );
+        assert_eq!(*queries.lock().unwrap(), ["", "n=1&last=3.19"]);
+
+        // Purging the repository drops its tag lists.
+        state.cache.tags().purge("alpine", None).unwrap();
+        assert_eq!(
+            outcome(&list(None, None).await.unwrap()),
+            Some(CacheOutcome::Miss)
+        );
+        assert_eq!(queries.lock().unwrap().len(), 3);
+
+        // The next page link is passed on under the local name, and a page
+        // carrying one is not cached.
+        for _ in 0..2 {
+            let page = list(Some(2), None).await.unwrap();
+            assert_eq!(outcome(&page), Some(CacheOutcome::Miss));
+            assert_eq!(
+                page.headers()[header::LINK],
+                r#"</v2/alpine/tags/list?n=2&last=latest>; rel="next""#
+            );
+        }
+        assert_eq!(queries.lock().unwrap().len(), 5);
+
+        // Upstream errors are not cached as tag lists.
+        let gone = || {
+            handle_get_tags(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path("gone".to_string()),
+                Query(TagsQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+        assert!(matches!(gone().await, Err(ProxyError::NameUnknown(_))));
+        assert!(state
+            .cache
+            .tags()
+            .get("gone", "list?n=&last=")
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_digest_manifests_cached_beyond_ttl() {
+        let manifest_digest = format!("sha256:{}", hex::encode(Sha256::digest(b"{}")));
+        let hits = Arc::new(std::sync::Mutex::new(std::collections::HashMap::<
+            String,
+            usize,
+        >::new()));
+        let counter = hits.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/:reference",
+            axum::routing::get(move |Path(reference): Path<String>| {
+                *counter.lock().unwrap().entry(reference).or_default() += 1;
+                async {
+                    (
+                        [(
+                            header::CONTENT_TYPE,
+                            "application/vnd.oci.image.manifest.v1+json",
+                        )],
+                        "{}",
+                    )
+                }
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.manifest_ttl_seconds = 1;
+        })
+        .await;
+
+        let pull = |reference: String| {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), reference)),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+
+        for _ in 0..2 {
+            pull("latest".to_string()).await.unwrap();
+            pull(manifest_digest.clone()).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        }
+        let hits = hits.lock().unwrap();
+        assert_eq!(hits["latest"], 2);
+        assert_eq!(hits[&manifest_digest], 1);
+    }
+
+    #[tokio::test]
+    async fn test_digest_manifest_rejected_on_mismatch() {
+        let manifest_digest = format!("sha256:{}", "ab".repeat(32));
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/:reference",
+            axum::routing::get(|| async {
+                (
+                    [(
+                        header::CONTENT_TYPE,
+                        "application/vnd.oci.image.manifest.v1+json",
+                    )],
+                    "{}",
+                )
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |_| {}).await;
+
+        let result = handle_get_manifest(
+            State(state.clone()),
+            Extension(admin_claims()),
+            Path(("alpine".to_string(), manifest_digest.clone())),
+            Query(ManifestQuery::default()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(result, Err(ProxyError::Internal(_))));
+        assert!(state
+            .cache
+            .manifests()
+            .get_stale("alpine", &manifest_digest)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_manifest_served_while_circuit_open() {
+        let hits = Arc::new(std::sync::Mutex::new(0));
+        let counter = hits.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(move || {
+                let mut hits = counter.lock().unwrap();
+                *hits += 1;
+                let status = if *hits == 1 {
+                    StatusCode::OK
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                async move {
+                    (
+                        status,
+                        [(
+                            header::CONTENT_TYPE,
+                            "application/vnd.oci.image.manifest.v1+json",
+                        )],
+                        "{}",
+                    )
+                }
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.manifest_ttl_seconds = 1;
+            config.upstream.max_retries = 0;
+            config.upstream.circuit_failure_threshold = 1;
+        })
+        .await;
+
+        let pull = |reference: &str| {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), reference.to_string())),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+
+        pull("latest").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+
+        // The failure opens the circuit; later pulls are not sent upstream.
+        assert!(matches!(
+            pull("latest").await,
+            Err(ProxyError::UpstreamStatus { status: 500, .. })
+        ));
+        let response = pull("latest").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(matches!(
+            pull("edge").await,
+            Err(ProxyError::CircuitOpen { .. })
+        ));
+        assert_eq!(*hits.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_content_served_on_upstream_error() {
+        let healthy = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let status = {
+            let healthy = healthy.clone();
+            move || {
+                if healthy.load(std::sync::atomic::Ordering::SeqCst) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::BAD_GATEWAY
+                }
+            }
+        };
+        let blob_status = status.clone();
+        let router = axum::Router::new()
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(move || {
+                    let status = status();
+                    async move {
+                        (
+                            status,
+                            [(
+                                header::CONTENT_TYPE,
+                                "application/vnd.oci.image.manifest.v1+json",
+                            )],
+                            "{}",
+                        )
+                    }
+                }),
+            )
+            .route(
+                &format!("/v2/library/alpine/blobs/{}", DIGEST),
+                axum::routing::get(move || {
+                    let status = blob_status();
+                    async move { (status, "layer") }
+                }),
+            );
+        let upstream = spawn_upstream(router).await;
+
+        let state_with = |serve_stale_on_error: bool| {
+            let upstream = upstream.clone();
+            async move {
+                alpine_state(&upstream, |config| {
+                    config.cache.manifest_ttl_seconds = 1;
+                    config.cache.serve_stale_on_error = serve_stale_on_error;
+                    config.upstream.max_retries = 0;
+                    config.upstream.circuit_failure_threshold = 0;
+                })
+                .await
+            }
+        };
+        let pull_manifest = |state: &Arc<RegistryState>| {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), "latest".to_string())),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+        let pull_cached_blob = |state: &Arc<RegistryState>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+            handle_get_blob(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), DIGEST.to_string())),
+                headers,
+            )
+        };
+
+        let (stale, _stale_temp) = state_with(true).await;
+        let (strict, _strict_temp) = state_with(false).await;
+        for state in [&stale, &strict] {
+            pull_manifest(state).await.unwrap();
+            state
+                .cache
+                .put(DIGEST, Bytes::from_static(b"layer"), None)
+                .await
+                .unwrap();
+        }
+        healthy.store(false, std::sync::atomic::Ordering::SeqCst);
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+
+        let manifest = pull_manifest(&stale).await.unwrap();
+        assert_eq!(manifest.status(), StatusCode::OK);
+        assert_eq!(manifest.headers()[header::WARNING], STALE_WARNING);
+        let blob = pull_cached_blob(&stale).await.unwrap();
+        assert_eq!(blob.headers()[header::WARNING], STALE_WARNING);
+        let body = axum::body::to_bytes(blob.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+
+        assert!(pull_manifest(&strict).await.is_err());
+        assert!(pull_cached_blob(&strict).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_not_modified_when_etag_matches() {
+        let manifest = r#"{"schemaVersion":2}"#;
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(move || async move {
+                (
+                    [(
+                        header::CONTENT_TYPE,
+                        "application/vnd.oci.image.manifest.v1+json",
+                    )],
+                    manifest,
+                )
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |_| {}).await;
+        let pull = |if_none_match: Option<String>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = if_none_match {
+                headers.insert(header::IF_NONE_MATCH, value.parse().unwrap());
+            }
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), "latest".to_string())),
+                Query(ManifestQuery::default()),
+                headers,
+            )
+        };
+
+        let etag = format!(
+            "\"sha256:{}\"",
+            hex::encode(Sha256::digest(manifest.as_bytes()))
+        );
+        let response = pull(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+        let response = pull(Some(format!("\"sha256:stale\", W/{}", etag)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/vnd.oci.image.manifest.v1+json"
+        );
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+
+        let response = pull(Some("\"sha256:stale\"".to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_layers_prefetched_after_manifest_pull() {
+        let layer = b"prefetched layer";
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(layer)));
+        let manifest = json!({
+            "schemaVersion": 2,
+            "config": { "digest": "sha256:../../../escaped" },
+            "layers": [{ "digest": digest }],
+        })
+        .to_string();
+        let blob_fetches = Arc::new(std::sync::Mutex::new(0));
+        let counter = blob_fetches.clone();
+        let router = axum::Router::new()
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(move || async move {
+                    (
+                        [(
+                            header::CONTENT_TYPE,
+                            "application/vnd.oci.image.manifest.v1+json",
+                        )],
+                        manifest,
+                    )
+                }),
+            )
+            .route(
+                &format!("/v2/library/alpine/blobs/{}", digest),
+                axum::routing::get(move || {
+                    *counter.lock().unwrap() += 1;
+                    async move { &layer[..] }
+                }),
+            );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.prefetch_layers = true;
+        })
+        .await;
+
+        let pull_manifest = || {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), "latest".to_string())),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+        pull_manifest().await.unwrap();
+        for _ in 0..100 {
+            if state.prefetcher.fetched() == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.prefetcher.fetched(), 1);
+
+        let response = handle_get_blob(
+            State(state.clone()),
+            Extension(admin_claims()),
+            Path(("alpine".to_string(), digest.clone())),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.extensions().get::<CacheOutcome>(),
+            Some(&CacheOutcome::Hit)
+        );
+        assert_eq!(state.prefetcher.hits(), 1);
+
+        // Cached layers are not fetched again.
+        pull_manifest().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(*blob_fetches.lock().unwrap(), 1);
+
+        // Digests from the upstream's manifest never name a cache path
+        // unchecked.
+        let resolved = state.config.resolve_repository("alpine").unwrap();
+        assert!(matches!(
+            crate::prefetch::fetch_blob(&state, &resolved, "sha256:../../../escaped").await,
+            Err(ProxyError::DigestInvalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_version_check_probes_upstreams_when_configured() {
+        let router = axum::Router::new().route(
+            "/v2/",
+            axum::routing::get(|| async { StatusCode::UNAUTHORIZED }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let registries = format!(
+            r#"
+[[registries]]
+id = "hub"
+url = "{upstream}"
+allow_http = true
+"#
+        );
+        let version_check = |registries: &str| {
+            let temp = tempfile::TempDir::new().unwrap();
+            let mut config = crate::test_support::test_config(temp.path(), registries);
+            config.upstream.verify_upstream_on_ping = true;
+            async move {
+                let state = crate::test_support::state_from_config(config).await;
+                let response = handle_version_check(State(state)).await;
+                drop(temp);
+                response
+            }
+        };
+
+        // A 401 from upstream still shows it is reachable.
+        let response = version_check(&registries).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let unreachable = format!(
+            r#"{registries}
+[[registries]]
+id = "down"
+url = "http://127.0.0.1:1"
+allow_http = true
+"#
+        );
+        assert!(matches!(
+            version_check(&unreachable).await,
+            Err(ProxyError::ServiceUnavailable(message)) if message.contains("down")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_head_blob_answered_from_cached_manifest() {
+        let known = "sha256:1111111111111111111111111111111111111111111111111111111111111111";
+        let unknown = "sha256:2222222222222222222222222222222222222222222222222222222222222222";
+        let manifest = json!({
+            "schemaVersion": 2,
+            "layers": [{ "digest": known, "size": 4096 }],
+        })
+        .to_string();
+        let blob_requests = Arc::new(std::sync::Mutex::new(0));
+        let counter = blob_requests.clone();
+        let router = axum::Router::new()
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(move || async move {
+                    (
+                        [(
+                            header::CONTENT_TYPE,
+                            "application/vnd.oci.image.manifest.v1+json",
+                        )],
+                        manifest,
+                    )
+                }),
+            )
+            .route(
+                "/v2/library/alpine/blobs/:digest",
+                axum::routing::get(move || {
+                    *counter.lock().unwrap() += 1;
+                    async { "blob" }
+                }),
+            );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.manifest_ttl_seconds = 60;
+        })
+        .await;
+        let head = |digest: &str| {
+            handle_head_blob(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), digest.to_string())),
+            )
+        };
+
+        handle_get_manifest(
+            State(state.clone()),
+            Extension(admin_claims()),
+            Path(("alpine".to_string(), "latest".to_string())),
+            Query(ManifestQuery::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+
+        let response = head(known).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "4096");
+        assert_eq!(*blob_requests.lock().unwrap(), 0);
+
+        head(unknown).await.unwrap();
+        assert_eq!(*blob_requests.lock().unwrap(), 1);
+
+        // Cached blobs are answered from their metadata, without counting as
+        // a pull of the blob.
+        state
+            .cache
+            .put(unknown, Bytes::from_static(b"cached"), None)
+            .await
+            .unwrap();
+        let access_count = || {
+            state
+                .cache
+                .entries()
+                .find(|entry| entry.digest == unknown)
+                .unwrap()
+                .access_count
+        };
+        let (hits, accesses) = (state.cache.stats(), access_count());
+        let response = head(unknown).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "6");
+        assert_eq!(*blob_requests.lock().unwrap(), 1);
+        assert_eq!(state.cache.stats(), hits);
+        assert_eq!(access_count(), accesses);
+    }
+
+    #[tokio::test]
+    async fn test_client_accept_forwarded_upstream() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        // Answers with the first media type the request accepts.
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(move |headers: HeaderMap| {
+                let accept = headers[header::ACCEPT].to_str().unwrap().to_string();
+                recorded.lock().unwrap().push(accept.clone());
+                let content_type = accept.split([',', ';']).next().unwrap().to_string();
+                async move { ([(header::CONTENT_TYPE, content_type)], "{}") }
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.manifest_ttl_seconds = 60;
+        })
+        .await;
+        let pull = |accept: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), "latest".to_string())),
+                Query(ManifestQuery::default()),
+                headers,
+            )
+        };
+
+        // Without an Accept header the configured media types are sent.
+        let docker = "application/vnd.docker.distribution.manifest.v2+json";
+        pull(None).await.unwrap();
+        let defaults = seen.lock().unwrap().last().unwrap().clone();
+        assert!(defaults.starts_with(docker) && defaults.contains("index"));
+
+        // A cached manifest the client does not accept is fetched again.
+        let helm = "application/vnd.cncf.helm.chart.v1+json";
+        let response = pull(Some(helm)).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], helm);
+        assert_eq!(seen.lock().unwrap().last().unwrap(), helm);
+        pull(Some(helm)).await.unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_404s_negatively_cached_after_min_misses() {
+        let hits = Arc::new(std::sync::Mutex::new(0));
+        let counter = hits.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/:reference",
+            axum::routing::get(move || {
+                *counter.lock().unwrap() += 1;
+                async { StatusCode::NOT_FOUND }
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.negative_ttl_seconds = 60;
+            config.cache.negative_cache_min_misses = 2;
+        })
+        .await;
+
+        let pull = |reference: &str| {
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), reference.to_string())),
+                Query(ManifestQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+
+        // A single 404 may be a tag still propagating, so it is retried.
+        assert!(matches!(
+            pull("fresh").await,
+            Err(ProxyError::ManifestUnknown(_))
+        ));
+        assert!(!state.cache.missing_manifests().contains("alpine", "fresh"));
+
+        for _ in 0..3 {
+            assert!(matches!(
+                pull("gone").await,
+                Err(ProxyError::ManifestUnknown(_))
+            ));
+        }
+        assert!(state.cache.missing_manifests().contains("alpine", "gone"));
+        assert_eq!(*hits.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_negative_entry_cleared_once_manifest_found() {
+        let pushed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let exists = pushed.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/v2",
+            axum::routing::get(move || {
+                let found = exists.load(std::sync::atomic::Ordering::SeqCst);
+                async move {
+                    if found {
+                        (StatusCode::OK, "{}")
+                    } else {
+                        (StatusCode::NOT_FOUND, "")
+                    }
+                }
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.negative_ttl_seconds = 60;
+            config.cache.negative_cache_min_misses = 1;
+        })
+        .await;
+
+        let pull = |cache_control: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = cache_control {
+                headers.insert(header::CACHE_CONTROL, value.parse().unwrap());
+            }
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), "v2".to_string())),
+                Query(ManifestQuery::default()),
+                headers,
+            )
+        };
+
+        assert!(pull(None).await.is_err());
+        pushed.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(matches!(
+            pull(None).await,
+            Err(ProxyError::ManifestUnknown(_))
+        ));
+
+        pull(Some("no-cache")).await.unwrap();
+        assert!(!state.cache.missing_manifests().contains("alpine", "v2"));
+        pull(None).await.unwrap();
+    }
+
+    #[test]
+    fn test_claim_credentials_are_not_logged() {
+        let auth = UpstreamAuth {
+            username: "alice".to_string(),
+            password: "alice-pat".to_string(),
+            refresh_token: None,
+            client_id: None,
+        };
+        assert!(!format!("{:?}", auth).contains("alice-pat"));
+    }
+
+    #[tokio::test]
+    async fn test_manifest_list_resolved_to_requested_platform() {
+        const AMD: &str = r#"{"config": "amd"}"#;
+        const ARM: &str = r#"{"config": "arm"}"#;
+        let digest = |manifest: &str| format!("sha256:{}", hex::encode(Sha256::digest(manifest)));
+        let index = format!(
+            r#"{{"schemaVersion": 2, "manifests": [
+            {{"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "{}",
+             "platform": {{"os": "linux", "architecture": "amd64"}}}},
+            {{"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "{}",
+             "platform": {{"os": "linux", "architecture": "arm64", "variant": "v8"}}}}
+        ]}}"#,
+            digest(AMD),
+            digest(ARM)
+        );
+        let served = index.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/:reference",
+            axum::routing::get(move |Path(reference): Path<String>| {
+                let (content_type, body) = if reference == "latest" {
+                    ("application/vnd.oci.image.index.v1+json", served.clone())
+                } else if reference == digest(AMD) {
+                    (
+                        "application/vnd.oci.image.manifest.v1+json",
+                        AMD.to_string(),
+                    )
+                } else {
+                    (
+                        "application/vnd.oci.image.manifest.v1+json",
+                        ARM.to_string(),
+                    )
+                };
+                async move { ([(header::CONTENT_TYPE, content_type)], body) }
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.manifest_ttl_seconds = 60;
+            config.upstream.default_platform = Some("linux/arm64".to_string());
+        })
+        .await;
+
+        let pull = |platform: Option<&str>, accept: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            handle_get_manifest(
+                State(state.clone()),
+                Extension(admin_claims()),
+                Path(("alpine".to_string(), "latest".to_string())),
+                Query(ManifestQuery {
+                    platform: platform.map(str::to_string),
+                }),
+                headers,
+            )
+        };
+        let body = |response: Response| async move {
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+
+        let response = pull(Some("linux/amd64"), None).await.unwrap();
+        assert_eq!(body(response).await, AMD);
+        let cached = state
+            .cache
+            .manifests()
+            .get("alpine", "latest#linux/amd64")
+            .unwrap()
+            .expect("resolved manifest cached per platform");
+        assert_eq!(
+            cached.content_type,
+            "application/vnd.oci.image.manifest.v1+json"
+        );
+
+        // The default applies only to clients that cannot take an index.
+        let response = pull(None, Some("application/vnd.oci.image.manifest.v1+json"))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, ARM);
+        let response = pull(None, Some("application/vnd.oci.image.index.v1+json"))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, index);
+
+        assert!(matches!(
+            pull(Some("windows/amd64"), None).await,
+            Err(ProxyError::ManifestUnknown(_))
+        ));
+        assert!(matches!(
+            pull(Some("linux"), None).await,
+            Err(ProxyError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_referrers_proxied_or_emulated_from_tag_schema() {
+        const SUBJECT: &str =
+            "sha256:abababababababababababababababababababababababababababababababab";
+        let index = r#"{"schemaVersion": 2, "manifests": [
+            {"digest": "sha256:sig", "artifactType": "application/vnd.dev.cosign.artifact.sig.v1+json"},
+            {"digest": "sha256:sbom", "artifactType": "application/spdx+json"}
+        ]}"#;
+        let hits = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = hits.clone();
+        let router = axum::Router::new()
+            .route(
+                "/v2/library/alpine/referrers/:digest",
+                axum::routing::get(move |axum::extract::RawQuery(query)| {
+                    seen.lock().unwrap().push(query.unwrap_or_default());
+                    async move { index }
+                }),
+            )
+            .route(
+                "/v2/library/legacy/manifests/sha256-abababababababababababababababababababababababababababababababab",
+                axum::routing::get(move || async move { index }),
+            );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = test_state(&format!(
+            r#"{}
+[[repositories]]
+name = "legacy"
+registry_id = "hub"
+upstream_name = "library/legacy"
+
+[[repositories]]
+name = "bare"
+registry_id = "hub"
+upstream_name = "library/bare"
+"#,
+            alpine_registry(&upstream)
+        ))
+        .await;
+
+        let referrers = |repository: &str, artifact_type: Option<&str>| {
+            let state = state.clone();
+            let repository = repository.to_string();
+            let artifact_type = artifact_type.map(str::to_string);
+            async move {
+                let response = handle_get_referrers(
+                    State(state),
+                    Extension(admin_claims()),
+                    Path((repository, SUBJECT.to_string())),
+                    Query(ReferrersQuery { artifact_type }),
+                )
+                .await
+                .unwrap();
+                let filtered = response.headers().contains_key("OCI-Filters-Applied");
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let index: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let digests: Vec<String> = index["manifests"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|descriptor| descriptor["digest"].as_str().unwrap().to_string())
+                    .collect();
+                (digests, filtered)
+            }
+        };
+
+        assert_eq!(
+            referrers("alpine", None).await,
+            (
+                vec!["sha256:sig".to_string(), "sha256:sbom".to_string()],
+                false
+            )
+        );
+        assert_eq!(
+            referrers("alpine", Some("application/spdx+json")).await,
+            (vec!["sha256:sbom".to_string()], true)
+        );
+        // Served from the cache the second time.
+        referrers("alpine", None).await;
+        assert_eq!(
+            *hits.lock().unwrap(),
+            ["", "artifactType=application%2Fspdx%2Bjson"]
+        );
+
+        assert_eq!(
+            referrers("legacy", Some("application/spdx+json")).await,
+            (vec!["sha256:sbom".to_string()], true)
+        );
+        // Nothing refers to subjects without a referrers tag.
+        assert_eq!(referrers("bare", None).await, (Vec::new(), false));
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_repository_never_writes_blobs() {
+        let upstream = spawn_upstream(blob_upstream(DIGEST, b"layer")).await;
+        let (state, temp) = test_state(&format!(
+            r#"{}cache = {{ no_cache = true }}
+
+[[repositories]]
+name = "cached"
+registry_id = "hub"
+upstream_name = "library/alpine"
+"#,
+            alpine_registry(&upstream)
+        ))
+        .await;
+
+        let response = pull_blob(&state, "alpine").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.cache.get(DIGEST).await.unwrap().is_none());
+        assert!(!temp.path().join("blobs").exists());
+
+        let response = pull_blob(&state, "cached").await;
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(state.cache.get(DIGEST).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_blobs_points_client_at_upstream_redirect() {
+        let cdn_requests = Arc::new(std::sync::Mutex::new(0));
+        let counter = cdn_requests.clone();
+        let router = axum::Router::new()
+            .route(
+                &format!("/v2/library/alpine/blobs/{}", DIGEST),
+                axum::routing::get(|| async { axum::response::Redirect::temporary("/cdn/layer") }),
+            )
+            .route(
+                "/cdn/layer",
+                axum::routing::get(move || {
+                    *counter.lock().unwrap() += 1;
+                    async { "layer" }
+                }),
+            )
+            .route(
+                "/v2/library/direct/blobs/:digest",
+                axum::routing::get(|| async { "layer" }),
+            );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.registries[0].redirect_blobs = true;
+            let mut direct = config.repositories[0].clone();
+            direct.name = "direct".to_string();
+            direct.upstream_name = "library/direct".to_string();
+            config.repositories.push(direct);
+        })
+        .await;
+
+        let response = pull_blob(&state, "alpine").await;
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            format!("{}/cdn/layer", upstream)
+        );
+        assert_eq!(*cdn_requests.lock().unwrap(), 0);
+        assert!(state.cache.get(DIGEST).await.unwrap().is_none());
+
+        // A blob upstream serves without redirecting is passed through.
+        let response = pull_blob(&state, "direct").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+
+        // Blobs already in the cache are still served locally.
+        state
+            .cache
+            .put(DIGEST, Bytes::from_static(b"layer"), None)
+            .await
+            .unwrap();
+        assert_eq!(pull_blob(&state, "alpine").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_blob_with_wrong_digest_is_not_cached() {
+        const WRONG: &str =
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+        let upstream = spawn_upstream(blob_upstream(WRONG, b"layer")).await;
+        let (state, _temp) = alpine_state(&upstream, |_| {}).await;
+
+        let response = handle_get_blob(
+            State(state.clone()),
+            Extension(admin_claims()),
+            Path(("alpine".to_string(), WRONG.to_string())),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await;
+        assert!(body.is_err());
+        assert!(state.cache.get(WRONG).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_head_blob_without_upstream_length_not_downloaded() {
+        let blob_requests = Arc::new(std::sync::Mutex::new(0));
+        let counter = blob_requests.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/blobs/:digest",
+            axum::routing::get(move || {
+                *counter.lock().unwrap() += 1;
+                async { "layer" }
+            })
+            .head(|| async {
+                Body::from_stream(futures::stream::empty::<std::io::Result<Bytes>>())
+            }),
+        );
+        let upstream = spawn_upstream(router).await;
+        let (state, _temp) = alpine_state(&upstream, |_| {}).await;
+
+        let response = handle_head_blob(
+            State(state),
+            Extension(admin_claims()),
+            Path(("alpine".to_string(), DIGEST.to_string())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(
+            axum::body::HttpBody::size_hint(response.body()).exact(),
+            None
+        );
+        assert_eq!(*blob_requests.lock().unwrap(), 0);
+    }
+
+    /// Pulls the 5-byte `layer` blob with the given size limits and reports
+    /// whether it was cached.
+    async fn cached_within(min_blob_bytes: u64, max_cacheable_bytes: u64) -> bool {
+        let upstream = spawn_upstream(blob_upstream(DIGEST, b"layer")).await;
+        let (state, _temp) = alpine_state(&upstream, |config| {
+            config.cache.min_blob_bytes = min_blob_bytes;
+            config.cache.max_cacheable_bytes = max_cacheable_bytes;
+        })
+        .await;
+
+        let response = pull_blob(&state, "alpine").await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"layer");
+        state.cache.contains(DIGEST)
+    }
+
+    #[tokio::test]
+    async fn test_blob_size_limits_are_inclusive() {
+        assert!(cached_within(5, 5).await);
+        assert!(cached_within(0, 0).await);
+        assert!(!cached_within(6, 0).await);
+        assert!(!cached_within(0, 4).await);
+    }
+
+    #[tokio::test]
+    async fn test_blob_without_length_checked_while_streaming() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut config = crate::test_support::test_config(temp.path(), "");
+        config.cache.max_cacheable_bytes = 4;
+        let state = crate::test_support::state_from_config(config).await;
+
+        let chunks = futures::stream::iter([
+            Ok(Bytes::from_static(b"lay")),
+            Ok(Bytes::from_static(b"er")),
+        ]);
+        let (client, mut client_body) = mpsc::channel(16);
+        stream_into_cache(
+            state.cache.clone(),
+            DIGEST.to_string(),
+            None,
+            chunks,
+            client,
+        )
+        .await;
+
+        let mut received = Vec::new();
+        while let Some(chunk) = client_body.next().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, b"layer");
+        assert!(!state.cache.contains(DIGEST));
+    }
 
     #[test]
     fn test_check_access_with_all_permission() {
         let claims = Claims {
             sub: "user".to_string(),
             exp: None,
+            upstream_auth: None,
             access: AccessLevel::All,
         };
 
@@ -188,6 +2899,7 @@ mod tests {
         let claims = Claims {
             sub: "user".to_string(),
             exp: None,
+            upstream_auth: None,
             access: AccessLevel::Repositories {
                 repos: vec!["allowed".to_string()],
             },
@@ -0,0 +1,131 @@
+//! Limits how many distinct repositories one token subject can access within
+//! a sliding window. A leaked pull token sweeping through every repository
+//! is stopped after the first few, while normal clients returning to the
+//! same repositories are unaffected.
+
+use crate::config::RepositoryLimit;
+use crate::error::{ProxyError, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How often subjects whose accesses have all left the window are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct RepositoryGuard {
+    limit: Option<RepositoryLimit>,
+    subjects: Mutex<Subjects>,
+}
+
+struct Subjects {
+    /// Per subject, the repositories accessed within the window and when each
+    /// was last accessed.
+    accessed: HashMap<String, HashMap<String, Instant>>,
+    next_prune: Instant,
+}
+
+impl RepositoryGuard {
+    pub fn new(limit: Option<RepositoryLimit>) -> Self {
+        Self {
+            limit,
+            subjects: Mutex::new(Subjects {
+                accessed: HashMap::new(),
+                next_prune: Instant::now() + PRUNE_INTERVAL,
+            }),
+        }
+    }
+
+    /// Records an access by `subject` to `repository`, refusing it when the
+    /// subject has already accessed the maximum number of other repositories
+    /// within the window.
+    pub fn check(&self, subject: &str, repository: &str) -> Result<()> {
+        let Some(limit) = &self.limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let window = Duration::from_secs(limit.window_seconds);
+
+        let in_window = |last_seen: &mut Instant| now.duration_since(*last_seen) < window;
+
+        let mut subjects = self.subjects.lock().unwrap();
+        // Other subjects are only swept periodically, so a request does not
+        // walk every tracked subject.
+        if now >= subjects.next_prune {
+            subjects.accessed.retain(|_, repositories| {
+                repositories.retain(|_, last_seen| in_window(last_seen));
+                !repositories.is_empty()
+            });
+            subjects.next_prune = now + PRUNE_INTERVAL;
+        }
+
+        let repositories = subjects.accessed.entry(subject.to_string()).or_default();
+        repositories.retain(|_, last_seen| in_window(last_seen));
+        if !repositories.contains_key(repository) && repositories.len() >= limit.max_repositories {
+            warn!(
+                "Subject {} exceeded {} distinct repositories within {}s; denied {}",
+                subject, limit.max_repositories, limit.window_seconds, repository
+            );
+            return Err(ProxyError::Forbidden(format!(
+                "Too many distinct repositories accessed within {} seconds",
+                limit.window_seconds
+            )));
+        }
+        repositories.insert(repository.to_string(), now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subject_over_distinct_repository_cap_is_blocked() {
+        let guard = RepositoryGuard::new(Some(RepositoryLimit {
+            max_repositories: 2,
+            window_seconds: 60,
+        }));
+
+        assert!(guard.check("leaked", "app").is_ok());
+        assert!(guard.check("leaked", "db").is_ok());
+        assert!(matches!(
+            guard.check("leaked", "secrets"),
+            Err(ProxyError::Forbidden(_))
+        ));
+        // Repositories already within the window stay accessible.
+        assert!(guard.check("leaked", "app").is_ok());
+
+        assert!(guard.check("ci", "app").is_ok());
+        assert!(guard.check("ci", "secrets").is_ok());
+    }
+
+    #[test]
+    fn test_accesses_expire_after_window() {
+        let guard = RepositoryGuard::new(Some(RepositoryLimit {
+            max_repositories: 1,
+            window_seconds: 0,
+        }));
+
+        assert!(guard.check("user", "app").is_ok());
+        assert!(guard.check("user", "db").is_ok());
+        assert!(RepositoryGuard::new(None).check("user", "app").is_ok());
+    }
+
+    #[test]
+    fn test_idle_subjects_swept_periodically() {
+        let guard = RepositoryGuard::new(Some(RepositoryLimit {
+            max_repositories: 1,
+            window_seconds: 0,
+        }));
+        let tracked = || guard.subjects.lock().unwrap().accessed.len();
+
+        assert!(guard.check("idle", "app").is_ok());
+        assert!(guard.check("active", "app").is_ok());
+        assert_eq!(tracked(), 2);
+
+        guard.subjects.lock().unwrap().next_prune = Instant::now();
+        assert!(guard.check("active", "app").is_ok());
+        assert_eq!(tracked(), 1);
+    }
+}
@@ -0,0 +1,107 @@
+//! Correlation ids tying together every log line written for one request.
+//! The id comes from the client's `X-Request-Id` header when it sends a
+//! usable one and is generated otherwise. It is echoed in the response
+//! headers and in error bodies so a failed pull can be traced in the logs.
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied id that is accepted as is.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled by the current task, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+pub async fn request_id_middleware(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_acceptable(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+    let header_value = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value.clone());
+
+    let span = tracing::info_span!("request", request_id = %id);
+    let mut response = REQUEST_ID
+        .scope(id, next.run(request))
+        .instrument(span)
+        .await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value);
+    response
+}
+
+fn is_acceptable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProxyError;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/fail",
+                get(|| async { ProxyError::NameUnknown("app".into()) }),
+            )
+            .layer(middleware::from_fn(request_id_middleware))
+    }
+
+    async fn fail(request_id: Option<&str>) -> (String, serde_json::Value) {
+        let mut request = Request::get("/fail");
+        if let Some(id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, id);
+        }
+        let response = app()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (id, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_request_id_echoed_in_headers_and_errors() {
+        let (id, body) = fail(Some("client-trace-42")).await;
+        assert_eq!(id, "client-trace-42");
+        assert_eq!(body["request_id"], "client-trace-42");
+
+        for unusable in [None, Some("has spaces"), Some(&*"x".repeat(200))] {
+            let (id, body) = fail(unusable).await;
+            assert_eq!(id.len(), 32);
+            assert_eq!(body["request_id"], id.as_str());
+        }
+    }
+}
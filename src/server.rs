@@ -0,0 +1,230 @@
+//! Serving the router to clients, over plain TCP or TLS, with the configured
+//! HTTP versions.
+
+use crate::config::{HttpProtocol, ServerConfig};
+use crate::tls;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::RustlsAcceptor;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tower::Service;
+use tracing::{debug, info, warn};
+
+/// Serves `app` until `shutdown` resolves, then gives requests in flight up
+/// to `shutdown_timeout_seconds` to finish.
+pub async fn serve(
+    config: &ServerConfig,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let tls = match &config.tls {
+        Some(tls_config) => {
+            let rustls = tls::load(tls_config, &config.protocols).await?;
+            tls::reload_on_sighup(rustls.clone(), tls_config.clone(), config.protocols.clone());
+            Some(RustlsAcceptor::new(rustls))
+        }
+        None => None,
+    };
+
+    // The builder negotiates both versions unless restricted to one. Over
+    // TLS, ALPN has already narrowed what clients offer.
+    let mut builder = Builder::new(TokioExecutor::new());
+    match (
+        config.protocols.contains(&HttpProtocol::Http1),
+        config.protocols.contains(&HttpProtocol::Http2),
+    ) {
+        (true, false) => builder = builder.http1_only(),
+        (false, true) => builder = builder.http2_only(),
+        _ => {}
+    }
+
+    let bind_addr = format!("{}:{}", config.bind_address, config.port);
+    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
+    info!(
+        "Listening on {} ({}{:?})",
+        bind_addr,
+        if tls.is_some() { "TLS, " } else { "" },
+        config.protocols
+    );
+
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors; give connections time
+                    // to close.
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let (builder, app, watcher) = (builder.clone(), app.clone(), graceful.watcher());
+        match &tls {
+            Some(acceptor) => {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    match acceptor.accept(stream, ()).await {
+                        Ok((stream, ())) => {
+                            serve_connection(&builder, watcher, stream, peer, app).await
+                        }
+                        Err(e) => debug!("TLS handshake with {} failed: {}", peer, e),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(async move {
+                    serve_connection(&builder, watcher, stream, peer, app).await
+                });
+            }
+        }
+    }
+    drop(listener);
+
+    let timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+    tokio::select! {
+        _ = graceful.shutdown() => info!("All in-flight requests finished"),
+        _ = tokio::time::sleep(timeout) => warn!(
+            "In-flight requests still running after {}s; abandoning them",
+            timeout.as_secs()
+        ),
+    }
+    Ok(())
+}
+
+/// Serves one client connection until it closes or shutdown completes it.
+async fn serve_connection<I>(
+    builder: &Builder<TokioExecutor>,
+    watcher: Watcher,
+    io: I,
+    peer: SocketAddr,
+    app: Router,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |request: hyper::Request<Incoming>| {
+        let mut request = request.map(axum::body::Body::new);
+        request.extensions_mut().insert(ConnectInfo(peer));
+        app.clone().call(request)
+    });
+    let connection = builder.serve_connection(TokioIo::new(io), service);
+    if let Err(e) = watcher.watch(connection.into_owned()).await {
+        debug!("Connection from {} ended with an error: {}", peer, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Running {
+        port: u16,
+        stop: tokio::sync::oneshot::Sender<()>,
+        task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    }
+
+    /// Serves a router answering `/healthz` with the server settings in
+    /// `extra`.
+    fn start(extra: &str) -> Running {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: ServerConfig = toml::from_str(&format!(
+            "bind_address = \"127.0.0.1\"\nport = {}\n{}",
+            port, extra
+        ))
+        .unwrap();
+        let app = Router::new().route("/healthz", axum::routing::get(|| async { "ok" }));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            serve(&config, app, async {
+                let _ = stopped.await;
+            })
+            .await
+        });
+        Running { port, stop, task }
+    }
+
+    /// Requests `/healthz`, waiting for the server to come up.
+    async fn get(client: &reqwest::Client, url: &str) -> reqwest::Result<reqwest::Response> {
+        for _ in 0..50 {
+            match client.get(url).send().await {
+                Err(e) if e.is_connect() => tokio::time::sleep(Duration::from_millis(20)).await,
+                outcome => return outcome,
+            }
+        }
+        client.get(url).send().await
+    }
+
+    impl Running {
+        async fn stop(self) {
+            self.stop.send(()).unwrap();
+            self.task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_serves_both_versions() {
+        let server = start("");
+        let url = format!("http://127.0.0.1:{}/healthz", server.port);
+
+        let h2c = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let response = get(&h2c, &url).await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        let response = get(&reqwest::Client::new(), &url).await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_11);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_http1_only_refuses_h2c() {
+        let server = start(r#"protocols = ["http1"]"#);
+        let url = format!("http://127.0.0.1:{}/healthz", server.port);
+
+        let response = get(&reqwest::Client::new(), &url).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        let h2c = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        assert!(get(&h2c, &url).await.is_err());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_serves_https() {
+        let tls = tls::fixture_config();
+        let server = start(&format!(
+            "[tls]\ncert_path = {:?}\nkey_path = {:?}",
+            tls.cert_path, tls.key_path
+        ));
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let url = format!("https://127.0.0.1:{}/healthz", server.port);
+        let response = get(&client, &url).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        server.stop().await;
+    }
+}
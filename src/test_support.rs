@@ -0,0 +1,248 @@
+//! Shared helpers for unit tests.
+
+use crate::auth::{AccessLevel, Claims};
+use crate::cache::BlobCache;
+use crate::config::Config;
+use crate::metrics::PullLatency;
+use crate::prefetch::Prefetcher;
+use crate::registry::RegistryState;
+use crate::repository_guard::RepositoryGuard;
+use crate::upstream::UpstreamClient;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// Parses a test config. `extra` is appended after the required sections and
+/// may contain `[[registries]]`, `[[repositories]]` and further tables.
+pub fn test_config(cache_dir: &std::path::Path, extra: &str) -> Config {
+    let config_toml = format!(
+        r#"
+[server]
+
+[auth]
+jwt_secret = "test-secret"
+
+[cache]
+directory = "{}"
+max_size_bytes = 1048576
+max_age_seconds = 3600
+
+{}
+"#,
+        cache_dir.display(),
+        extra
+    );
+
+    toml::from_str(&config_toml).unwrap()
+}
+
+pub async fn test_state(extra: &str) -> (Arc<RegistryState>, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let config = test_config(temp_dir.path(), extra);
+    (state_from_config(config).await, temp_dir)
+}
+
+/// The `hub` registry serving from `upstream`, with `alpine` mapped to its
+/// `library/alpine`: the setup most handler tests share. It ends inside the
+/// `alpine` mapping, so further keys and tables can be appended.
+pub fn alpine_registry(upstream: &str) -> String {
+    format!(
+        r#"
+[[registries]]
+id = "hub"
+url = "{upstream}"
+allow_http = true
+
+[[repositories]]
+name = "alpine"
+registry_id = "hub"
+upstream_name = "library/alpine"
+"#
+    )
+}
+
+/// State for [`alpine_registry`], with `configure` applied to the config
+/// first.
+pub async fn alpine_state(
+    upstream: &str,
+    configure: impl FnOnce(&mut Config),
+) -> (Arc<RegistryState>, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = test_config(temp_dir.path(), &alpine_registry(upstream));
+    configure(&mut config);
+    (state_from_config(config).await, temp_dir)
+}
+
+pub async fn state_from_config(config: Config) -> Arc<RegistryState> {
+    let cache = Arc::new(BlobCache::new(config.cache.clone()).await.unwrap());
+    let upstream = UpstreamClient::new(
+        &config.upstream,
+        &config.registries,
+        config.server.user_agent(),
+    )
+    .unwrap();
+
+    Arc::new(RegistryState {
+        repository_guard: RepositoryGuard::new(config.auth.repository_limit.clone()),
+        prefetcher: Prefetcher::new(&config.cache),
+        config,
+        upstream,
+        cache,
+        pull_latency: PullLatency::default(),
+    })
+}
+
+pub fn admin_claims() -> Claims {
+    Claims {
+        sub: "admin".to_string(),
+        exp: None,
+        upstream_auth: None,
+        access: AccessLevel::All,
+    }
+}
+
+/// A token carrying `claims`, signed with the `jwt_secret` of
+/// [`test_config`].
+pub fn token_for(claims: &Claims) -> String {
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        claims,
+        &jsonwebtoken::EncodingKey::from_secret(b"test-secret"),
+    )
+    .unwrap()
+}
+
+/// A token for [`admin_claims`].
+pub fn admin_token() -> String {
+    token_for(&admin_claims())
+}
+
+pub fn repo_claims(repos: &[&str]) -> Claims {
+    Claims {
+        sub: "user".to_string(),
+        exp: None,
+        upstream_auth: None,
+        access: AccessLevel::Repositories {
+            repos: repos.iter().map(|r| r.to_string()).collect(),
+        },
+    }
+}
+
+/// Serves `router` on an ephemeral local port and returns its base URL.
+pub async fn spawn_upstream(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+    format!("http://{}", addr)
+}
+
+/// A minimal upstream serving a single blob for `library/alpine`.
+pub fn blob_upstream(digest: &'static str, data: &'static [u8]) -> axum::Router {
+    axum::Router::new().route(
+        &format!("/v2/library/alpine/blobs/{}", digest),
+        axum::routing::get(move || async move { data }),
+    )
+}
+
+/// An upstream requiring bearer tokens for `library/alpine` manifests. Every
+/// `Authorization` header sent to its `/token` endpoint is recorded.
+pub fn token_upstream() -> (axum::Router, Arc<std::sync::Mutex<Vec<String>>>) {
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+
+    let token_requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = token_requests.clone();
+
+    let router = axum::Router::new()
+        .route(
+            "/token",
+            axum::routing::get(move |headers: HeaderMap| async move {
+                let authorization = headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                recorded.lock().unwrap().push(authorization);
+                axum::Json(serde_json::json!({ "token": "upstream-token" }))
+            }),
+        )
+        .route(
+            "/v2/library/alpine/manifests/:reference",
+            axum::routing::get(|headers: HeaderMap| async move {
+                let authorized = headers
+                    .get(header::AUTHORIZATION)
+                    .is_some_and(|v| v == "Bearer upstream-token");
+                if !authorized {
+                    let host = headers[header::HOST].to_str().unwrap().to_string();
+                    let challenge = format!(
+                        r#"Bearer realm="http://{}/token",service="test",scope="repository:library/alpine:pull""#,
+                        host
+                    );
+                    return (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge)],
+                    )
+                        .into_response();
+                }
+                (
+                    [(
+                        header::CONTENT_TYPE,
+                        "application/vnd.oci.image.manifest.v1+json",
+                    )],
+                    r#"{"schemaVersion":2}"#,
+                )
+                    .into_response()
+            }),
+        );
+
+    (router, token_requests)
+}
+
+/// An in-memory stand-in for an S3 bucket, handling path-style object
+/// `PUT`, `GET`, `HEAD` and `DELETE`. Returns the router and its objects,
+/// keyed by `<bucket>/<key>`.
+pub fn s3_upstream() -> (
+    axum::Router,
+    Arc<std::sync::Mutex<std::collections::HashMap<String, bytes::Bytes>>>,
+) {
+    use axum::extract::Path;
+    use axum::http::{header, Method, StatusCode};
+    use axum::response::IntoResponse;
+
+    let objects = Arc::new(std::sync::Mutex::new(std::collections::HashMap::<
+        String,
+        bytes::Bytes,
+    >::new()));
+    let store = objects.clone();
+
+    let router = axum::Router::new().route(
+        "/*key",
+        axum::routing::any(
+            move |method: Method, Path(key): Path<String>, body: bytes::Bytes| async move {
+                let mut objects = store.lock().unwrap();
+                let etag = (header::ETAG, format!("\"{}\"", key.len()));
+                let last_modified = (
+                    header::LAST_MODIFIED,
+                    "Mon, 05 Oct 2026 10:00:00 GMT".to_string(),
+                );
+                match method {
+                    Method::PUT => {
+                        objects.insert(key.clone(), body);
+                        ([etag], "").into_response()
+                    }
+                    Method::DELETE => {
+                        objects.remove(&key);
+                        StatusCode::NO_CONTENT.into_response()
+                    }
+                    Method::GET | Method::HEAD => match objects.get(&key) {
+                        Some(data) => ([etag, last_modified], data.clone()).into_response(),
+                        None => StatusCode::NOT_FOUND.into_response(),
+                    },
+                    _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+                }
+            },
+        ),
+    );
+    (router, objects)
+}
@@ -0,0 +1,133 @@
+//! TLS termination, so the proxy can serve HTTPS without a separate reverse
+//! proxy. The certificate is reloaded on SIGHUP, so it can be rotated
+//! without dropping connections.
+
+use crate::config::{HttpProtocol, TlsConfig};
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::ServerConfig;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Loads the certificate and key, offering `protocols` through ALPN.
+pub async fn load(config: &TlsConfig, protocols: &[HttpProtocol]) -> anyhow::Result<RustlsConfig> {
+    // Fails only when a provider is already installed, which is just as good.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let server_config = server_config(config, protocols).await.with_context(|| {
+        format!(
+            "Failed to load TLS certificate {} and key {}",
+            config.cert_path.display(),
+            config.key_path.display()
+        )
+    })?;
+    Ok(RustlsConfig::from_config(server_config))
+}
+
+async fn server_config(
+    config: &TlsConfig,
+    protocols: &[HttpProtocol],
+) -> std::io::Result<Arc<ServerConfig>> {
+    let loaded = RustlsConfig::from_pem_file(&config.cert_path, &config.key_path).await?;
+    let mut server_config = ServerConfig::clone(&loaded.get_inner());
+    // Preferred protocol first.
+    server_config.alpn_protocols = [
+        (HttpProtocol::Http2, &b"h2"[..]),
+        (HttpProtocol::Http1, &b"http/1.1"[..]),
+    ]
+    .into_iter()
+    .filter(|(protocol, _)| protocols.contains(protocol))
+    .map(|(_, id)| id.to_vec())
+    .collect();
+    Ok(Arc::new(server_config))
+}
+
+#[cfg(unix)]
+pub fn reload_on_sighup(rustls: RustlsConfig, config: TlsConfig, protocols: Vec<HttpProtocol>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!(
+                "Failed to listen for SIGHUP; TLS certificate reload disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            reload(&rustls, &config, &protocols).await;
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_rustls: RustlsConfig, _config: TlsConfig, _protocols: Vec<HttpProtocol>) {}
+
+/// Replaces the served certificate. New connections use it right away; a
+/// certificate that fails to load leaves the current one in place.
+async fn reload(rustls: &RustlsConfig, config: &TlsConfig, protocols: &[HttpProtocol]) {
+    match server_config(config, protocols).await {
+        Ok(server_config) => {
+            rustls.reload_from_config(server_config);
+            info!(
+                "Reloaded TLS certificate from {}",
+                config.cert_path.display()
+            );
+        }
+        Err(e) => warn!(
+            "Failed to reload TLS certificate from {}; keeping the current one: {}",
+            config.cert_path.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+pub fn fixture_config() -> TlsConfig {
+    TlsConfig {
+        cert_path: "tests/fixtures/server_cert.pem".into(),
+        key_path: "tests/fixtures/server_key.pem".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: [HttpProtocol; 2] = [HttpProtocol::Http1, HttpProtocol::Http2];
+
+    #[tokio::test]
+    async fn test_failed_reload_keeps_certificate() {
+        let rustls = load(&fixture_config(), &BOTH).await.unwrap();
+        let before = rustls.get_inner();
+
+        let missing = TlsConfig {
+            cert_path: "tests/fixtures/missing.pem".into(),
+            ..fixture_config()
+        };
+        reload(&rustls, &missing, &BOTH).await;
+        assert!(Arc::ptr_eq(&before, &rustls.get_inner()));
+
+        reload(&rustls, &fixture_config(), &BOTH).await;
+        assert!(!Arc::ptr_eq(&before, &rustls.get_inner()));
+
+        assert!(format!("{:#}", load(&missing, &BOTH).await.unwrap_err())
+            .contains("tests/fixtures/missing.pem"));
+    }
+
+    #[tokio::test]
+    async fn test_alpn_offers_configured_protocols() {
+        let alpn = |protocols: &'static [HttpProtocol]| async move {
+            let rustls = load(&fixture_config(), protocols).await.unwrap();
+            let alpn = rustls.get_inner().alpn_protocols.clone();
+            // Reloading keeps the protocol set.
+            reload(&rustls, &fixture_config(), protocols).await;
+            assert_eq!(rustls.get_inner().alpn_protocols, alpn);
+            alpn
+        };
+        assert_eq!(alpn(&BOTH).await, [&b"h2"[..], b"http/1.1"]);
+        assert_eq!(alpn(&[HttpProtocol::Http1]).await, [b"http/1.1"]);
+    }
+}
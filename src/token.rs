@@ -0,0 +1,299 @@
+//! Docker token authentication: clients present a username and password and
+//! receive a JWT scoped to the repositories they asked for, which they then
+//! send as a bearer token. This is the flow `docker login` and `docker pull`
+//! follow after the `WWW-Authenticate` challenge on a 401.
+
+use crate::auth::{verify_credentials, AccessLevel, Claims, ANONYMOUS_SUBJECT};
+use crate::error::{ProxyError, Result};
+use crate::registry::RegistryState;
+use axum::{
+    extract::{RawQuery, State},
+    http::{header, HeaderMap},
+    Json,
+};
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::info;
+
+pub async fn handle_token(
+    State(state): State<Arc<RegistryState>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Value>> {
+    let auth = &state.config.auth;
+    let params: Vec<(String, String)> =
+        reqwest::Url::parse(&format!("http://token/?{}", query.unwrap_or_default()))
+            .map(|url| url.query_pairs().into_owned().collect())
+            .unwrap_or_default();
+
+    if let Some((_, service)) = params.iter().find(|(key, _)| key == "service") {
+        if *service != auth.service {
+            return Err(ProxyError::BadRequest(format!(
+                "Unknown service: {}",
+                service
+            )));
+        }
+    }
+
+    let scopes: Vec<&str> = params
+        .iter()
+        .filter(|(key, _)| key == "scope")
+        .flat_map(|(_, value)| value.split_whitespace())
+        .collect();
+    let (subject, access) = match basic_credentials(&headers) {
+        Some((username, password)) => {
+            // Password hashing is deliberately slow; keep it off the runtime
+            // workers.
+            let users = state.clone();
+            let user = tokio::task::spawn_blocking(move || {
+                verify_credentials(&users.config.users, &username, &password).cloned()
+            })
+            .await
+            .map_err(|e| ProxyError::Internal(format!("Password verification failed: {}", e)))?
+            .ok_or_else(|| ProxyError::Unauthorized("Invalid username or password".into()))?;
+            // Without a scope (as on `docker login`) the token carries the
+            // user's own access; otherwise only the requested repositories
+            // the user may pull.
+            let access = if scopes.is_empty() {
+                user.access.clone()
+            } else {
+                AccessLevel::Repositories {
+                    repos: pulled_repositories(&scopes, |repository| {
+                        user.access.can_access(repository)
+                    }),
+                }
+            };
+            (user.username.clone(), access)
+        }
+        // Clients without credentials get a token for the public
+        // repositories they asked to pull.
+        None => {
+            let repos = pulled_repositories(&scopes, |repository| {
+                state.config.is_public_repository(repository)
+            });
+            if repos.is_empty() {
+                return Err(ProxyError::Unauthorized(
+                    "Username and password required".into(),
+                ));
+            }
+            (
+                ANONYMOUS_SUBJECT.to_string(),
+                AccessLevel::Repositories { repos },
+            )
+        }
+    };
+    let secret = auth
+        .jwt_secret
+        .as_ref()
+        .ok_or_else(|| ProxyError::Internal("Token issuance requires auth.jwt_secret".into()))?;
+
+    let issued_at = Utc::now();
+    let claims = Claims {
+        sub: subject,
+        exp: Some((issued_at.timestamp() as u64 + auth.token_ttl_seconds) as usize),
+        access,
+        upstream_auth: None,
+    };
+    let mut payload = serde_json::to_value(&claims)
+        .map_err(|e| ProxyError::Internal(format!("Failed to encode claims: {}", e)))?;
+    if let Some(issuer) = &auth.issuer {
+        payload["iss"] = json!(issuer);
+    }
+    if let Some(audience) = &auth.audience {
+        payload["aud"] = json!(audience);
+    }
+    let token = encode(
+        &Header::default(),
+        &payload,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .map_err(|e| ProxyError::Internal(format!("Failed to sign token: {}", e)))?;
+
+    info!("Issued token for {}: {:?}", claims.sub, claims.access);
+    Ok(Json(json!({
+        "token": token,
+        "access_token": token,
+        "expires_in": auth.token_ttl_seconds,
+        "issued_at": issued_at.to_rfc3339(),
+    })))
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Repositories named by pull `scopes` that `permitted` allows.
+fn pulled_repositories(scopes: &[&str], permitted: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut repos: Vec<String> = scopes
+        .iter()
+        .filter_map(|scope| pull_scope(scope))
+        .filter(|repository| permitted(repository))
+        .map(str::to_string)
+        .collect();
+    repos.sort();
+    repos.dedup();
+    repos
+}
+
+/// Repository named by a `repository:<name>:<actions>` scope that includes the
+/// `pull` action. Other resource types and actions are not granted.
+fn pull_scope(scope: &str) -> Option<&str> {
+    let (resource_type, rest) = scope.split_once(':')?;
+    let (name, actions) = rest.rsplit_once(':')?;
+    (resource_type == "repository" && actions.split(',').any(|action| action == "pull"))
+        .then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{alpine_registry, test_state};
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::Argon2;
+    use axum::http::HeaderValue;
+    use jsonwebtoken::{decode, DecodingKey, Validation};
+
+    fn basic(username: &str, password: &str) -> HeaderMap {
+        let encoded =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", encoded)).unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_token_scoped_to_permitted_pull_scopes() {
+        let salt = SaltString::encode_b64(b"test-salt-bytes").unwrap();
+        let hash = Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let (state, _temp) = test_state(&format!(
+            r#"
+[[users]]
+username = "ci"
+password_hash = "{hash}"
+access = {{ type = "repositories", repos = ["alpine", "team/*"] }}
+"#
+        ))
+        .await;
+
+        let query = "service=cargo-bay&scope=repository:alpine:pull\
+                     &scope=repository:team/app:pull,push&scope=repository:secret:pull\
+                     &scope=repository:alpine:pull";
+        let Json(response) = handle_token(
+            State(state.clone()),
+            basic("ci", "hunter2"),
+            RawQuery(Some(query.into())),
+        )
+        .await
+        .unwrap();
+
+        let claims = decode::<Claims>(
+            response["token"].as_str().unwrap(),
+            &DecodingKey::from_secret(b"test-secret"),
+            &Validation::default(),
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims.sub, "ci");
+        let AccessLevel::Repositories { repos } = claims.access else {
+            panic!("expected a repository-scoped token");
+        };
+        assert_eq!(repos, ["alpine", "team/app"]);
+
+        for headers in [
+            basic("ci", "wrong"),
+            basic("nobody", "hunter2"),
+            HeaderMap::new(),
+        ] {
+            assert!(matches!(
+                handle_token(State(state.clone()), headers, RawQuery(None)).await,
+                Err(ProxyError::Unauthorized(_))
+            ));
+        }
+        assert!(matches!(
+            handle_token(
+                State(state),
+                basic("ci", "hunter2"),
+                RawQuery(Some("service=other".into()))
+            )
+            .await,
+            Err(ProxyError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_token_covers_public_repositories_only() {
+        let (state, _temp) = test_state(&format!(
+            r#"{}public = true
+
+[[repositories]]
+name = "app"
+registry_id = "hub"
+upstream_name = "team/app"
+"#,
+            alpine_registry("https://registry-1.docker.io")
+        ))
+        .await;
+
+        let query = "scope=repository:alpine:pull&scope=repository:app:pull\
+                     &scope=repository:alpine:pull";
+        let Json(response) = handle_token(
+            State(state.clone()),
+            HeaderMap::new(),
+            RawQuery(Some(query.into())),
+        )
+        .await
+        .unwrap();
+        let claims = decode::<Claims>(
+            response["token"].as_str().unwrap(),
+            &DecodingKey::from_secret(b"test-secret"),
+            &Validation::default(),
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims.sub, ANONYMOUS_SUBJECT);
+        let AccessLevel::Repositories { repos } = claims.access else {
+            panic!("expected a repository-scoped token");
+        };
+        assert_eq!(repos, ["alpine"]);
+
+        assert!(matches!(
+            handle_token(
+                State(state),
+                HeaderMap::new(),
+                RawQuery(Some("scope=repository:app:pull".into()))
+            )
+            .await,
+            Err(ProxyError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_pull_scope_parsing() {
+        assert_eq!(
+            pull_scope("repository:library/alpine:pull"),
+            Some("library/alpine")
+        );
+        assert_eq!(pull_scope("repository:app:push,pull"), Some("app"));
+        assert_eq!(pull_scope("repository:app:push"), None);
+        assert_eq!(pull_scope("registry:catalog:*"), None);
+        assert_eq!(pull_scope("repository"), None);
+    }
+}
@@ -0,0 +1,142 @@
+//! State for resumable blob uploads (`/v2/<name>/blobs/uploads/`).
+//!
+//! The proxy hands clients its own upload UUID and remembers which upstream
+//! session it stands for, so each `PATCH` chunk and the final `PUT` are sent
+//! to the location the upstream returned for the previous step. Sessions are
+//! kept in sled so they survive across the requests making up one upload.
+//!
+//! Push proxying is not routed yet; writes are still rejected with 403.
+#![cfg_attr(not(test), allow(dead_code))]
+
+use crate::error::{ProxyError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub repository: String,
+    /// Where the next chunk or the final commit must be sent upstream.
+    pub upstream_location: String,
+    /// Bytes acknowledged by the upstream so far.
+    pub offset: u64,
+    pub started: DateTime<Utc>,
+}
+
+pub struct UploadSessions {
+    tree: sled::Tree,
+}
+
+impl UploadSessions {
+    pub fn new(tree: sled::Tree) -> Self {
+        Self { tree }
+    }
+
+    /// Records a session the upstream opened at `upstream_location` and
+    /// returns the UUID to hand to the client.
+    pub fn start(&self, repository: &str, upstream_location: &str) -> Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let session = UploadSession {
+            repository: repository.to_string(),
+            upstream_location: upstream_location.to_string(),
+            offset: 0,
+            started: Utc::now(),
+        };
+        self.store(&id, &session)?;
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<UploadSession>> {
+        let Some(data) = self.tree.get(id).map_err(session_error)? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| ProxyError::Internal(format!("Corrupt upload session {}: {}", id, e)))
+    }
+
+    /// Records an accepted chunk: the upstream's new location for the session
+    /// and the number of bytes it received.
+    pub fn advance(
+        &self,
+        id: &str,
+        upstream_location: &str,
+        chunk_len: u64,
+    ) -> Result<UploadSession> {
+        let mut session = self.require(id)?;
+        session.upstream_location = upstream_location.to_string();
+        session.offset += chunk_len;
+        self.store(id, &session)?;
+        Ok(session)
+    }
+
+    /// Ends the session, returning the upstream URL that commits the upload
+    /// as `digest`.
+    pub fn commit(&self, id: &str, digest: &str) -> Result<String> {
+        let session = self.require(id)?;
+        self.tree.remove(id).map_err(session_error)?;
+
+        let mut url = reqwest::Url::parse(&session.upstream_location).map_err(|e| {
+            ProxyError::Internal(format!("Invalid upstream upload location: {}", e))
+        })?;
+        url.query_pairs_mut().append_pair("digest", digest);
+        Ok(url.to_string())
+    }
+
+    fn require(&self, id: &str) -> Result<UploadSession> {
+        self.get(id)?
+            .ok_or_else(|| ProxyError::BlobUploadUnknown(id.to_string()))
+    }
+
+    fn store(&self, id: &str, session: &UploadSession) -> Result<()> {
+        let data = serde_json::to_vec(session).map_err(|e| {
+            ProxyError::Internal(format!("Failed to serialize upload session: {}", e))
+        })?;
+        self.tree.insert(id, data).map_err(session_error)?;
+        Ok(())
+    }
+}
+
+fn session_error(e: sled::Error) -> ProxyError {
+    ProxyError::Internal(format!("Upload session storage error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> UploadSessions {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        UploadSessions::new(db.open_tree("uploads").unwrap())
+    }
+
+    #[test]
+    fn test_two_chunk_upload_follows_upstream_session() {
+        let sessions = sessions();
+        let upstream = "https://registry.example.com/v2/team/app/blobs/uploads";
+
+        let id = sessions
+            .start("app", &format!("{}/abc?_state=0", upstream))
+            .unwrap();
+
+        sessions
+            .advance(&id, &format!("{}/abc?_state=1", upstream), 5)
+            .unwrap();
+        let session = sessions
+            .advance(&id, &format!("{}/abc?_state=2", upstream), 3)
+            .unwrap();
+        assert_eq!(session.repository, "app");
+        assert_eq!(session.offset, 8);
+        assert_eq!(sessions.get(&id).unwrap(), Some(session));
+
+        let commit_url = sessions.commit(&id, "sha256:abc").unwrap();
+        assert_eq!(
+            commit_url,
+            format!("{}/abc?_state=2&digest=sha256%3Aabc", upstream)
+        );
+        assert_eq!(sessions.get(&id).unwrap(), None);
+        assert!(matches!(
+            sessions.advance(&id, upstream, 1),
+            Err(ProxyError::BlobUploadUnknown(_))
+        ));
+    }
+}
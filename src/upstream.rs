@@ -1,35 +1,262 @@
-use crate::config::{ResolvedRepository, UpstreamAuth};
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::config::{ProxyConfig, Registry, ResolvedRepository, UpstreamAuth, UpstreamConfig};
 use crate::error::{ProxyError, Result};
+use crate::loop_guard::{instance_id, LOOP_GUARD_HEADER};
+use crate::metrics::UpstreamRequests;
+use crate::upstream_limit::{ConcurrencyLimit, Slot};
+use crate::upstream_throttle::UpstreamThrottle;
+use anyhow::Context;
 use bytes::Bytes;
-use reqwest::{header, Client, Response, StatusCode};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use rand::Rng;
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct AuthToken {
     token: Option<String>,
     access_token: Option<String>,
+    /// Seconds the token stays valid.
+    expires_in: Option<u64>,
 }
 
 pub struct UpstreamClient {
-    client: Client,
-    tokens: Arc<RwLock<HashMap<String, String>>>,
+    /// Client for registries without their own, e.g. in tests.
+    default_client: Client,
+    /// Clients keyed by registry id, carrying that registry's TLS settings.
+    clients: HashMap<String, Client>,
+    /// Like `default_client` and `clients`, but returning redirects rather
+    /// than following them.
+    default_direct_client: Client,
+    direct_clients: HashMap<String, Client>,
+    tokens: Arc<RwLock<HashMap<String, CachedToken>>>,
+    max_url_length: usize,
+    max_retries: u32,
+    retry_base_delay_ms: u64,
+    retry_max_delay: Duration,
+    request_timeout: Duration,
+    /// `Accept` header for manifest requests, in the configured preference
+    /// order.
+    manifest_accept: String,
+    host_failures: std::sync::Mutex<HashMap<String, u64>>,
+    registry_health: std::sync::Mutex<HashMap<String, RegistryHealth>>,
+    throttle: UpstreamThrottle,
+    circuit_breaker: CircuitBreaker,
+    requests: UpstreamRequests,
+    concurrency: ConcurrencyLimit,
+    /// Credentials of ECR registries, keyed by registry id.
+    #[cfg(feature = "ecr")]
+    ecr: HashMap<String, crate::ecr::EcrCredentials>,
+    /// Credentials of Google registries, keyed by registry id.
+    #[cfg(feature = "gcp")]
+    gcp: HashMap<String, crate::gcp::GcpCredentials>,
+    /// Ids of registries whose mirrors are sent the registry's credentials.
+    authenticated_mirrors: HashSet<String>,
 }
 
-impl UpstreamClient {
-    pub fn new() -> Self {
-        let client = Client::builder()
-            .user_agent("docker-registry-proxy/0.1.0")
-            .build()
-            .unwrap_or_default();
+/// How an upstream request is sent.
+#[derive(Clone, Copy)]
+struct RequestOptions<'a> {
+    /// `Accept` header to send, if any.
+    accept: Option<&'a str>,
+    /// Send a HEAD rather than a GET.
+    head: bool,
+    follow_redirects: bool,
+}
+
+impl Default for RequestOptions<'_> {
+    fn default() -> Self {
+        Self {
+            accept: None,
+            head: false,
+            follow_redirects: true,
+        }
+    }
+}
 
+impl<'a> RequestOptions<'a> {
+    fn accepting(accept: &'a str) -> Self {
         Self {
-            client,
+            accept: Some(accept),
+            ..Self::default()
+        }
+    }
+}
+
+/// One page of a tag list.
+pub struct UpstreamTags {
+    pub data: Bytes,
+    /// Query string of the next page when upstream paginated the list, from
+    /// its `Link: <...>; rel="next"` header.
+    pub next: Option<String>,
+}
+
+/// A blob response whose body has not been read yet. It keeps its slot of
+/// `max_concurrent_upstream` until the body has been read or dropped.
+pub struct UpstreamBlob {
+    response: Response,
+    slot: Slot,
+}
+
+impl UpstreamBlob {
+    pub fn content_length(&self) -> Option<u64> {
+        self.response.content_length()
+    }
+
+    pub async fn bytes(self) -> Result<Bytes> {
+        self.response.bytes().await.map_err(ProxyError::Upstream)
+    }
+
+    pub fn bytes_stream(self) -> impl Stream<Item = reqwest::Result<Bytes>> {
+        let slot = self.slot;
+        self.response.bytes_stream().map(move |chunk| {
+            let _ = &slot;
+            chunk
+        })
+    }
+}
+
+/// Where [`UpstreamClient::locate_blob`] found a blob.
+pub enum BlobLocation {
+    /// Upstream redirected to this URL.
+    Redirect(String),
+    /// Upstream serves the blob itself.
+    Served(UpstreamBlob),
+}
+
+/// An upstream token and the scopes it was issued for.
+#[derive(Clone)]
+struct CachedToken {
+    token: String,
+    scopes: BTreeSet<String>,
+    /// When the token service says the token lapses; `None` if it did not
+    /// say.
+    expires_at: Option<Instant>,
+}
+
+impl CachedToken {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+}
+
+/// How long before its stated expiry a token is replaced, so none lapses in
+/// flight.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// Tokens obtained for one upstream request before its 401 is returned, so
+/// an upstream naming a new scope in every challenge cannot keep the proxy
+/// requesting tokens.
+const MAX_TOKEN_REQUESTS: usize = 3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RegistryHealth {
+    pub healthy: bool,
+    pub last_error: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl UpstreamClient {
+    pub fn new(
+        config: &UpstreamConfig,
+        registries: &[Registry],
+        user_agent: &str,
+    ) -> anyhow::Result<Self> {
+        let mut clients = HashMap::new();
+        let mut direct_clients = HashMap::new();
+        for registry in registries {
+            let client =
+                build_client(config, Some(registry), user_agent, true).with_context(|| {
+                    format!("Failed to set up client for registry '{}'", registry.id)
+                })?;
+            clients.insert(registry.id.clone(), client);
+            let client = build_client(config, Some(registry), user_agent, false)?;
+            direct_clients.insert(registry.id.clone(), client);
+        }
+        let default_client = build_client(config, None, user_agent, true)?;
+        let default_direct_client = build_client(config, None, user_agent, false)?;
+
+        #[cfg(feature = "ecr")]
+        let ecr = registries
+            .iter()
+            .filter_map(|registry| {
+                let credentials =
+                    crate::ecr::EcrCredentials::new(registry.ecr.clone()?, default_client.clone());
+                Some((registry.id.clone(), credentials))
+            })
+            .collect();
+
+        #[cfg(feature = "gcp")]
+        let mut gcp = HashMap::new();
+        #[cfg(feature = "gcp")]
+        for registry in registries {
+            if let Some(config) = &registry.gcp {
+                let credentials = crate::gcp::GcpCredentials::new(config, default_client.clone())
+                    .with_context(|| {
+                    format!("Failed to set up gcp auth for registry '{}'", registry.id)
+                })?;
+                gcp.insert(registry.id.clone(), credentials);
+            }
+        }
+
+        Ok(Self {
+            default_client,
+            clients,
+            default_direct_client,
+            direct_clients,
             tokens: Arc::new(RwLock::new(HashMap::new())),
+            max_url_length: config.max_url_length,
+            max_retries: config.max_retries,
+            retry_base_delay_ms: config.retry_base_delay_ms,
+            retry_max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            request_timeout: Duration::from_secs(config.request_timeout_seconds),
+            manifest_accept: manifest_accept(&config.manifest_media_types),
+            host_failures: std::sync::Mutex::new(HashMap::new()),
+            registry_health: std::sync::Mutex::new(HashMap::new()),
+            throttle: UpstreamThrottle::new(
+                registries,
+                Duration::from_millis(config.throttle_max_wait_ms),
+            ),
+            circuit_breaker: CircuitBreaker::new(
+                config.circuit_failure_threshold,
+                Duration::from_secs(config.circuit_open_seconds),
+            ),
+            requests: UpstreamRequests::default(),
+            concurrency: ConcurrencyLimit::new(config.max_concurrent_upstream),
+            #[cfg(feature = "ecr")]
+            ecr,
+            #[cfg(feature = "gcp")]
+            gcp,
+            authenticated_mirrors: registries
+                .iter()
+                .filter(|registry| registry.authenticate_mirrors)
+                .map(|registry| registry.id.clone())
+                .collect(),
+        })
+    }
+
+    fn client_for(&self, repo: &ResolvedRepository) -> &Client {
+        self.clients
+            .get(&repo.registry_id)
+            .unwrap_or(&self.default_client)
+    }
+
+    fn check_url_length(&self, url: &str) -> Result<()> {
+        if url.len() > self.max_url_length {
+            return Err(ProxyError::BadRequest(format!(
+                "Upstream URL exceeds maximum length of {} bytes",
+                self.max_url_length
+            )));
         }
+        Ok(())
     }
 
     pub async fn get_manifest(
@@ -37,18 +264,31 @@ impl UpstreamClient {
         repo: &ResolvedRepository,
         reference: &str,
     ) -> Result<(Bytes, String)> {
-        let url = format!(
-            "{}/v2/{}/manifests/{}",
-            repo.registry_url, repo.upstream_name, reference
-        );
+        self.get_manifest_accepting(repo, reference, None).await
+    }
 
-        let response = self.make_authenticated_request(repo, &url, true).await?;
+    /// Fetches a manifest, sending the client's `Accept` header so artifact
+    /// types beyond images (Helm charts, WASM modules, signatures) negotiate
+    /// correctly. Without one, the configured manifest media types are sent.
+    pub async fn get_manifest_accepting(
+        &self,
+        repo: &ResolvedRepository,
+        reference: &str,
+        accept: Option<&str>,
+    ) -> Result<(Bytes, String)> {
+        let path = format!(
+            "/v2/{}/manifests/{}",
+            encode_name(&repo.upstream_name),
+            encode_segment(reference)
+        );
+        let accept = accept.unwrap_or(&self.manifest_accept);
+        let _slot = self.concurrency.acquire().await;
+        let response = self
+            .make_authenticated_request(repo, &path, RequestOptions::accepting(accept))
+            .await?;
 
         if response.status() == StatusCode::NOT_FOUND {
-            return Err(ProxyError::NotFound(format!(
-                "Manifest not found: {}",
-                reference
-            )));
+            return Err(ProxyError::ManifestUnknown(reference.to_string()));
         }
 
         let content_type = response
@@ -58,179 +298,1091 @@ impl UpstreamClient {
             .unwrap_or("application/vnd.docker.distribution.manifest.v2+json")
             .to_string();
 
-        let bytes = response
-            .bytes()
-            .await
-            .map_err(|e| ProxyError::Upstream(e))?;
+        let bytes = response.bytes().await.map_err(ProxyError::Upstream)?;
 
         Ok((bytes, content_type))
     }
 
-    pub async fn get_blob(&self, repo: &ResolvedRepository, digest: &str) -> Result<Bytes> {
-        let url = format!(
-            "{}/v2/{}/blobs/{}",
-            repo.registry_url, repo.upstream_name, digest
+    /// Starts fetching a blob. The body is left unread so callers can stream it.
+    pub async fn get_blob(&self, repo: &ResolvedRepository, digest: &str) -> Result<UpstreamBlob> {
+        let path = format!(
+            "/v2/{}/blobs/{}",
+            encode_name(&repo.upstream_name),
+            encode_segment(digest)
         );
+        let slot = self.concurrency.acquire().await;
+        let response = self
+            .make_authenticated_request(repo, &path, RequestOptions::default())
+            .await?;
 
-        let response = self.make_authenticated_request(repo, &url, false).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(ProxyError::BlobUnknown(digest.to_string()));
+        }
+
+        Ok(UpstreamBlob { response, slot })
+    }
+
+    /// Checks that a blob exists upstream with a HEAD request, returning its
+    /// size if upstream states it. A redirect (e.g. to a CDN) is not followed:
+    /// it shows that the blob exists, but not its size.
+    pub async fn head_blob(&self, repo: &ResolvedRepository, digest: &str) -> Result<Option<u64>> {
+        let path = format!(
+            "/v2/{}/blobs/{}",
+            encode_name(&repo.upstream_name),
+            encode_segment(digest)
+        );
+        let options = RequestOptions {
+            head: true,
+            follow_redirects: false,
+            ..RequestOptions::default()
+        };
+        let _slot = self.concurrency.acquire().await;
+        let response = self
+            .make_authenticated_request(repo, &path, options)
+            .await?;
 
         if response.status() == StatusCode::NOT_FOUND {
-            return Err(ProxyError::NotFound(format!("Blob not found: {}", digest)));
+            return Err(ProxyError::BlobUnknown(digest.to_string()));
+        }
+        if response.status().is_redirection() {
+            return Ok(None);
         }
+        let response = response.error_for_status().map_err(ProxyError::Upstream)?;
+        // The body of a HEAD response is empty, so the length can only come
+        // from the header.
+        Ok(response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok()))
+    }
 
-        response.bytes().await.map_err(|e| ProxyError::Upstream(e))
+    /// Finds where a blob is served from without downloading it. The GET is
+    /// sent without following redirects: a redirect (e.g. to a CDN or object
+    /// store) yields its target, while a blob upstream serves itself is
+    /// returned for streaming.
+    pub async fn locate_blob(
+        &self,
+        repo: &ResolvedRepository,
+        digest: &str,
+    ) -> Result<BlobLocation> {
+        let path = format!(
+            "/v2/{}/blobs/{}",
+            encode_name(&repo.upstream_name),
+            encode_segment(digest)
+        );
+        let options = RequestOptions {
+            follow_redirects: false,
+            ..RequestOptions::default()
+        };
+        let slot = self.concurrency.acquire().await;
+        let response = self
+            .make_authenticated_request(repo, &path, options)
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(ProxyError::BlobUnknown(digest.to_string()));
+        }
+        if !response.status().is_redirection() {
+            return Ok(BlobLocation::Served(UpstreamBlob { response, slot }));
+        }
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| response.url().join(value).ok())
+            .ok_or_else(|| {
+                ProxyError::Internal(format!(
+                    "Upstream redirected blob {} without a valid Location",
+                    digest
+                ))
+            })?;
+        Ok(BlobLocation::Redirect(location.to_string()))
     }
 
-    pub async fn get_tags(&self, repo: &ResolvedRepository) -> Result<Bytes> {
-        let url = format!("{}/v2/{}/tags/list", repo.registry_url, repo.upstream_name);
+    /// Fetches the referrers index of `digest`, filtered by artifact type if
+    /// given. Returns `None` when the upstream does not support the referrers
+    /// API and answers 404.
+    pub async fn get_referrers(
+        &self,
+        repo: &ResolvedRepository,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Option<Bytes>> {
+        let mut path = format!(
+            "/v2/{}/referrers/{}",
+            encode_name(&repo.upstream_name),
+            encode_segment(digest)
+        );
+        if let Some(artifact_type) = artifact_type {
+            let mut url = reqwest::Url::parse("http://referrers/").expect("valid URL");
+            url.query_pairs_mut()
+                .append_pair("artifactType", artifact_type);
+            path = format!("{}?{}", path, url.query().unwrap_or_default());
+        }
+        let _slot = self.concurrency.acquire().await;
+        let response = self
+            .make_authenticated_request(repo, &path, RequestOptions::default())
+            .await?;
 
-        let response = self.make_authenticated_request(repo, &url, false).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status().map_err(ProxyError::Upstream)?;
 
-        response.bytes().await.map_err(|e| ProxyError::Upstream(e))
+        response
+            .bytes()
+            .await
+            .map(Some)
+            .map_err(ProxyError::Upstream)
     }
 
+    /// Fetches the tag list, passing on the `n` and `last` pagination
+    /// parameters when given. `None` if upstream does not know the
+    /// repository.
+    pub async fn get_tags(
+        &self,
+        repo: &ResolvedRepository,
+        n: Option<u32>,
+        last: Option<&str>,
+    ) -> Result<Option<UpstreamTags>> {
+        let mut path = format!("/v2/{}/tags/list", encode_name(&repo.upstream_name));
+        if n.is_some() || last.is_some() {
+            let mut url = reqwest::Url::parse("http://tags/").expect("valid URL");
+            {
+                let mut query = url.query_pairs_mut();
+                if let Some(n) = n {
+                    query.append_pair("n", &n.to_string());
+                }
+                if let Some(last) = last {
+                    query.append_pair("last", last);
+                }
+            }
+            path = format!("{}?{}", path, url.query().unwrap_or_default());
+        }
+        let _slot = self.concurrency.acquire().await;
+        let response = self
+            .make_authenticated_request(repo, &path, RequestOptions::default())
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status().map_err(ProxyError::Upstream)?;
+        let next = response
+            .headers()
+            .get_all(header::LINK)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(next_page_query);
+        let data = response.bytes().await.map_err(ProxyError::Upstream)?;
+
+        Ok(Some(UpstreamTags { data, next }))
+    }
+
+    /// Requests `path` from the repository's registry or its mirrors. An
+    /// upstream 429 becomes `RateLimited` and a 5xx `UpstreamStatus`, passing
+    /// the status and its `Retry-After` on to the client.
     async fn make_authenticated_request(
         &self,
         repo: &ResolvedRepository,
-        url: &str,
-        include_manifest_headers: bool,
+        path: &str,
+        options: RequestOptions<'_>,
     ) -> Result<Response> {
-        let mut request = self.client.get(url);
+        self.circuit_breaker.check(&repo.registry_id)?;
+        let started = Instant::now();
+        let outcome = self.request_with_failover(repo, path, options).await;
+        let success = match &outcome {
+            Ok(response) => Some(!response.status().is_server_error()),
+            Err(ProxyError::Upstream(_) | ProxyError::GatewayTimeout(_)) => Some(false),
+            // Refused locally, without reaching the registry.
+            Err(_) => None,
+        };
+        if let Some(success) = success {
+            self.circuit_breaker.record(&repo.registry_id, success);
+            self.requests.record(repo, success, started.elapsed());
+        }
 
-        if include_manifest_headers {
-            request = request
-                .header(
-                    header::ACCEPT,
-                    "application/vnd.docker.distribution.manifest.v2+json",
-                )
-                .header(
-                    header::ACCEPT,
-                    "application/vnd.docker.distribution.manifest.list.v2+json",
-                )
-                .header(header::ACCEPT, "application/vnd.oci.image.manifest.v1+json")
-                .header(header::ACCEPT, "application/vnd.oci.image.index.v1+json");
+        // Failures with credentials supplied by a caller say nothing about
+        // the registry's own configuration.
+        if !repo.caller_credentials {
+            self.record_health(&repo.registry_id, &outcome);
         }
 
-        let cache_key = format!("{}:{}", repo.registry_url, repo.upstream_name);
+        let response = outcome?;
+        let status = response.status();
+        let retry_after =
+            || parse_retry_after(response.headers()).map(|delay| delay.as_secs_f64().ceil() as u64);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = retry_after();
+            warn!(
+                "Registry {} is rate limiting requests (retry after {:?}s)",
+                repo.registry_id, retry_after
+            );
+            return Err(ProxyError::RateLimited(retry_after));
+        }
+        if status.is_server_error() {
+            return Err(ProxyError::UpstreamStatus {
+                status: status.as_u16(),
+                retry_after: retry_after(),
+            });
+        }
+        Ok(response)
+    }
 
-        {
-            let tokens = self.tokens.read().await;
-            if let Some(token) = tokens.get(&cache_key) {
-                request = request.bearer_auth(token);
+    fn record_health(&self, registry_id: &str, outcome: &Result<Response>) {
+        let last_error = match outcome {
+            Ok(response) if response.status().is_server_error() => {
+                Some(format!("Upstream returned {}", response.status()))
             }
+            Ok(_) => None,
+            // Rejected locally, before the registry was contacted.
+            Err(ProxyError::BadRequest(_)) => return,
+            Err(e) => Some(e.to_string()),
+        };
+
+        if let Some(error) = &last_error {
+            warn!("Registry {} is unhealthy: {}", registry_id, error);
         }
+        self.registry_health.lock().unwrap().insert(
+            registry_id.to_string(),
+            RegistryHealth {
+                healthy: last_error.is_none(),
+                last_error,
+                checked_at: Utc::now(),
+            },
+        );
+    }
 
-        let response = request.send().await?;
+    /// Probes the registry's `/v2/` endpoint, trying its mirrors in turn. Any
+    /// answer short of a 5xx, including 401, shows the registry is reachable.
+    pub async fn ping(&self, registry: &Registry) -> Result<()> {
+        let client = self
+            .clients
+            .get(&registry.id)
+            .unwrap_or(&self.default_client);
+        let timeout = registry
+            .request_timeout_seconds
+            .map_or(self.request_timeout, Duration::from_secs);
+        let mut last_error = String::new();
+
+        for base_url in std::iter::once(&registry.url).chain(&registry.mirrors) {
+            let url = format!("{}/v2/", base_url.trim_end_matches('/'));
+            last_error = match tokio::time::timeout(timeout, client.get(&url).send()).await {
+                Ok(Ok(response)) if !response.status().is_server_error() => return Ok(()),
+                Ok(Ok(response)) => format!("{} returned {}", url, response.status()),
+                Ok(Err(e)) => format!("{}: {}", url, e),
+                Err(_) => format!("{} did not respond within {:?}", url, timeout),
+            };
+            warn!("Ping of registry {} failed: {}", registry.id, last_error);
+        }
+        Err(ProxyError::ServiceUnavailable(format!(
+            "Registry {} is unreachable: {}",
+            registry.id, last_error
+        )))
+    }
 
-        if response.status() == StatusCode::UNAUTHORIZED {
-            debug!("Received 401, attempting authentication");
+    /// Health of each registry as of its most recent request. Registries that
+    /// have not been contacted yet are absent.
+    pub fn registry_health(&self) -> HashMap<String, RegistryHealth> {
+        self.registry_health.lock().unwrap().clone()
+    }
 
-            if let Some(auth_header) = response.headers().get(header::WWW_AUTHENTICATE) {
-                let auth_str = auth_header
-                    .to_str()
-                    .map_err(|_| ProxyError::Internal("Invalid WWW-Authenticate header".into()))?;
+    /// Requests `path` from the registry, failing over to each mirror in turn
+    /// when a host cannot be reached or answers with a 5xx.
+    async fn request_with_failover(
+        &self,
+        repo: &ResolvedRepository,
+        path: &str,
+        options: RequestOptions<'_>,
+    ) -> Result<Response> {
+        let hosts: Vec<&String> = std::iter::once(&repo.registry_url)
+            .chain(&repo.mirrors)
+            .collect();
+        let mut last_outcome = None;
+
+        for (index, base_url) in hosts.iter().enumerate() {
+            let url = format!("{}{}", base_url, path);
+            self.check_url_length(&url)?;
 
-                let token = self.authenticate(auth_str, repo.auth.as_ref()).await?;
+            let outcome = self.request_from_host(repo, base_url, &url, options).await;
+            let failed = match &outcome {
+                Ok(response) => response.status().is_server_error(),
+                Err(ProxyError::Upstream(_) | ProxyError::GatewayTimeout(_)) => true,
+                Err(_) => false,
+            };
 
-                {
-                    let mut tokens = self.tokens.write().await;
-                    tokens.insert(cache_key, token.clone());
+            if !failed {
+                if index == 0 {
+                    debug!("Upstream {} served {}", base_url, path);
+                } else {
+                    info!("Mirror {} served {}", base_url, path);
                 }
+                return outcome;
+            }
+
+            *self
+                .host_failures
+                .lock()
+                .unwrap()
+                .entry(base_url.to_string())
+                .or_default() += 1;
+            if index + 1 < hosts.len() {
+                warn!(
+                    "Upstream {} failed for {}, trying next mirror",
+                    base_url, path
+                );
+            }
+            last_outcome = Some(outcome);
+        }
 
-                let mut retry_request = self.client.get(url).bearer_auth(&token);
+        last_outcome.expect("the primary registry URL is always tried")
+    }
 
-                if include_manifest_headers {
-                    retry_request = retry_request
-                        .header(
-                            header::ACCEPT,
-                            "application/vnd.docker.distribution.manifest.v2+json",
-                        )
-                        .header(
-                            header::ACCEPT,
-                            "application/vnd.docker.distribution.manifest.list.v2+json",
-                        )
-                        .header(header::ACCEPT, "application/vnd.oci.image.manifest.v1+json")
-                        .header(header::ACCEPT, "application/vnd.oci.image.index.v1+json");
-                }
+    /// Circuit state and number of times it opened, per registry id.
+    pub fn circuits(&self) -> Vec<(String, CircuitState, u64)> {
+        self.circuit_breaker.circuits()
+    }
+
+    pub fn circuit_state(&self, registry_id: &str) -> CircuitState {
+        self.circuit_breaker.state(registry_id)
+    }
+
+    /// Upstream fetches holding a slot of `max_concurrent_upstream`.
+    pub fn in_flight(&self) -> usize {
+        self.concurrency.in_flight()
+    }
+
+    /// Upstream fetches waiting for a slot of `max_concurrent_upstream`.
+    pub fn queued(&self) -> usize {
+        self.concurrency.queued()
+    }
+
+    /// Requests that reached a registry, per registry and repository mapping.
+    pub fn requests(&self) -> &UpstreamRequests {
+        &self.requests
+    }
+
+    /// Requests delayed or refused by registry rate limits, per registry id.
+    pub fn throttled_requests(&self) -> Vec<(String, u64)> {
+        self.throttle.throttled()
+    }
+
+    /// Failed requests per registry URL (primary or mirror), sorted by URL.
+    pub fn host_failures(&self) -> Vec<(String, u64)> {
+        let mut failures: Vec<_> = self
+            .host_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(host, count)| (host.clone(), *count))
+            .collect();
+        failures.sort();
+        failures
+    }
+
+    async fn request_from_host(
+        &self,
+        repo: &ResolvedRepository,
+        base_url: &str,
+        url: &str,
+        options: RequestOptions<'_>,
+    ) -> Result<Response> {
+        // Mirrors may be run by someone else, so the registry's credentials
+        // only go to them when configured to.
+        let credentialed =
+            base_url == repo.registry_url || self.authenticated_mirrors.contains(&repo.registry_id);
+        let credential_for =
+            |scopes: &BTreeSet<String>| credentialed.then(|| repo.credential_for(scopes)).flatten();
+        let pull_scopes = pull_scopes(repo);
+        let mut held = self
+            .cached_token(&token_cache_key(
+                base_url,
+                credential_for(&pull_scopes),
+                &pull_scopes,
+            ))
+            .await;
+        let timeout = repo
+            .request_timeout_seconds
+            .map_or(self.request_timeout, Duration::from_secs);
 
-                return Ok(retry_request.send().await?);
+        // ECR takes basic auth on every request rather than issuing tokens.
+        #[cfg(feature = "ecr")]
+        if let Some(ecr) = self.ecr.get(&repo.registry_id) {
+            if credentialed && !repo.caller_credentials {
+                let auth = ecr.credential().await?;
+                return self
+                    .send_with_retry(&repo.registry_id, timeout, || {
+                        self.build_request(repo, url, options, None)
+                            .basic_auth(&auth.username, Some(&auth.password))
+                    })
+                    .await;
             }
         }
 
+        let send = |token: Option<String>| {
+            self.send_with_retry(&repo.registry_id, timeout, move || {
+                self.build_request(repo, url, options, token.as_deref())
+            })
+        };
+
+        let mut response = send(held.as_ref().map(|held| held.token.clone())).await?;
+        let mut authenticated = false;
+        let mut token_requests = 0;
+
+        // A token can be refused for lacking a scope the first challenge did
+        // not ask for, e.g. a layer living in another repository. The new
+        // challenge's scopes are then added to those already held.
+        while response.status() == StatusCode::UNAUTHORIZED {
+            let Some(auth_header) = response.headers().get(header::WWW_AUTHENTICATE) else {
+                break;
+            };
+            let auth_str = auth_header
+                .to_str()
+                .map_err(|_| ProxyError::Internal("Invalid WWW-Authenticate header".into()))?;
+            let challenge = parse_www_authenticate(auth_str)?;
+
+            let mut scopes = held
+                .as_ref()
+                .map(|held| held.scopes.clone())
+                .unwrap_or_default();
+            let held_count = scopes.len();
+            scopes.extend(challenge.scopes.iter().cloned());
+            let widened = scopes.len() > held_count;
+            if authenticated && !widened {
+                debug!("Upstream refused a token already covering its challenge");
+                break;
+            }
+
+            let challenge_scopes = challenge.scopes.iter().cloned().collect();
+            let cache_key = token_cache_key(base_url, credential_for(&challenge_scopes), &scopes);
+            let cached = self
+                .cached_token(&cache_key)
+                .await
+                .filter(|cached| Some(&cached.token) != held.as_ref().map(|held| &held.token));
+            let token = match cached {
+                Some(cached) => {
+                    authenticated = false;
+                    cached
+                }
+                None => {
+                    if token_requests == MAX_TOKEN_REQUESTS {
+                        warn!(
+                            "Upstream still refused access after {} token requests",
+                            token_requests
+                        );
+                        break;
+                    }
+                    token_requests += 1;
+                    debug!("Received 401, authenticating for scopes {:?}", scopes);
+                    let token = self
+                        .authenticate(repo, &challenge, scopes, credentialed)
+                        .await?;
+                    self.tokens.write().await.insert(cache_key, token.clone());
+                    authenticated = true;
+                    token
+                }
+            };
+
+            response = send(Some(token.token.clone())).await?;
+            held = Some(token);
+        }
+
         Ok(response)
     }
 
-    async fn authenticate(
+    async fn cached_token(&self, cache_key: &str) -> Option<CachedToken> {
+        self.tokens
+            .read()
+            .await
+            .get(cache_key)
+            .filter(|cached| !cached.is_expired())
+            .cloned()
+    }
+
+    fn build_request(
         &self,
-        www_authenticate: &str,
-        upstream_auth: Option<&UpstreamAuth>,
-    ) -> Result<String> {
-        let params = parse_www_authenticate(www_authenticate)?;
+        repo: &ResolvedRepository,
+        url: &str,
+        options: RequestOptions<'_>,
+        token: Option<&str>,
+    ) -> RequestBuilder {
+        let client = if options.follow_redirects {
+            self.client_for(repo)
+        } else {
+            self.direct_clients
+                .get(&repo.registry_id)
+                .unwrap_or(&self.default_direct_client)
+        };
+        let method = if options.head {
+            reqwest::Method::HEAD
+        } else {
+            reqwest::Method::GET
+        };
+        let mut request = client.request(method, url);
+
+        if let Some(accept) = options.accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+
+        request
+    }
+
+    /// Sends an idempotent request, retrying network errors and transient 5xx
+    /// responses with exponential backoff and jitter. A `Retry-After` header
+    /// from the upstream takes precedence over the computed delay, unless it
+    /// asks for more than `retry_max_delay_ms`: then the response is returned
+    /// rather than holding the client that long. Timeouts
+    /// are not retried, as a hung upstream would hold the client for several
+    /// times the timeout. Every attempt counts against the registry's rate
+    /// limit.
+    async fn send_with_retry(
+        &self,
+        registry_id: &str,
+        timeout: Duration,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response> {
+        let mut attempt = 0;
 
-        let realm = params
+        loop {
+            self.throttle.acquire(registry_id).await?;
+            let outcome = match tokio::time::timeout(timeout, build().send()).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    return Err(ProxyError::GatewayTimeout(format!(
+                        "Upstream did not respond within {} seconds",
+                        timeout.as_secs()
+                    )))
+                }
+            };
+
+            let retry_after = match &outcome {
+                Ok(response) if !is_retryable_status(response.status()) => {
+                    return outcome.map_err(upstream_error);
+                }
+                Ok(response) => parse_retry_after(response.headers()),
+                Err(e) if !is_retryable_error(e) => return outcome.map_err(upstream_error),
+                Err(_) => None,
+            };
+
+            if attempt >= self.max_retries
+                || retry_after.is_some_and(|delay| delay > self.retry_max_delay)
+            {
+                return outcome.map_err(upstream_error);
+            }
+            attempt += 1;
+
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+            match &outcome {
+                Ok(response) => debug!(
+                    "Upstream returned {}, retry {} in {:?}",
+                    response.status(),
+                    attempt,
+                    delay
+                ),
+                Err(e) => debug!(
+                    "Upstream request failed: {}, retry {} in {:?}",
+                    e, attempt, delay
+                ),
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Exponential backoff with "equal jitter": half the delay is fixed and
+    /// the other half random, so concurrent retries spread out.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .retry_base_delay_ms
+            .saturating_mul(1 << (attempt - 1).min(16));
+        let half = delay / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0..=delay - half))
+            .min(self.retry_max_delay)
+    }
+
+    /// Obtains a token from the challenge's realm covering all of `scopes`.
+    /// The credential is selected by the challenge's own scopes, so scopes
+    /// carried over from an earlier token do not change which one is used.
+    /// A credential with a refresh token is exchanged through the OAuth2
+    /// `refresh_token` grant; otherwise the token is fetched with basic auth
+    /// and each scope sent as its own `scope` parameter. Without
+    /// `credentialed`, the token is requested anonymously.
+    async fn authenticate(
+        &self,
+        repo: &ResolvedRepository,
+        challenge: &Challenge,
+        scopes: BTreeSet<String>,
+        credentialed: bool,
+    ) -> Result<CachedToken> {
+        let realm = challenge
             .get("realm")
             .ok_or_else(|| ProxyError::Internal("WWW-Authenticate header missing realm".into()))?;
 
         let mut auth_url = reqwest::Url::parse(realm)
             .map_err(|_| ProxyError::Internal("Invalid realm URL".into()))?;
 
-        if let Some(service) = params.get("service") {
-            auth_url.query_pairs_mut().append_pair("service", service);
-        }
-
-        if let Some(scope) = params.get("scope") {
-            auth_url.query_pairs_mut().append_pair("scope", scope);
-        }
-
-        let mut request = self.client.get(auth_url);
+        #[cfg(feature = "gcp")]
+        let gcp_credential = match self.gcp.get(&repo.registry_id) {
+            _ if !credentialed => None,
+            Some(_) if !crate::gcp::accepts_realm(&auth_url, &repo.registry_url) => {
+                warn!(
+                    "Not sending Google credentials of registry {} to token realm {}",
+                    repo.registry_id, realm
+                );
+                None
+            }
+            Some(gcp) if !repo.caller_credentials => Some(gcp.credential().await?),
+            _ => None,
+        };
+        let challenge_scopes = challenge.scopes.iter().cloned().collect();
+        #[cfg(feature = "gcp")]
+        let credential = gcp_credential
+            .as_ref()
+            .or_else(|| repo.credential_for(&challenge_scopes))
+            .filter(|_| credentialed);
+        #[cfg(not(feature = "gcp"))]
+        let credential = repo
+            .credential_for(&challenge_scopes)
+            .filter(|_| credentialed);
+        let request = match credential.and_then(|auth| Some((auth, auth.refresh_token.as_ref()?))) {
+            Some((auth, refresh_token)) => {
+                let scope = scopes
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let mut form = vec![
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token.as_str()),
+                    (
+                        "client_id",
+                        auth.client_id.as_deref().unwrap_or(env!("CARGO_PKG_NAME")),
+                    ),
+                    ("scope", scope.as_str()),
+                ];
+                if let Some(service) = challenge.get("service") {
+                    form.push(("service", service));
+                }
+                self.client_for(repo).post(auth_url).form(&form)
+            }
+            None => {
+                if let Some(service) = challenge.get("service") {
+                    auth_url.query_pairs_mut().append_pair("service", service);
+                }
+                for scope in &scopes {
+                    auth_url.query_pairs_mut().append_pair("scope", scope);
+                }
 
-        if let Some(auth) = upstream_auth {
-            request = request.basic_auth(&auth.username, Some(&auth.password));
-        }
+                let mut request = self.client_for(repo).get(auth_url);
+                if let Some(auth) = credential {
+                    request = request.basic_auth(&auth.username, Some(&auth.password));
+                }
+                request
+            }
+        };
 
         let response = request.send().await?;
 
         if !response.status().is_success() {
-            return Err(ProxyError::Internal(format!(
-                "Authentication failed with status: {}",
-                response.status()
-            )));
+            let mut message = format!("Authentication failed with status: {}", response.status());
+            if let Some(detail) = challenge.error_detail() {
+                message = format!("{} (upstream challenge: {})", message, detail);
+            }
+            return Err(ProxyError::Internal(message));
         }
 
         let auth_response: AuthToken = response.json().await?;
 
-        auth_response
+        let token = auth_response
             .token
             .or(auth_response.access_token)
-            .ok_or_else(|| ProxyError::Internal("No token in auth response".into()))
+            .ok_or_else(|| ProxyError::Internal("No token in auth response".into()))?;
+        let expires_at = auth_response.expires_in.map(|seconds| {
+            Instant::now() + Duration::from_secs(seconds).saturating_sub(TOKEN_EXPIRY_MARGIN)
+        });
+        Ok(CachedToken {
+            token,
+            scopes,
+            expires_at,
+        })
+    }
+}
+
+/// Builds an `Accept` value ranking `media_types` in order with descending
+/// quality values, so upstreams that negotiate pick the earliest one they
+/// support.
+fn manifest_accept(media_types: &[String]) -> String {
+    media_types
+        .iter()
+        .enumerate()
+        .map(|(rank, media_type)| match rank {
+            0 => media_type.clone(),
+            _ => {
+                let quality = 1.0 - 0.1 * rank.min(9) as f64;
+                format!("{};q={:.1}", media_type, quality)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Scope of the token a pull from `repo` is first attempted with.
+fn pull_scopes(repo: &ResolvedRepository) -> BTreeSet<String> {
+    BTreeSet::from([format!("repository:{}:pull", repo.upstream_name)])
+}
+
+/// Tokens are cached per host, selected credential and scope set, so mirrors
+/// and callers supplying their own upstream credentials never share tokens,
+/// and a token widened for a cross-repository request does not replace the
+/// one covering the repository alone.
+fn token_cache_key(
+    base_url: &str,
+    credential: Option<&UpstreamAuth>,
+    scopes: &BTreeSet<String>,
+) -> String {
+    let identity = match credential {
+        None => "anonymous".to_string(),
+        Some(auth) => {
+            let mut hasher = Sha256::new();
+            hasher.update(format!(
+                "{}:{}:{}",
+                auth.username,
+                auth.password,
+                auth.refresh_token.as_deref().unwrap_or_default()
+            ));
+            hex::encode(&hasher.finalize()[..8])
+        }
+    };
+    let scopes = scopes.iter().map(String::as_str).collect::<Vec<_>>();
+    format!("{}:{}:{}", base_url, identity, scopes.join(" "))
+}
+
+/// Query string of the `rel="next"` target in a `Link` header, such as
+/// `n=100&last=3.19` from `</v2/library/alpine/tags/list?n=100&last=3.19>;
+/// rel="next"`. Only the query is kept, as the path names the upstream
+/// repository.
+fn next_page_query(link: &str) -> Option<String> {
+    link.split(',').find_map(|link| {
+        let (target, params) = link.trim().strip_prefix('<')?.split_once('>')?;
+        params
+            .split(';')
+            .any(|param| matches!(param.replace(' ', "").as_str(), "rel=\"next\"" | "rel=next"))
+            .then(|| target.split_once('?').map(|(_, query)| query.to_string()))
+            .flatten()
+    })
+}
+
+/// Builds a client with the transport settings of `registry`, or the global
+/// ones without it. Without `follow_redirects`, redirects are returned as
+/// responses.
+fn build_client(
+    config: &UpstreamConfig,
+    registry: Option<&Registry>,
+    user_agent: &str,
+    follow_redirects: bool,
+) -> anyhow::Result<Client> {
+    let mut default_headers = header::HeaderMap::new();
+    default_headers.insert(
+        LOOP_GUARD_HEADER,
+        header::HeaderValue::from_static(instance_id()),
+    );
+
+    let mut builder = Client::builder()
+        .user_agent(user_agent)
+        .default_headers(default_headers)
+        .connect_timeout(Duration::from_secs(config.connect_timeout_seconds));
+
+    if !follow_redirects {
+        builder = builder.redirect(reqwest::redirect::Policy::none());
+    }
+
+    // Without any proxy configured, reqwest applies the proxy environment
+    // variables itself.
+    let proxy = registry
+        .and_then(|r| r.proxy.as_ref())
+        .or(config.proxy.as_ref());
+    if let Some(proxy) = proxy {
+        for proxy in build_proxies(proxy)? {
+            builder = builder.proxy(proxy);
+        }
+    }
+
+    if let Some(registry) = registry {
+        builder = builder.https_only(!registry.allow_http);
+
+        if let Some(max_idle) = registry.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(seconds) = registry.pool_idle_timeout_seconds {
+            builder = builder.pool_idle_timeout(Duration::from_secs(seconds));
+        }
+
+        if let Some(path) = &registry.ca_certificate {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid CA certificate {}", path.display()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        if let Some(tls) = &registry.tls {
+            let certificate = std::fs::read(&tls.client_certificate).with_context(|| {
+                format!(
+                    "Failed to read client certificate {}",
+                    tls.client_certificate.display()
+                )
+            })?;
+            let key = std::fs::read(&tls.client_key).with_context(|| {
+                format!("Failed to read client key {}", tls.client_key.display())
+            })?;
+            let identity =
+                reqwest::Identity::from_pkcs8_pem(&certificate, &key).with_context(|| {
+                    format!(
+                        "Invalid client certificate {} or key {}",
+                        tls.client_certificate.display(),
+                        tls.client_key.display()
+                    )
+                })?;
+            builder = builder.identity(identity);
+        }
+
+        if registry.insecure_skip_tls_verify {
+            if config.allow_insecure_tls {
+                warn!(
+                    "TLS certificate verification is disabled for registry '{}'",
+                    registry.id
+                );
+                builder = builder.danger_accept_invalid_certs(true);
+            } else {
+                warn!(
+                    "Ignoring insecure_skip_tls_verify for registry '{}': upstream.allow_insecure_tls is not set",
+                    registry.id
+                );
+            }
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Percent-encodes a path segment of an upstream URL, so a tag or digest
+/// cannot add segments, a query or a fragment. `:` is left as is for digests.
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~' | b':') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Percent-encodes each `/`-separated component of a repository name.
+fn encode_name(name: &str) -> String {
+    name.split('/')
+        .map(encode_segment)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Builds the per-scheme proxies for `config`. Configuring any proxy turns
+/// off reqwest's own environment lookup, so unset schemes and the bypass list
+/// are taken from the environment here.
+fn build_proxies(config: &ProxyConfig) -> anyhow::Result<Vec<reqwest::Proxy>> {
+    let no_proxy = match &config.no_proxy {
+        Some(list) => reqwest::NoProxy::from_string(list),
+        None => reqwest::NoProxy::from_env(),
+    };
+
+    let http = config
+        .http_proxy
+        .clone()
+        .or_else(|| env_proxy("HTTP_PROXY"));
+    let https = config
+        .https_proxy
+        .clone()
+        .or_else(|| env_proxy("HTTPS_PROXY"));
+
+    let mut proxies = Vec::new();
+    if let Some(url) = http {
+        proxies.push(reqwest::Proxy::http(&url).context("Invalid http_proxy URL")?);
+    }
+    if let Some(url) = https {
+        proxies.push(reqwest::Proxy::https(&url).context("Invalid https_proxy URL")?);
+    }
+
+    Ok(proxies
+        .into_iter()
+        .map(|proxy| {
+            let proxy = match &config.auth {
+                Some(auth) => proxy.basic_auth(&auth.username, &auth.password),
+                None => proxy,
+            };
+            proxy.no_proxy(no_proxy.clone())
+        })
+        .collect())
+}
+
+fn env_proxy(name: &str) -> Option<String> {
+    std::env::var(name)
+        .or_else(|_| std::env::var(name.to_lowercase()))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn is_retryable_error(error: &reqwest::Error) -> bool {
+    !error.is_timeout() && (error.is_connect() || error.is_request())
+}
+
+fn upstream_error(error: reqwest::Error) -> ProxyError {
+    if error.is_timeout() {
+        ProxyError::GatewayTimeout(error.to_string())
+    } else {
+        ProxyError::Upstream(error)
+    }
+}
+
+/// Parses `Retry-After` as either delay-seconds or an HTTP date.
+fn parse_retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Parameters of a `Bearer` challenge. Names are case-insensitive and kept
+/// lowercased; `scope` may be repeated, so its values are collected apart.
+#[derive(Debug, Default)]
+struct Challenge {
+    params: HashMap<String, String>,
+    scopes: Vec<String>,
+}
+
+impl Challenge {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.params.is_empty() && self.scopes.is_empty()
+    }
+
+    /// The challenge's `error` and `error_description`, e.g.
+    /// `insufficient_scope: authentication required`.
+    fn error_detail(&self) -> Option<String> {
+        match (self.get("error"), self.get("error_description")) {
+            (Some(error), Some(description)) => Some(format!("{}: {}", error, description)),
+            (Some(error), None) => Some(error.to_string()),
+            (None, Some(description)) => Some(description.to_string()),
+            (None, None) => None,
+        }
     }
 }
 
-fn parse_www_authenticate(header: &str) -> Result<HashMap<String, String>> {
-    let mut params = HashMap::new();
+/// Parses the auth-params of a `Bearer` challenge (RFC 9110). Quoted values
+/// may contain commas and backslash escapes. Other schemes yield nothing.
+fn parse_www_authenticate(header: &str) -> Result<Challenge> {
+    let mut challenge = Challenge::default();
 
     let header = header.trim();
-    if !header.starts_with("Bearer ") {
-        return Ok(params);
+    let Some((scheme, params)) = header.split_once(' ') else {
+        return Ok(challenge);
+    };
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return Ok(challenge);
     }
 
-    let params_str = &header[7..];
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        let name: String =
+            std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ',')).collect();
+        if name.is_empty() && chars.peek().is_none() {
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            // A bare token without a value; nothing to record.
+            continue;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value.extend(std::iter::from_fn(|| chars.next_if(|c| *c != ',')));
+            value = value.trim_end().to_string();
+        }
 
-    for part in params_str.split(',') {
-        let part = part.trim();
-        if let Some(eq_pos) = part.find('=') {
-            let key = part[..eq_pos].trim().to_string();
-            let value = part[eq_pos + 1..].trim().trim_matches('"').to_string();
-            params.insert(key, value);
+        let name = name.trim().to_ascii_lowercase();
+        if name == "scope" {
+            challenge.scopes.push(value);
+        } else if !name.is_empty() {
+            challenge.params.insert(name, value);
         }
     }
 
-    Ok(params)
+    Ok(challenge)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::DEFAULT_USER_AGENT;
+
+    #[tokio::test]
+    async fn test_user_agent_sent_upstream() {
+        use axum::http::{header, HeaderMap};
+
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(|headers: HeaderMap| async move {
+                headers[header::USER_AGENT].to_str().unwrap().to_string()
+            }),
+        );
+        let url = crate::test_support::spawn_upstream(router).await;
+        let fetch_with = |user_agent: &str| {
+            let client = UpstreamClient::new(&UpstreamConfig::default(), &[], user_agent).unwrap();
+            let repo = local_repo(url.clone());
+            async move { client.get_manifest(&repo, "latest").await.unwrap().0 }
+        };
+
+        assert_eq!(
+            fetch_with(DEFAULT_USER_AGENT).await,
+            format!("docker-registry-proxy/{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(fetch_with("mirror-bot/2.1").await, "mirror-bot/2.1");
+    }
 
     #[test]
     fn test_parse_www_authenticate() {
@@ -239,9 +1391,448 @@ mod tests {
 
         assert_eq!(params.get("realm").unwrap(), "https://auth.docker.io/token");
         assert_eq!(params.get("service").unwrap(), "registry.docker.io");
+        assert_eq!(params.scopes, ["repository:library/alpine:pull"]);
+    }
+
+    #[test]
+    fn test_parse_www_authenticate_error_and_quoted_commas() {
+        let header = r#"Bearer realm="https://auth.example.com/token",service="registry",scope="repository:app:pull,push",scope="repository:base:pull",error="insufficient_scope",error_description="needs \"push\", too""#;
+        let challenge = parse_www_authenticate(header).unwrap();
+
+        assert_eq!(
+            challenge.scopes,
+            ["repository:app:pull,push", "repository:base:pull"]
+        );
+        assert_eq!(
+            challenge.error_detail().unwrap(),
+            r#"insufficient_scope: needs "push", too"#
+        );
+
+        let challenge =
+            parse_www_authenticate("bearer Realm=https://auth.example.com , error=invalid_token")
+                .unwrap();
+        assert_eq!(challenge.get("realm").unwrap(), "https://auth.example.com");
+        assert_eq!(challenge.error_detail().unwrap(), "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn test_token_widened_when_second_challenge_adds_scope() {
+        use axum::extract::RawQuery;
+        use axum::http::{header, HeaderMap, StatusCode};
+        use axum::response::IntoResponse;
+
+        let token_queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = token_queries.clone();
+        let challenge = |host: &str, scope: &str| {
+            format!(
+                r#"Bearer realm="http://{}/token",service="test",scope="{}",error="insufficient_scope""#,
+                host, scope
+            )
+        };
+        let router = axum::Router::new()
+            .route(
+                "/token",
+                axum::routing::get(move |RawQuery(query): RawQuery| async move {
+                    let query = query.unwrap_or_default();
+                    recorded.lock().unwrap().push(query.clone());
+                    let token = if query.contains("base") {
+                        "wide"
+                    } else {
+                        "narrow"
+                    };
+                    axum::Json(serde_json::json!({ "token": token }))
+                }),
+            )
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(move |headers: HeaderMap| async move {
+                    let host = headers[header::HOST].to_str().unwrap().to_string();
+                    let scope = match headers.get(header::AUTHORIZATION) {
+                        Some(value) if value == "Bearer wide" => return "{}".into_response(),
+                        Some(value) if value == "Bearer narrow" => "repository:library/base:pull",
+                        _ => "repository:library/alpine:pull",
+                    };
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge(&host, scope))],
+                    )
+                        .into_response()
+                }),
+            );
+        let url = crate::test_support::spawn_upstream(router).await;
+        let client = retrying_client(1);
+        let repo = local_repo(url);
+
+        let response = client.get_manifest(&repo, "latest").await.unwrap();
+        assert_eq!(response.0, "{}");
+        {
+            let queries = token_queries.lock().unwrap();
+            assert_eq!(queries.len(), 2);
+            assert!(queries[1].contains("scope=repository%3Alibrary%2Falpine%3Apull"));
+            assert!(queries[1].contains("scope=repository%3Alibrary%2Fbase%3Apull"));
+        }
+
+        // The widened token is cached and reused.
+        client.get_manifest(&repo, "latest").await.unwrap();
+        assert_eq!(token_queries.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_token_requests_capped_when_every_challenge_adds_scope() {
+        use axum::http::{header, HeaderMap, StatusCode};
+
+        let token_requests = Arc::new(std::sync::Mutex::new(0));
+        let counter = token_requests.clone();
+        let router = axum::Router::new()
+            .route(
+                "/token",
+                axum::routing::get(move || {
+                    let mut requests = counter.lock().unwrap();
+                    *requests += 1;
+                    let token = format!("token-{}", requests);
+                    async move { axum::Json(serde_json::json!({ "token": token })) }
+                }),
+            )
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(move |headers: HeaderMap| async move {
+                    let host = headers[header::HOST].to_str().unwrap().to_string();
+                    let token = headers
+                        .get(header::AUTHORIZATION)
+                        .map(|value| value.to_str().unwrap().to_string())
+                        .unwrap_or_default();
+                    let challenge = format!(
+                        r#"Bearer realm="http://{}/token",service="test",scope="repository:{}:pull""#,
+                        host,
+                        token.replace(' ', "-")
+                    );
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge)],
+                    )
+                }),
+            );
+        let url = crate::test_support::spawn_upstream(router).await;
+
+        retrying_client(1)
+            .get_manifest(&local_repo(url), "latest")
+            .await
+            .ok();
+        assert_eq!(*token_requests.lock().unwrap(), MAX_TOKEN_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_token_requested_with_credential_matching_scope() {
+        use crate::config::{RegistryCredential, UpstreamAuth};
+        use axum::http::{header, HeaderMap, StatusCode, Uri};
+        use axum::response::IntoResponse;
+
+        let token_requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = token_requests.clone();
+        let manifest = |uri: Uri, headers: HeaderMap| async move {
+            if headers.contains_key(header::AUTHORIZATION) {
+                return "{}".into_response();
+            }
+            let name = uri.path()["/v2/".len()..]
+                .trim_end_matches("/manifests/latest")
+                .to_string();
+            let challenge = format!(
+                r#"Bearer realm="http://{}/token",service="test",scope="repository:{}:pull""#,
+                headers[header::HOST].to_str().unwrap(),
+                name
+            );
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, challenge)],
+            )
+                .into_response()
+        };
+        let router = axum::Router::new()
+            .route(
+                "/token",
+                axum::routing::get(move |headers: HeaderMap| async move {
+                    let authorization = headers[header::AUTHORIZATION].to_str().unwrap();
+                    recorded.lock().unwrap().push(authorization.to_string());
+                    axum::Json(serde_json::json!({ "token": "token" }))
+                }),
+            )
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(manifest),
+            )
+            .route(
+                "/v2/team/app/manifests/latest",
+                axum::routing::get(manifest),
+            );
+        let url = crate::test_support::spawn_upstream(router).await;
+
+        let credential = |username: &str, repositories: &[&str]| RegistryCredential {
+            repositories: repositories.iter().map(|r| r.to_string()).collect(),
+            ..UpstreamAuth {
+                username: username.to_string(),
+                password: "secret".to_string(),
+                refresh_token: None,
+                client_id: None,
+            }
+            .into()
+        };
+        let credentials = vec![credential("reader", &[]), credential("team", &["team/*"])];
+        let client = retrying_client(1);
+        for upstream_name in ["team/app", "library/alpine"] {
+            let repo = ResolvedRepository {
+                upstream_name: upstream_name.to_string(),
+                credentials: credentials.clone(),
+                ..local_repo(url.clone())
+            };
+            client.get_manifest(&repo, "latest").await.unwrap();
+        }
+
+        use base64::Engine;
+        let basic = |username: &str| {
+            let credentials = format!("{}:secret", username);
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        };
         assert_eq!(
-            params.get("scope").unwrap(),
-            "repository:library/alpine:pull"
+            *token_requests.lock().unwrap(),
+            [basic("team"), basic("reader")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cross_repository_scope_keeps_repository_token() {
+        use crate::config::RegistryCredential;
+        use axum::http::{header, HeaderMap, StatusCode, Uri};
+        use axum::response::IntoResponse;
+        use base64::Engine;
+
+        // Tokens name the credential and scopes they were issued for.
+        let token_requests = Arc::new(std::sync::Mutex::new(0));
+        let counter = token_requests.clone();
+        let token = move |headers: HeaderMap, uri: Uri| {
+            *counter.lock().unwrap() += 1;
+            let basic = headers[header::AUTHORIZATION].to_str().unwrap();
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(basic.trim_start_matches("Basic "))
+                .unwrap();
+            let username = String::from_utf8(decoded).unwrap();
+            let username = username.split(':').next().unwrap().to_string();
+            let token = format!("{}|{}", username, uri.query().unwrap_or_default());
+            async move { axum::Json(serde_json::json!({ "token": token })) }
+        };
+        // `latest` needs the team credential; `cross` also needs a scope on
+        // another repository.
+        let manifest = |required: &'static str, scope: &'static str| {
+            move |headers: HeaderMap| async move {
+                let token = headers
+                    .get(header::AUTHORIZATION)
+                    .map(|value| value.to_str().unwrap().to_string())
+                    .unwrap_or_default();
+                if token.contains(required) {
+                    return "{}".into_response();
+                }
+                let challenge = format!(
+                    r#"Bearer realm="http://{}/token",service="test",scope="{}""#,
+                    headers[header::HOST].to_str().unwrap(),
+                    scope
+                );
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, challenge)],
+                )
+                    .into_response()
+            }
+        };
+        let router = axum::Router::new()
+            .route("/token", axum::routing::get(token))
+            .route(
+                "/v2/team/app/manifests/latest",
+                axum::routing::get(manifest("Bearer team|", "repository:team/app:pull")),
+            )
+            .route(
+                "/v2/team/app/manifests/cross",
+                axum::routing::get(manifest("library%2Fbase", "repository:library/base:pull")),
+            );
+        let url = crate::test_support::spawn_upstream(router).await;
+
+        let credential = |username: &str, repositories: &[&str]| RegistryCredential {
+            repositories: repositories.iter().map(|r| r.to_string()).collect(),
+            ..UpstreamAuth {
+                username: username.to_string(),
+                password: "secret".to_string(),
+                refresh_token: None,
+                client_id: None,
+            }
+            .into()
+        };
+        let repo = ResolvedRepository {
+            upstream_name: "team/app".to_string(),
+            credentials: vec![credential("reader", &[]), credential("team", &["team/*"])],
+            ..local_repo(url)
+        };
+        let client = retrying_client(1);
+
+        for reference in ["latest", "cross", "latest"] {
+            let (body, _) = client.get_manifest(&repo, reference).await.unwrap();
+            assert_eq!(body, "{}", "pulling {}", reference);
+        }
+        assert_eq!(*token_requests.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_exchanged_for_expiring_access_token() {
+        use crate::config::UpstreamAuth;
+        use axum::http::{header, HeaderMap, StatusCode};
+        use axum::response::IntoResponse;
+
+        let grants = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = grants.clone();
+        let router = axum::Router::new()
+            .route(
+                "/token",
+                axum::routing::post(
+                    move |axum::Form(form): axum::Form<HashMap<String, String>>| async move {
+                        let mut grants = recorded.lock().unwrap();
+                        grants.push(form);
+                        // The first token expires within the safety margin.
+                        let expires_in = if grants.len() == 1 { 5 } else { 3600 };
+                        axum::Json(serde_json::json!({
+                            "access_token": format!("access-{}", grants.len()),
+                            "expires_in": expires_in,
+                        }))
+                    },
+                ),
+            )
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(move |headers: HeaderMap| async move {
+                    if headers.contains_key(header::AUTHORIZATION) {
+                        return "{}".into_response();
+                    }
+                    let challenge = format!(
+                        r#"Bearer realm="http://{}/token",service="gitlab",scope="repository:library/alpine:pull""#,
+                        headers[header::HOST].to_str().unwrap()
+                    );
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge)],
+                    )
+                        .into_response()
+                }),
+            );
+        let url = crate::test_support::spawn_upstream(router).await;
+        let repo = ResolvedRepository {
+            credentials: vec![UpstreamAuth {
+                username: "robot".to_string(),
+                password: String::new(),
+                refresh_token: Some("offline-token".to_string()),
+                client_id: Some("proxy-client".to_string()),
+            }
+            .into()],
+            ..local_repo(url)
+        };
+        let client = retrying_client(1);
+        for _ in 0..3 {
+            client.get_manifest(&repo, "latest").await.unwrap();
+        }
+
+        let grants = grants.lock().unwrap();
+        assert_eq!(grants.len(), 2);
+        let expected: HashMap<String, String> = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", "offline-token"),
+            ("client_id", "proxy-client"),
+            ("scope", "repository:library/alpine:pull"),
+            ("service", "gitlab"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(grants[0], expected);
+    }
+
+    #[cfg(feature = "gcp")]
+    #[tokio::test]
+    async fn test_gcp_access_token_sent_to_token_service() {
+        use axum::http::{header, HeaderMap, StatusCode};
+        use axum::response::IntoResponse;
+
+        let token_requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = token_requests.clone();
+        let router = axum::Router::new()
+            .route(
+                "/oauth/token",
+                axum::routing::post(|| async {
+                    axum::Json(serde_json::json!({
+                        "access_token": "ya29.access",
+                        "expires_in": 3600,
+                    }))
+                }),
+            )
+            .route(
+                "/token",
+                axum::routing::get(move |headers: HeaderMap| async move {
+                    let authorization = headers[header::AUTHORIZATION].to_str().unwrap();
+                    recorded.lock().unwrap().push(authorization.to_string());
+                    axum::Json(serde_json::json!({ "token": "registry-token" }))
+                }),
+            )
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(|headers: HeaderMap| async move {
+                    if headers.contains_key(header::AUTHORIZATION) {
+                        return "{}".into_response();
+                    }
+                    let challenge = format!(
+                        r#"Bearer realm="http://{}/token",service="europe-docker.pkg.dev",scope="repository:library/alpine:pull""#,
+                        headers[header::HOST].to_str().unwrap()
+                    );
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge)],
+                    )
+                        .into_response()
+                }),
+            );
+        let url = crate::test_support::spawn_upstream(router).await;
+
+        let temp = tempfile::tempdir().unwrap();
+        let key_path = temp.path().join("key.json");
+        std::fs::write(
+            &key_path,
+            serde_json::json!({
+                "client_email": "puller@project.iam.gserviceaccount.com",
+                "private_key": include_str!("../tests/fixtures/rsa_private.pem"),
+                "token_uri": format!("{}/oauth/token", url),
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let registry: Registry = toml::from_str(&format!(
+            "id = \"gar\"\nurl = \"{}\"\nallow_http = true\n[gcp]\nservice_account_key = {:?}\n",
+            url, key_path
+        ))
+        .unwrap();
+        let client = UpstreamClient::new(
+            &UpstreamConfig::default(),
+            std::slice::from_ref(&registry),
+            DEFAULT_USER_AGENT,
+        )
+        .unwrap();
+        let repo = ResolvedRepository {
+            registry_id: "gar".to_string(),
+            ..local_repo(url)
+        };
+
+        client.get_manifest(&repo, "latest").await.unwrap();
+        use base64::Engine;
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode("oauth2accesstoken:ya29.access");
+        assert_eq!(
+            *token_requests.lock().unwrap(),
+            [format!("Basic {}", credentials)]
         );
     }
 
@@ -251,4 +1842,647 @@ mod tests {
         let params = parse_www_authenticate(header).unwrap();
         assert!(params.is_empty());
     }
+
+    #[test]
+    fn test_case_normalization_shares_token_cache_entry() {
+        let mut config = crate::test_support::test_config(
+            std::path::Path::new("/tmp/cache"),
+            r#"
+[[registries]]
+id = "dockerhub"
+url = "https://registry-1.docker.io"
+"#,
+        );
+        config.default_registry_id = Some("dockerhub".to_string());
+
+        for normalize in [false, true] {
+            config.server.normalize_repository_case = normalize;
+            let upper = config.resolve_repository("Library/Alpine").unwrap();
+            let lower = config.resolve_repository("library/alpine").unwrap();
+            assert_eq!(
+                token_cache_key(&upper.registry_url, None, &pull_scopes(&upper))
+                    == token_cache_key(&lower.registry_url, None, &pull_scopes(&lower)),
+                normalize
+            );
+        }
+    }
+
+    fn local_repo(registry_url: String) -> ResolvedRepository {
+        ResolvedRepository {
+            upstream_name: "library/alpine".to_string(),
+            registry_url,
+            ..Default::default()
+        }
+    }
+
+    /// An upstream answering manifest requests with `statuses` in turn (200
+    /// once they run out), counting the requests it receives.
+    async fn flaky_upstream(
+        statuses: Vec<(u16, Option<&'static str>)>,
+    ) -> (String, Arc<std::sync::Mutex<usize>>) {
+        let hits = Arc::new(std::sync::Mutex::new(0));
+        let counter = hits.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(move || {
+                let counter = counter.clone();
+                let statuses = statuses.clone();
+                async move {
+                    let mut hits = counter.lock().unwrap();
+                    let (status, retry_after) = statuses.get(*hits).copied().unwrap_or((200, None));
+                    *hits += 1;
+                    let mut response = axum::http::Response::builder().status(status);
+                    if let Some(retry_after) = retry_after {
+                        response = response.header("retry-after", retry_after);
+                    }
+                    response.body(axum::body::Body::from("{}")).unwrap()
+                }
+            }),
+        );
+        (crate::test_support::spawn_upstream(router).await, hits)
+    }
+
+    fn retrying_client(retry_base_delay_ms: u64) -> UpstreamClient {
+        UpstreamClient::new(
+            &UpstreamConfig {
+                max_retries: 3,
+                retry_base_delay_ms,
+                retry_max_delay_ms: 60_000,
+                ..Default::default()
+            },
+            &[],
+            DEFAULT_USER_AGENT,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_retried() {
+        let (url, hits) = flaky_upstream(vec![(503, None), (502, None)]).await;
+        let result = retrying_client(1)
+            .get_manifest(&local_repo(url), "latest")
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(*hits.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_not_retried() {
+        let (url, hits) = flaky_upstream(vec![(404, None)]).await;
+        let result = retrying_client(1)
+            .get_manifest(&local_repo(url), "latest")
+            .await;
+        assert!(matches!(result, Err(ProxyError::ManifestUnknown(_))));
+        assert_eq!(*hits.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_overrides_backoff() {
+        // The computed backoff would be at least 30s; Retry-After asks for 0.
+        let (url, hits) = flaky_upstream(vec![(503, Some("0"))]).await;
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            retrying_client(60_000).get_manifest(&local_repo(url), "latest"),
+        )
+        .await
+        .expect("Retry-After was not honored");
+        assert!(result.is_ok());
+        assert_eq!(*hits.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_beyond_max_delay_not_retried() {
+        let (url, hits) = flaky_upstream(vec![(503, Some("86400"))]).await;
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            retrying_client(1).get_manifest(&local_repo(url), "latest"),
+        )
+        .await
+        .expect("slept for the upstream's Retry-After");
+        assert!(result.is_err());
+        assert_eq!(*hits.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_rate_limit_passed_on() {
+        let (url, hits) = flaky_upstream(vec![(429, Some("30"))]).await;
+        let result = retrying_client(1)
+            .get_manifest(&local_repo(url), "latest")
+            .await;
+        assert!(matches!(result, Err(ProxyError::RateLimited(Some(30)))));
+        assert_eq!(*hits.lock().unwrap(), 1);
+
+        let response = axum::response::IntoResponse::into_response(result.unwrap_err());
+        assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "30");
+
+        let (url, _) = flaky_upstream(vec![(429, None)]).await;
+        let result = retrying_client(1)
+            .get_manifest(&local_repo(url), "latest")
+            .await;
+        assert!(matches!(result, Err(ProxyError::RateLimited(None))));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_server_error_status_kept_after_retries() {
+        let (url, hits) = flaky_upstream(vec![(503, Some("0")); 4]).await;
+        let result = retrying_client(1)
+            .get_manifest(&local_repo(url), "latest")
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProxyError::UpstreamStatus {
+                status: 503,
+                retry_after: Some(0)
+            })
+        ));
+        assert_eq!(*hits.lock().unwrap(), 4);
+
+        let (url, _) = flaky_upstream(vec![(501, None)]).await;
+        let result = retrying_client(1)
+            .get_manifest(&local_repo(url), "latest")
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProxyError::UpstreamStatus {
+                status: 501,
+                retry_after: None
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hung_upstream_times_out() {
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                "{}"
+            }),
+        );
+        let url = crate::test_support::spawn_upstream(router).await;
+        let repo = ResolvedRepository {
+            request_timeout_seconds: Some(1),
+            ..local_repo(url)
+        };
+
+        let started = std::time::Instant::now();
+        let result = retrying_client(1).get_manifest(&repo, "latest").await;
+
+        assert!(matches!(result, Err(ProxyError::GatewayTimeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            axum::response::IntoResponse::into_response(result.unwrap_err()).status(),
+            axum::http::StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_beyond_concurrency_limit_waits() {
+        const DIGEST: &str = "sha256:abc";
+        let url = crate::test_support::spawn_upstream(crate::test_support::blob_upstream(
+            DIGEST, b"layer",
+        ))
+        .await;
+        let client = Arc::new(
+            UpstreamClient::new(
+                &UpstreamConfig {
+                    max_concurrent_upstream: 1,
+                    ..Default::default()
+                },
+                &[],
+                DEFAULT_USER_AGENT,
+            )
+            .unwrap(),
+        );
+        let repo = local_repo(url);
+
+        let first = client.get_blob(&repo, DIGEST).await.unwrap();
+        let second = tokio::spawn({
+            let client = client.clone();
+            let repo = repo.clone();
+            async move { client.get_blob(&repo, DIGEST).await.unwrap().bytes().await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!second.is_finished());
+        assert_eq!((client.in_flight(), client.queued()), (1, 1));
+
+        // The slot is held until the first blob's body has been streamed.
+        let body: Vec<_> = first.bytes_stream().collect().await;
+        assert_eq!(body.len(), 1);
+        assert_eq!(second.await.unwrap().unwrap(), "layer");
+        assert_eq!((client.in_flight(), client.queued()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_to_mirror() {
+        let (primary, _) = flaky_upstream(vec![(503, None)]).await;
+        let (mirror, mirror_hits) = flaky_upstream(vec![]).await;
+        let repo = ResolvedRepository {
+            mirrors: vec!["http://127.0.0.1:1".to_string(), mirror.clone()],
+            ..local_repo(primary.clone())
+        };
+        let client = UpstreamClient::new(
+            &UpstreamConfig {
+                max_retries: 0,
+                ..Default::default()
+            },
+            &[],
+            DEFAULT_USER_AGENT,
+        )
+        .unwrap();
+
+        assert!(client.get_manifest(&repo, "latest").await.is_ok());
+        assert_eq!(*mirror_hits.lock().unwrap(), 1);
+
+        let mut expected = vec![(primary, 1), ("http://127.0.0.1:1".to_string(), 1)];
+        expected.sort();
+        assert_eq!(client.host_failures(), expected);
+
+        let scopes = pull_scopes(&repo);
+        assert_ne!(
+            token_cache_key(&repo.registry_url, None, &scopes),
+            token_cache_key(&mirror, None, &scopes)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_credentials_sent_to_mirrors_only_when_configured() {
+        use crate::config::UpstreamAuth;
+        use axum::http::{header, HeaderMap, StatusCode};
+        use axum::response::IntoResponse;
+
+        let token_requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = token_requests.clone();
+        let router = axum::Router::new()
+            .route(
+                "/token",
+                axum::routing::get(move |headers: HeaderMap| async move {
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push(headers.contains_key(header::AUTHORIZATION));
+                    axum::Json(serde_json::json!({ "token": "token" }))
+                }),
+            )
+            .route(
+                "/v2/library/alpine/manifests/latest",
+                axum::routing::get(|headers: HeaderMap| async move {
+                    if headers.contains_key(header::AUTHORIZATION) {
+                        return "{}".into_response();
+                    }
+                    let challenge = format!(
+                        r#"Bearer realm="http://{}/token",service="mirror""#,
+                        headers[header::HOST].to_str().unwrap()
+                    );
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge)],
+                    )
+                        .into_response()
+                }),
+            );
+        let mirror = crate::test_support::spawn_upstream(router).await;
+        let (primary, _) = flaky_upstream(vec![(503, None); 2]).await;
+
+        for authenticate_mirrors in [false, true] {
+            let registry: Registry = toml::from_str(&format!(
+                "id = \"hub\"\nurl = \"{}\"\nallow_http = true\nauthenticate_mirrors = {}\n",
+                primary, authenticate_mirrors
+            ))
+            .unwrap();
+            let client = UpstreamClient::new(
+                &UpstreamConfig {
+                    max_retries: 0,
+                    ..Default::default()
+                },
+                &[registry],
+                DEFAULT_USER_AGENT,
+            )
+            .unwrap();
+            let repo = ResolvedRepository {
+                registry_id: "hub".to_string(),
+                mirrors: vec![mirror.clone()],
+                credentials: vec![UpstreamAuth {
+                    username: "robot".to_string(),
+                    password: "secret".to_string(),
+                    refresh_token: None,
+                    client_id: None,
+                }
+                .into()],
+                ..local_repo(primary.clone())
+            };
+            client.get_manifest(&repo, "latest").await.unwrap();
+        }
+        assert_eq!(*token_requests.lock().unwrap(), [false, true]);
+    }
+
+    #[test]
+    fn test_upstream_path_segments_encoded() {
+        assert_eq!(encode_segment("v1.2_rc-1"), "v1.2_rc-1");
+        assert_eq!(
+            encode_segment(&format!("sha256:{}", "ab".repeat(32))),
+            format!("sha256:{}", "ab".repeat(32))
+        );
+        assert_eq!(encode_segment("../x?y=1#z"), "..%2Fx%3Fy%3D1%23z");
+        assert_eq!(encode_name("library/alpine"), "library/alpine");
+        assert_eq!(encode_name("org/a b"), "org/a%20b");
+    }
+
+    #[tokio::test]
+    async fn test_registry_clients_enforce_transport_settings() {
+        let registry: Registry = toml::from_str(
+            r#"
+id = "internal"
+url = "https://registry.internal"
+ca_certificate = "/nonexistent/ca.pem"
+"#,
+        )
+        .unwrap();
+        let error =
+            UpstreamClient::new(&UpstreamConfig::default(), &[registry], DEFAULT_USER_AGENT)
+                .err()
+                .unwrap();
+        assert!(format!("{:#}", error).contains("/nonexistent/ca.pem"));
+
+        let (url, hits) = flaky_upstream(vec![]).await;
+        let registry: Registry =
+            toml::from_str(&format!("id = \"internal\"\nurl = \"{}\"", url)).unwrap();
+        let client =
+            UpstreamClient::new(&UpstreamConfig::default(), &[registry], DEFAULT_USER_AGENT)
+                .unwrap();
+        let repo = ResolvedRepository {
+            registry_id: "internal".to_string(),
+            ..local_repo(url)
+        };
+        assert!(client.get_manifest(&repo, "latest").await.is_err());
+        assert_eq!(*hits.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_registry_pool_settings_control_connection_reuse() {
+        // Records the client port of every request, one per connection.
+        let ports = Arc::new(std::sync::Mutex::new(std::collections::HashSet::new()));
+        let seen = ports.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(
+                move |axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<
+                    std::net::SocketAddr,
+                >| {
+                    seen.lock().unwrap().insert(peer.port());
+                    async { "{}" }
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+
+        let connections = |pool_settings: &str| {
+            let registry: Registry = toml::from_str(&format!(
+                "id = \"internal\"\nurl = \"{}\"\nallow_http = true\n{}",
+                url, pool_settings
+            ))
+            .unwrap();
+            let client =
+                UpstreamClient::new(&UpstreamConfig::default(), &[registry], DEFAULT_USER_AGENT)
+                    .unwrap();
+            let repo = ResolvedRepository {
+                registry_id: "internal".to_string(),
+                ..local_repo(url.clone())
+            };
+            let ports = ports.clone();
+            async move {
+                ports.lock().unwrap().clear();
+                for _ in 0..3 {
+                    client.get_manifest(&repo, "latest").await.unwrap();
+                }
+                let count = ports.lock().unwrap().len();
+                count
+            }
+        };
+
+        assert_eq!(connections("").await, 1);
+        assert_eq!(connections("pool_max_idle_per_host = 0").await, 3);
+    }
+
+    #[test]
+    fn test_client_identity_loaded_at_startup() {
+        let registry = |certificate: &str, key: &str| -> Registry {
+            toml::from_str(&format!(
+                r#"
+id = "enterprise"
+url = "https://registry.corp.example"
+
+[tls]
+client_certificate = "{}"
+client_key = "{}"
+"#,
+                certificate, key
+            ))
+            .unwrap()
+        };
+        let config = UpstreamConfig::default();
+
+        let valid = registry(
+            "tests/fixtures/client_cert.pem",
+            "tests/fixtures/client_key.pem",
+        );
+        assert!(UpstreamClient::new(&config, &[valid], DEFAULT_USER_AGENT).is_ok());
+
+        let missing = registry(
+            "tests/fixtures/missing.pem",
+            "tests/fixtures/client_key.pem",
+        );
+        let error = UpstreamClient::new(&config, &[missing], DEFAULT_USER_AGENT)
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("tests/fixtures/missing.pem"));
+
+        // A public key is not a private key for the certificate.
+        let invalid = registry(
+            "tests/fixtures/client_cert.pem",
+            "tests/fixtures/rsa_public.pem",
+        );
+        let error = UpstreamClient::new(&config, &[invalid], DEFAULT_USER_AGENT)
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("enterprise"));
+    }
+
+    #[tokio::test]
+    async fn test_requests_sent_through_configured_proxy() {
+        // Requests to a proxy carry the upstream URL in absolute form; the
+        // fake proxy answers them directly.
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(move |request: axum::extract::Request| {
+                let recorded = recorded.clone();
+                async move {
+                    let authorization = request
+                        .headers()
+                        .get(axum::http::header::PROXY_AUTHORIZATION)
+                        .map(|value| value.to_str().unwrap().to_string());
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push((request.uri().to_string(), authorization));
+                    "{}"
+                }
+            }),
+        );
+        let proxy_url = crate::test_support::spawn_upstream(router).await;
+
+        let registry: Registry = toml::from_str(&format!(
+            r#"
+id = "external"
+url = "http://registry.external.example"
+allow_http = true
+
+[proxy]
+http_proxy = "{}"
+no_proxy = "internal.example"
+auth = {{ username = "egress", password = "secret" }}
+"#,
+            proxy_url
+        ))
+        .unwrap();
+        let client =
+            UpstreamClient::new(&UpstreamConfig::default(), &[registry], DEFAULT_USER_AGENT)
+                .unwrap();
+        let repo = ResolvedRepository {
+            registry_id: "external".to_string(),
+            ..local_repo("http://registry.external.example".to_string())
+        };
+
+        assert!(client.get_manifest(&repo, "latest").await.is_ok());
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            seen[0].0,
+            "http://registry.external.example/v2/library/alpine/manifests/latest"
+        );
+        use base64::Engine;
+        let credentials = base64::engine::general_purpose::STANDARD.encode("egress:secret");
+        assert_eq!(seen[0].1, Some(format!("Basic {}", credentials)));
+    }
+
+    #[tokio::test]
+    async fn test_manifest_accept_follows_configured_preference() {
+        const DOCKER: &str = "application/vnd.docker.distribution.manifest.v2+json";
+        const OCI: &str = "application/vnd.oci.image.manifest.v1+json";
+
+        // Serves whichever supported representation the client ranks first.
+        let router = axum::Router::new().route(
+            "/v2/library/alpine/manifests/latest",
+            axum::routing::get(|headers: axum::http::HeaderMap| async move {
+                let accept = headers[axum::http::header::ACCEPT]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let chosen = accept
+                    .split(',')
+                    .map(|entry| entry.split(';').next().unwrap().trim())
+                    .find(|media_type| [DOCKER, OCI].contains(media_type))
+                    .unwrap()
+                    .to_string();
+                ([(axum::http::header::CONTENT_TYPE, chosen)], accept)
+            }),
+        );
+        let url = crate::test_support::spawn_upstream(router).await;
+
+        let fetch = |media_types: Vec<&str>| {
+            let config = UpstreamConfig {
+                manifest_media_types: media_types.into_iter().map(String::from).collect(),
+                ..Default::default()
+            };
+            let repo = local_repo(url.clone());
+            async move {
+                UpstreamClient::new(&config, &[], DEFAULT_USER_AGENT)
+                    .unwrap()
+                    .get_manifest(&repo, "latest")
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let (accept, content_type) = fetch(vec![DOCKER, OCI]).await;
+        assert_eq!(content_type, DOCKER);
+        assert_eq!(accept, format!("{}, {};q=0.9", DOCKER, OCI));
+
+        let (accept, content_type) = fetch(vec![OCI, DOCKER]).await;
+        assert_eq!(content_type, OCI);
+        assert_eq!(accept, format!("{}, {};q=0.9", OCI, DOCKER));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+
+        headers.insert(header::RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_next_page_query() {
+        assert_eq!(
+            next_page_query(r#"</v2/library/alpine/tags/list?n=2&last=3.19>; rel="next""#)
+                .as_deref(),
+            Some("n=2&last=3.19")
+        );
+        assert_eq!(
+            next_page_query(r#"<https://a.example/prev?n=2>; rel="prev", <https://a.example/next?last=b>; rel=next"#)
+                .as_deref(),
+            Some("last=b")
+        );
+        assert_eq!(
+            next_page_query(r#"</v2/x/tags/list?n=2>; rel="prev""#),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overlong_url_rejected_locally() {
+        let client = UpstreamClient::new(
+            &UpstreamConfig {
+                max_url_length: 64,
+                ..Default::default()
+            },
+            &[],
+            DEFAULT_USER_AGENT,
+        )
+        .unwrap();
+        let repo = ResolvedRepository {
+            upstream_name: "library/alpine".to_string(),
+            // Unroutable address: the request must fail before anything is sent.
+            registry_url: "http://0.0.0.0:1".to_string(),
+            ..Default::default()
+        };
+
+        let long_reference = "a".repeat(128);
+        let result = client.get_manifest(&repo, &long_reference).await;
+        assert!(matches!(result, Err(ProxyError::BadRequest(_))));
+
+        let result = client
+            .get_blob(&repo, &format!("sha256:{}", long_reference))
+            .await;
+        assert!(matches!(result, Err(ProxyError::BadRequest(_))));
+    }
 }
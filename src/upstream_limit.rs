@@ -0,0 +1,112 @@
+//! Bounds the upstream fetches in flight across all registries, so that a
+//! burst of cache misses queues for a slot instead of opening a connection
+//! each and exhausting rate limits or file descriptors.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub struct ConcurrencyLimit {
+    /// `None` when fetches are unlimited.
+    semaphore: Option<Arc<Semaphore>>,
+    in_flight: Arc<AtomicUsize>,
+    queued: AtomicUsize,
+}
+
+/// A fetch's place among those in flight, held until the fetch's response
+/// has been read or dropped.
+pub struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a fetch as queued until it gets its slot or is abandoned.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Allows `max` fetches at once; 0 allows any number.
+    pub fn new(max: usize) -> Self {
+        Self {
+            semaphore: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits until fewer than the maximum fetches are in flight.
+    pub async fn acquire(&self) -> Slot {
+        let permit = match &self.semaphore {
+            Some(semaphore) => {
+                self.queued.fetch_add(1, Ordering::Relaxed);
+                let _queued = Queued(&self.queued);
+                let permit = semaphore.clone().acquire_owned().await;
+                Some(permit.expect("the semaphore is never closed"))
+            }
+            None => None,
+        };
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Slot {
+            _permit: permit,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_fetch_beyond_limit_waits_for_a_slot() {
+        let limit = Arc::new(ConcurrencyLimit::new(2));
+        let first = limit.acquire().await;
+        let _second = limit.acquire().await;
+        assert_eq!(limit.in_flight(), 2);
+
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                let _slot = limit.acquire().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        assert_eq!(limit.queued(), 1);
+
+        drop(first);
+        waiting.await.unwrap();
+        assert_eq!(limit.queued(), 0);
+        assert_eq!(limit.in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_zero_is_unlimited() {
+        let limit = ConcurrencyLimit::new(0);
+        let slots: Vec<_> = futures::future::join_all((0..100).map(|_| limit.acquire())).await;
+        assert_eq!(limit.in_flight(), 100);
+        assert_eq!(limit.queued(), 0);
+        drop(slots);
+        assert_eq!(limit.in_flight(), 0);
+    }
+}
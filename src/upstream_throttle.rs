@@ -0,0 +1,164 @@
+//! Caps the proxy's own request rate to each upstream registry, so that all
+//! clients together stay under provider quotas such as Docker Hub's pull
+//! limits. Requests over the rate wait briefly for their turn; when the wait
+//! would be too long they are refused instead.
+
+use crate::config::Registry;
+use crate::error::{ProxyError, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+struct Bucket {
+    per_second: f64,
+    capacity: f64,
+    /// Goes negative while requests are queued for future tokens.
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct UpstreamThrottle {
+    /// Buckets for registries with a `max_requests_per_second`.
+    buckets: HashMap<String, Mutex<Bucket>>,
+    max_wait: Duration,
+    throttled: Mutex<HashMap<String, u64>>,
+}
+
+impl UpstreamThrottle {
+    pub fn new(registries: &[Registry], max_wait: Duration) -> Self {
+        let now = Instant::now();
+        let buckets = registries
+            .iter()
+            .filter_map(|registry| {
+                let per_second = registry.max_requests_per_second?;
+                // Allow one second's worth of requests at once, and at least one.
+                let capacity = per_second.max(1.0);
+                let bucket = Bucket {
+                    per_second,
+                    capacity,
+                    tokens: capacity,
+                    updated: now,
+                };
+                Some((registry.id.clone(), Mutex::new(bucket)))
+            })
+            .collect();
+        Self {
+            buckets,
+            max_wait,
+            throttled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a request to `registry_id` is within its rate. Fails
+    /// without waiting when the request would have to wait longer than the
+    /// configured maximum.
+    pub async fn acquire(&self, registry_id: &str) -> Result<()> {
+        let Some(bucket) = self.buckets.get(registry_id) else {
+            return Ok(());
+        };
+        let wait = {
+            let mut bucket = bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * bucket.per_second).min(bucket.capacity);
+            bucket.updated = now;
+
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens).max(0.0) / bucket.per_second);
+            if wait > self.max_wait {
+                drop(bucket);
+                self.record_throttled(registry_id);
+                warn!(
+                    "Upstream rate limit of registry {} reached; refusing request ({:?} wait)",
+                    registry_id, wait
+                );
+                return Err(ProxyError::RateLimited(Some(
+                    wait.as_secs_f64().ceil() as u64
+                )));
+            }
+            bucket.tokens -= 1.0;
+            wait
+        };
+
+        if !wait.is_zero() {
+            self.record_throttled(registry_id);
+            debug!(
+                "Upstream rate limit of registry {} reached; delaying request by {:?}",
+                registry_id, wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    fn record_throttled(&self, registry_id: &str) {
+        *self
+            .throttled
+            .lock()
+            .unwrap()
+            .entry(registry_id.to_string())
+            .or_default() += 1;
+    }
+
+    /// Requests delayed or refused per registry id, sorted by id.
+    pub fn throttled(&self) -> Vec<(String, u64)> {
+        let mut throttled: Vec<_> = self
+            .throttled
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(registry, count)| (registry.clone(), *count))
+            .collect();
+        throttled.sort();
+        throttled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(id: &str, max_requests_per_second: Option<f64>) -> Registry {
+        let mut registry: Registry = toml::from_str(&format!(
+            "id = \"{}\"\nurl = \"https://{}.example\"",
+            id, id
+        ))
+        .unwrap();
+        registry.max_requests_per_second = max_requests_per_second;
+        registry
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_rate_refused_when_wait_too_long() {
+        let throttle = UpstreamThrottle::new(
+            &[registry("hub", Some(2.0)), registry("ghcr", None)],
+            Duration::ZERO,
+        );
+
+        for _ in 0..2 {
+            throttle.acquire("hub").await.unwrap();
+        }
+        assert!(matches!(
+            throttle.acquire("hub").await,
+            Err(ProxyError::RateLimited(Some(1)))
+        ));
+        for _ in 0..10 {
+            throttle.acquire("ghcr").await.unwrap();
+        }
+        assert_eq!(throttle.throttled(), [("hub".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_rate_queued_briefly() {
+        let throttle =
+            UpstreamThrottle::new(&[registry("hub", Some(50.0))], Duration::from_secs(1));
+
+        let started = Instant::now();
+        for _ in 0..52 {
+            throttle.acquire("hub").await.unwrap();
+        }
+        // The two requests beyond the burst wait 20ms each.
+        assert!(started.elapsed() >= Duration::from_millis(35));
+        assert_eq!(throttle.throttled(), [("hub".to_string(), 2)]);
+    }
+}
@@ -0,0 +1,68 @@
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::{routing::get, Router};
+use docker_registry_proxy::{build_state, router_with_state, Config};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::json;
+use tower::ServiceExt;
+
+const DIGEST: &str = "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85";
+
+#[tokio::test]
+async fn test_embedded_router_serves_cached_blob() {
+    let temp = tempfile::TempDir::new().unwrap();
+    let config = Config::from_toml(&format!(
+        r#"
+[server]
+
+[auth]
+jwt_secret = "test-secret"
+
+[cache]
+directory = "{}"
+max_size_bytes = 1048576
+max_age_seconds = 3600
+
+[[registries]]
+id = "hub"
+url = "https://registry-1.docker.io"
+
+[[repositories]]
+name = "alpine"
+registry_id = "hub"
+upstream_name = "library/alpine"
+"#,
+        temp.path().display()
+    ))
+    .unwrap();
+
+    let state = build_state(config).await.unwrap();
+    state
+        .cache
+        .put(DIGEST, bytes::Bytes::from_static(b"layer"), None)
+        .await
+        .unwrap();
+    let app = Router::new()
+        .route("/app", get(|| async { "host application" }))
+        .merge(router_with_state(state.clone()).await.unwrap());
+
+    let token = encode(
+        &Header::default(),
+        &json!({ "sub": "embedder", "access": { "type": "all" } }),
+        &EncodingKey::from_secret(b"test-secret"),
+    )
+    .unwrap();
+    let request = Request::get(format!("/v2/alpine/blobs/{DIGEST}"))
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"layer");
+
+    let request = Request::get("/app").body(Body::empty()).unwrap();
+    assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::OK);
+}